@@ -0,0 +1,460 @@
+//! Canonical workloads for timing the interpreter, so performance work has
+//! the same baselines to compare against from one change to the next.
+//!
+//! Each workload is a `Function` built directly in bytecode, taking no
+//! arguments and returning an `Integer` checksum that is known up front,
+//! so a timing run also catches a change that made it fast by making it
+//! wrong. The bytecode has no ops for lists, computed table keys or string
+//! concatenation, so the workloads call small natives defined here for
+//! those; time spent in them counts toward the workload.
+
+use std::time::{Duration, Instant};
+
+use crate::bytecode::ops::*;
+use crate::bytecode::Op;
+use crate::datamodel::{Function, List, NativeFn, Str, Table, Tuple, Value};
+use crate::natives::{call_order, int_arg, str_arg};
+use crate::{VirtualMachine, VmError};
+
+pub struct Workload {
+    pub name: &'static str,
+    pub main: Function,
+    /// What `main` returns when run correctly.
+    pub expected: i64,
+}
+
+/// Every workload, at sizes that take a few milliseconds each in a release
+/// build.
+pub fn workloads() -> Vec<Workload> {
+    vec![
+        fib(20),
+        list_sort(300),
+        map_churn(200, 32),
+        string_build(2_000),
+    ]
+}
+
+/// Naive recursive Fibonacci, which is almost entirely calls and integer
+/// arithmetic.
+pub fn fib(n: u32) -> Workload {
+    // fib(f, n) = n < 2 ? n : f(f, n - 1) + f(f, n - 2), passing itself
+    // along since a function can't hold a constant of itself
+    let mut a = Asm::default();
+    let recurse = a.label();
+    a.op(Store(1)).op(Store(2));
+    a.op(Load(2)).op(Push(Value::Integer(2))).op(Lt);
+    a.jump(recurse, JumpIfNot(0));
+    a.op(Load(2)).op(Return);
+    a.bind(recurse);
+    for k in [1, 2] {
+        a.op(Load(1))
+            .op(Load(2))
+            .op(Push(Value::Integer(k)))
+            .op(Sub);
+        a.op(Load(1)).op(Call(2));
+    }
+    a.op(Add).op(Return);
+    let f: Value = a.finish().into();
+    let main = function(vec![
+        Push(f.clone()).into(),
+        Push(Value::Integer(n as i64)).into(),
+        Push(f).into(),
+        Call(2).into(),
+        Return.into(),
+    ]);
+    let (mut x, mut y) = (0i64, 1i64);
+    for _ in 0..n {
+        (x, y) = (y, x + y);
+    }
+    Workload {
+        name: "fib",
+        main,
+        expected: x,
+    }
+}
+
+/// Scrambled values used to fill the list in `list_sort`.
+fn scrambled(i: i64) -> i64 {
+    i * 7919 % 1009
+}
+
+/// Insertion sort of `len` (at least 2) scrambled integers in a `List`,
+/// which is mostly loads, compares and native calls. Returns the sum of
+/// each element times its index, which is only right if the list ended up
+/// sorted.
+pub fn list_sort(len: usize) -> Workload {
+    let len = len.max(2) as i64;
+    // locals: 1 list, 2 i, 3 len, 4 j, 5 key, 6 element, 7 sum
+    let mut a = Asm::default();
+    a.native(list_new, 0).op(Store(1));
+    a.op(Push(Value::Integer(len))).op(Store(3));
+    a.op(Push(Value::Integer(0))).op(Store(2));
+    let fill = a.here();
+    a.op(Load(1));
+    a.op(Load(2)).op(Push(Value::Integer(7919))).op(Mul);
+    a.op(Push(Value::Integer(1009))).op(Rem);
+    a.native(list_push, 2).op(Pop);
+    a.inc_jump_lt(2, 3, fill);
+
+    a.op(Push(Value::Integer(1))).op(Store(2));
+    let outer = a.here();
+    a.op(Load(1)).op(Load(2)).native(list_get, 2).op(Store(5));
+    a.op(Load(2))
+        .op(Push(Value::Integer(1)))
+        .op(Sub)
+        .op(Store(4));
+    let inner = a.here();
+    let place = a.label();
+    a.op(Load(4)).op(Push(Value::Integer(0))).op(Lt);
+    a.jump(place, JumpIf(0));
+    a.op(Load(1)).op(Load(4)).native(list_get, 2).op(Store(6));
+    a.op(Load(5)).op(Load(6)).op(Lt);
+    a.jump(place, JumpIfNot(0));
+    a.op(Load(1))
+        .op(Load(4))
+        .op(Push(Value::Integer(1)))
+        .op(Add);
+    a.op(Load(6)).native(list_set, 3).op(Pop);
+    a.op(Load(4))
+        .op(Push(Value::Integer(1)))
+        .op(Sub)
+        .op(Store(4));
+    a.jump(inner, Jump(0));
+    a.bind(place);
+    a.op(Load(1))
+        .op(Load(4))
+        .op(Push(Value::Integer(1)))
+        .op(Add);
+    a.op(Load(5)).native(list_set, 3).op(Pop);
+    a.inc_jump_lt(2, 3, outer);
+
+    a.op(Push(Value::Integer(0))).op(Store(7));
+    a.op(Push(Value::Integer(0))).op(Store(2));
+    let sum = a.here();
+    a.op(Load(1)).op(Load(2)).native(list_get, 2);
+    a.op(Load(2)).op(Mul).op(Load(7)).op(Add).op(Store(7));
+    a.inc_jump_lt(2, 3, sum);
+    a.op(Load(7)).op(Return);
+
+    let mut sorted: Vec<i64> = (0..len).map(scrambled).collect();
+    sorted.sort();
+    Workload {
+        name: "list_sort",
+        main: a.finish(),
+        expected: sorted.iter().zip(0..).map(|(v, i)| v * i).sum(),
+    }
+}
+
+/// `rounds` times over, fills a new `Table` with `keys` fields and reads
+/// them all back, which is mostly allocation and shape transitions.
+/// Both counts are at least 1.
+pub fn map_churn(rounds: usize, keys: usize) -> Workload {
+    let (rounds, keys) = (rounds.max(1) as i64, keys.max(1) as i64);
+    // locals: 1 round, 2 rounds, 3 i, 4 keys, 5 table, 6 sum
+    let mut a = Asm::default();
+    a.op(Push(Value::Integer(0))).op(Store(1));
+    a.op(Push(Value::Integer(rounds))).op(Store(2));
+    a.op(Push(Value::Integer(keys))).op(Store(4));
+    a.op(Push(Value::Integer(0))).op(Store(6));
+    let round = a.here();
+    a.op(NewTable).op(Store(5));
+    a.op(Push(Value::Integer(0))).op(Store(3));
+    let fill = a.here();
+    a.op(Load(5))
+        .op(Load(3))
+        .op(Push(Value::Integer(7)))
+        .op(Mul);
+    a.op(Load(3)).native(map_set, 3).op(Pop);
+    a.inc_jump_lt(3, 4, fill);
+    a.op(Push(Value::Integer(0))).op(Store(3));
+    let read = a.here();
+    a.op(Load(5))
+        .op(Load(3))
+        .op(Push(Value::Integer(7)))
+        .op(Mul);
+    a.native(map_get, 2).op(Load(6)).op(Add).op(Store(6));
+    a.inc_jump_lt(3, 4, read);
+    a.inc_jump_lt(1, 2, round);
+    a.op(Load(6)).op(Return);
+    Workload {
+        name: "map_churn",
+        main: a.finish(),
+        expected: rounds * keys * (keys - 1) / 2,
+    }
+}
+
+/// Builds a string of `len` (at least 1) pieces by repeated
+/// concatenation, which is mostly copying. Returns its length in bytes.
+pub fn string_build(len: usize) -> Workload {
+    let len = len.max(1) as i64;
+    // locals: 1 string, 2 i, 3 len
+    let mut a = Asm::default();
+    a.op(Push(Value::Str("".into()))).op(Store(1));
+    a.op(Push(Value::Integer(0))).op(Store(2));
+    a.op(Push(Value::Integer(len))).op(Store(3));
+    let append = a.here();
+    a.op(Load(1)).op(Push(Value::Str("ab".into())));
+    a.native(str_concat, 2).op(Store(1));
+    a.inc_jump_lt(2, 3, append);
+    a.op(Load(1)).native(str_len, 1).op(Return);
+    Workload {
+        name: "string_build",
+        main: a.finish(),
+        expected: 2 * len,
+    }
+}
+
+/// How long the runs of a workload took.
+#[derive(Clone)]
+pub struct Timing {
+    pub runs: u32,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    /// Ops executed in each run.
+    pub steps: u64,
+}
+
+impl Timing {
+    pub fn mean(&self) -> Duration {
+        self.total / self.runs.max(1)
+    }
+}
+
+pub enum BenchError {
+    Vm(VmError),
+    /// The workload returned something other than its expected checksum.
+    Mismatch(Value),
+}
+
+/// Runs `workload` `runs` times, each in a new VM that `configure` gets to
+/// set up first (to turn on leaf calls or tiering, say). Only the run
+/// itself is timed.
+pub fn measure(
+    workload: &Workload,
+    runs: u32,
+    mut configure: impl FnMut(&mut VirtualMachine),
+) -> Result<Timing, BenchError> {
+    let mut timing = Timing {
+        runs,
+        total: Duration::ZERO,
+        min: Duration::MAX,
+        max: Duration::ZERO,
+        steps: 0,
+    };
+    for _ in 0..runs {
+        let mut vm = VirtualMachine::new(workload.main.clone());
+        configure(&mut vm);
+        let start = Instant::now();
+        let result = vm.run_until_exited();
+        let elapsed = start.elapsed();
+        match result.map_err(BenchError::Vm)? {
+            Value::Integer(i) if i == workload.expected => {}
+            other => return Err(BenchError::Mismatch(other)),
+        }
+        timing.total += elapsed;
+        timing.min = timing.min.min(elapsed);
+        timing.max = timing.max.max(elapsed);
+        timing.steps = vm.usage().steps;
+    }
+    if runs == 0 {
+        timing.min = Duration::ZERO;
+    }
+    Ok(timing)
+}
+
+/// `measure` with the VM's defaults.
+pub fn time(workload: &Workload, runs: u32) -> Result<Timing, BenchError> {
+    measure(workload, runs, |_| {})
+}
+
+fn function(ops: Vec<Op>) -> Function {
+    Function {
+        module: Tuple::new(Vec::new()),
+        ops: ops.into(),
+    }
+}
+
+/// Assembles ops with labelled jumps, for workloads too long to count
+/// offsets in by hand.
+#[derive(Default)]
+struct Asm {
+    ops: Vec<Op>,
+    labels: Vec<Option<usize>>,
+    /// Jumps to patch, by op index, with the label they go to.
+    fixups: Vec<(usize, usize)>,
+}
+
+impl Asm {
+    fn op(&mut self, op: impl Into<Op>) -> &mut Asm {
+        self.ops.push(op.into());
+        self
+    }
+
+    /// A label to `bind` later.
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn bind(&mut self, label: usize) {
+        self.labels[label] = Some(self.ops.len());
+    }
+
+    /// A label bound to the next op.
+    fn here(&mut self) -> usize {
+        let label = self.label();
+        self.bind(label);
+        label
+    }
+
+    /// Emits `jump`, which is patched to go to `label`.
+    fn jump(&mut self, label: usize, jump: impl Into<Op>) -> &mut Asm {
+        self.fixups.push((self.ops.len(), label));
+        self.op(jump)
+    }
+
+    fn inc_jump_lt(&mut self, local: u8, limit: u8, label: usize) -> &mut Asm {
+        let offset = 0;
+        self.jump(
+            label,
+            IncJumpLt {
+                local,
+                limit,
+                offset,
+            },
+        )
+    }
+
+    /// Calls `native` with the `argc` values on top of the stack.
+    fn native(&mut self, native: NativeFn, argc: u8) -> &mut Asm {
+        self.op(Push(Value::NativeFn(native))).op(Call(argc))
+    }
+
+    fn finish(mut self) -> Function {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label].expect("unbound label");
+            let offset = target as i32 - at as i32 - 1;
+            match &mut self.ops[at] {
+                Op::Jump(op) => op.0 = offset,
+                Op::JumpIf(op) => op.0 = offset,
+                Op::JumpIfNot(op) => op.0 = offset,
+                Op::IncJumpLt(op) => op.offset = offset,
+                _ => unreachable!("only jumps are patched"),
+            }
+        }
+        function(self.ops)
+    }
+}
+
+fn list_new(_args: Vec<Value>) -> Value {
+    List::new(Vec::new()).into()
+}
+
+fn list_arg(args: &[Value], index: usize) -> Option<List> {
+    match args.get(index) {
+        Some(Value::List(l)) => Some(l.clone()),
+        _ => None,
+    }
+}
+
+/// `list_push(list, value)`
+fn list_push(args: Vec<Value>) -> Value {
+    let mut args = call_order(args);
+    if let (Some(list), Some(val)) = (list_arg(&args, 0), args.pop()) {
+        list.items.borrow_mut().push(val);
+    }
+    Value::None
+}
+
+/// `list_get(list, index)`
+fn list_get(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (list_arg(&args, 0), int_arg(&args, 1)) {
+        (Some(list), Some(i)) => list.items.borrow().get(i as usize).cloned(),
+        _ => None,
+    }
+    .unwrap_or(Value::None)
+}
+
+/// `list_set(list, index, value)`
+fn list_set(args: Vec<Value>) -> Value {
+    let mut args = call_order(args);
+    if let (Some(list), Some(i), Some(val)) = (list_arg(&args, 0), int_arg(&args, 1), args.pop()) {
+        if let Some(slot) = list.items.borrow_mut().get_mut(i as usize) {
+            *slot = val;
+        }
+    }
+    Value::None
+}
+
+fn table_arg(args: &[Value], index: usize) -> Option<Table> {
+    match args.get(index) {
+        Some(Value::Table(t)) => Some(t.clone()),
+        _ => None,
+    }
+}
+
+/// `map_get(table, key)`
+fn map_get(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (table_arg(&args, 0), int_arg(&args, 1)) {
+        (Some(table), Some(key)) => table.get(key as u64).unwrap_or(Value::None),
+        _ => Value::None,
+    }
+}
+
+/// `map_set(table, key, value)`
+fn map_set(args: Vec<Value>) -> Value {
+    let mut args = call_order(args);
+    if let (Some(table), Some(key), Some(val)) =
+        (table_arg(&args, 0), int_arg(&args, 1), args.pop())
+    {
+        table.set(key as u64, val);
+    }
+    Value::None
+}
+
+/// `str_concat(a, b)`
+fn str_concat(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (str_arg(&args, 0), str_arg(&args, 1)) {
+        (Some(a), Some(b)) => Value::Str(Str::from(format!("{}{}", a, b))),
+        _ => Value::None,
+    }
+}
+
+/// `str_len(s)`, in bytes.
+fn str_len(args: Vec<Value>) -> Value {
+    match str_arg(&args, 0) {
+        Some(s) => Value::Integer(s.len() as i64),
+        None => Value::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workloads_return_their_checksums() {
+        let small = [fib(10), list_sort(20), map_churn(3, 5), string_build(10)];
+        for workload in &small {
+            let timing = match time(workload, 2) {
+                Ok(timing) => timing,
+                Err(BenchError::Vm(e)) => panic!("{}: {}", workload.name, e.backtrace()),
+                Err(BenchError::Mismatch(_)) => panic!("{}: wrong result", workload.name),
+            };
+            assert_eq!(timing.runs, 2);
+            assert!(timing.min <= timing.max && timing.steps > 0);
+        }
+        // configuration reaches each run's VM
+        let mut configured = 0;
+        assert!(measure(&fib(5), 3, |vm| {
+            vm.set_leaf_calls(true);
+            configured += 1;
+        })
+        .is_ok());
+        assert_eq!(configured, 3);
+    }
+}
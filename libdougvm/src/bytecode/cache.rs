@@ -0,0 +1,53 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use crate::datamodel::{Record, Shape, Table};
+
+/// Number of shapes a single access site remembers before it starts
+/// evicting the oldest entry.
+pub const CACHE_WAYS: usize = 4;
+
+type Entry = (Weak<Shape>, usize);
+
+/// A polymorphic inline cache for one field access site, mapping table
+/// and record shapes to the slot holding the site's key. A table that gains a field
+/// moves to a new shape, so stale entries simply stop matching; entries
+/// hold the shape weakly so a freed shape's address can't be reused while
+/// it is still cached.
+#[derive(Clone, Default)]
+pub struct FieldCache {
+    entries: Rc<RefCell<Vec<Entry>>>,
+}
+
+impl FieldCache {
+    pub fn new() -> FieldCache {
+        FieldCache::default()
+    }
+
+    /// Returns the cached slot of `key` in `table`, filling the cache on a
+    /// miss. `None` means the table itself has no such field.
+    pub fn slot(&self, table: &Table, key: u64) -> Option<usize> {
+        self.slot_in(table.shape(), key)
+    }
+
+    /// As `slot`, for a record.
+    pub fn record_slot(&self, record: &Record, key: u64) -> Option<usize> {
+        self.slot_in(record.shape().clone(), key)
+    }
+
+    fn slot_in(&self, shape: Rc<Shape>, key: u64) -> Option<usize> {
+        let mut entries = self.entries.borrow_mut();
+        if let Some((_, slot)) = entries
+            .iter()
+            .find(|(s, _)| s.as_ptr() == Rc::as_ptr(&shape))
+        {
+            return Some(*slot);
+        }
+        let slot = shape.slot(key)?;
+        if entries.len() == CACHE_WAYS {
+            entries.remove(0);
+        }
+        entries.push((Rc::downgrade(&shape), slot));
+        Some(slot)
+    }
+}
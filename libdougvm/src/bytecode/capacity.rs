@@ -0,0 +1,484 @@
+//! Worst-case frame memory and call depth, worked out from the bytecode so
+//! an embedder can size its limits from the scripts it will run rather
+//! than by trial.
+//!
+//! Each function gets a `FrameInfo`: how many locals it uses and how deep
+//! its value stack can get. A `CallGraph` follows calls through `Push`
+//! constants, as `graph::call_graph_dot` does, plus any the host declares,
+//! and a `Bound` adds up the frames along the most expensive chain of
+//! calls. Values are counted as in
+//! `Usage::peak_values`, so a bound can be checked against what a run
+//! actually used.
+//!
+//! Recursion makes the depth depend on the input. Functions that can call
+//! themselves, directly or round a cycle, are reported as a `Recursion`
+//! with the cost of each trip around, which turns a value budget into a
+//! depth (see `Bound::max_frames`).
+//!
+//! Calls the analysis can't follow (a callee loaded from a local or the
+//! module, `Invoke`, `Resume`, `LoadModule`) set `opaque_calls`, and the bound leaves
+//! out whatever they run unless the host declares them.
+
+use std::collections::HashMap;
+
+use super::ops::Push;
+use super::Op;
+use crate::datamodel::{Function, Identity, Tuple, Value};
+
+/// The most a single frame of a function holds.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FrameInfo {
+    /// Locals used, the module in local 0 included.
+    pub locals: usize,
+    /// Deepest the value stack gets over the arguments it starts with,
+    /// which the caller's stack is counted as holding.
+    pub stack: usize,
+    /// Whether the function makes calls the analysis can't follow.
+    pub opaque_calls: bool,
+}
+
+impl FrameInfo {
+    pub fn values(&self) -> usize {
+        self.locals + self.stack
+    }
+}
+
+/// What running a function can cost, its own frame included.
+#[derive(Clone)]
+pub struct Bound {
+    /// Deepest the call stack gets, going round each call cycle once.
+    pub frames: usize,
+    /// Most values held at once over all frames, likewise.
+    pub values: usize,
+    /// The cheapest call cycle reachable from the function, if any.
+    pub recursion: Option<Recursion>,
+    /// Whether any function reachable from here makes opaque calls.
+    pub opaque_calls: bool,
+}
+
+/// A set of functions that can call each other round in a cycle. Every
+/// further trip round adds at most `frames` frames holding `values`.
+#[derive(Clone)]
+pub struct Recursion {
+    pub functions: Vec<Function>,
+    pub frames: usize,
+    pub values: usize,
+}
+
+impl Bound {
+    /// The deepest the call stack can get while holding at most `values`
+    /// values, or `None` if that isn't enough for even one trip round the
+    /// call chain.
+    pub fn max_frames(&self, values: usize) -> Option<usize> {
+        if values < self.values {
+            return None;
+        }
+        Some(match &self.recursion {
+            Some(r) => self.frames + (values - self.values) / r.values.max(1) * r.frames,
+            None => self.frames,
+        })
+    }
+}
+
+/// Works out a function's `FrameInfo`. Unreachable ops are ignored, and an
+/// op that would pop an empty stack is taken to leave it empty.
+pub fn frame_info(func: &Function) -> FrameInfo {
+    let ops = &func.ops;
+    let mut locals = 1;
+    let mut opaque_calls = false;
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::Load(l) => locals = locals.max(l.0 as usize + 1),
+            Op::Store(s) => locals = locals.max(s.0 as usize + 1),
+            Op::IncJumpLt(op) => locals = locals.max(op.local.max(op.limit) as usize + 1),
+            Op::AddImm(op) => locals = locals.max(op.local as usize + 1),
+            Op::AddLocals(op) => locals = locals.max(op.a.max(op.b) as usize + 1),
+            Op::MakeClosure(op) => {
+                let highest = op.locals.iter().max().map_or(0, |&l| l as usize + 1);
+                locals = locals.max(highest)
+            }
+            Op::Call(_) => {
+                let pushed = i.checked_sub(1).map(|at| &ops[at]);
+                let known = matches!(
+                    pushed,
+                    Some(Op::Push(Push(Value::Function(_) | Value::NativeFn(_))))
+                );
+                opaque_calls |= !known;
+            }
+            Op::Invoke(_) | Op::Resume(_) | Op::LoadModule(_) => opaque_calls = true,
+            _ => {}
+        }
+    }
+
+    let mut depth: Vec<Option<usize>> = vec![None; ops.len()];
+    let mut pending = vec![(0, 0)];
+    let mut stack = 0;
+    while let Some((at, d)) = pending.pop() {
+        if at >= ops.len() || depth[at].is_some_and(|seen| seen >= d) {
+            continue;
+        }
+        depth[at] = Some(d);
+        stack = stack.max(d);
+        let op = &ops[at];
+        let after = |pop: usize, push: usize| d.saturating_sub(pop) + push;
+        let next = match op {
+            Op::Return(_) | Op::Halt(_) | Op::Throw(_) => None,
+            Op::Jump(_) => Some(d),
+            Op::JumpIf(_) | Op::JumpIfNot(_) => Some(after(1, 0)),
+            Op::SwitchStr(_) | Op::Switch(_) => {
+                for offset in op.jump_offsets() {
+                    let to = (at as i64 + 1 + offset as i64) as usize;
+                    pending.push((to, d.saturating_sub(1)));
+                }
+                continue;
+            }
+            Op::ForIter(_) => {
+                // the iterator is popped when the loop ends
+                let end = (at as i64 + 1 + op.jump_offset().unwrap() as i64) as usize;
+                pending.push((end, d.saturating_sub(1)));
+                pending.push((at + 1, d + 1));
+                continue;
+            }
+            Op::IncJumpLt(_) | Op::PopHandler(_) | Op::Exit(_) => Some(d),
+            Op::Enter(_) => Some(after(2, 1)),
+            Op::PushHandler(_) => {
+                // the handler starts with the exception pushed
+                let handler = (at as i64 + 1 + op.jump_offset().unwrap() as i64) as usize;
+                pending.push((handler, d + 1));
+                Some(d)
+            }
+            Op::Call(c) => Some(after(c.0 as usize + 1, 1)),
+            Op::Invoke(i) => Some(after(i.argc as usize + 2, 1)),
+            Op::CallHost(c) => Some(after(c.argc as usize, 1)),
+            Op::Try(_) | Op::Yield(_) => Some(after(1, 1)),
+            Op::Resume(_) => Some(after(2, 1)),
+            Op::LoadModule(_) => Some(d + 1),
+            _ => op.stack_effect().map(|(pop, push)| after(pop, push)),
+        };
+        if let Some(next) = next {
+            if let (Some(offset), false) = (op.jump_offset(), matches!(op, Op::PushHandler(_))) {
+                pending.push(((at as i64 + 1 + offset as i64) as usize, next));
+            }
+            if !matches!(op, Op::Jump(_)) {
+                pending.push((at + 1, next));
+            }
+        }
+    }
+    FrameInfo {
+        locals,
+        stack,
+        opaque_calls,
+    }
+}
+
+/// The functions `func` can call: those it pushes as constants.
+fn callees(func: &Function) -> impl Iterator<Item = &Function> {
+    func.ops.iter().filter_map(|op| match op {
+        Op::Push(Push(Value::Function(f))) => Some(f),
+        _ => None,
+    })
+}
+
+/// Which functions can call which, with each one's `FrameInfo`. Calls
+/// through `Push` constants are found as functions are added; the host
+/// declares the rest with `add_call`, from what it knows of how the module
+/// is linked.
+#[derive(Default)]
+pub struct CallGraph {
+    funcs: Vec<Function>,
+    index: HashMap<usize, usize>,
+    edges: Vec<Vec<usize>>,
+    frames: Vec<FrameInfo>,
+}
+
+impl CallGraph {
+    pub fn new() -> CallGraph {
+        CallGraph::default()
+    }
+
+    /// The graph of the functions in `module`'s slots and everything they
+    /// push.
+    pub fn of_module(module: &Tuple) -> CallGraph {
+        let mut graph = CallGraph::new();
+        for i in 0..module.len() {
+            if let Some(Value::Function(f)) = module.get(i) {
+                graph.add_function(&f);
+            }
+        }
+        graph
+    }
+
+    /// Adds `func` and everything it pushes, if not already in the graph.
+    pub fn add_function(&mut self, func: &Function) -> usize {
+        if let Some(&v) = self.index.get(&func.identity()) {
+            return v;
+        }
+        let v = self.funcs.len();
+        self.index.insert(func.identity(), v);
+        self.funcs.push(func.clone());
+        self.edges.push(Vec::new());
+        self.frames.push(frame_info(func));
+        for callee in callees(func).cloned().collect::<Vec<_>>() {
+            let w = self.add_function(&callee);
+            self.edges[v].push(w);
+        }
+        v
+    }
+
+    /// Declares that `caller` can call `callee`, adding both if needed.
+    pub fn add_call(&mut self, caller: &Function, callee: &Function) {
+        let v = self.add_function(caller);
+        let w = self.add_function(callee);
+        if !self.edges[v].contains(&w) {
+            self.edges[v].push(w);
+        }
+    }
+
+    /// Replaces the worked-out `FrameInfo` of `func`, say to clear
+    /// `opaque_calls` once its calls have been declared.
+    pub fn set_frame(&mut self, func: &Function, frame: FrameInfo) {
+        let v = self.add_function(func);
+        self.frames[v] = frame;
+    }
+
+    pub fn frame(&self, func: &Function) -> Option<&FrameInfo> {
+        self.index.get(&func.identity()).map(|&v| &self.frames[v])
+    }
+
+    /// Bounds every function in the graph.
+    pub fn analyze(&self) -> Capacity {
+        let n = self.funcs.len();
+        let mut tarjan = Tarjan {
+            edges: &self.edges,
+            low: vec![0; n],
+            order: vec![None; n],
+            on_stack: vec![false; n],
+            stack: Vec::new(),
+            sccs: Vec::new(),
+        };
+        for v in 0..n {
+            if tarjan.order[v].is_none() {
+                tarjan.visit(v);
+            }
+        }
+
+        // sccs come out callees first, so each one's callees are done
+        let infos = &self.frames;
+        let mut scc_of = vec![0; n];
+        let mut scc_bounds: Vec<Bound> = Vec::new();
+        for (s, scc) in tarjan.sccs.iter().enumerate() {
+            scc.iter().for_each(|&v| scc_of[v] = s);
+            let recursive = scc.len() > 1 || self.edges[scc[0]].contains(&scc[0]);
+            let frames = scc.len();
+            let values = scc.iter().map(|&v| infos[v].values()).sum();
+            let mut bound = Bound {
+                frames,
+                values,
+                recursion: recursive.then(|| Recursion {
+                    functions: scc.iter().map(|&v| self.funcs[v].clone()).collect(),
+                    frames,
+                    values,
+                }),
+                opaque_calls: scc.iter().any(|&v| infos[v].opaque_calls),
+            };
+            let (mut deepest, mut biggest) = (0, 0);
+            for &v in scc {
+                for &w in &self.edges[v] {
+                    if scc_of[w] == s {
+                        continue;
+                    }
+                    let callee = &scc_bounds[scc_of[w]];
+                    deepest = deepest.max(callee.frames);
+                    biggest = biggest.max(callee.values);
+                    bound.opaque_calls |= callee.opaque_calls;
+                    bound.recursion = cheapest(bound.recursion.take(), callee.recursion.clone());
+                }
+            }
+            bound.frames += deepest;
+            bound.values += biggest;
+            scc_bounds.push(bound);
+        }
+        let bounds = self
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(v, func)| (func.identity(), scc_bounds[scc_of[v]].clone()))
+            .collect();
+        Capacity { bounds }
+    }
+}
+
+/// Tarjan's strongly connected components, over a graph's edges.
+struct Tarjan<'a> {
+    edges: &'a [Vec<usize>],
+    low: Vec<usize>,
+    order: Vec<Option<usize>>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    sccs: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, v: usize) {
+        let n = self.sccs.iter().map(Vec::len).sum::<usize>() + self.stack.len();
+        self.order[v] = Some(n);
+        self.low[v] = n;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+        for &w in &self.edges[v] {
+            match self.order[w] {
+                None => {
+                    self.visit(w);
+                    self.low[v] = self.low[v].min(self.low[w]);
+                }
+                Some(order) if self.on_stack[w] => self.low[v] = self.low[v].min(order),
+                Some(_) => {}
+            }
+        }
+        if Some(self.low[v]) == self.order[v] {
+            let mut scc = Vec::new();
+            while let Some(w) = self.stack.pop() {
+                self.on_stack[w] = false;
+                scc.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.sccs.push(scc);
+        }
+    }
+}
+
+/// The bound of every function in a `CallGraph`.
+pub struct Capacity {
+    bounds: HashMap<usize, Bound>,
+}
+
+impl Capacity {
+    /// What calling `func` can cost, if it is in the analysed graph.
+    pub fn bound(&self, func: &Function) -> Option<&Bound> {
+        self.bounds.get(&func.identity())
+    }
+}
+
+/// Of two cycles, the one with the cheapest trip per frame, which gets
+/// deepest on a given budget.
+fn cheapest(a: Option<Recursion>, b: Option<Recursion>) -> Option<Recursion> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(match a.values * b.frames <= b.values * a.frames {
+            true => a,
+            false => b,
+        }),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn bounds_cover_what_a_run_uses() {
+        // leaf(a, b) = a + b, stored through locals
+        let leaf = function(vec![
+            Store(1).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        assert_eq!(
+            frame_info(&leaf),
+            FrameInfo {
+                locals: 3,
+                stack: 2,
+                opaque_calls: false,
+            }
+        );
+        let main = function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(2)).into(),
+            Push(leaf.clone().into()).into(),
+            Call(2).into(),
+            Push(Value::Integer(3)).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let mut graph = CallGraph::new();
+        graph.add_function(&main);
+        assert_eq!(graph.frame(&main).unwrap().stack, 3);
+        let capacity = graph.analyze();
+        let bound = capacity.bound(&main).unwrap();
+        assert_eq!((bound.frames, bound.values), (2, 9));
+        assert!(bound.recursion.is_none() && !bound.opaque_calls);
+        assert_eq!(bound.max_frames(100), Some(2));
+
+        let mut vm = VirtualMachine::new(main);
+        vm.set_leaf_calls(false);
+        assert!(vm.run_until_exited().is_ok());
+        let usage = vm.usage();
+        assert!(usage.peak_frames <= bound.frames && usage.peak_values <= bound.values);
+    }
+
+    #[test]
+    fn recursion_is_priced_per_trip() {
+        // even calls odd, which calls even through a callee it loads, and
+        // both stop at a base case
+        let odd = function(vec![
+            Store(1).into(),
+            Load(1).into(),
+            JumpIfNot(3).into(),
+            Load(1).into(),
+            Call(0).into(),
+            Return.into(),
+            Push(Value::None).into(),
+            Return.into(),
+        ]);
+        let even = function(vec![
+            Push(odd.clone().into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let mut graph = CallGraph::new();
+        graph.add_function(&even);
+        assert!(graph.frame(&odd).unwrap().opaque_calls);
+        let bound = graph.analyze().bound(&even).unwrap().clone();
+        assert!(bound.recursion.is_none() && bound.opaque_calls);
+
+        graph.add_call(&odd, &even);
+        let frame = FrameInfo {
+            opaque_calls: false,
+            ..*graph.frame(&odd).unwrap()
+        };
+        graph.set_frame(&odd, frame);
+        let main = function(vec![
+            Push(even.clone().into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        graph.add_function(&main);
+        let capacity = graph.analyze();
+        let bound = capacity.bound(&main).unwrap();
+        assert!(!bound.opaque_calls);
+        // main (2 values), even (2) and odd (3) once round
+        assert_eq!((bound.frames, bound.values), (3, 7));
+        let recursion = bound.recursion.as_ref().unwrap();
+        assert_eq!((recursion.frames, recursion.values), (2, 5));
+        assert_eq!(recursion.functions.len(), 2);
+        assert_eq!(bound.max_frames(6), None);
+        assert_eq!(bound.max_frames(20), Some(7));
+
+        // a handler starts with the exception on the stack
+        let handles = function(vec![
+            PushHandler(2).into(),
+            PopHandler.into(),
+            Halt.into(),
+            Return.into(),
+        ]);
+        assert_eq!(frame_info(&handles).stack, 1);
+    }
+}
@@ -0,0 +1,175 @@
+//! Conditional compilation, decided when a compiled file is linked rather
+//! than when it is compiled, so one file can serve debug and release
+//! builds, or hosts with different natives available.
+//!
+//! The compiler records a `Cfg`, a condition on feature flags, for each
+//! function or block of ops that is only wanted under some features, in a
+//! `CfgTable` it saves alongside the code (see `serialize::save_with_cfg`).
+//! Loading with a set of `Features` (see `serialize::load_with_features`)
+//! then leaves out every block whose condition doesn't hold, and every
+//! function: a reference to a left-out function loads as `None`.
+//!
+//! A block must leave the stack as it found it, as an `if` body with no
+//! `else` does, so code after it runs the same either way. Jumps into a
+//! block that is left out land on the op after it.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use crate::bytecode::ops::Pop;
+use crate::bytecode::{relocate, Op};
+use crate::datamodel::{Function, Identity, Str};
+
+/// A condition on feature flags, as `cfg(...)` is in Rust.
+#[derive(Clone)]
+pub enum Cfg {
+    Flag(Str),
+    Not(Box<Cfg>),
+    /// Holds if every condition does, including when there are none.
+    All(Vec<Cfg>),
+    /// Holds if any condition does, so never when there are none.
+    Any(Vec<Cfg>),
+}
+
+impl Cfg {
+    pub fn flag(name: &str) -> Cfg {
+        Cfg::Flag(Str::from(name))
+    }
+
+    pub fn holds(&self, features: &Features) -> bool {
+        match self {
+            Cfg::Flag(name) => features.is_enabled(name),
+            Cfg::Not(cfg) => !cfg.holds(features),
+            Cfg::All(cfgs) => cfgs.iter().all(|c| c.holds(features)),
+            Cfg::Any(cfgs) => cfgs.iter().any(|c| c.holds(features)),
+        }
+    }
+}
+
+/// The feature flags code is linked with; any flag not enabled is off.
+#[derive(Default)]
+pub struct Features {
+    enabled: HashSet<Str>,
+}
+
+impl Features {
+    pub fn new() -> Features {
+        Features::default()
+    }
+
+    pub fn enable(&mut self, flag: &str) {
+        self.enabled.insert(Str::from(flag));
+    }
+
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.enabled.contains(flag)
+    }
+}
+
+/// The conditions on one function.
+#[derive(Clone, Default)]
+pub struct FunctionCfg {
+    /// When the function is included at all; always, if `None`.
+    pub when: Option<Cfg>,
+    /// Op ranges only included when their condition holds.
+    pub blocks: Vec<(Range<usize>, Cfg)>,
+}
+
+/// The conditions a compiler recorded, by function.
+#[derive(Default)]
+pub struct CfgTable {
+    /// Holding each function so its identity isn't reused.
+    functions: HashMap<usize, (Function, FunctionCfg)>,
+}
+
+impl CfgTable {
+    pub fn new() -> CfgTable {
+        CfgTable::default()
+    }
+
+    fn entry(&mut self, func: &Function) -> &mut FunctionCfg {
+        let entry = self.functions.entry(func.identity());
+        &mut entry
+            .or_insert_with(|| (func.clone(), FunctionCfg::default()))
+            .1
+    }
+
+    /// Includes `func` only when `cfg` holds.
+    pub fn function(&mut self, func: &Function, cfg: Cfg) {
+        self.entry(func).when = Some(cfg);
+    }
+
+    /// Includes ops `ops` of `func` only when `cfg` holds.
+    pub fn block(&mut self, func: &Function, ops: Range<usize>, cfg: Cfg) {
+        self.entry(func).blocks.push((ops, cfg));
+    }
+
+    pub fn get(&self, func: &Function) -> Option<&FunctionCfg> {
+        self.functions.get(&func.identity()).map(|(_, cfg)| cfg)
+    }
+}
+
+/// `ops` without the blocks whose condition doesn't hold, with jumps
+/// fixed up.
+pub fn strip(ops: &[Op], blocks: &[(Range<usize>, Cfg)], features: &Features) -> Vec<Op> {
+    let left_out: Vec<&Range<usize>> = (blocks.iter())
+        .filter(|(_, cfg)| !cfg.holds(features))
+        .map(|(range, _)| range)
+        .collect();
+    if left_out.is_empty() {
+        return ops.to_vec();
+    }
+    let kept: Vec<bool> = (0..ops.len())
+        .map(|i| !left_out.iter().any(|range| range.contains(&i)))
+        .collect();
+    // a left-out op maps to the next kept one, so jumps to it land there;
+    // it is replaced for `relocate`, which would fix its jump up in place
+    let mut map = Vec::with_capacity(ops.len() + 1);
+    let mut view = Vec::with_capacity(ops.len());
+    let mut rebuilt = Vec::new();
+    for (op, &keep) in ops.iter().zip(&kept) {
+        map.push(rebuilt.len());
+        match keep {
+            true => {
+                rebuilt.push(op.clone());
+                view.push(op.clone());
+            }
+            false => view.push(Pop.into()),
+        }
+    }
+    map.push(rebuilt.len());
+    relocate(&view, &map, &mut rebuilt);
+    rebuilt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::datamodel::Value;
+
+    #[test]
+    fn left_out_blocks_are_removed_and_jumps_fixed() {
+        let mut features = Features::new();
+        features.enable("debug");
+        let release = Cfg::Not(Box::new(Cfg::flag("debug")));
+        assert!(!release.holds(&features));
+        assert!(Cfg::All(Vec::new()).holds(&features));
+        assert!(!Cfg::Any(Vec::new()).holds(&features));
+
+        // if x { <release-only block> }; return 1
+        let ops: Vec<Op> = vec![
+            Load(1).into(),
+            JumpIfNot(2).into(),
+            Push(Value::Integer(0)).into(),
+            Pop.into(),
+            Push(Value::Integer(1)).into(),
+            Return.into(),
+        ];
+        let stripped = strip(&ops, &[(2..4, release.clone())], &features);
+        assert_eq!(stripped.len(), 4);
+        assert!(matches!(stripped[1], Op::JumpIfNot(JumpIfNot(0))));
+        assert!(matches!(stripped[2], Op::Push(Push(Value::Integer(1)))));
+        assert_eq!(strip(&ops, &[(2..4, release)], &Features::new()).len(), 6);
+    }
+}
@@ -0,0 +1,192 @@
+//! Upgrading bytecode built against an older op set. The op set has a
+//! version number; whoever stores bytecode records it alongside, and
+//! passes it to `upgrade` when loading, which runs one shim per version
+//! step to rewrite deprecated ops into current ones.
+//!
+//! When an op is changed or retired, bump `OP_SET_VERSION` and add a shim
+//! for the old version to `SHIMS`, usually a `rewrite` that expands the
+//! old op. Adding ops bumps the version too, so an older VM reports newer
+//! bytecode as too new rather than failing on an unknown op, but needs no
+//! rewriting.
+//!
+//! - 1: the first op set.
+//! - 2: adds `PushHandler`, `PopHandler` and `Throw`.
+//! - 3: adds `CallHost`.
+//! - 4: adds `Halt`. Running off the end of a function is now an error
+//!   rather than an implicit return, so functions that could get a `Halt`
+//!   appended.
+//! - 5: adds `Yield` and `Resume`.
+//! - 6: adds `LoadModule`.
+//! - 7: adds `NewRecord`, and `GetField` and `SetField` take records.
+//! - 8: adds `Intern`.
+//! - 9: adds `AddIntUnchecked` and `SubIntUnchecked`.
+//! - 10: adds `AddImm` and `AddLocals`.
+//! - 11: adds `Enter` and `Exit`.
+//! - 12: adds `NewMap`, `MapInsert`, `MapGet`, `MapDelete`, `MapContains`
+//!   and `MapIter`.
+//! - 13: adds `GetIter` and `ForIter`.
+//! - 14: adds `SwitchStr`.
+//! - 15: adds `Same`, `DeepEq` and `Cmp`.
+//! - 16: adds `Switch`.
+//! - 17: adds `MakeClosure` and `LoadUpvalue`.
+
+use std::collections::HashMap;
+
+use crate::bytecode::ops::{Halt, Push};
+use crate::bytecode::verify::reaches_end;
+use crate::bytecode::{relocate, Op};
+use crate::datamodel::{Function, Identity, Value};
+
+/// The version of the current op set.
+pub const OP_SET_VERSION: u32 = 17;
+
+/// Rewrites a function from op set version `n` to `n + 1`.
+pub type Shim = fn(&Function) -> Function;
+
+/// `SHIMS[i]` upgrades version `i + 1` to `i + 2`.
+const SHIMS: [Shim; OP_SET_VERSION as usize - 1] = [
+    unchanged, unchanged, add_halts, unchanged, unchanged, unchanged, unchanged, unchanged,
+    unchanged, unchanged, unchanged, unchanged, unchanged, unchanged, unchanged, unchanged,
+];
+
+/// The shim for versions that only added ops.
+fn unchanged(func: &Function) -> Function {
+    func.clone()
+}
+
+/// Makes the implicit return off the end of version 3 functions explicit.
+fn add_halts(func: &Function) -> Function {
+    let tail = |ops: &[Op]| match reaches_end(ops) {
+        true => vec![Halt.into()],
+        false => Vec::new(),
+    };
+    transform(func, &|_| None, &tail, &mut HashMap::new())
+}
+
+pub enum CompatError {
+    /// Built for an op set this version of the crate doesn't know yet.
+    TooNew(u32),
+    /// Version 0 was never used.
+    Unknown(u32),
+}
+
+/// Brings `func` (and the functions it references) from op set `version`
+/// up to `OP_SET_VERSION`.
+pub fn upgrade(func: &Function, version: u32) -> Result<Function, CompatError> {
+    match version {
+        0 => Err(CompatError::Unknown(version)),
+        v if v > OP_SET_VERSION => Err(CompatError::TooNew(v)),
+        v => Ok(SHIMS[v as usize - 1..]
+            .iter()
+            .fold(func.clone(), |func, shim| shim(&func))),
+    }
+}
+
+/// Replaces each op for which `expand` returns `Some` with the ops it
+/// returns, fixing up jumps, in `func` and every function it references
+/// through `Push` constants. Jumps themselves are never passed to
+/// `expand`, and replacements must not contain jumps.
+pub fn rewrite(func: &Function, expand: &dyn Fn(&Op) -> Option<Vec<Op>>) -> Function {
+    transform(func, expand, &|_| Vec::new(), &mut HashMap::new())
+}
+
+/// `rewrite`, also appending the ops `tail` returns for each function's
+/// original ops; jumps to the end land on the first of them.
+fn transform(
+    func: &Function,
+    expand: &dyn Fn(&Op) -> Option<Vec<Op>>,
+    tail: &dyn Fn(&[Op]) -> Vec<Op>,
+    done: &mut HashMap<usize, Function>,
+) -> Function {
+    if let Some(done) = done.get(&func.identity()) {
+        return done.clone();
+    }
+    let ops = &func.ops;
+    let mut out = Vec::with_capacity(ops.len());
+    let mut map = Vec::with_capacity(ops.len() + 1);
+    for op in ops.iter() {
+        map.push(out.len());
+        match op {
+            Op::Push(Push(Value::Function(f))) => {
+                out.push(Push(transform(f, expand, tail, done).into()).into())
+            }
+            op if op.jump_offset().is_some() => out.push(op.clone()),
+            op => match expand(op) {
+                Some(replacement) => out.extend(replacement),
+                None => out.push(op.clone()),
+            },
+        }
+    }
+    map.push(out.len());
+    out.extend(tail(ops));
+    relocate(ops, &map, &mut out);
+    let rewritten = Function {
+        module: func.module.clone(),
+        ops: out.into(),
+    };
+    done.insert(func.identity(), rewritten.clone());
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::verify;
+    use crate::datamodel::Tuple;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn rewrites_keep_jumps_pointing_at_the_same_ops() {
+        // if 1 { -(5) } else { 9 }, with Neg standing in for a retired op
+        let func = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(Value::Integer(1)).into(),
+                JumpIfNot(4).into(),
+                Push(Value::Integer(5)).into(),
+                Neg.into(),
+                Neg.into(),
+                Jump(1).into(),
+                Push(Value::Integer(9)).into(),
+                Return.into(),
+            ]
+            .into(),
+        };
+        let rewritten = rewrite(&func, &|op| match op {
+            Op::Neg(_) => Some(vec![Push(Value::Integer(-1)).into(), Mul.into()]),
+            _ => None,
+        });
+        assert_eq!(rewritten.ops.len(), func.ops.len() + 2);
+        assert!(!rewritten.ops.iter().any(|op| matches!(op, Op::Neg(_))));
+        let mut vm = VirtualMachine::new(rewritten);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(5))));
+        assert!(upgrade(&func, OP_SET_VERSION).is_ok());
+        assert!(matches!(
+            upgrade(&func, OP_SET_VERSION + 1),
+            Err(CompatError::TooNew(_))
+        ));
+    }
+
+    #[test]
+    fn version_3_functions_get_an_explicit_end() {
+        // if 1 { 5 }, ending without a return
+        let func = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(Value::Integer(1)).into(),
+                JumpIfNot(2).into(),
+                Push(Value::Integer(5)).into(),
+                Pop.into(),
+            ]
+            .into(),
+        };
+        assert!(verify::verify(&func).is_err());
+        let upgraded = upgrade(&func, 3).ok().unwrap();
+        assert!(verify::verify(&upgraded).is_ok());
+        assert!(matches!(upgraded.ops[1].jump_offset(), Some(2)));
+        assert!(matches!(upgraded.ops[4], Op::Halt(_)));
+        let ended = upgrade(&upgraded, 3).ok().unwrap();
+        assert_eq!(ended.ops.len(), upgraded.ops.len());
+    }
+}
@@ -0,0 +1,258 @@
+//! A disassembler: a readable listing of a function and every function it
+//! pushes as a constant, for reading compiler output.
+//!
+//! Functions are numbered `fn0`, `fn1`, ... in the order they are reached,
+//! `fn0` being the one asked for, and each is listed with its identity and
+//! then one line per op: the index, the mnemonic, the operands, and for
+//! jumps where they land. Constants are shown as values, with nested
+//! functions by number so they can be looked up further down.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::ops::*;
+use super::speculate::Guard;
+use super::Op;
+use crate::datamodel::{Function, Identity, Value};
+
+/// Longest rendering of a constant before it is cut short.
+pub const MAX_VALUE_WIDTH: usize = 60;
+
+/// Aggregate constants are listed this deep before showing `...`.
+const MAX_VALUE_DEPTH: usize = 3;
+
+struct Listing {
+    numbers: HashMap<usize, usize>,
+    pending: Vec<Function>,
+}
+
+impl Listing {
+    /// The number of `func`, queueing it to be listed if it is new.
+    fn number(&mut self, func: &Function) -> usize {
+        let next = self.numbers.len();
+        *self.numbers.entry(func.identity()).or_insert_with(|| {
+            self.pending.push(func.clone());
+            next
+        })
+    }
+
+    fn value(&mut self, val: &Value, depth: usize) -> String {
+        if depth == MAX_VALUE_DEPTH {
+            return "...".to_string();
+        }
+        let list = |this: &mut Listing, items: &[Value]| -> String {
+            let items: Vec<_> = items.iter().map(|v| this.value(v, depth + 1)).collect();
+            items.join(", ")
+        };
+        match val {
+            Value::None => "None".to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::Real(r) => format!("{:?}", r),
+            Value::Decimal(d) => format!("{}d", d),
+            Value::Str(s) => format!("{:?}", &**s),
+            Value::Symbol(s) => format!(":{}", s.name()),
+            Value::Function(f) => format!("fn{}", self.number(f)),
+            Value::Closure(c) => format!("closure(fn{})", self.number(&c.function)),
+            Value::NativeFn(f) => format!("native@{:x}", *f as usize),
+            Value::Variant(v) => format!("#{}({})", v.tag(), self.value(v.payload(), depth + 1)),
+            Value::Tuple(t) => {
+                let items: Vec<_> = (0..t.len()).filter_map(|i| t.get(i)).collect();
+                format!("({})", list(self, &items))
+            }
+            Value::List(l) => format!("[{}]", list(self, &l.to_vec())),
+            Value::Table(t) => {
+                let fields = t.entries();
+                let fields: Vec<_> = fields
+                    .iter()
+                    .map(|(k, v)| format!("{:#x}: {}", k, self.value(v, depth + 1)))
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+            Value::Map(m) => {
+                let entries = m.entries();
+                let entries: Vec<_> = entries
+                    .iter()
+                    .map(|(k, v)| {
+                        format!(
+                            "{} => {}",
+                            self.value(k, depth + 1),
+                            self.value(v, depth + 1)
+                        )
+                    })
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Value::Record(r) => {
+                let fields = r.entries();
+                let fields: Vec<_> = fields
+                    .iter()
+                    .map(|(k, v)| format!("{:#x}: {}", k, self.value(v, depth + 1)))
+                    .collect();
+                format!("record {{{}}}", fields.join(", "))
+            }
+            other => other.get_type().as_str().to_string(),
+        }
+    }
+
+    fn constant(&mut self, val: &Value) -> String {
+        let text = self.value(val, 0);
+        match text.char_indices().nth(MAX_VALUE_WIDTH) {
+            Some((cut, _)) => format!("{}...", &text[..cut]),
+            None => text,
+        }
+    }
+
+    fn operands(&mut self, op: &Op) -> String {
+        match op {
+            Op::Push(Push(val)) => self.constant(val),
+            Op::Load(Load(i)) | Op::Store(Store(i)) | Op::Call(Call(i)) => i.to_string(),
+            Op::Jump(Jump(o))
+            | Op::JumpIf(JumpIf(o))
+            | Op::JumpIfNot(JumpIfNot(o))
+            | Op::ForIter(ForIter(o)) => {
+                format!("{:+}", o)
+            }
+            Op::PushHandler(PushHandler(o)) => format!("{:+}", o),
+            Op::IncJumpLt(op) => format!("{} {} {:+}", op.local, op.limit, op.offset),
+            Op::AddImm(op) => format!("{} {:+}", op.local, op.imm),
+            Op::AddLocals(op) => format!("{} {}", op.a, op.b),
+            Op::LoadUpvalue(LoadUpvalue(i)) => i.to_string(),
+            Op::MakeClosure(op) => {
+                let locals: Vec<_> = op.locals.iter().map(|l| l.to_string()).collect();
+                format!("[{}]", locals.join(", "))
+            }
+            Op::SwitchStr(op) => {
+                let cases: Vec<_> = op
+                    .sorted_cases()
+                    .iter()
+                    .map(|(s, o)| format!("{:?} {:+}", &**s, o))
+                    .collect();
+                format!("{{{}}} else {:+}", cases.join(", "), op.default)
+            }
+            Op::Switch(op) => {
+                let offsets: Vec<_> = op.offsets.iter().map(|o| format!("{:+}", o)).collect();
+                format!("{} [{}] else {:+}", op.low, offsets.join(", "), op.default)
+            }
+            Op::MakeVariant(MakeVariant(t)) | Op::IsTag(IsTag(t)) | Op::Unwrap(Unwrap(t)) => {
+                format!("#{}", t)
+            }
+            Op::Invoke(op) => format!("{} {}", op.method, op.argc),
+            Op::CallHost(op) => format!("{} {}", op.index, op.argc),
+            Op::GetField(op) => format!("{:#x}", op.key),
+            Op::SetField(op) => format!("{:#x}", op.key),
+            Op::LoadModule(LoadModule(name)) => format!("{:?}", &**name),
+            Op::NewRecord(op) => {
+                let keys: Vec<_> = op
+                    .shape
+                    .keys()
+                    .iter()
+                    .map(|k| format!("{:#x}", k))
+                    .collect();
+                format!("{{{}}}", keys.join(", "))
+            }
+            Op::Speculate(s) => {
+                let guard = match &s.guard {
+                    Guard::Stack(types) => {
+                        let types: Vec<_> = types.iter().map(|t| t.as_str()).collect();
+                        format!("stack [{}]", types.join(", "))
+                    }
+                    Guard::Local(i, t) => format!("local {} {}", i, t.as_str()),
+                };
+                format!(
+                    "{} ? {} {} : {} {}",
+                    guard,
+                    s.fast.name(),
+                    self.operands(&s.fast),
+                    s.generic.name(),
+                    self.operands(&s.generic)
+                )
+                .replace("  ", " ")
+                .trim_end()
+                .to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn function(&mut self, func: &Function, out: &mut String) {
+        let ops = &func.ops;
+        writeln!(
+            out,
+            "fn{} @{:x} ({} ops)",
+            self.numbers[&func.identity()],
+            func.identity(),
+            ops.len()
+        )
+        .unwrap();
+        let width = ops.len().saturating_sub(1).to_string().len();
+        for (at, op) in ops.iter().enumerate() {
+            let mut line = format!("  {:>width$}  {:<12}{}", at, op.name(), self.operands(op));
+            if let Some(offset) = op.jump_offset() {
+                write!(line, "  (-> {})", at as i64 + 1 + offset as i64).unwrap();
+            }
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+    }
+}
+
+/// Lists `func` and every function reachable through its constants.
+pub fn disasm(func: &Function) -> String {
+    let mut listing = Listing {
+        numbers: HashMap::new(),
+        pending: Vec::new(),
+    };
+    listing.number(func);
+    let mut out = String::new();
+    let mut next = 0;
+    // listing a function can number more, so go by number
+    while next < listing.pending.len() {
+        if next > 0 {
+            out.push('\n');
+        }
+        let func = listing.pending[next].clone();
+        listing.function(&func, &mut out);
+        next += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::speculate::Speculate;
+    use crate::datamodel::{Tuple, ValueType, Variant};
+    use crate::testing::function;
+
+    #[test]
+    fn lists_functions_and_constants() {
+        let helper = function(vec![Push(Value::Integer(1)).into(), Return.into()]);
+        let ints: std::rc::Rc<[ValueType]> = vec![ValueType::Integer, ValueType::Integer].into();
+        let main = function(vec![
+            Push(helper.clone().into()).into(),
+            Call(0).into(),
+            Push(Variant::some(Value::Str("hi".into())).into()).into(),
+            JumpIfNot(1).into(),
+            Speculate::new(Guard::Stack(ints), AddInt.into(), Add.into()).into(),
+            Push(Tuple::new(vec![helper.into(), Value::Real(0.5)]).into()).into(),
+            Return.into(),
+        ]);
+        let listing = disasm(&main);
+        let expected = [
+            format!("fn0 @{:x} (7 ops)", main.identity()),
+            "  0  Push        fn1".to_string(),
+            "  1  Call        0".to_string(),
+            "  2  Push        #0(\"hi\")".to_string(),
+            "  3  JumpIfNot   +1  (-> 5)".to_string(),
+            "  4  Speculate   stack [Integer, Integer] ? AddInt : Add".to_string(),
+            "  5  Push        (fn1, 0.5)".to_string(),
+            "  6  Return".to_string(),
+            String::new(),
+        ];
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines[..9], expected);
+        // the helper is listed once, after main
+        assert_eq!(lines[10], "  0  Push        1");
+        assert_eq!(listing.matches("fn1 @").count(), 1);
+        assert_eq!(lines.len(), 12);
+    }
+}
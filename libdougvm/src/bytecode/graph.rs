@@ -0,0 +1,237 @@
+//! Rendering of a function's control-flow graph and a module's call graph
+//! as Graphviz DOT, plus a self-contained HTML view of the CFG for when
+//! Graphviz isn't at hand. Meant for debugging the optimizer, so the
+//! output favours showing every op over looking pretty.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use super::ops::{Load, Push, Store};
+use super::Op;
+use crate::datamodel::{Function, Identity, Tuple, Value};
+
+/// A straight run of ops, `start..end`, and the blocks it can continue to.
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+}
+
+fn target(at: usize, offset: i32, len: usize) -> usize {
+    (at as i64 + 1 + offset as i64).clamp(0, len as i64) as usize
+}
+
+/// Splits `func` into basic blocks, in op order. A successor equal to
+/// `func.ops.len()` runs off the end, which `verify` rejects.
+pub fn blocks(func: &Function) -> Vec<Block> {
+    let ops = &func.ops;
+    let mut leaders = BTreeSet::from([0]);
+    for (i, op) in ops.iter().enumerate() {
+        for offset in op.jump_offsets() {
+            leaders.insert(target(i, offset, ops.len()));
+            leaders.insert(i + 1);
+        }
+        if matches!(op, Op::Return(_) | Op::Halt(_) | Op::Try(_) | Op::Throw(_)) {
+            leaders.insert(i + 1);
+        }
+    }
+    let starts: Vec<usize> = leaders.into_iter().filter(|&i| i < ops.len()).collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).copied().unwrap_or(ops.len());
+            let last = end - 1;
+            let mut successors = Vec::new();
+            match &ops[last] {
+                // a throw's successor is a handler, which the PushHandler
+                // edge already reaches
+                Op::Return(_) | Op::Halt(_) | Op::Throw(_) => {}
+                Op::Jump(_) | Op::SwitchStr(_) | Op::Switch(_) => {
+                    for offset in ops[last].jump_offsets() {
+                        successors.push(target(last, offset, ops.len()));
+                    }
+                }
+                op => {
+                    if let Some(offset) = op.jump_offset() {
+                        successors.push(target(last, offset, ops.len()));
+                    }
+                    successors.push(end);
+                }
+            }
+            Block {
+                start,
+                end,
+                successors,
+            }
+        })
+        .collect()
+}
+
+/// A one-line description of an op with its operands.
+pub fn describe(op: &Op, at: usize, len: usize) -> String {
+    match op {
+        Op::Push(Push(val)) => format!("Push {}", describe_value(val)),
+        Op::Load(Load(i)) => format!("Load {}", i),
+        Op::Store(Store(i)) => format!("Store {}", i),
+        Op::Call(call) => format!("Call {}", call.0),
+        Op::Speculate(s) => format!("Speculate {}", s.generic.name()),
+        Op::GetField(op) => format!("GetField {:#x}", op.key),
+        Op::SetField(op) => format!("SetField {:#x}", op.key),
+        op => match op.jump_offset() {
+            Some(offset) => format!("{} -> {}", op.name(), target(at, offset, len)),
+            None => op.name().to_string(),
+        },
+    }
+}
+
+fn describe_value(val: &Value) -> String {
+    match val {
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        Value::Str(s) => format!("{:?}", &**s),
+        Value::Function(f) => format!("fn@{:x}", f.identity()),
+        val => val.get_type().as_str().to_string(),
+    }
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The control-flow graph of `func` in DOT, one node per block.
+pub fn cfg_dot(func: &Function) -> String {
+    let ops = &func.ops;
+    let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+    for block in blocks(func) {
+        let mut label = String::new();
+        for i in block.start..block.end {
+            let line = format!("{:>4}  {}", i, describe(&ops[i], i, ops.len()));
+            write!(label, "{}\\l", escape_dot(&line)).unwrap();
+        }
+        writeln!(dot, "    b{} [label=\"{}\"];", block.start, label).unwrap();
+        for succ in &block.successors {
+            match *succ == ops.len() {
+                true => writeln!(dot, "    b{} -> exit;", block.start).unwrap(),
+                false => writeln!(dot, "    b{} -> b{};", block.start, succ).unwrap(),
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// The control-flow graph of `func` as a standalone HTML page, with each
+/// block's successors linked.
+pub fn cfg_html(func: &Function, title: &str) -> String {
+    let ops = &func.ops;
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1>\n",
+        escape_html(title)
+    );
+    for block in blocks(func) {
+        writeln!(html, "<h2 id=\"b{0}\">block {0}</h2>\n<pre>", block.start).unwrap();
+        for i in block.start..block.end {
+            let line = format!("{:>4}  {}", i, describe(&ops[i], i, ops.len()));
+            writeln!(html, "{}", escape_html(&line)).unwrap();
+        }
+        html.push_str("</pre>\n<p>next:");
+        for succ in &block.successors {
+            match *succ == ops.len() {
+                true => html.push_str(" exit"),
+                false => write!(html, " <a href=\"#b{0}\">block {0}</a>", succ).unwrap(),
+            }
+        }
+        html.push_str("</p>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// The call graph of `module` in DOT. Functions in module slots are named
+/// by slot; functions only reachable through `Push` constants by identity.
+/// An edge means the caller pushes the callee, which is how calls are made.
+pub fn call_graph_dot(module: &Tuple) -> String {
+    let mut names = HashMap::new();
+    let mut work = Vec::new();
+    for i in 0..module.len() {
+        if let Some(Value::Function(func)) = module.get(i) {
+            names
+                .entry(func.identity())
+                .or_insert_with(|| format!("slot{}", i));
+            work.push(func);
+        }
+    }
+    let mut dot = String::from("digraph calls {\n    node [shape=ellipse, fontname=monospace];\n");
+    let mut done = BTreeSet::new();
+    while let Some(func) = work.pop() {
+        if !done.insert(func.identity()) {
+            continue;
+        }
+        let from = names[&func.identity()].clone();
+        writeln!(dot, "    {};", from).unwrap();
+        let mut callees = BTreeSet::new();
+        for op in func.ops.iter() {
+            if let Op::Push(Push(Value::Function(callee))) = op {
+                let name = names
+                    .entry(callee.identity())
+                    .or_insert_with(|| format!("fn_{:x}", callee.identity()))
+                    .clone();
+                if callees.insert(name.clone()) {
+                    writeln!(dot, "    {} -> {};", from, name).unwrap();
+                }
+                work.push(callee.clone());
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+
+    #[test]
+    fn splits_blocks_at_branches() {
+        // if 1 { 2 } else { 3 }
+        let func = function(vec![
+            Push(Value::Integer(1)).into(),
+            JumpIfNot(2).into(),
+            Push(Value::Integer(2)).into(),
+            Return.into(),
+            Push(Value::Integer(3)).into(),
+            Return.into(),
+        ]);
+        let blocks = blocks(&func);
+        let spans: Vec<_> = blocks.iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(spans, [(0, 2), (2, 4), (4, 6)]);
+        assert_eq!(blocks[0].successors, [4, 2]);
+        assert!(blocks[1].successors.is_empty());
+
+        let dot = cfg_dot(&func);
+        assert!(dot.contains("b0 -> b4;") && dot.contains("JumpIfNot -> 4"));
+        assert!(cfg_html(&func, "<f>").contains("<title>&lt;f&gt;</title>"));
+    }
+
+    #[test]
+    fn call_graph_names_module_slots() {
+        let leaf = function(vec![Push(Value::Integer(1)).into(), Return.into()]);
+        let main = function(vec![
+            Push(leaf.clone().into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let module = Tuple::new(vec![main.into(), leaf.into()]);
+        assert!(call_graph_dot(&module).contains("slot0 -> slot1;"));
+    }
+}
@@ -1,15 +1,193 @@
+use crate::coroutine::Coroutine;
+use crate::datamodel::{
+    Closure, Function, NativeFn, Str, Tag, Value, ValueTryIntoError, ValueType,
+};
+use crate::suspend::Token;
 use crate::CallStack;
-use crate::datamodel::{Value, Function, NativeFn, ValueType, ValueTryIntoError};
+
+pub mod cache;
+pub mod capacity;
+pub mod cfg;
+pub mod compat;
+pub mod disasm;
+pub mod graph;
+pub mod ops;
+pub mod serialize;
+pub mod spec;
+pub mod speculate;
+pub mod verify;
 
 pub trait Operation {
     fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError>;
 }
 
-pub enum Op {}
+macro_rules! create_op_enum {
+    ($($n:ident),+) => {
+        #[derive(Clone)]
+        pub enum Op {
+            $($n(ops::$n)),+
+        }
+
+        impl Operation for Op {
+            fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+                match self {
+                    $(Op::$n(op) => op.exec(m)),+
+                }
+            }
+        }
+
+        impl Op {
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Op::$n(_) => stringify!($n)),+
+                }
+            }
+        }
+
+        $(
+            impl From<ops::$n> for Op {
+                fn from(op: ops::$n) -> Self {
+                    Op::$n(op)
+                }
+            }
+        )+
+    };
+}
+
+create_op_enum! {
+    Push, Pop, Load, Store, Jump, JumpIf, JumpIfNot, IncJumpLt, Select, Call, Return,
+    GetIter, ForIter, SwitchStr, Switch,
+    Add, Sub, Mul, Div, Rem, Neg, Eq, Ne, Lt, Le, Gt, Ge, Same, DeepEq, Cmp,
+    AddInt, SubInt, LtInt, AddIntUnchecked, SubIntUnchecked, AddImm, AddLocals, Speculate,
+    MakeVariant, IsTag, GetTag, Unwrap, Try,
+    Implements, Invoke,
+    NewTable, GetField, SetField,
+    NewMap, MapInsert, MapGet, MapDelete, MapContains, MapIter,
+    PushHandler, PopHandler, Throw, Enter, Exit,
+    CallHost, Halt,
+    Yield, Resume,
+    LoadModule, NewRecord, Intern,
+    MakeClosure, LoadUpvalue
+}
 
-impl Operation for Op {
-    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
-        panic!()
+impl Op {
+    /// How many values a straight-line op pops and pushes, or `None` for
+    /// ops that branch, call or return.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        Some(match self {
+            Op::Push(_) | Op::Load(_) | Op::NewTable(_) => (0, 1),
+            Op::AddImm(_) | Op::AddLocals(_) => (0, 1),
+            Op::Pop(_) | Op::Store(_) => (1, 0),
+            Op::Select(_) => (3, 1),
+            Op::Add(_) | Op::Sub(_) | Op::Mul(_) | Op::Div(_) | Op::Rem(_) => (2, 1),
+            Op::Eq(_) | Op::Ne(_) | Op::Lt(_) | Op::Le(_) | Op::Gt(_) | Op::Ge(_) => (2, 1),
+            Op::Same(_) | Op::DeepEq(_) | Op::Cmp(_) => (2, 1),
+            Op::AddInt(_) | Op::SubInt(_) | Op::LtInt(_) => (2, 1),
+            Op::AddIntUnchecked(_) | Op::SubIntUnchecked(_) => (2, 1),
+            Op::Neg(_) | Op::MakeVariant(_) | Op::IsTag(_) | Op::GetTag(_) | Op::Unwrap(_) => {
+                (1, 1)
+            }
+            Op::Implements(_) => (2, 1),
+            Op::NewRecord(op) => (op.shape.keys().len(), 1),
+            Op::Intern(_) | Op::MakeClosure(_) => (1, 1),
+            Op::LoadUpvalue(_) => (0, 1),
+            Op::GetField(_) => (1, 1),
+            Op::SetField(_) => (2, 0),
+            Op::NewMap(_) => (0, 1),
+            Op::MapInsert(_) => (3, 0),
+            Op::MapGet(_) | Op::MapDelete(_) | Op::MapContains(_) => (2, 1),
+            Op::MapIter(_) | Op::GetIter(_) => (1, 1),
+            Op::Speculate(s) => return s.generic.stack_effect(),
+            Op::Jump(_) | Op::JumpIf(_) | Op::JumpIfNot(_) | Op::IncJumpLt(_) => return None,
+            Op::ForIter(_) | Op::SwitchStr(_) | Op::Switch(_) => return None,
+            Op::Call(_) | Op::Invoke(_) | Op::Return(_) | Op::Try(_) => return None,
+            Op::PushHandler(_) | Op::PopHandler(_) | Op::Throw(_) => return None,
+            Op::Enter(_) | Op::Exit(_) => return None,
+            Op::CallHost(_) | Op::Halt(_) => return None,
+            Op::Yield(_) | Op::Resume(_) | Op::LoadModule(_) => return None,
+        })
+    }
+
+    /// The relative offset of a branching op; a switch's default.
+    pub fn jump_offset(&self) -> Option<i32> {
+        match self {
+            Op::SwitchStr(op) => Some(op.default),
+            Op::Switch(op) => Some(op.default),
+            Op::Jump(ops::Jump(offset))
+            | Op::JumpIf(ops::JumpIf(offset))
+            | Op::JumpIfNot(ops::JumpIfNot(offset))
+            | Op::ForIter(ops::ForIter(offset)) => Some(*offset),
+            Op::IncJumpLt(op) => Some(op.offset),
+            Op::PushHandler(ops::PushHandler(offset)) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// Every offset a branching op can jump by: `jump_offset`'s, then a
+    /// switch's cases'.
+    pub fn jump_offsets(&self) -> Vec<i32> {
+        match self {
+            Op::SwitchStr(op) => {
+                let cases = op.cases.values().copied();
+                std::iter::once(op.default).chain(cases).collect()
+            }
+            Op::Switch(op) => {
+                let cases = op.offsets.iter().copied();
+                std::iter::once(op.default).chain(cases).collect()
+            }
+            op => op.jump_offset().into_iter().collect(),
+        }
+    }
+
+    /// A copy of a branching op with a new offset; other ops are returned
+    /// unchanged. A switch only has its default replaced (see
+    /// `SwitchStr::map_offsets` and `Switch::map_offsets`).
+    pub fn with_jump_offset(&self, offset: i32) -> Op {
+        match self {
+            Op::Jump(_) => ops::Jump(offset).into(),
+            Op::JumpIf(_) => ops::JumpIf(offset).into(),
+            Op::JumpIfNot(_) => ops::JumpIfNot(offset).into(),
+            Op::ForIter(_) => ops::ForIter(offset).into(),
+            Op::IncJumpLt(op) => ops::IncJumpLt {
+                offset,
+                ..op.clone()
+            }
+            .into(),
+            Op::PushHandler(_) => ops::PushHandler(offset).into(),
+            Op::SwitchStr(op) => ops::SwitchStr {
+                default: offset,
+                ..op.clone()
+            }
+            .into(),
+            Op::Switch(op) => ops::Switch {
+                default: offset,
+                ..op.clone()
+            }
+            .into(),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// Rebuilds `ops` after ops have been inserted or removed, fixing up every
+/// jump. `map[i]` is the new index of old op `i`, and `map[ops.len()]` the
+/// new end; ops not in `map` are assumed not to be jump targets.
+pub fn relocate(ops: &[Op], map: &[usize], rebuilt: &mut [Op]) {
+    for (old, op) in ops.iter().enumerate() {
+        let from = map[old];
+        let moved = |offset: i32| {
+            let target = (old as i64 + 1 + offset as i64) as usize;
+            (map[target.min(ops.len())] as i64 - (from as i64 + 1)) as i32
+        };
+        match op {
+            Op::SwitchStr(op) => rebuilt[from] = op.map_offsets(moved).into(),
+            Op::Switch(op) => rebuilt[from] = op.map_offsets(moved).into(),
+            op => {
+                if let Some(offset) = op.jump_offset() {
+                    rebuilt[from] = op.with_jump_offset(moved(offset));
+                }
+            }
+        }
     }
 }
 
@@ -17,15 +195,83 @@ pub enum OpAction {
     None,
     Jump(i32),
     Call(Function, Vec<Value>),
+    CallClosure(Closure, Vec<Value>),
     CallNative(NativeFn, Vec<Value>),
     Return(Value),
+    /// Installs a handler at this offset; see `ops::PushHandler`.
+    PushHandler(i32),
+    PopHandler,
+    /// Unwinds to the innermost handler with this exception.
+    Throw(Value),
+    /// Opens a scope releasing this resource; see `ops::Enter`.
+    Enter(Value, NativeFn),
+    Exit,
+    /// Calls a registered host function; args are in call order.
+    CallHost(u32, Vec<Value>),
+    /// Suspends the running coroutine; see `crate::coroutine`.
+    Yield(Value),
+    Resume(Coroutine, Value),
+    /// Imports a module; see `crate::modules`.
+    LoadModule(Str),
+    Intern(Str),
+    /// Waits for the host to `resume` the VM; see `crate::suspend`.
+    Suspend(Token),
 }
 
 pub enum OpError {
     StackEmpty,
-    LocalRead(u8),
+    /// A local that was never stored, with its name if the function's
+    /// metadata has one (see `VirtualMachine::set_local_names`).
+    LocalRead(u8, Option<Str>),
     IndexRead(i64),
     IndexWrite(i64),
+    FieldRead(u64),
+    /// `SetField` of a field a `Record` doesn't have.
+    FieldWrite(u64),
+    /// `MapGet` of a key the map doesn't have.
+    KeyRead(Value),
     IntoType(ValueTryIntoError),
     BadType(ValueType),
+    BadTag {
+        found: Tag,
+        expected: Tag,
+    },
+    NotImplemented(ValueType),
+    Overflow,
+    DivideByZero,
+    Interrupted,
+    /// The script waits on a stream, but was run with `run_until_exited`.
+    Blocked,
+    /// The watchdog tripped, but the VM was run with `run_until_exited`.
+    HotLoop,
+    /// The fuel ran out, but the VM was run with `run_until_exited`.
+    OutOfFuel,
+    /// `Resume` of a coroutine that is running or done.
+    NotResumable,
+    /// `Yield` outside any coroutine.
+    NotInCoroutine,
+    /// The cursor ran past the last op, which verified bytecode can't.
+    FellOffEnd,
+    /// `LoadModule` of a name the VM's `ModuleTable` doesn't have.
+    NoModule(Str),
+    /// `LoadModule` of a module whose top-level code is still running.
+    ImportCycle(Str),
+    /// A thrown exception no handler caught.
+    Uncaught(Value),
+    /// `CallHost` named an index the VM's registry doesn't have.
+    NoHostFn(u32),
+    /// A call left more resources open than the VM's limit allows.
+    TooManyResources(usize),
+    /// A call would take the call stack past the VM's depth limit, which
+    /// it holds (see `VmConfig::max_call_depth`).
+    StackOverflow(usize),
+    /// `LoadUpvalue` of an upvalue the running closure didn't capture, or
+    /// outside any closure.
+    UpvalueRead(u8),
+}
+
+impl From<ValueTryIntoError> for OpError {
+    fn from(e: ValueTryIntoError) -> Self {
+        OpError::IntoType(e)
+    }
 }
@@ -0,0 +1,519 @@
+//! Arithmetic and comparison ops. Binary ops pop the right operand, then
+//! the left. Mixing `Integer` and `Real` promotes to `Real`; integer
+//! overflow and division by zero are errors rather than wrapping.
+//!
+//! `Integer` and `Decimal` mix into `Decimal`, but `Decimal` never mixes
+//! with `Real`: that would bring back the rounding errors decimals avoid.
+//!
+//! A `Record` operand whose shape has a method for the op (see
+//! `Shape::set_method`) makes the op a call to it instead: `__add__`,
+//! `__sub__`, `__mul__`, `__div__`, `__rem__` and `__neg__`, and `__eq__`,
+//! `__ne__`, `__lt__`, `__le__`, `__gt__` and `__ge__`. The left operand's
+//! method is used if it has one, else the right's, and either is called
+//! with the operands in order and returns the result.
+//!
+//! `Real` math is plain IEEE 754 double precision: no op is fused or
+//! reassociated, here or in the optimizer, so results only vary across
+//! architectures in NaN bit patterns. `FloatMode` removes that, or keeps
+//! reals on a fixed-point grid.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+
+use super::call;
+use crate::bytecode::{OpAction, OpError, Operation};
+use crate::datamodel::{field_key, Decimal, Rounding, Value, ValueType, DECIMAL_DIV_SCALE};
+use crate::CallStack;
+
+enum Operands {
+    Int(i64, i64),
+    Real(f64, f64),
+    Dec(Decimal, Decimal),
+}
+
+fn operands(lhs: Value, rhs: Value) -> Result<Operands, OpError> {
+    Ok(match (lhs, rhs) {
+        (Value::Integer(a), Value::Integer(b)) => Operands::Int(a, b),
+        (Value::Integer(a), Value::Real(b)) => Operands::Real(a as f64, b),
+        (Value::Real(a), Value::Integer(b)) => Operands::Real(a, b as f64),
+        (Value::Real(a), Value::Real(b)) => Operands::Real(a, b),
+        (Value::Integer(a), Value::Decimal(b)) => Operands::Dec(a.into(), b),
+        (Value::Decimal(a), Value::Integer(b)) => Operands::Dec(a, b.into()),
+        (Value::Decimal(a), Value::Decimal(b)) => Operands::Dec(a, b),
+        (Value::Decimal(_), Value::Real(_)) => return Err(OpError::BadType(ValueType::Real)),
+        (Value::Real(_), Value::Decimal(_)) => return Err(OpError::BadType(ValueType::Decimal)),
+        (Value::Integer(_) | Value::Real(_) | Value::Decimal(_), other) | (other, _) => {
+            return Err(OpError::BadType(other.get_type()))
+        }
+    })
+}
+
+/// How `Real` results are normalized.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FloatMode {
+    /// Results are left as the hardware produced them.
+    Native,
+    /// Every NaN result is replaced with the one canonical quiet NaN.
+    Canonical,
+    /// As `Canonical`, and results are rounded (half to even) to a
+    /// multiple of `2^-bits`, as a fixed-point number would be.
+    Fixed(u8),
+}
+
+thread_local! {
+    static FLOAT_MODE: Cell<FloatMode> = const { Cell::new(FloatMode::Native) };
+}
+
+/// Sets the float mode for arithmetic on this thread. Constant folding
+/// also uses it, so set it before optimizing the code it applies to.
+pub fn set_float_mode(mode: FloatMode) {
+    FLOAT_MODE.with(|m| m.set(mode));
+}
+
+pub fn float_mode() -> FloatMode {
+    FLOAT_MODE.with(|m| m.get())
+}
+
+fn normalize(r: f64) -> f64 {
+    match float_mode() {
+        FloatMode::Native => r,
+        _ if r.is_nan() => f64::NAN,
+        FloatMode::Canonical => r,
+        FloatMode::Fixed(bits) => {
+            let scale = (2.0f64).powi(bits as i32);
+            match (r * scale).round_ties_even() / scale {
+                fixed if fixed.is_finite() => fixed,
+                // too large for the grid; it is already a whole number
+                _ => r,
+            }
+        }
+    }
+}
+
+/// The operator method `key` of the first record among `operands` whose
+/// shape has one.
+fn overload(key: u64, operands: &[&Value]) -> Option<Value> {
+    operands.iter().find_map(|val| match val {
+        Value::Record(r) => r.shape().method(key),
+        _ => None,
+    })
+}
+
+fn binary(
+    m: &mut CallStack,
+    method: u64,
+    int: fn(i64, i64) -> Result<i64, OpError>,
+    real: fn(f64, f64) -> f64,
+    dec: fn(Decimal, Decimal) -> Result<Decimal, OpError>,
+) -> Result<OpAction, OpError> {
+    let rhs = m.pop()?;
+    let lhs = m.pop()?;
+    if let Some(method) = overload(method, &[&lhs, &rhs]) {
+        // args go last-first, see `ops::Call`
+        return call(method, vec![rhs, lhs]);
+    }
+    m.push(match operands(lhs, rhs)? {
+        Operands::Int(a, b) => Value::Integer(int(a, b)?),
+        Operands::Real(a, b) => Value::Real(normalize(real(a, b))),
+        Operands::Dec(a, b) => Value::Decimal(dec(a, b)?),
+    });
+    Ok(OpAction::None)
+}
+
+fn checked(result: Option<i64>) -> Result<i64, OpError> {
+    result.ok_or(OpError::Overflow)
+}
+
+fn divisor(b: i64) -> Result<i64, OpError> {
+    match b {
+        0 => Err(OpError::DivideByZero),
+        b => Ok(b),
+    }
+}
+
+fn dec_checked(result: Option<Decimal>) -> Result<Decimal, OpError> {
+    result.ok_or(OpError::Overflow)
+}
+
+fn dec_divisor(b: Decimal) -> Result<Decimal, OpError> {
+    match b.units() {
+        0 => Err(OpError::DivideByZero),
+        _ => Ok(b),
+    }
+}
+
+macro_rules! binary_op {
+    ($(#[$doc:meta])* $n:ident, $method:literal, $int:expr, $real:expr, $dec:expr) => {
+        $(#[$doc])*
+        #[derive(Clone)]
+        pub struct $n;
+
+        impl Operation for $n {
+            fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+                binary(m, field_key($method), $int, $real, $dec)
+            }
+        }
+    };
+}
+
+binary_op!(
+    Add,
+    "__add__",
+    |a, b| checked(a.checked_add(b)),
+    |a, b| a + b,
+    |a, b| dec_checked(a.checked_add(&b))
+);
+binary_op!(
+    Sub,
+    "__sub__",
+    |a, b| checked(a.checked_sub(b)),
+    |a, b| a - b,
+    |a, b| dec_checked(a.checked_sub(&b))
+);
+binary_op!(
+    Mul,
+    "__mul__",
+    |a, b| checked(a.checked_mul(b)),
+    |a, b| a * b,
+    |a, b| dec_checked(a.checked_mul(&b))
+);
+binary_op!(
+    /// Integer division rounds toward zero. `Decimal` division keeps at
+    /// least `DECIMAL_DIV_SCALE` digits after the point, rounding half to
+    /// even; use the decimal natives for other precisions or rounding.
+    Div,
+    "__div__",
+    |a, b| checked(a.checked_div(divisor(b)?)),
+    |a, b| a / b,
+    |a, b| {
+        let scale = a.scale().max(b.scale()).max(DECIMAL_DIV_SCALE);
+        dec_checked(a.checked_div(&dec_divisor(b)?, scale, Rounding::HalfEven))
+    }
+);
+binary_op!(
+    /// The remainder takes the sign of the left operand.
+    Rem,
+    "__rem__",
+    |a, b| checked(a.checked_rem(divisor(b)?)),
+    |a, b| a % b,
+    |a, b| dec_checked(a.checked_rem(&dec_divisor(b)?))
+);
+
+#[derive(Clone)]
+pub struct Neg;
+
+impl Operation for Neg {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = match m.pop()? {
+            Value::Integer(i) => Value::Integer(checked(i.checked_neg())?),
+            Value::Real(r) => Value::Real(normalize(-r)),
+            Value::Decimal(d) => Value::Decimal(d.checked_neg().ok_or(OpError::Overflow)?),
+            other => match overload(field_key("__neg__"), &[&other]) {
+                Some(method) => return call(method, vec![other]),
+                None => return Err(OpError::BadType(other.get_type())),
+            },
+        };
+        m.push(val);
+        Ok(OpAction::None)
+    }
+}
+
+/// Compares two scalars. Numbers compare by value (NaN compares unequal to
+/// everything), `Str`s lexicographically, `Timestamp`s and `Duration`s
+/// chronologically, `Symbol`s by identity (equal or unordered), and `None`
+/// only equals `None`. Scalars of different kinds
+/// are unordered. Aggregates are rejected rather than compared by
+/// reference, whichever side they are on.
+pub(crate) fn compare(lhs: &Value, rhs: &Value) -> Result<Option<Ordering>, OpError> {
+    Ok(match (lhs, rhs) {
+        (Value::None, Value::None) => Some(Ordering::Equal),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        (Value::Timestamp(a), Value::Timestamp(b)) => Some(a.0.cmp(&b.0)),
+        (Value::Duration(a), Value::Duration(b)) => Some(a.0.cmp(&b.0)),
+        (Value::Symbol(a), Value::Symbol(b)) => (a == b).then_some(Ordering::Equal),
+        (Value::Decimal(_), Value::Real(_)) | (Value::Real(_), Value::Decimal(_)) => None,
+        (
+            Value::Integer(_) | Value::Real(_) | Value::Decimal(_),
+            Value::Integer(_) | Value::Real(_) | Value::Decimal(_),
+        ) => match operands(lhs.clone(), rhs.clone())? {
+            Operands::Int(a, b) => Some(a.cmp(&b)),
+            Operands::Real(a, b) => a.partial_cmp(&b),
+            Operands::Dec(a, b) => Some(a.cmp(&b)),
+        },
+        (a, b) if is_scalar(a) && is_scalar(b) => None,
+        (a, b) => {
+            let aggregate = if is_scalar(a) { b } else { a };
+            return Err(OpError::BadType(aggregate.get_type()));
+        }
+    })
+}
+
+fn is_scalar(val: &Value) -> bool {
+    matches!(
+        val,
+        Value::None
+            | Value::Integer(_)
+            | Value::Real(_)
+            | Value::Decimal(_)
+            | Value::Str(_)
+            | Value::Timestamp(_)
+            | Value::Duration(_)
+            | Value::Symbol(_)
+    )
+}
+
+fn is_number(val: &Value) -> bool {
+    matches!(val, Value::Integer(_) | Value::Real(_) | Value::Decimal(_))
+}
+
+/// `compare` for `Eq` and `Ne`, where values of different kinds are
+/// unequal rather than an error.
+fn compare_eq(lhs: &Value, rhs: &Value) -> Result<Option<Ordering>, OpError> {
+    let same_kind = lhs.get_type() == rhs.get_type() || (is_number(lhs) && is_number(rhs));
+    match same_kind {
+        true => compare(lhs, rhs),
+        false => Ok(None),
+    }
+}
+
+macro_rules! compare_op {
+    ($(#[$doc:meta])* $n:ident, $method:literal, $test:expr) => {
+        compare_op!($(#[$doc])* $n, $method, $test, compare);
+    };
+    ($(#[$doc:meta])* $n:ident, $method:literal, $test:expr, $compare:ident) => {
+        $(#[$doc])*
+        #[derive(Clone)]
+        pub struct $n;
+
+        impl Operation for $n {
+            fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+                let rhs = m.pop()?;
+                let lhs = m.pop()?;
+                if let Some(method) = overload(field_key($method), &[&lhs, &rhs]) {
+                    return call(method, vec![rhs, lhs]);
+                }
+                let test: fn(Option<Ordering>) -> bool = $test;
+                m.push(test($compare(&lhs, &rhs)?).into());
+                Ok(OpAction::None)
+            }
+        }
+    };
+}
+
+compare_op!(
+    /// Values of different kinds are unequal rather than an error.
+    Eq,
+    "__eq__",
+    |o| o == Some(Ordering::Equal),
+    compare_eq
+);
+compare_op!(Ne, "__ne__", |o| o != Some(Ordering::Equal), compare_eq);
+compare_op!(Lt, "__lt__", |o| o == Some(Ordering::Less));
+compare_op!(Le, "__le__", |o| matches!(
+    o,
+    Some(Ordering::Less | Ordering::Equal)
+));
+compare_op!(Gt, "__gt__", |o| o == Some(Ordering::Greater));
+compare_op!(Ge, "__ge__", |o| matches!(
+    o,
+    Some(Ordering::Greater | Ordering::Equal)
+));
+
+/// `Add` specialized for two `Integer`s. Only valid behind a guard (see
+/// `bytecode::speculate`); other operands are a `BadType` error.
+#[derive(Clone)]
+pub struct AddInt;
+
+impl Operation for AddInt {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let (a, b) = pop_ints(m)?;
+        m.push(Value::Integer(checked(a.checked_add(b))?));
+        Ok(OpAction::None)
+    }
+}
+
+/// `Sub` specialized for two `Integer`s; see `AddInt`.
+#[derive(Clone)]
+pub struct SubInt;
+
+impl Operation for SubInt {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let (a, b) = pop_ints(m)?;
+        m.push(Value::Integer(checked(a.checked_sub(b))?));
+        Ok(OpAction::None)
+    }
+}
+
+/// `Lt` specialized for two `Integer`s; see `AddInt`.
+#[derive(Clone)]
+pub struct LtInt;
+
+impl Operation for LtInt {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let (a, b) = pop_ints(m)?;
+        m.push((a < b).into());
+        Ok(OpAction::None)
+    }
+}
+
+/// `AddInt` without the overflow check, wrapping instead. Only valid where
+/// the operands are known not to overflow (see `optimize::ranges`).
+#[derive(Clone)]
+pub struct AddIntUnchecked;
+
+impl Operation for AddIntUnchecked {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let (a, b) = pop_ints(m)?;
+        m.push(Value::Integer(a.wrapping_add(b)));
+        Ok(OpAction::None)
+    }
+}
+
+/// `SubInt` without the overflow check; see `AddIntUnchecked`.
+#[derive(Clone)]
+pub struct SubIntUnchecked;
+
+impl Operation for SubIntUnchecked {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let (a, b) = pop_ints(m)?;
+        m.push(Value::Integer(a.wrapping_sub(b)));
+        Ok(OpAction::None)
+    }
+}
+
+/// `Load(local)`, `Push(Integer(imm))`, `Add` in one op (see
+/// `optimize::fuse`), with the same result and errors.
+#[derive(Clone)]
+pub struct AddImm {
+    pub local: u8,
+    pub imm: i32,
+}
+
+impl Operation for AddImm {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let lhs = m.load(self.local)?;
+        if let Value::Integer(a) = *lhs {
+            m.push(Value::Integer(checked(a.checked_add(self.imm as i64))?));
+            return Ok(OpAction::None);
+        }
+        m.push(lhs.clone());
+        m.push(Value::Integer(self.imm as i64));
+        Add.exec(m)
+    }
+}
+
+/// `Load(a)`, `Load(b)`, `Add` in one op; see `AddImm`.
+#[derive(Clone)]
+pub struct AddLocals {
+    pub a: u8,
+    pub b: u8,
+}
+
+impl Operation for AddLocals {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        if let (Value::Integer(a), Value::Integer(b)) = (m.load(self.a)?, m.load(self.b)?) {
+            let sum = checked(a.checked_add(*b))?;
+            m.push(Value::Integer(sum));
+            return Ok(OpAction::None);
+        }
+        let (a, b) = (m.load(self.a)?.clone(), m.load(self.b)?.clone());
+        m.push(a);
+        m.push(b);
+        Add.exec(m)
+    }
+}
+
+fn pop_ints(m: &mut CallStack) -> Result<(i64, i64), OpError> {
+    match (m.pop()?, m.pop()?) {
+        (Value::Integer(b), Value::Integer(a)) => Ok((a, b)),
+        (Value::Integer(_), other) | (other, _) => Err(OpError::BadType(other.get_type())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(a: f64, b: f64) -> f64 {
+        let mut m = CallStack::new();
+        m.push(Value::Real(a));
+        m.push(Value::Real(b));
+        assert!(Add.exec(&mut m).is_ok());
+        match m.pop() {
+            Ok(Value::Real(r)) => r,
+            _ => panic!("expected a Real"),
+        }
+    }
+
+    #[test]
+    fn float_modes_normalize_results() {
+        let nan = f64::from_bits(0x7ff8_0000_dead_beef);
+        set_float_mode(FloatMode::Canonical);
+        assert_eq!(add(nan, 1.0).to_bits(), f64::NAN.to_bits());
+        assert_eq!(add(0.1, 0.2), 0.1 + 0.2);
+        set_float_mode(FloatMode::Fixed(4));
+        assert_eq!(add(0.1, 0.2), 0.3125);
+        assert_eq!(add(1e300, 1e300), 2e300);
+        set_float_mode(FloatMode::Native);
+        assert!(add(nan, 1.0).is_nan());
+    }
+
+    fn run(op: impl Operation, a: Value, b: Value) -> Result<Value, OpError> {
+        let mut m = CallStack::new();
+        m.push(a);
+        m.push(b);
+        op.exec(&mut m)?;
+        m.pop()
+    }
+
+    #[test]
+    fn different_kinds_are_unequal_either_way() {
+        let list = || Value::List(crate::datamodel::List::new(Vec::new()));
+        let one = || Value::Integer(1);
+        let is = |r: Result<Value, OpError>, want: bool| {
+            assert!(matches!(r, Ok(v) if v.is_truthy() == want));
+        };
+        is(run(Eq, one(), list()), false);
+        is(run(Eq, list(), one()), false);
+        is(run(Ne, one(), list()), true);
+        is(run(Ne, list(), one()), true);
+        is(run(Eq, Value::Str("1".into()), one()), false);
+        is(run(Eq, one(), Value::Real(1.0)), true);
+        // ordering an aggregate is an error, whichever side it is on
+        assert!(matches!(
+            run(Lt, one(), list()),
+            Err(OpError::BadType(ValueType::List))
+        ));
+        assert!(matches!(
+            run(Lt, list(), one()),
+            Err(OpError::BadType(ValueType::List))
+        ));
+        assert!(matches!(
+            run(Eq, list(), list()),
+            Err(OpError::BadType(ValueType::List))
+        ));
+    }
+
+    #[test]
+    fn times_order_within_their_kind() {
+        use crate::bytecode::ops::Cmp;
+        use crate::datamodel::{Duration, Timestamp};
+
+        let at = |t| Value::Timestamp(Timestamp(t));
+        let span = |d| Value::Duration(Duration(d));
+        let is = |r: Result<Value, OpError>, want: bool| {
+            assert!(matches!(r, Ok(v) if v.is_truthy() == want));
+        };
+        is(run(Eq, at(5), at(5)), true);
+        is(run(Lt, at(-1), at(5)), true);
+        is(run(Ge, at(5), at(6)), false);
+        is(run(Ne, span(2), span(3)), true);
+        is(run(Gt, span(3), span(-3)), true);
+        // a moment and a span, or a number, are different kinds
+        is(run(Eq, at(5), span(5)), false);
+        is(run(Lt, at(5), span(6)), false);
+        is(run(Eq, at(5), Value::Integer(5)), false);
+        assert!(matches!(run(Cmp, at(1), at(2)), Ok(Value::Integer(-1))));
+        assert!(matches!(run(Cmp, span(2), span(2)), Ok(Value::Integer(0))));
+    }
+}
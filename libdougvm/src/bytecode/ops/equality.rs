@@ -0,0 +1,307 @@
+//! Equality and ordering over every kind of value, where `Eq` and `Lt`
+//! only take scalars:
+//!
+//! - `Same` is identity. Lists, maps, tables, records, tuples, buffers,
+//!   functions, iterators and other reference values are the same only if
+//!   they are the same object; variants are if their tags are and their
+//!   payloads are the same; scalars are if `Eq` finds them equal. It never
+//!   fails, and values of different kinds are never the same.
+//! - `DeepEq` is structural: lists and tuples are equal if their items
+//!   are, in order; maps, tables and records if they have the same keys
+//!   with equal values, in any order; buffers if their bytes are; variants
+//!   if their tags and payloads are. Everything else compares as `Same`.
+//! - `Cmp` orders sortable values: numbers and `Str`s as `Lt` does, and
+//!   lists and tuples lexicographically by their items. Values that don't
+//!   order against each other (NaN, a `Real` and a `Decimal`, a `Str` and
+//!   a number, any other kind) are a `BadType` error.
+//!
+//! Deep comparisons follow cycles: meeting a pair of containers again while
+//! still comparing them counts as equal, so two lists that each hold
+//! themselves are equal rather than compared forever.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use super::arith::compare;
+use crate::bytecode::{OpAction, OpError, Operation};
+use crate::datamodel::{Identity, Value};
+use crate::CallStack;
+
+/// Deepest nesting `DeepEq` and `Cmp` will follow before failing with
+/// `OpError::Overflow`.
+pub const MAX_COMPARE_DEPTH: usize = 256;
+
+/// The identity of a reference value.
+fn identity(val: &Value) -> Option<usize> {
+    Some(match val {
+        Value::List(l) => l.identity(),
+        Value::Map(m) => m.identity(),
+        Value::Table(t) => t.identity(),
+        Value::Record(r) => r.identity(),
+        Value::Tuple(t) => t.identity(),
+        Value::TupleWeak(w) => w.upgrade()?.identity(),
+        Value::Buffer(b) => b.identity(),
+        Value::Function(f) => f.identity(),
+        Value::Closure(c) => c.identity(),
+        Value::NativeFn(f) => *f as usize,
+        Value::Iter(it) => it.identity(),
+        Value::Interface(i) => i.identity(),
+        Value::Coroutine(c) => c.identity(),
+        Value::Unknown(u) => u.identity(),
+        _ => return None,
+    })
+}
+
+/// Whether `a` and `b` are the same value, as `Same` decides.
+pub fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Variant(a), Value::Variant(b)) => {
+            a.tag() == b.tag() && same(a.payload(), b.payload())
+        }
+        (Value::Timestamp(a), Value::Timestamp(b)) => a.0 == b.0,
+        (Value::Duration(a), Value::Duration(b)) => a.0 == b.0,
+        _ => match (identity(a), identity(b)) {
+            (Some(x), Some(y)) => x == y && a.get_type() == b.get_type(),
+            (None, None) => matches!(compare(a, b), Ok(Some(Ordering::Equal))),
+            _ => false,
+        },
+    }
+}
+
+/// Whether `a` and `b` are equal, as `DeepEq` decides.
+pub fn deep_eq(a: &Value, b: &Value) -> Result<bool, OpError> {
+    Deep::default().eq(a, b)
+}
+
+/// How `a` orders against `b`, as `Cmp` decides.
+pub fn order(a: &Value, b: &Value) -> Result<Ordering, OpError> {
+    Deep::default().cmp(a, b)
+}
+
+fn items(val: &Value) -> Vec<Value> {
+    match val {
+        Value::List(l) => l.to_vec(),
+        Value::Tuple(t) => (0..t.len()).filter_map(|i| t.get(i)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Default)]
+struct Deep {
+    /// Pairs of containers being compared, by identity.
+    active: HashSet<(usize, usize)>,
+    depth: usize,
+}
+
+impl Deep {
+    /// Runs `f` one level further down.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, OpError>) -> Result<T, OpError> {
+        if self.depth == MAX_COMPARE_DEPTH {
+            return Err(OpError::Overflow);
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Runs `f` on a pair of containers, or returns `equal` if the pair is
+    /// already being compared further up.
+    fn pair<T>(
+        &mut self,
+        a: &Value,
+        b: &Value,
+        equal: T,
+        f: impl FnOnce(&mut Self) -> Result<T, OpError>,
+    ) -> Result<T, OpError> {
+        let pair = (identity(a).unwrap_or(0), identity(b).unwrap_or(0));
+        if !self.active.insert(pair) {
+            return Ok(equal);
+        }
+        let result = self.nested(f);
+        self.active.remove(&pair);
+        result
+    }
+
+    fn all(&mut self, a: &[Value], b: &[Value]) -> Result<bool, OpError> {
+        if a.len() != b.len() {
+            return Ok(false);
+        }
+        for (a, b) in a.iter().zip(b) {
+            if !self.eq(a, b)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether every field of `a` is in `b`, with an equal value, and they
+    /// have as many fields.
+    fn fields(
+        &mut self,
+        a: Vec<(u64, Value)>,
+        b_len: usize,
+        b: impl Fn(u64) -> Option<Value>,
+    ) -> Result<bool, OpError> {
+        if a.len() != b_len {
+            return Ok(false);
+        }
+        for (key, val) in a {
+            match b(key) {
+                Some(other) if self.eq(&val, &other)? => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    fn eq(&mut self, a: &Value, b: &Value) -> Result<bool, OpError> {
+        match (a, b) {
+            (Value::List(_), Value::List(_)) | (Value::Tuple(_), Value::Tuple(_)) => {
+                self.pair(a, b, true, |this| this.all(&items(a), &items(b)))
+            }
+            (Value::Map(x), Value::Map(y)) => self.pair(a, b, true, |this| {
+                if x.len() != y.len() {
+                    return Ok(false);
+                }
+                for (key, val) in x.entries() {
+                    match y.get(&key)? {
+                        Some(other) if this.eq(&val, &other)? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }),
+            (Value::Table(x), Value::Table(y)) => self.pair(a, b, true, |this| {
+                this.fields(x.entries(), y.len(), |key| y.get(key))
+            }),
+            (Value::Record(x), Value::Record(y)) => self.pair(a, b, true, |this| {
+                let len = y.shape().keys().len();
+                this.fields(x.entries(), len, |key| y.get(key))
+            }),
+            (Value::Buffer(x), Value::Buffer(y)) => Ok(x.to_vec() == y.to_vec()),
+            (Value::Variant(x), Value::Variant(y)) => {
+                Ok(x.tag() == y.tag() && self.nested(|this| this.eq(x.payload(), y.payload()))?)
+            }
+            _ => Ok(same(a, b)),
+        }
+    }
+
+    fn cmp(&mut self, a: &Value, b: &Value) -> Result<Ordering, OpError> {
+        match (a, b) {
+            (Value::List(_), Value::List(_)) | (Value::Tuple(_), Value::Tuple(_)) => {
+                self.pair(a, b, Ordering::Equal, |this| {
+                    let (a, b) = (items(a), items(b));
+                    for (a, b) in a.iter().zip(&b) {
+                        match this.cmp(a, b)? {
+                            Ordering::Equal => {}
+                            unequal => return Ok(unequal),
+                        }
+                    }
+                    Ok(a.len().cmp(&b.len()))
+                })
+            }
+            _ => compare(a, b)?.ok_or(OpError::BadType(b.get_type())),
+        }
+    }
+}
+
+/// Pops two values and pushes whether they are the same value (see the
+/// module docs).
+#[derive(Clone)]
+pub struct Same;
+
+impl Operation for Same {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let rhs = m.pop()?;
+        let lhs = m.pop()?;
+        m.push(same(&lhs, &rhs).into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops two values and pushes whether they are structurally equal (see
+/// the module docs).
+#[derive(Clone)]
+pub struct DeepEq;
+
+impl Operation for DeepEq {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let rhs = m.pop()?;
+        let lhs = m.pop()?;
+        m.push(deep_eq(&lhs, &rhs)?.into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops two values and pushes -1, 0 or 1 as the left orders before, with
+/// or after the right (see the module docs).
+#[derive(Clone)]
+pub struct Cmp;
+
+impl Operation for Cmp {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let rhs = m.pop()?;
+        let lhs = m.pop()?;
+        m.push(Value::Integer(order(&lhs, &rhs)? as i64));
+        Ok(OpAction::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::{List, Map, Tuple, ValueType};
+
+    fn list(items: Vec<Value>) -> Value {
+        List::new(items).into()
+    }
+
+    #[test]
+    fn identity_structure_and_order_are_separate() {
+        let ints = || list(vec![Value::Integer(1), Value::Integer(2)]);
+        let a = ints();
+        assert!(same(&a, &a.clone()));
+        assert!(!same(&a, &ints()));
+        assert!(same(&Value::Integer(1), &Value::Real(1.0)));
+        assert!(matches!(deep_eq(&a, &ints()), Ok(true)));
+        let tuple = Tuple::new(vec![Value::Integer(1), Value::Integer(2)]).into();
+        assert!(matches!(deep_eq(&a, &tuple), Ok(false)));
+
+        // maps are equal whatever order their keys went in
+        let (x, y) = (Map::new(), Map::new());
+        for (map, keys) in [(&x, [1, 2]), (&y, [2, 1])] {
+            for key in keys {
+                assert!(map.insert(Value::Integer(key), ints()).is_ok());
+            }
+        }
+        assert!(matches!(deep_eq(&x.into(), &y.into()), Ok(true)));
+
+        // lists holding themselves
+        let cyclic = || {
+            let l = List::new(vec![Value::Integer(1)]);
+            l.items.borrow_mut().push(l.clone().into());
+            Value::List(l)
+        };
+        assert!(matches!(deep_eq(&cyclic(), &cyclic()), Ok(true)));
+        assert!(matches!(order(&cyclic(), &cyclic()), Ok(Ordering::Equal)));
+
+        let longer = list(vec![
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::Integer(0),
+        ]);
+        assert!(matches!(order(&a, &longer), Ok(Ordering::Less)));
+        let strs = list(vec![Value::Str("a".into())]);
+        assert!(matches!(
+            order(&a, &strs),
+            Err(OpError::BadType(ValueType::Str))
+        ));
+
+        let mut m = CallStack::new();
+        m.push(longer);
+        m.push(a);
+        assert!(Cmp.exec(&mut m).is_ok());
+        assert!(matches!(m.pop(), Ok(Value::Integer(1))));
+    }
+}
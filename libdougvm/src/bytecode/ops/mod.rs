@@ -0,0 +1,784 @@
+use std::convert::TryInto;
+
+mod arith;
+mod equality;
+pub use super::speculate::Speculate;
+pub use arith::*;
+pub use equality::*;
+
+use super::cache::FieldCache;
+use super::{OpAction, OpError, Operation};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::datamodel::{
+    Closure, Interface, Iter, Map, Record, Shape, Str, Table, Tag, Value, Variant, ERR, OK,
+};
+use crate::CallStack;
+
+#[derive(Clone)]
+pub struct Push(pub Value);
+
+impl Operation for Push {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        m.push(self.0.clone());
+        Ok(OpAction::None)
+    }
+}
+
+#[derive(Clone)]
+pub struct Pop;
+
+impl Operation for Pop {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        m.pop()?;
+        Ok(OpAction::None)
+    }
+}
+
+#[derive(Clone)]
+pub struct Load(pub u8);
+
+impl Operation for Load {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.load(self.0)?.clone();
+        m.push(val);
+        Ok(OpAction::None)
+    }
+}
+
+#[derive(Clone)]
+pub struct Store(pub u8);
+
+impl Operation for Store {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.pop()?;
+        m.store(self.0, val);
+        Ok(OpAction::None)
+    }
+}
+
+/// Relative jump; the offset is applied to the cursor after it has already
+/// advanced past this op, so `Jump(-1)` loops on itself.
+#[derive(Clone)]
+pub struct Jump(pub i32);
+
+impl Operation for Jump {
+    fn exec(&self, _m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Jump(self.0))
+    }
+}
+
+/// Pops a value and jumps (see `Jump`) by the offset of the case whose
+/// string it is, or by `default` if it is no case's or not a `Str`. The
+/// cases are a hash table, so a match on strings takes one lookup however
+/// many arms it has.
+#[derive(Clone)]
+pub struct SwitchStr {
+    pub cases: Rc<HashMap<Str, i32>>,
+    pub default: i32,
+}
+
+impl SwitchStr {
+    pub fn new(cases: impl IntoIterator<Item = (Str, i32)>, default: i32) -> SwitchStr {
+        SwitchStr {
+            cases: Rc::new(cases.into_iter().collect()),
+            default,
+        }
+    }
+
+    /// The cases in string order, as they are saved and listed.
+    pub fn sorted_cases(&self) -> Vec<(Str, i32)> {
+        let mut cases: Vec<_> = self.cases.iter().map(|(s, o)| (s.clone(), *o)).collect();
+        cases.sort();
+        cases
+    }
+
+    /// A copy with every offset, `default`'s included, passed through `f`.
+    pub fn map_offsets(&self, f: impl Fn(i32) -> i32) -> SwitchStr {
+        let cases = self.cases.iter().map(|(s, o)| (s.clone(), f(*o)));
+        SwitchStr::new(cases, f(self.default))
+    }
+}
+
+impl Operation for SwitchStr {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let offset = match m.pop()? {
+            Value::Str(s) => self.cases.get(&s).copied(),
+            _ => None,
+        };
+        Ok(OpAction::Jump(offset.unwrap_or(self.default)))
+    }
+}
+
+/// Pops a value and jumps (see `Jump`) by `offsets[n - low]`, where `n` is
+/// the value if it is an `Integer`, or its tag if it is a `Variant`; or by
+/// `default` if `n` is outside the table or the value is neither. A match
+/// on small integers or on enum tags takes one indexed load however many
+/// arms it has.
+#[derive(Clone)]
+pub struct Switch {
+    pub low: i64,
+    pub offsets: Rc<[i32]>,
+    pub default: i32,
+}
+
+impl Switch {
+    pub fn new(low: i64, offsets: Vec<i32>, default: i32) -> Switch {
+        Switch {
+            low,
+            offsets: offsets.into(),
+            default,
+        }
+    }
+
+    /// The offset `n` jumps by.
+    pub fn offset_of(&self, n: i64) -> i32 {
+        let index = n
+            .checked_sub(self.low)
+            .and_then(|i| usize::try_from(i).ok());
+        index
+            .and_then(|i| self.offsets.get(i).copied())
+            .unwrap_or(self.default)
+    }
+
+    /// A copy with every offset, `default`'s included, passed through `f`.
+    pub fn map_offsets(&self, f: impl Fn(i32) -> i32) -> Switch {
+        let offsets = self.offsets.iter().map(|o| f(*o)).collect();
+        Switch::new(self.low, offsets, f(self.default))
+    }
+}
+
+impl Operation for Switch {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let offset = match m.pop()? {
+            Value::Integer(n) => self.offset_of(n),
+            Value::Variant(v) => self.offset_of(v.tag() as i64),
+            _ => self.default,
+        };
+        Ok(OpAction::Jump(offset))
+    }
+}
+
+/// Pops an iterable and pushes an `Iter` over it, following
+/// `Value::iter`; error if it isn't iterable.
+#[derive(Clone)]
+pub struct GetIter;
+
+impl Operation for GetIter {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.pop()?;
+        let iter = val.iter().ok_or(OpError::BadType(val.get_type()))?;
+        m.push(iter.into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Advances the `Iter` on top of the stack: pushes its next item, leaving
+/// the iterator under it, or once it is exhausted pops it and jumps (see
+/// `Jump`). A for-loop is `GetIter`, then `ForIter` to after the loop,
+/// the body, and a `Jump` back to the `ForIter`.
+#[derive(Clone)]
+pub struct ForIter(pub i32);
+
+impl Operation for ForIter {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let iter: Iter = m.pop()?.try_into()?;
+        match iter.next() {
+            Some(item) => {
+                m.push(iter.into());
+                m.push(item);
+                Ok(OpAction::None)
+            }
+            None => Ok(OpAction::Jump(self.0)),
+        }
+    }
+}
+
+/// Pops a condition and jumps (see `Jump`) if it is truthy.
+#[derive(Clone)]
+pub struct JumpIf(pub i32);
+
+impl Operation for JumpIf {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(match m.pop()?.is_truthy() {
+            true => OpAction::Jump(self.0),
+            false => OpAction::None,
+        })
+    }
+}
+
+/// Pops a condition and jumps (see `Jump`) if it is falsy.
+#[derive(Clone)]
+pub struct JumpIfNot(pub i32);
+
+impl Operation for JumpIfNot {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(match m.pop()?.is_truthy() {
+            true => OpAction::None,
+            false => OpAction::Jump(self.0),
+        })
+    }
+}
+
+/// Fused tail of a counted loop: `local += 1`, then jump (see `Jump`) if
+/// `local < limit`, where both are `Integer` locals.
+#[derive(Clone)]
+pub struct IncJumpLt {
+    pub local: u8,
+    pub limit: u8,
+    pub offset: i32,
+}
+
+impl Operation for IncJumpLt {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let limit = match m.load(self.limit)? {
+            Value::Integer(i) => *i,
+            other => return Err(OpError::BadType(other.get_type())),
+        };
+        let next = match m.load(self.local)? {
+            Value::Integer(i) => i.checked_add(1).ok_or(OpError::Overflow)?,
+            other => return Err(OpError::BadType(other.get_type())),
+        };
+        m.store(self.local, Value::Integer(next));
+        Ok(match next < limit {
+            true => OpAction::Jump(self.offset),
+            false => OpAction::None,
+        })
+    }
+}
+
+/// Pops `b`, `a`, then a condition (pushed in the order `cond, a, b`) and
+/// pushes `a` if the condition is truthy, else `b`, without branching.
+#[derive(Clone)]
+pub struct Select;
+
+impl Operation for Select {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let b = m.pop()?;
+        let a = m.pop()?;
+        let cond = m.pop()?;
+        m.push(if cond.is_truthy() { a } else { b });
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops `n` values off the stack, so for `[A, B, C]` with `C` on top the
+/// result is `[C, B, A]`. See `Call` for why that order is kept.
+fn pop_args(m: &mut CallStack, n: usize) -> Result<Vec<Value>, OpError> {
+    (0..n).map(|_| m.pop()).collect()
+}
+
+fn call(callee: Value, args: Vec<Value>) -> Result<OpAction, OpError> {
+    match callee {
+        Value::Function(func) => Ok(OpAction::Call(func, args)),
+        Value::NativeFn(func) => Ok(OpAction::CallNative(func, args)),
+        Value::Closure(closure) => Ok(OpAction::CallClosure(closure, args)),
+        other => Err(OpError::BadType(other.get_type())),
+    }
+}
+
+/// Pops a callable, then `argc` arguments. For expr `f(A, B, C)` the
+/// compiler pushes `A, B, C, f`, so the args are collected as `[C, B, A]`;
+/// the VM pushes them onto the callee's stack in that order, leaving `A` on
+/// top to be popped (and stored into a local) first.
+#[derive(Clone)]
+pub struct Call(pub u8);
+
+impl Operation for Call {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let callee = m.pop()?;
+        let args = pop_args(m, self.0 as usize)?;
+        call(callee, args)
+    }
+}
+
+/// Pops a `Function` and pushes a `Closure` of it, capturing copies of
+/// `locals` as its upvalues 0, 1, ... in order.
+#[derive(Clone)]
+pub struct MakeClosure {
+    pub locals: Rc<[u8]>,
+}
+
+impl Operation for MakeClosure {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let function = m.pop()?.try_into()?;
+        let upvalues = (self.locals.iter())
+            .map(|&i| m.load(i).cloned())
+            .collect::<Result<Rc<[Value]>, _>>()?;
+        m.push(Closure { function, upvalues }.into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pushes upvalue `n` of the closure the frame is running.
+#[derive(Clone)]
+pub struct LoadUpvalue(pub u8);
+
+impl Operation for LoadUpvalue {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.upvalue(self.0)?.clone();
+        m.push(val);
+        Ok(OpAction::None)
+    }
+}
+
+#[derive(Clone)]
+pub struct Return;
+
+impl Operation for Return {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Return(m.pop()?))
+    }
+}
+
+/// Leaves the frame with `None` as its result, as a function without a
+/// result of its own ends. Running past the last op is an error (see
+/// `verify`), so every function ends in this, `Return`, `Throw` or `Jump`.
+#[derive(Clone)]
+pub struct Halt;
+
+impl Operation for Halt {
+    fn exec(&self, _m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Return(Value::None))
+    }
+}
+
+/// Pops a payload and pushes it wrapped in a `Variant` with the given tag.
+#[derive(Clone)]
+pub struct MakeVariant(pub Tag);
+
+impl Operation for MakeVariant {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let payload = m.pop()?;
+        m.push(Variant::new(self.0, payload).into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a `Variant` and pushes whether its tag matches.
+#[derive(Clone)]
+pub struct IsTag(pub Tag);
+
+impl Operation for IsTag {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let variant: Variant = m.pop()?.try_into()?;
+        m.push((variant.tag() == self.0).into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a `Variant` and pushes its tag as an `Integer`.
+#[derive(Clone)]
+pub struct GetTag;
+
+impl Operation for GetTag {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let variant: Variant = m.pop()?.try_into()?;
+        m.push(Value::Integer(variant.tag() as i64));
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a `Variant` and pushes its payload, failing if the tag doesn't match.
+#[derive(Clone)]
+pub struct Unwrap(pub Tag);
+
+impl Operation for Unwrap {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let variant: Variant = m.pop()?.try_into()?;
+        if variant.tag() != self.0 {
+            return Err(OpError::BadTag {
+                found: variant.tag(),
+                expected: self.0,
+            });
+        }
+        m.push(variant.payload().clone());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops an Option or Result `Variant`. The success arm's payload is pushed;
+/// the failure arm is returned from the current function unchanged, like
+/// Rust's `?` operator.
+#[derive(Clone)]
+pub struct Try;
+
+impl Operation for Try {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let variant: Variant = m.pop()?.try_into()?;
+        match variant.tag() {
+            OK => {
+                m.push(variant.payload().clone());
+                Ok(OpAction::None)
+            }
+            ERR => Ok(OpAction::Return(variant.into())),
+            found => Err(OpError::BadTag {
+                found,
+                expected: OK,
+            }),
+        }
+    }
+}
+
+/// Pops an `Interface`, then a value, and pushes whether the value's type
+/// implements the interface.
+#[derive(Clone)]
+pub struct Implements;
+
+impl Operation for Implements {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let interface: Interface = m.pop()?.try_into()?;
+        let val = m.pop()?;
+        m.push(interface.is_implemented_by(&val).into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops an `Interface`, then `argc` arguments, then the receiver, and calls
+/// the receiver type's implementation of `method`. The receiver is passed
+/// as the first argument (see `Call` for argument order).
+#[derive(Clone)]
+pub struct Invoke {
+    pub method: u16,
+    pub argc: u8,
+}
+
+impl Operation for Invoke {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let interface: Interface = m.pop()?.try_into()?;
+        // receiver sits below the args, so it ends up last, i.e. popped first
+        let args = pop_args(m, self.argc as usize + 1)?;
+        let receiver = &args[args.len() - 1];
+        let table = interface
+            .table(receiver.type_key())
+            .ok_or_else(|| OpError::NotImplemented(receiver.get_type()))?;
+        let method = table
+            .get(self.method as usize)
+            .ok_or(OpError::IndexRead(self.method as i64))?
+            .clone();
+        call(method, args)
+    }
+}
+
+/// Pushes a new, empty `Table`.
+#[derive(Clone)]
+pub struct NewTable;
+
+impl Operation for NewTable {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        m.push(Table::new().into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a `Str` and pushes the `Symbol` of that name from the VM's
+/// intern table.
+#[derive(Clone)]
+pub struct Intern;
+
+impl Operation for Intern {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Intern(m.pop()?.try_into()?))
+    }
+}
+
+/// Pops one value per field of `shape`, the last field's on top, and
+/// pushes a `Record` holding them.
+#[derive(Clone)]
+pub struct NewRecord {
+    pub shape: Rc<Shape>,
+}
+
+impl Operation for NewRecord {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let mut values = pop_args(m, self.shape.keys().len())?;
+        values.reverse();
+        m.push(Record::new(self.shape.clone(), values).unwrap().into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a `Table` or `Record` and pushes the field with the given key,
+/// following a table's prototype chain if it has one. Own fields are found
+/// through the site's inline cache.
+#[derive(Clone)]
+pub struct GetField {
+    pub key: u64,
+    pub cache: FieldCache,
+}
+
+impl GetField {
+    pub fn new(key: u64) -> GetField {
+        GetField {
+            key,
+            cache: FieldCache::new(),
+        }
+    }
+}
+
+impl Operation for GetField {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let table: Table = match m.pop()? {
+            Value::Record(record) => {
+                let slot = self.cache.record_slot(&record, self.key);
+                let val = slot.ok_or(OpError::FieldRead(self.key))?;
+                m.push(record.get_slot(val));
+                return Ok(OpAction::None);
+            }
+            other => other.try_into()?,
+        };
+        let val = match self.cache.slot(&table, self.key) {
+            Some(slot) => table.get_slot(slot),
+            None => table.lookup(self.key),
+        };
+        m.push(val.ok_or(OpError::FieldRead(self.key))?);
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a value, then a `Table` or `Record`, and stores the value in its
+/// own field (prototypes are never written through). A record can't gain
+/// fields, so setting one it lacks is an error.
+#[derive(Clone)]
+pub struct SetField {
+    pub key: u64,
+    pub cache: FieldCache,
+}
+
+impl SetField {
+    pub fn new(key: u64) -> SetField {
+        SetField {
+            key,
+            cache: FieldCache::new(),
+        }
+    }
+}
+
+impl Operation for SetField {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.pop()?;
+        let table: Table = match m.pop()? {
+            Value::Record(record) => {
+                let slot = self.cache.record_slot(&record, self.key);
+                record.set_slot(slot.ok_or(OpError::FieldWrite(self.key))?, val);
+                return Ok(OpAction::None);
+            }
+            other => other.try_into()?,
+        };
+        match self.cache.slot(&table, self.key) {
+            Some(slot) => table.set_slot(slot, val),
+            None => table.set(self.key, val),
+        }
+        Ok(OpAction::None)
+    }
+}
+
+/// Pushes a new, empty `Map`.
+#[derive(Clone)]
+pub struct NewMap;
+
+impl Operation for NewMap {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        m.push(Map::new().into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a value, a key, then a `Map`, and sets the key to the value.
+#[derive(Clone)]
+pub struct MapInsert;
+
+impl Operation for MapInsert {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.pop()?;
+        let key = m.pop()?;
+        let map: Map = m.pop()?.try_into()?;
+        map.insert(key, val)?;
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a key then a `Map` and pushes the key's value; error if missing.
+#[derive(Clone)]
+pub struct MapGet;
+
+impl Operation for MapGet {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let key = m.pop()?;
+        let map: Map = m.pop()?.try_into()?;
+        let val = map.get(&key)?.ok_or(OpError::KeyRead(key))?;
+        m.push(val);
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a key then a `Map`, removes the key's entry, and pushes 1 if there
+/// was one, else 0.
+#[derive(Clone)]
+pub struct MapDelete;
+
+impl Operation for MapDelete {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let key = m.pop()?;
+        let map: Map = m.pop()?.try_into()?;
+        m.push(map.remove(&key)?.is_some().into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a key then a `Map` and pushes 1 if the map has the key, else 0.
+#[derive(Clone)]
+pub struct MapContains;
+
+impl Operation for MapContains {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let key = m.pop()?;
+        let map: Map = m.pop()?.try_into()?;
+        m.push(map.contains(&key)?.into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops a `Map` and pushes an `Iter` over its entries as `(key, value)`
+/// tuples, in order. It iterates a snapshot, so changing the map while
+/// iterating doesn't affect it.
+#[derive(Clone)]
+pub struct MapIter;
+
+impl Operation for MapIter {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let map: Map = m.pop()?.try_into()?;
+        m.push(map.iter().into());
+        Ok(OpAction::None)
+    }
+}
+
+/// Pops `argc` arguments and calls the host function registered at
+/// `index` in the VM's `NativeRegistry` with them, pushing its result.
+/// Arguments are pushed in order, as for `Call`.
+#[derive(Clone)]
+pub struct CallHost {
+    pub index: u32,
+    pub argc: u8,
+}
+
+impl Operation for CallHost {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let mut args = pop_args(m, self.argc as usize)?;
+        args.reverse();
+        Ok(OpAction::CallHost(self.index, args))
+    }
+}
+
+/// Installs an exception handler in the current frame, at this offset
+/// (see `Jump`). If an exception is thrown before the matching
+/// `PopHandler`, here or in a function called from here, the stack is cut
+/// back to its height at this op, the exception is pushed, and execution
+/// continues at the handler. Handlers nest; the innermost is used first.
+#[derive(Clone)]
+pub struct PushHandler(pub i32);
+
+impl Operation for PushHandler {
+    fn exec(&self, _m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::PushHandler(self.0))
+    }
+}
+
+/// Removes the current frame's innermost handler, at the end of the code
+/// it guards.
+#[derive(Clone)]
+pub struct PopHandler;
+
+impl Operation for PopHandler {
+    fn exec(&self, _m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::PopHandler)
+    }
+}
+
+/// Pops a release function, then a resource, and pushes the resource
+/// back, opening a scope in the current frame that ends at the matching
+/// `Exit`. However the scope is left, by `Exit`, by returning or by an
+/// exception unwinding past it, the release function is called once with
+/// the resource, and its result discarded. Scopes nest with each other and
+/// with handlers; the innermost is released first. A VM stopped by an
+/// error nothing catches leaves its scopes open, to the finalizers of
+/// whatever userdata they hold (see `crate::finalize`).
+///
+/// The release function must be a `NativeFn`, as a host resource's
+/// close or free would be.
+#[derive(Clone)]
+pub struct Enter;
+
+impl Operation for Enter {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let release = match m.pop()? {
+            Value::NativeFn(func) => func,
+            other => return Err(OpError::BadType(other.get_type())),
+        };
+        let resource = m.pop()?;
+        m.push(resource.clone());
+        Ok(OpAction::Enter(resource, release))
+    }
+}
+
+/// Closes the current frame's innermost scope, releasing its resource
+/// (see `Enter`).
+#[derive(Clone)]
+pub struct Exit;
+
+impl Operation for Exit {
+    fn exec(&self, _m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Exit)
+    }
+}
+
+/// Pops a value and throws it. Errors raised by ops are thrown the same
+/// way, as the exceptions in `crate::exception`, when a handler is
+/// installed.
+#[derive(Clone)]
+pub struct Throw;
+
+impl Operation for Throw {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Throw(m.pop()?))
+    }
+}
+
+/// Pops a value and suspends the running coroutine with it, handing it to
+/// the resumer. When resumed, pushes the value it was resumed with. See
+/// `crate::coroutine`.
+#[derive(Clone)]
+pub struct Yield;
+
+impl Operation for Yield {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::Yield(m.pop()?))
+    }
+}
+
+/// Pushes the value of the named module, running its top-level code first
+/// if this is its first import. See `crate::modules`.
+#[derive(Clone)]
+pub struct LoadModule(pub Str);
+
+impl Operation for LoadModule {
+    fn exec(&self, _m: &mut CallStack) -> Result<OpAction, OpError> {
+        Ok(OpAction::LoadModule(self.0.clone()))
+    }
+}
+
+/// Pops a value, then a `Coroutine`, and runs the coroutine with the value
+/// until it yields or returns, pushing what it yielded or returned.
+#[derive(Clone)]
+pub struct Resume;
+
+impl Operation for Resume {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        let val = m.pop()?;
+        match m.pop()? {
+            Value::Coroutine(co) => Ok(OpAction::Resume(co, val)),
+            other => Err(OpError::BadType(other.get_type())),
+        }
+    }
+}
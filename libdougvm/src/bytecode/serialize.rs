@@ -0,0 +1,1275 @@
+//! A binary format for compiled functions, so a program can be compiled
+//! once and loaded later without the compiler.
+//!
+//! A file holds a graph rather than a tree: the tuples and functions it
+//! reaches are stored once each, in tables, and referred to by index, so
+//! sharing survives a round trip and a module tuple holding functions that
+//! refer back to it can be stored at all. Functions are written callees
+//! first, and tuple items last. Natives are stored by name and looked up in
+//! a `NativeTable` on load.
+//!
+//! Layout, little-endian:
+//!
+//! ```text
+//! magic "DGBC", format u16, op set u32, checksum u64 (FNV-1a of the body)
+//! body: tuple count u32, each tuple's length u32
+//!       function count u32, each function: cfg, module tuple u32,
+//!           op count u32, ops, block count u32, each block: start u32,
+//!           end u32, cfg
+//!       each tuple's items
+//!       root function u32
+//! cfg: 0 (always), 1 flag name, 2 cfg (not), 3 or 4 count u32, cfgs
+//!      (all or any)
+//! ```
+//!
+//! Format 1 files, from before conditional code (see `cfg`), have no cfgs
+//! or blocks and still load.
+//!
+//! Bytecode from an older op set is upgraded on load (see `compat`), and
+//! then verified (see `verify`).
+//! `Speculate` ops are stored as their generic op; tiering can specialize
+//! again after loading. Iterators, interfaces and host values can't be
+//! stored.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bytecode::cfg::{self, Cfg, CfgTable, Features};
+use crate::bytecode::compat::{self, CompatError, OP_SET_VERSION};
+use crate::bytecode::ops::*;
+use crate::bytecode::{verify, Op};
+use crate::codec::Reader;
+use crate::datamodel::{
+    Buffer, Decimal, Duration, Function, Identity, List, Map, NativeFn, Record, Shape, Str, Table,
+    Timestamp, Tuple, Value, ValueType, Variant,
+};
+
+pub const MAGIC: &[u8; 4] = b"DGBC";
+pub const FORMAT_VERSION: u16 = 2;
+/// Deepest nesting of a stored `Cfg`.
+const MAX_CFG_DEPTH: usize = 64;
+/// Deepest nesting of a stored value.
+const MAX_VALUE_DEPTH: usize = 64;
+const HEADER_LEN: usize = 4 + 2 + 4 + 8;
+
+/// Natives a file may refer to, by name.
+#[derive(Default)]
+pub struct NativeTable {
+    entries: Vec<(String, NativeFn)>,
+}
+
+impl NativeTable {
+    pub fn new() -> NativeTable {
+        NativeTable::default()
+    }
+
+    pub fn add(&mut self, name: &str, func: NativeFn) {
+        self.entries.push((name.to_string(), func));
+    }
+
+    fn name_of(&self, func: NativeFn) -> Option<&str> {
+        let found = self
+            .entries
+            .iter()
+            .find(|(_, f)| *f as usize == func as usize);
+        found.map(|(name, _)| name.as_str())
+    }
+
+    fn get(&self, name: &str) -> Option<NativeFn> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, f)| *f)
+    }
+}
+
+pub enum SaveError {
+    /// A constant of this kind can't be stored.
+    Unserializable(ValueType),
+    /// A native missing from the `NativeTable`.
+    UnnamedNative,
+    /// A constant nested deeper than a file may hold, or holding itself.
+    TooDeep,
+}
+
+pub enum LoadError {
+    BadMagic,
+    /// Written in a format version this crate can't read.
+    UnsupportedFormat(u16),
+    OpSet(CompatError),
+    /// The body doesn't match its checksum.
+    Corrupt,
+    Truncated,
+    /// Structurally invalid, e.g. an out-of-range reference or jump.
+    Malformed(String),
+    UnknownNative(String),
+    /// The root function's cfg leaves it out under the features given.
+    Excluded,
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Unserializable(t) => write!(f, "a {} can't be serialized", t.as_str()),
+            SaveError::UnnamedNative => write!(f, "a native is missing from the native table"),
+            SaveError::TooDeep => write!(f, "a constant is nested too deeply"),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "not a bytecode file"),
+            LoadError::UnsupportedFormat(v) => write!(f, "unsupported format version {}", v),
+            LoadError::OpSet(CompatError::TooNew(v)) => {
+                write!(
+                    f,
+                    "op set {} is newer than this VM's ({})",
+                    v, OP_SET_VERSION
+                )
+            }
+            LoadError::OpSet(CompatError::Unknown(v)) => write!(f, "unknown op set {}", v),
+            LoadError::Corrupt => write!(f, "checksum mismatch; the file is corrupt"),
+            LoadError::Truncated => write!(f, "file is truncated"),
+            LoadError::Malformed(what) => write!(f, "malformed file: {}", what),
+            LoadError::UnknownNative(name) => write!(f, "unknown native {}", name),
+            LoadError::Excluded => write!(f, "the root function is left out by its cfg"),
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// value kinds
+const NONE: u8 = 0;
+const INTEGER: u8 = 1;
+const REAL: u8 = 2;
+const DECIMAL: u8 = 3;
+const STR: u8 = 4;
+const BUFFER: u8 = 5;
+const TIMESTAMP: u8 = 6;
+const DURATION: u8 = 7;
+const LIST: u8 = 8;
+const TABLE: u8 = 9;
+const VARIANT: u8 = 10;
+const TUPLE_REF: u8 = 11;
+const TUPLE_WEAK_REF: u8 = 12;
+const FUNCTION_REF: u8 = 13;
+const NATIVE: u8 = 14;
+/// A dead weak reference.
+const TUPLE_WEAK_DEAD: u8 = 15;
+const RECORD: u8 = 16;
+const MAP: u8 = 17;
+
+// cfg kinds
+const ALWAYS: u8 = 0;
+const FLAG: u8 = 1;
+const NOT: u8 = 2;
+const ALL: u8 = 3;
+const ANY: u8 = 4;
+
+fn encode_cfg(cfg: Option<&Cfg>, out: &mut Vec<u8>) {
+    match cfg {
+        None => out.push(ALWAYS),
+        Some(Cfg::Flag(name)) => {
+            out.push(FLAG);
+            chunk(out, name.as_bytes());
+        }
+        Some(Cfg::Not(cfg)) => {
+            out.push(NOT);
+            encode_cfg(Some(cfg), out);
+        }
+        Some(Cfg::All(cfgs)) => encode_cfgs(ALL, cfgs, out),
+        Some(Cfg::Any(cfgs)) => encode_cfgs(ANY, cfgs, out),
+    }
+}
+
+fn encode_cfgs(kind: u8, cfgs: &[Cfg], out: &mut Vec<u8>) {
+    out.push(kind);
+    out.extend((cfgs.len() as u32).to_le_bytes());
+    cfgs.iter().for_each(|c| encode_cfg(Some(c), out));
+}
+
+struct Encoder<'a> {
+    natives: &'a NativeTable,
+    tuples: Vec<Tuple>,
+    tuple_ids: HashMap<usize, u32>,
+    functions: Vec<Function>,
+    function_ids: HashMap<(usize, usize), u32>,
+    /// Tuples whose items haven't been scanned yet.
+    pending: Vec<Tuple>,
+}
+
+fn function_key(func: &Function) -> (usize, usize) {
+    (func.identity(), func.module.identity())
+}
+
+fn tuple_items(t: &Tuple) -> Vec<Value> {
+    (0..t.len()).filter_map(|i| t.get(i)).collect()
+}
+
+impl<'a> Encoder<'a> {
+    fn tuple(&mut self, t: &Tuple) -> u32 {
+        if let Some(id) = self.tuple_ids.get(&t.identity()) {
+            return *id;
+        }
+        let id = self.tuples.len() as u32;
+        self.tuple_ids.insert(t.identity(), id);
+        self.tuples.push(t.clone());
+        self.pending.push(t.clone());
+        id
+    }
+
+    /// Registers `func` after everything its ops push.
+    fn function(&mut self, func: &Function) -> Result<u32, SaveError> {
+        if let Some(id) = self.function_ids.get(&function_key(func)) {
+            return Ok(*id);
+        }
+        self.tuple(&func.module);
+        for op in func.ops.iter() {
+            if let Op::Push(Push(val)) = generic(op) {
+                self.scan(val)?;
+            }
+        }
+        let id = self.functions.len() as u32;
+        self.function_ids.insert(function_key(func), id);
+        self.functions.push(func.clone());
+        Ok(id)
+    }
+
+    /// Registers the tuples and functions `val` refers to.
+    fn scan(&mut self, val: &Value) -> Result<(), SaveError> {
+        self.scan_at(val, 0)
+    }
+
+    fn scan_at(&mut self, val: &Value, depth: usize) -> Result<(), SaveError> {
+        if depth > MAX_VALUE_DEPTH {
+            return Err(SaveError::TooDeep);
+        }
+        let depth = depth + 1;
+        match val {
+            Value::Tuple(t) => {
+                self.tuple(t);
+            }
+            Value::TupleWeak(w) => {
+                if let Some(t) = w.upgrade() {
+                    self.tuple(&t);
+                }
+            }
+            Value::Function(f) => {
+                self.function(f)?;
+            }
+            Value::List(l) => l.to_vec().iter().try_for_each(|v| self.scan_at(v, depth))?,
+            Value::Table(t) => t
+                .entries()
+                .iter()
+                .try_for_each(|(_, v)| self.scan_at(v, depth))?,
+            Value::Map(m) => m
+                .entries()
+                .iter()
+                .try_for_each(|(_, v)| self.scan_at(v, depth))?,
+            Value::Record(r) => r
+                .entries()
+                .iter()
+                .try_for_each(|(_, v)| self.scan_at(v, depth))?,
+            Value::Variant(v) => self.scan_at(v.payload(), depth)?,
+            Value::NativeFn(f) => {
+                self.natives.name_of(*f).ok_or(SaveError::UnnamedNative)?;
+            }
+            Value::Interface(_)
+            | Value::Closure(_)
+            | Value::Iter(_)
+            | Value::Coroutine(_)
+            | Value::Symbol(_)
+            | Value::Unknown(_) => return Err(SaveError::Unserializable(val.get_type())),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn value(&self, val: &Value, out: &mut Vec<u8>) -> Result<(), SaveError> {
+        self.value_at(val, out, 0)
+    }
+
+    /// Nested no deeper than `Reader::value_at` will load.
+    fn value_at(&self, val: &Value, out: &mut Vec<u8>, depth: usize) -> Result<(), SaveError> {
+        if depth > MAX_VALUE_DEPTH {
+            return Err(SaveError::TooDeep);
+        }
+        let depth = depth + 1;
+        match val {
+            Value::None => out.push(NONE),
+            Value::Integer(i) => {
+                out.push(INTEGER);
+                out.extend(i.to_le_bytes());
+            }
+            Value::Real(r) => {
+                out.push(REAL);
+                out.extend(r.to_bits().to_le_bytes());
+            }
+            Value::Decimal(d) => {
+                out.push(DECIMAL);
+                out.extend(d.units().to_le_bytes());
+                out.push(d.scale());
+            }
+            Value::Str(s) => {
+                out.push(STR);
+                chunk(out, s.as_bytes());
+            }
+            Value::Buffer(b) => {
+                out.push(BUFFER);
+                chunk(out, &b.to_vec());
+            }
+            Value::Timestamp(t) => {
+                out.push(TIMESTAMP);
+                out.extend(t.0.to_le_bytes());
+            }
+            Value::Duration(d) => {
+                out.push(DURATION);
+                out.extend(d.0.to_le_bytes());
+            }
+            Value::List(l) => {
+                let items = l.to_vec();
+                out.push(LIST);
+                out.extend((items.len() as u32).to_le_bytes());
+                items
+                    .iter()
+                    .try_for_each(|v| self.value_at(v, out, depth))?;
+            }
+            Value::Table(t) => {
+                let entries = t.entries();
+                out.push(TABLE);
+                out.extend((entries.len() as u32).to_le_bytes());
+                for (k, v) in entries {
+                    out.extend(k.to_le_bytes());
+                    self.value_at(&v, out, depth)?;
+                }
+            }
+            Value::Map(m) => {
+                let entries = m.entries();
+                out.push(MAP);
+                out.extend((entries.len() as u32).to_le_bytes());
+                for (k, v) in entries {
+                    self.value_at(&k, out, depth)?;
+                    self.value_at(&v, out, depth)?;
+                }
+            }
+            Value::Record(r) => {
+                out.push(RECORD);
+                self.keys(r.shape().keys(), out);
+                r.entries()
+                    .iter()
+                    .try_for_each(|(_, v)| self.value_at(v, out, depth))?;
+            }
+            Value::Variant(v) => {
+                out.push(VARIANT);
+                out.extend(v.tag().to_le_bytes());
+                self.value_at(v.payload(), out, depth)?;
+            }
+            Value::Tuple(t) => {
+                out.push(TUPLE_REF);
+                out.extend(self.tuple_ids[&t.identity()].to_le_bytes());
+            }
+            Value::TupleWeak(w) => match w.upgrade() {
+                Some(t) => {
+                    out.push(TUPLE_WEAK_REF);
+                    out.extend(self.tuple_ids[&t.identity()].to_le_bytes());
+                }
+                None => out.push(TUPLE_WEAK_DEAD),
+            },
+            Value::Function(f) => {
+                out.push(FUNCTION_REF);
+                out.extend(self.function_ids[&function_key(f)].to_le_bytes());
+            }
+            Value::NativeFn(f) => {
+                out.push(NATIVE);
+                chunk(out, self.natives.name_of(*f).unwrap().as_bytes());
+            }
+            other => return Err(SaveError::Unserializable(other.get_type())),
+        }
+        Ok(())
+    }
+
+    fn keys(&self, keys: &[u64], out: &mut Vec<u8>) {
+        out.extend((keys.len() as u32).to_le_bytes());
+        keys.iter().for_each(|k| out.extend(k.to_le_bytes()));
+    }
+
+    fn op(&self, op: &Op, out: &mut Vec<u8>) -> Result<(), SaveError> {
+        let op = generic(op);
+        out.push(opcode(op));
+        match op {
+            Op::Push(Push(val)) => self.value(val, out)?,
+            Op::Load(Load(i)) | Op::Store(Store(i)) | Op::Call(Call(i)) => out.push(*i),
+            Op::LoadUpvalue(LoadUpvalue(i)) => out.push(*i),
+            Op::MakeClosure(op) => chunk(out, &op.locals),
+            Op::Jump(Jump(o))
+            | Op::JumpIf(JumpIf(o))
+            | Op::JumpIfNot(JumpIfNot(o))
+            | Op::ForIter(ForIter(o)) => out.extend(o.to_le_bytes()),
+            Op::PushHandler(PushHandler(o)) => out.extend(o.to_le_bytes()),
+            Op::CallHost(op) => {
+                out.extend(op.index.to_le_bytes());
+                out.push(op.argc);
+            }
+            Op::IncJumpLt(op) => {
+                out.extend([op.local, op.limit]);
+                out.extend(op.offset.to_le_bytes());
+            }
+            Op::AddImm(op) => {
+                out.push(op.local);
+                out.extend(op.imm.to_le_bytes());
+            }
+            Op::AddLocals(op) => out.extend([op.a, op.b]),
+            Op::Switch(op) => {
+                out.extend(op.low.to_le_bytes());
+                out.extend(op.default.to_le_bytes());
+                out.extend((op.offsets.len() as u32).to_le_bytes());
+                op.offsets.iter().for_each(|o| out.extend(o.to_le_bytes()));
+            }
+            Op::SwitchStr(op) => {
+                out.extend(op.default.to_le_bytes());
+                let cases = op.sorted_cases();
+                out.extend((cases.len() as u32).to_le_bytes());
+                for (s, offset) in cases {
+                    chunk(out, s.as_bytes());
+                    out.extend(offset.to_le_bytes());
+                }
+            }
+            Op::MakeVariant(MakeVariant(t)) | Op::IsTag(IsTag(t)) | Op::Unwrap(Unwrap(t)) => {
+                out.extend(t.to_le_bytes())
+            }
+            Op::Invoke(op) => {
+                out.extend(op.method.to_le_bytes());
+                out.push(op.argc);
+            }
+            Op::LoadModule(LoadModule(name)) => self.value(&Value::Str(name.clone()), out)?,
+            Op::NewRecord(op) => self.keys(op.shape.keys(), out),
+            Op::GetField(op) => out.extend(op.key.to_le_bytes()),
+            Op::SetField(op) => out.extend(op.key.to_le_bytes()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn chunk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+/// The op a `Speculate` falls back to; other ops unchanged.
+fn generic(op: &Op) -> &Op {
+    match op {
+        Op::Speculate(s) => generic(&s.generic),
+        op => op,
+    }
+}
+
+/// Opcodes follow the order ops are declared in (see `spec::SPEC`); they
+/// are part of the format and must not be renumbered.
+fn opcode(op: &Op) -> u8 {
+    match op {
+        Op::Push(_) => 0,
+        Op::Pop(_) => 1,
+        Op::Load(_) => 2,
+        Op::Store(_) => 3,
+        Op::Jump(_) => 4,
+        Op::JumpIf(_) => 5,
+        Op::JumpIfNot(_) => 6,
+        Op::IncJumpLt(_) => 7,
+        Op::Select(_) => 8,
+        Op::Call(_) => 9,
+        Op::Return(_) => 10,
+        Op::Add(_) => 11,
+        Op::Sub(_) => 12,
+        Op::Mul(_) => 13,
+        Op::Div(_) => 14,
+        Op::Rem(_) => 15,
+        Op::Neg(_) => 16,
+        Op::Eq(_) => 17,
+        Op::Ne(_) => 18,
+        Op::Lt(_) => 19,
+        Op::Le(_) => 20,
+        Op::Gt(_) => 21,
+        Op::Ge(_) => 22,
+        Op::AddInt(_) => 23,
+        Op::SubInt(_) => 24,
+        Op::LtInt(_) => 25,
+        Op::Speculate(_) => 26,
+        Op::MakeVariant(_) => 27,
+        Op::IsTag(_) => 28,
+        Op::GetTag(_) => 29,
+        Op::Unwrap(_) => 30,
+        Op::Try(_) => 31,
+        Op::Implements(_) => 32,
+        Op::Invoke(_) => 33,
+        Op::NewTable(_) => 34,
+        Op::GetField(_) => 35,
+        Op::SetField(_) => 36,
+        Op::PushHandler(_) => 37,
+        Op::PopHandler(_) => 38,
+        Op::Throw(_) => 39,
+        Op::CallHost(_) => 40,
+        Op::Halt(_) => 41,
+        Op::Yield(_) => 42,
+        Op::Resume(_) => 43,
+        Op::LoadModule(_) => 44,
+        Op::NewRecord(_) => 45,
+        Op::Intern(_) => 46,
+        Op::AddIntUnchecked(_) => 47,
+        Op::SubIntUnchecked(_) => 48,
+        Op::AddImm(_) => 49,
+        Op::AddLocals(_) => 50,
+        Op::Enter(_) => 51,
+        Op::Exit(_) => 52,
+        Op::NewMap(_) => 53,
+        Op::MapInsert(_) => 54,
+        Op::MapGet(_) => 55,
+        Op::MapDelete(_) => 56,
+        Op::MapContains(_) => 57,
+        Op::MapIter(_) => 58,
+        Op::GetIter(_) => 59,
+        Op::ForIter(_) => 60,
+        Op::SwitchStr(_) => 61,
+        Op::Same(_) => 62,
+        Op::DeepEq(_) => 63,
+        Op::Cmp(_) => 64,
+        Op::Switch(_) => 65,
+        Op::MakeClosure(_) => 66,
+        Op::LoadUpvalue(_) => 67,
+    }
+}
+
+/// Encodes `func` with everything it reaches.
+pub fn save(func: &Function, natives: &NativeTable) -> Result<Vec<u8>, SaveError> {
+    save_with_cfg(func, natives, &CfgTable::new())
+}
+
+/// Encodes `func` with everything it reaches, and the conditions in `cfgs`
+/// on those functions, for `load_with_features` to apply.
+pub fn save_with_cfg(
+    func: &Function,
+    natives: &NativeTable,
+    cfgs: &CfgTable,
+) -> Result<Vec<u8>, SaveError> {
+    let mut enc = Encoder {
+        natives,
+        tuples: Vec::new(),
+        tuple_ids: HashMap::new(),
+        functions: Vec::new(),
+        function_ids: HashMap::new(),
+        pending: Vec::new(),
+    };
+    let root = enc.function(func)?;
+    while let Some(t) = enc.pending.pop() {
+        tuple_items(&t).iter().try_for_each(|v| enc.scan(v))?;
+    }
+    let mut body = Vec::new();
+    body.extend((enc.tuples.len() as u32).to_le_bytes());
+    for t in &enc.tuples {
+        body.extend((t.len() as u32).to_le_bytes());
+    }
+    body.extend((enc.functions.len() as u32).to_le_bytes());
+    for f in &enc.functions {
+        let cfg = cfgs.get(f);
+        encode_cfg(cfg.and_then(|c| c.when.as_ref()), &mut body);
+        body.extend(enc.tuple_ids[&f.module.identity()].to_le_bytes());
+        body.extend((f.ops.len() as u32).to_le_bytes());
+        for op in f.ops.iter() {
+            enc.op(op, &mut body)?;
+        }
+        let blocks = cfg.map_or(&[][..], |c| &c.blocks);
+        body.extend((blocks.len() as u32).to_le_bytes());
+        for (range, cfg) in blocks {
+            body.extend((range.start as u32).to_le_bytes());
+            body.extend((range.end as u32).to_le_bytes());
+            encode_cfg(Some(cfg), &mut body);
+        }
+    }
+    for t in &enc.tuples {
+        for item in tuple_items(t) {
+            enc.value(&item, &mut body)?;
+        }
+    }
+    body.extend(root.to_le_bytes());
+
+    let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+    out.extend(MAGIC);
+    out.extend(FORMAT_VERSION.to_le_bytes());
+    out.extend(OP_SET_VERSION.to_le_bytes());
+    out.extend(checksum(&body).to_le_bytes());
+    out.extend(body);
+    Ok(out)
+}
+
+struct Decoder<'a, 'b> {
+    r: Reader<'b>,
+    natives: &'a NativeTable,
+    tuples: Vec<Tuple>,
+    functions: Vec<Function>,
+    /// Which functions their cfg leaves out, by index.
+    excluded: Vec<bool>,
+}
+
+fn malformed(what: &str) -> LoadError {
+    LoadError::Malformed(what.to_string())
+}
+
+impl<'a, 'b> Decoder<'a, 'b> {
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        self.r.u8().ok_or(LoadError::Truncated)
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        self.r.u32().ok_or(LoadError::Truncated)
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        self.r.u64().ok_or(LoadError::Truncated)
+    }
+
+    fn i32(&mut self) -> Result<i32, LoadError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn bytes(&mut self) -> Result<&'b [u8], LoadError> {
+        self.r.chunk().ok_or(LoadError::Truncated)
+    }
+
+    /// A record shape, as its key count and keys.
+    fn shape(&mut self) -> Result<Rc<Shape>, LoadError> {
+        let keys = (0..self.count()?)
+            .map(|_| self.u64())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Shape::of(&keys))
+    }
+
+    /// A count of items that each take at least one byte, checked against
+    /// what is left so a corrupt count can't make us allocate wildly.
+    fn count(&mut self) -> Result<usize, LoadError> {
+        let n = self.u32()? as usize;
+        match n <= self.r.remaining() {
+            true => Ok(n),
+            false => Err(LoadError::Truncated),
+        }
+    }
+
+    fn cfg(&mut self, depth: usize) -> Result<Option<Cfg>, LoadError> {
+        if depth > MAX_CFG_DEPTH {
+            return Err(malformed("cfg nested too deeply"));
+        }
+        Ok(Some(match self.u8()? {
+            ALWAYS => return Ok(None),
+            FLAG => {
+                let bytes = self.bytes()?;
+                let s = std::str::from_utf8(bytes).map_err(|_| malformed("flag is not UTF-8"))?;
+                Cfg::flag(s)
+            }
+            NOT => Cfg::Not(Box::new(self.some_cfg(depth)?)),
+            kind @ (ALL | ANY) => {
+                let cfgs = (0..self.count()?)
+                    .map(|_| self.some_cfg(depth))
+                    .collect::<Result<_, _>>()?;
+                match kind {
+                    ALL => Cfg::All(cfgs),
+                    _ => Cfg::Any(cfgs),
+                }
+            }
+            kind => return Err(LoadError::Malformed(format!("unknown cfg kind {}", kind))),
+        }))
+    }
+
+    /// A cfg nested in another, which can't be "always".
+    fn some_cfg(&mut self, depth: usize) -> Result<Cfg, LoadError> {
+        self.cfg(depth + 1)?
+            .ok_or_else(|| malformed("nested cfg is empty"))
+    }
+
+    fn tuple_ref(&mut self) -> Result<Tuple, LoadError> {
+        let id = self.u32()? as usize;
+        self.tuples
+            .get(id)
+            .cloned()
+            .ok_or_else(|| malformed("tuple index out of range"))
+    }
+
+    fn value(&mut self) -> Result<Value, LoadError> {
+        self.value_at(0)
+    }
+
+    fn value_at(&mut self, depth: usize) -> Result<Value, LoadError> {
+        if depth > MAX_VALUE_DEPTH {
+            return Err(malformed("value nested too deeply"));
+        }
+        Ok(match self.u8()? {
+            NONE => Value::None,
+            INTEGER => Value::Integer(self.u64()? as i64),
+            REAL => Value::Real(f64::from_bits(self.u64()?)),
+            DECIMAL => {
+                let units = self.r.take(16).ok_or(LoadError::Truncated)?;
+                let units = i128::from_le_bytes(units.try_into().unwrap());
+                Decimal::new(units, self.u8()?)
+                    .ok_or_else(|| malformed("decimal scale"))?
+                    .into()
+            }
+            STR => {
+                let bytes = self.bytes()?;
+                let s = std::str::from_utf8(bytes).map_err(|_| malformed("string is not UTF-8"))?;
+                Value::Str(s.into())
+            }
+            BUFFER => Buffer::new(self.bytes()?.to_vec()).into(),
+            TIMESTAMP => Timestamp(self.u64()? as i64).into(),
+            DURATION => Duration(self.u64()? as i64).into(),
+            LIST => {
+                let n = self.count()?;
+                List::new(
+                    (0..n)
+                        .map(|_| self.value_at(depth + 1))
+                        .collect::<Result<_, _>>()?,
+                )
+                .into()
+            }
+            TABLE => {
+                let table = Table::new();
+                for _ in 0..self.count()? {
+                    let key = self.u64()?;
+                    table.set(key, self.value_at(depth + 1)?);
+                }
+                table.into()
+            }
+            MAP => {
+                let map = Map::new();
+                for _ in 0..self.count()? {
+                    let key = self.value_at(depth + 1)?;
+                    let val = self.value_at(depth + 1)?;
+                    map.insert(key, val)
+                        .map_err(|_| malformed("unhashable map key"))?;
+                }
+                map.into()
+            }
+            RECORD => {
+                let shape = self.shape()?;
+                let values = (0..shape.keys().len())
+                    .map(|_| self.value_at(depth + 1))
+                    .collect::<Result<_, _>>()?;
+                Record::new(shape, values).unwrap().into()
+            }
+            VARIANT => {
+                let tag = self.u32()?;
+                Variant::new(tag, self.value_at(depth + 1)?).into()
+            }
+            TUPLE_REF => self.tuple_ref()?.into(),
+            TUPLE_WEAK_REF => self.tuple_ref()?.downgrade().into(),
+            // nothing else holds it, so it is dead again as soon as loaded
+            TUPLE_WEAK_DEAD => Tuple::new(Vec::new()).downgrade().into(),
+            FUNCTION_REF => {
+                let id = self.u32()? as usize;
+                // only functions already loaded: references point backwards
+                let func = self
+                    .functions
+                    .get(id)
+                    .ok_or_else(|| malformed("function index out of range"))?;
+                match self.excluded[id] {
+                    true => Value::None,
+                    false => func.clone().into(),
+                }
+            }
+            NATIVE => {
+                let name = String::from_utf8_lossy(self.bytes()?).into_owned();
+                Value::NativeFn(
+                    self.natives
+                        .get(&name)
+                        .ok_or(LoadError::UnknownNative(name))?,
+                )
+            }
+            kind => return Err(LoadError::Malformed(format!("unknown value kind {}", kind))),
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, LoadError> {
+        Ok(match self.u8()? {
+            0 => Push(self.value()?).into(),
+            1 => Pop.into(),
+            2 => Load(self.u8()?).into(),
+            3 => Store(self.u8()?).into(),
+            4 => Jump(self.i32()?).into(),
+            5 => JumpIf(self.i32()?).into(),
+            6 => JumpIfNot(self.i32()?).into(),
+            7 => IncJumpLt {
+                local: self.u8()?,
+                limit: self.u8()?,
+                offset: self.i32()?,
+            }
+            .into(),
+            8 => Select.into(),
+            9 => Call(self.u8()?).into(),
+            10 => Return.into(),
+            11 => Add.into(),
+            12 => Sub.into(),
+            13 => Mul.into(),
+            14 => Div.into(),
+            15 => Rem.into(),
+            16 => Neg.into(),
+            17 => Eq.into(),
+            18 => Ne.into(),
+            19 => Lt.into(),
+            20 => Le.into(),
+            21 => Gt.into(),
+            22 => Ge.into(),
+            23 => AddInt.into(),
+            24 => SubInt.into(),
+            25 => LtInt.into(),
+            27 => MakeVariant(self.u32()?).into(),
+            28 => IsTag(self.u32()?).into(),
+            29 => GetTag.into(),
+            30 => Unwrap(self.u32()?).into(),
+            31 => Try.into(),
+            32 => Implements.into(),
+            33 => {
+                let method = u16::from_le_bytes(
+                    self.r
+                        .take(2)
+                        .ok_or(LoadError::Truncated)?
+                        .try_into()
+                        .unwrap(),
+                );
+                Invoke {
+                    method,
+                    argc: self.u8()?,
+                }
+                .into()
+            }
+            34 => NewTable.into(),
+            35 => GetField::new(self.u64()?).into(),
+            36 => SetField::new(self.u64()?).into(),
+            37 => PushHandler(self.i32()?).into(),
+            38 => PopHandler.into(),
+            39 => Throw.into(),
+            40 => CallHost {
+                index: self.u32()?,
+                argc: self.u8()?,
+            }
+            .into(),
+            41 => Halt.into(),
+            42 => Yield.into(),
+            43 => Resume.into(),
+            44 => match self.value()? {
+                Value::Str(name) => LoadModule(name).into(),
+                _ => return Err(LoadError::Malformed("module name is not a Str".into())),
+            },
+            45 => NewRecord {
+                shape: self.shape()?,
+            }
+            .into(),
+            46 => Intern.into(),
+            47 => AddIntUnchecked.into(),
+            48 => SubIntUnchecked.into(),
+            49 => AddImm {
+                local: self.u8()?,
+                imm: self.i32()?,
+            }
+            .into(),
+            50 => AddLocals {
+                a: self.u8()?,
+                b: self.u8()?,
+            }
+            .into(),
+            51 => Enter.into(),
+            52 => Exit.into(),
+            53 => NewMap.into(),
+            54 => MapInsert.into(),
+            55 => MapGet.into(),
+            56 => MapDelete.into(),
+            57 => MapContains.into(),
+            58 => MapIter.into(),
+            59 => GetIter.into(),
+            60 => ForIter(self.i32()?).into(),
+            62 => Same.into(),
+            63 => DeepEq.into(),
+            64 => Cmp.into(),
+            65 => {
+                let low = self.u64()? as i64;
+                let default = self.i32()?;
+                let offsets = (0..self.count()?)
+                    .map(|_| self.i32())
+                    .collect::<Result<Vec<_>, _>>()?;
+                Switch::new(low, offsets, default).into()
+            }
+            66 => MakeClosure {
+                locals: self.bytes()?.into(),
+            }
+            .into(),
+            67 => LoadUpvalue(self.u8()?).into(),
+            61 => {
+                let default = self.i32()?;
+                let cases = (0..self.count()?)
+                    .map(|_| {
+                        let bytes = self.bytes()?;
+                        let s = std::str::from_utf8(bytes)
+                            .map_err(|_| malformed("string is not UTF-8"))?;
+                        Ok((Str::from(s), self.i32()?))
+                    })
+                    .collect::<Result<Vec<_>, LoadError>>()?;
+                SwitchStr::new(cases, default).into()
+            }
+            code => return Err(LoadError::Malformed(format!("unknown opcode {}", code))),
+        })
+    }
+}
+
+/// Checks that every jump lands inside the function (or just past its end).
+fn check_jumps(ops: &[Op]) -> Result<(), LoadError> {
+    for (i, op) in ops.iter().enumerate() {
+        for offset in op.jump_offsets() {
+            let target = i as i64 + 1 + offset as i64;
+            if target < 0 || target > ops.len() as i64 {
+                return Err(LoadError::Malformed(format!(
+                    "jump out of range at op {}",
+                    i
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a file written by `save`, upgrading older op sets. Code with a
+/// cfg is linked with no features enabled.
+pub fn load(bytes: &[u8], natives: &NativeTable) -> Result<Function, LoadError> {
+    load_with_features(bytes, natives, &Features::new())
+}
+
+/// Decodes a file written by `save_with_cfg`, leaving out the functions
+/// and blocks whose cfg doesn't hold under `features`.
+pub fn load_with_features(
+    bytes: &[u8],
+    natives: &NativeTable,
+    features: &Features,
+) -> Result<Function, LoadError> {
+    let (header, body) = match bytes.len() >= HEADER_LEN {
+        true => bytes.split_at(HEADER_LEN),
+        false if bytes.starts_with(MAGIC) || MAGIC.starts_with(bytes) => {
+            return Err(LoadError::Truncated)
+        }
+        false => return Err(LoadError::BadMagic),
+    };
+    if &header[..4] != MAGIC {
+        return Err(LoadError::BadMagic);
+    }
+    let format = u16::from_le_bytes(header[4..6].try_into().unwrap());
+    if format == 0 || format > FORMAT_VERSION {
+        return Err(LoadError::UnsupportedFormat(format));
+    }
+    let op_set = u32::from_le_bytes(header[6..10].try_into().unwrap());
+    if op_set == 0 || op_set > OP_SET_VERSION {
+        return Err(LoadError::OpSet(match op_set {
+            0 => CompatError::Unknown(op_set),
+            v => CompatError::TooNew(v),
+        }));
+    }
+    if u64::from_le_bytes(header[10..18].try_into().unwrap()) != checksum(body) {
+        return Err(LoadError::Corrupt);
+    }
+
+    let mut d = Decoder {
+        r: Reader::new(body),
+        natives,
+        tuples: Vec::new(),
+        functions: Vec::new(),
+        excluded: Vec::new(),
+    };
+    let has_cfg = format >= 2;
+    for _ in 0..d.count()? {
+        let len = d.count()?;
+        d.tuples.push(Tuple::new(vec![Value::None; len]));
+    }
+    for _ in 0..d.count()? {
+        let when = match has_cfg {
+            true => d.cfg(0)?,
+            false => None,
+        };
+        let module = d.tuple_ref()?;
+        let mut ops = (0..d.count()?)
+            .map(|_| d.op())
+            .collect::<Result<Vec<_>, _>>()?;
+        check_jumps(&ops)?;
+        if has_cfg {
+            let mut blocks = Vec::new();
+            for _ in 0..d.count()? {
+                let (start, end) = (d.u32()? as usize, d.u32()? as usize);
+                if start > end || end > ops.len() {
+                    return Err(malformed("block out of range"));
+                }
+                blocks.push((start..end, d.some_cfg(0)?));
+            }
+            ops = cfg::strip(&ops, &blocks, features);
+        }
+        d.excluded.push(!when.is_none_or(|c| c.holds(features)));
+        d.functions.push(Function {
+            module,
+            ops: ops.into(),
+        });
+    }
+    for t in d.tuples.clone() {
+        for i in 0..t.len() {
+            let val = d.value()?;
+            t.set(i, val);
+        }
+    }
+    let root = d.u32()? as usize;
+    if !d.r.is_empty() {
+        return Err(malformed("trailing bytes"));
+    }
+    let root_excluded = d.excluded.get(root).copied().unwrap_or(false);
+    let root = d
+        .functions
+        .get(root)
+        .ok_or_else(|| malformed("root index out of range"))?;
+    if root_excluded {
+        return Err(LoadError::Excluded);
+    }
+    let root = compat::upgrade(root, op_set).map_err(LoadError::OpSet)?;
+    verify::verify_all(&root).map_err(|(_, e)| LoadError::Malformed(e.to_string()))?;
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::speculate::Guard;
+    use crate::testing::function;
+    use crate::VirtualMachine;
+    use std::rc::Rc;
+
+    fn forty(_args: Vec<Value>) -> Value {
+        Value::Integer(40)
+    }
+
+    fn natives() -> NativeTable {
+        let mut natives = NativeTable::new();
+        natives.add("forty", forty);
+        natives
+    }
+
+    /// `helper() + 2`, where `helper` calls a native and is also held by
+    /// the module tuple.
+    fn program() -> Function {
+        let helper = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(Value::NativeFn(forty)).into(),
+                Call(0).into(),
+                Return.into(),
+            ]
+            .into(),
+        };
+        let module = Tuple::new(vec![helper.clone().into(), Value::Str("data".into())]);
+        let ints: Rc<[ValueType]> = vec![ValueType::Integer; 2].into();
+        Function {
+            module,
+            ops: vec![
+                Push(helper.into()).into(),
+                Call(0).into(),
+                Push(Value::Integer(2)).into(),
+                Push(Value::Integer(1)).into(),
+                JumpIfNot(0).into(),
+                Speculate::new(Guard::Stack(ints), AddInt.into(), Add.into()).into(),
+                Return.into(),
+            ]
+            .into(),
+        }
+    }
+
+    #[test]
+    fn round_trips_a_module() {
+        let natives = natives();
+        let bytes = save(&program(), &natives).ok().unwrap();
+        let loaded = load(&bytes, &natives).ok().unwrap();
+        assert!(matches!(loaded.module.get(1), Some(Value::Str(s)) if &*s == "data"));
+        assert!(matches!(loaded.ops[5], Op::Add(_)));
+        // the helper is still one function, shared by the module and main
+        match (loaded.module.get(0), &loaded.ops[0]) {
+            (Some(Value::Function(a)), Op::Push(Push(Value::Function(b)))) => {
+                assert_eq!(a.identity(), b.identity())
+            }
+            _ => panic!("expected functions"),
+        }
+        let mut vm = VirtualMachine::new(loaded);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(42))));
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let shape = Shape::of(&[1, 2]);
+        let record = Record::new(shape.clone(), vec![Value::Integer(3), Value::None]).unwrap();
+        let func = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(record.into()).into(),
+                Pop.into(),
+                Push(Value::Integer(4)).into(),
+                Push(Value::Integer(5)).into(),
+                NewRecord { shape }.into(),
+                GetField::new(1).into(),
+                Return.into(),
+            ]
+            .into(),
+        };
+        let natives = natives();
+        let loaded = load(&save(&func, &natives).ok().unwrap(), &natives)
+            .ok()
+            .unwrap();
+        match &loaded.ops[0] {
+            Op::Push(Push(Value::Record(r))) => {
+                assert!(matches!(r.get(1), Some(Value::Integer(3))));
+                assert_eq!(r.shape().keys(), [1, 2]);
+            }
+            _ => panic!("expected a record"),
+        }
+        let mut vm = VirtualMachine::new(loaded);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(4))));
+    }
+
+    #[test]
+    fn cfgs_leave_out_code_at_link_time() {
+        // x = 1; <debug only: x = helper()>; return x
+        let helper = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![Push(Value::Integer(40)).into(), Return.into()].into(),
+        };
+        let main = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(Value::Integer(1)).into(),
+                Store(1).into(),
+                Push(helper.clone().into()).into(),
+                Call(0).into(),
+                Store(1).into(),
+                Load(1).into(),
+                Return.into(),
+            ]
+            .into(),
+        };
+        let mut cfgs = CfgTable::new();
+        cfgs.function(&helper, Cfg::flag("debug"));
+        cfgs.block(&main, 2..5, Cfg::flag("debug"));
+        let natives = natives();
+        let bytes = save_with_cfg(&main, &natives, &cfgs).ok().unwrap();
+        let run = |features: &Features| {
+            let loaded = load_with_features(&bytes, &natives, features).ok().unwrap();
+            let len = loaded.ops.len();
+            let mut vm = VirtualMachine::new(loaded);
+            match vm.run_until_exited() {
+                Ok(Value::Integer(i)) => (len, i),
+                _ => panic!("expected an Integer"),
+            }
+        };
+        assert_eq!(run(&Features::new()), (4, 1));
+        let mut debug = Features::new();
+        debug.enable("debug");
+        assert_eq!(run(&debug), (7, 40));
+
+        let mut cfgs = CfgTable::new();
+        cfgs.function(&main, Cfg::Not(Box::new(Cfg::flag("debug"))));
+        let bytes = save_with_cfg(&main, &natives, &cfgs).ok().unwrap();
+        assert!(matches!(
+            load_with_features(&bytes, &natives, &debug),
+            Err(LoadError::Excluded)
+        ));
+    }
+
+    #[test]
+    fn loads_format_1_files() {
+        let func = Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![Push(Value::Integer(7)).into(), Return.into()].into(),
+        };
+        let bytes = save(&func, &NativeTable::new()).ok().unwrap();
+        // drop the function's cfg and block count, which format 1 lacks:
+        // they follow the tuple count, one tuple length and the function
+        // count, and the module, op count and 11 bytes of ops
+        let mut body = bytes[HEADER_LEN..].to_vec();
+        body.drain(32..36);
+        body.remove(12);
+        let mut old = bytes[..HEADER_LEN].to_vec();
+        old[4..6].copy_from_slice(&1u16.to_le_bytes());
+        old[10..18].copy_from_slice(&checksum(&body).to_le_bytes());
+        old.extend(body);
+        let loaded = load(&old, &NativeTable::new()).ok().unwrap();
+        let mut vm = VirtualMachine::new(loaded);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(7))));
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let natives = natives();
+        let bytes = save(&program(), &natives).ok().unwrap();
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(load(&flipped, &natives), Err(LoadError::Corrupt)));
+        assert!(matches!(
+            load(&bytes[..bytes.len() - 1], &natives),
+            Err(LoadError::Corrupt)
+        ));
+        assert!(matches!(
+            load(&bytes[..3], &natives),
+            Err(LoadError::Truncated)
+        ));
+        assert!(matches!(
+            load(b"not bytecode at all", &natives),
+            Err(LoadError::BadMagic)
+        ));
+        let mut newer = bytes.clone();
+        newer[6..10].copy_from_slice(&(OP_SET_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            load(&newer, &natives),
+            Err(LoadError::OpSet(CompatError::TooNew(_)))
+        ));
+        let err = load(&bytes, &NativeTable::new()).err().unwrap();
+        assert_eq!(err.to_string(), "unknown native forty");
+        assert!(matches!(
+            save(&program(), &NativeTable::new()),
+            Err(SaveError::UnnamedNative)
+        ));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_values() {
+        let nest = |n| (0..n).fold(Value::None, |v, _| List::new(vec![v]).into());
+        let file = |val| {
+            let func = function(vec![Push(val).into(), Return.into()]);
+            save(&func, &NativeTable::new())
+        };
+        let natives = NativeTable::new();
+        let saved = file(nest(MAX_VALUE_DEPTH)).ok().unwrap();
+        assert!(load(&saved, &natives).is_ok());
+        // what load would reject isn't saved
+        let err = file(nest(MAX_VALUE_DEPTH + 1)).err().unwrap();
+        assert!(matches!(err, SaveError::TooDeep));
+        let list = List::new(Vec::new());
+        list.items.borrow_mut().push(list.clone().into());
+        assert!(matches!(file(list.clone().into()), Err(SaveError::TooDeep)));
+        list.items.borrow_mut().clear();
+
+        // a file written some other way is still checked on load
+        let mut bytes = saved;
+        let outer = [LIST, 1, 0, 0, 0];
+        let at = HEADER_LEN
+            + bytes[HEADER_LEN..]
+                .windows(5)
+                .position(|w| w == outer)
+                .unwrap();
+        bytes.splice(at..at, outer);
+        let sum = checksum(&bytes[HEADER_LEN..]);
+        bytes[10..18].copy_from_slice(&sum.to_le_bytes());
+        let err = load(&bytes, &natives).err().unwrap();
+        assert!(matches!(err, LoadError::Malformed(_)));
+    }
+}
@@ -0,0 +1,360 @@
+//! A machine-readable description of the instruction set, and test vectors
+//! generated from it, for checking another implementation (or a compiled
+//! tier) against this interpreter.
+//!
+//! `SPEC` lists every op with its immediate operands, stack effect and a
+//! one-line summary; `spec_json` renders it as JSON. `vectors` runs every
+//! data op on every combination of values from a small pool that covers
+//! each kind and the edge cases (zeros, extremes, NaN), recording what the
+//! interpreter did; `vectors_jsonl` renders them one JSON object per line.
+//! Vectors are generated in `FloatMode::Native`.
+
+use crate::bytecode::ops::*;
+use crate::bytecode::{Op, OpError};
+use crate::datamodel::{Decimal, Function, Tuple, Value, Variant};
+use crate::difftest::{self, Outcome};
+
+pub struct OpSpec {
+    pub name: &'static str,
+    /// Immediate operands, in field order.
+    pub operands: &'static [&'static str],
+    /// Values popped, as a count or in terms of the operands.
+    pub pops: &'static str,
+    /// Values pushed once the op completes.
+    pub pushes: &'static str,
+    pub summary: &'static str,
+}
+
+macro_rules! spec {
+    ($($name:ident [$($operand:literal),*] $pops:literal -> $pushes:literal : $summary:literal;)+) => {
+        pub const SPEC: &[OpSpec] = &[
+            $(OpSpec {
+                name: stringify!($name),
+                operands: &[$($operand),*],
+                pops: $pops,
+                pushes: $pushes,
+                summary: $summary,
+            }),+
+        ];
+    };
+}
+
+spec! {
+    Push ["value"] "0" -> "1" : "push a constant";
+    Pop [] "1" -> "0" : "discard the top value";
+    Load ["local"] "0" -> "1" : "push a local; error if unset";
+    Store ["local"] "1" -> "0" : "pop into a local";
+    Jump ["offset"] "0" -> "0" : "cursor += offset, after advancing past the op";
+    JumpIf ["offset"] "1" -> "0" : "pop a condition and jump if truthy";
+    JumpIfNot ["offset"] "1" -> "0" : "pop a condition and jump if falsy";
+    IncJumpLt ["local", "limit", "offset"] "0" -> "0" : "local += 1, then jump if local < limit (Integer locals)";
+    GetIter [] "1" -> "1" : "pop an iterable and push an Iter over it";
+    ForIter ["offset"] "1" -> "2" : "push the next item of the Iter on top; once exhausted, pop it and jump";
+    SwitchStr ["cases", "default"] "1" -> "0" : "pop a value and jump by its case's offset, or by default if it is no case's Str";
+    Switch ["low", "offsets", "default"] "1" -> "0" : "pop an Integer, or a Variant's tag, and jump by offsets[n - low], or by default if out of range";
+    Select [] "3" -> "1" : "pop b, a, cond; push a if cond is truthy, else b";
+    Call ["argc"] "argc+1" -> "1" : "pop a callable then argc args; push its result when it returns";
+    Return [] "1" -> "0" : "pop the result and leave the frame";
+    Add [] "2" -> "1" : "lhs + rhs; Integer overflow is an error";
+    Sub [] "2" -> "1" : "lhs - rhs";
+    Mul [] "2" -> "1" : "lhs * rhs";
+    Div [] "2" -> "1" : "lhs / rhs; Integer division truncates, by zero is an error";
+    Rem [] "2" -> "1" : "remainder with the sign of lhs";
+    Neg [] "1" -> "1" : "negate a number";
+    Eq [] "2" -> "1" : "1 if equal, else 0; different kinds are unequal";
+    Ne [] "2" -> "1" : "the inverse of Eq";
+    Lt [] "2" -> "1" : "1 if lhs < rhs, else 0";
+    Le [] "2" -> "1" : "1 if lhs <= rhs, else 0";
+    Gt [] "2" -> "1" : "1 if lhs > rhs, else 0";
+    Ge [] "2" -> "1" : "1 if lhs >= rhs, else 0";
+    Same [] "2" -> "1" : "1 if lhs and rhs are the same value (aggregates by identity), else 0";
+    DeepEq [] "2" -> "1" : "1 if lhs and rhs are structurally equal, else 0";
+    Cmp [] "2" -> "1" : "-1, 0 or 1 as lhs orders before, with or after rhs; error if unordered";
+    AddInt [] "2" -> "1" : "Add for two Integers only";
+    SubInt [] "2" -> "1" : "Sub for two Integers only";
+    LtInt [] "2" -> "1" : "Lt for two Integers only";
+    AddIntUnchecked [] "2" -> "1" : "AddInt that wraps instead of checking for overflow";
+    SubIntUnchecked [] "2" -> "1" : "SubInt that wraps instead of checking for overflow";
+    AddImm ["local", "imm"] "0" -> "1" : "local + imm, as Load, Push and Add";
+    AddLocals ["a", "b"] "0" -> "1" : "local a + local b, as Load, Load and Add";
+    Speculate ["guard", "fast", "generic"] "as generic" -> "as generic" : "run fast while guard holds, else generic";
+    MakeVariant ["tag"] "1" -> "1" : "wrap a payload in a Variant";
+    IsTag ["tag"] "1" -> "1" : "1 if a Variant has the tag, else 0";
+    GetTag [] "1" -> "1" : "a Variant's tag as an Integer";
+    Unwrap ["tag"] "1" -> "1" : "a Variant's payload; error if the tag differs";
+    Try [] "1" -> "1" : "push a success payload, or return a failure variant unchanged";
+    Implements [] "2" -> "1" : "pop an Interface then a value; 1 if the value's type implements it";
+    Invoke ["method", "argc"] "argc+2" -> "1" : "pop an Interface, argc args and the receiver; call the receiver's method";
+    NewTable [] "0" -> "1" : "push a new, empty Table";
+    GetField ["key"] "1" -> "1" : "pop a Table and push a field, following prototypes; error if missing";
+    SetField ["key"] "2" -> "0" : "pop a value then a Table and set the table's own field";
+    NewMap [] "0" -> "1" : "push a new, empty Map";
+    MapInsert [] "3" -> "0" : "pop a value, a key, then a Map and set the key to the value";
+    MapGet [] "2" -> "1" : "pop a key then a Map and push the key's value; error if missing";
+    MapDelete [] "2" -> "1" : "pop a key then a Map, remove the key; 1 if it was there, else 0";
+    MapContains [] "2" -> "1" : "pop a key then a Map; 1 if the map has the key, else 0";
+    MapIter [] "1" -> "1" : "pop a Map and push an Iter over (key, value) tuples of a snapshot, in insertion order";
+    PushHandler ["offset"] "0" -> "0" : "install an exception handler at cursor + offset";
+    PopHandler [] "0" -> "0" : "remove the innermost handler of this frame";
+    Throw [] "1" -> "0" : "pop a value and unwind to the innermost handler with it";
+    Enter [] "2" -> "1" : "pop a release NativeFn then a resource; push the resource and release it when the scope is left";
+    Exit [] "0" -> "0" : "leave the innermost scope of this frame, releasing its resource";
+    CallHost ["index", "argc"] "argc" -> "1" : "pop argc args and call a registered host function";
+    Halt [] "0" -> "0" : "leave the frame with None as the result";
+    Yield [] "1" -> "1" : "pop a value and suspend the coroutine with it; push the value it is resumed with";
+    Resume [] "2" -> "1" : "pop a value then a Coroutine and run it until it yields or returns; push that result";
+    LoadModule ["name"] "0" -> "1" : "push a module's value, running its top-level code on first import";
+    NewRecord ["shape"] "fields" -> "1" : "pop a value per field of the shape, the last on top, into a new Record";
+    Intern [] "1" -> "1" : "pop a Str and push the VM's Symbol of that name";
+    MakeClosure ["locals"] "1" -> "1" : "pop a Function and push a Closure of it capturing copies of the locals";
+    LoadUpvalue ["n"] "0" -> "1" : "push upvalue n of the running closure";
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+pub fn spec_json() -> String {
+    let ops = SPEC.iter().map(|op| {
+        format!(
+            "{{\"name\":{},\"operands\":{},\"pops\":{},\"pushes\":{},\"summary\":{}}}",
+            json_str(op.name),
+            json_list(op.operands.iter().map(|o| json_str(o))),
+            json_str(op.pops),
+            json_str(op.pushes),
+            json_str(op.summary),
+        )
+    });
+    json_list(ops)
+}
+
+/// The values vectors draw their operands from.
+pub fn pool() -> Vec<Value> {
+    vec![
+        Value::None,
+        Value::Integer(0),
+        Value::Integer(1),
+        Value::Integer(-1),
+        Value::Integer(i64::MAX),
+        Value::Integer(i64::MIN),
+        Value::Real(0.5),
+        Value::Real(-0.0),
+        Value::Real(f64::NAN),
+        Decimal::new(15, 1).unwrap().into(),
+        Value::Str("a".into()),
+        Variant::new(1, Value::Integer(1)).into(),
+    ]
+}
+
+/// The ops vectors are generated for: every op that only works on the
+/// stack, with fixed immediates.
+fn data_ops() -> Vec<Op> {
+    vec![
+        Pop.into(),
+        Select.into(),
+        Add.into(),
+        Sub.into(),
+        Mul.into(),
+        Div.into(),
+        Rem.into(),
+        Neg.into(),
+        Eq.into(),
+        Ne.into(),
+        Lt.into(),
+        Le.into(),
+        Gt.into(),
+        Ge.into(),
+        AddInt.into(),
+        SubInt.into(),
+        LtInt.into(),
+        MakeVariant(1).into(),
+        IsTag(1).into(),
+        GetTag.into(),
+        Unwrap(1).into(),
+        Try.into(),
+    ]
+}
+
+pub fn spec_of(op: &Op) -> Option<&'static OpSpec> {
+    SPEC.iter().find(|s| s.name == op.name())
+}
+
+pub struct Vector {
+    pub op: Op,
+    /// Pushed in order before the op runs.
+    pub inputs: Vec<Value>,
+    pub outcome: Outcome,
+}
+
+impl Vector {
+    /// The program the vector runs: push the inputs, run the op, return
+    /// what it left on top (or `None`).
+    pub fn program(&self) -> Function {
+        let mut ops: Vec<Op> = self.inputs.iter().map(|v| Push(v.clone()).into()).collect();
+        ops.push(self.op.clone());
+        if spec_of(&self.op).is_some_and(|s| s.pushes == "0") {
+            ops.push(Push(Value::None).into());
+        }
+        ops.push(Return.into());
+        Function {
+            module: Tuple::new(Vec::new()),
+            ops: ops.into(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let outcome = match &self.outcome {
+            Outcome::Returned(val) => format!("\"returns\":{}", json_str(&render(val))),
+            Outcome::Failed(e) => format!("\"fails\":{}", json_str(error_name(e))),
+            Outcome::OutOfSteps => "\"diverges\":true".to_string(),
+        };
+        let inputs = json_list(self.inputs.iter().map(|v| json_str(&render(v))));
+        format!(
+            "{{\"op\":{},\"inputs\":{},{}}}",
+            json_str(self.op.name()),
+            inputs,
+            outcome
+        )
+    }
+}
+
+/// Values as they appear in vectors, e.g. `Integer(1)` or `Real(NaN)`.
+pub fn render(val: &Value) -> String {
+    match val {
+        Value::None => "None".to_string(),
+        Value::Integer(i) => format!("Integer({})", i),
+        Value::Real(r) => format!("Real({:?})", r),
+        Value::Decimal(d) => format!("Decimal({})", d),
+        Value::Str(s) => format!("Str({:?})", &**s),
+        Value::Variant(v) => format!("Variant({}, {})", v.tag(), render(v.payload())),
+        other => other.get_type().as_str().to_string(),
+    }
+}
+
+pub fn error_name(e: &OpError) -> &'static str {
+    match e {
+        OpError::StackEmpty => "StackEmpty",
+        OpError::LocalRead(..) => "LocalRead",
+        OpError::IndexRead(_) => "IndexRead",
+        OpError::IndexWrite(_) => "IndexWrite",
+        OpError::FieldRead(_) => "FieldRead",
+        OpError::FieldWrite(_) => "FieldWrite",
+        OpError::KeyRead(_) => "KeyRead",
+        OpError::IntoType(_) => "IntoType",
+        OpError::BadType(_) => "BadType",
+        OpError::BadTag { .. } => "BadTag",
+        OpError::NotImplemented(_) => "NotImplemented",
+        OpError::Overflow => "Overflow",
+        OpError::DivideByZero => "DivideByZero",
+        OpError::Interrupted => "Interrupted",
+        OpError::Blocked => "Blocked",
+        OpError::HotLoop => "HotLoop",
+        OpError::OutOfFuel => "OutOfFuel",
+        OpError::FellOffEnd => "FellOffEnd",
+        OpError::NotResumable => "NotResumable",
+        OpError::NotInCoroutine => "NotInCoroutine",
+        OpError::NoModule(_) => "NoModule",
+        OpError::ImportCycle(_) => "ImportCycle",
+        OpError::Uncaught(_) => "Uncaught",
+        OpError::NoHostFn(_) => "NoHostFn",
+        OpError::TooManyResources(_) => "TooManyResources",
+        OpError::StackOverflow(_) => "StackOverflow",
+        OpError::UpvalueRead(_) => "UpvalueRead",
+    }
+}
+
+/// Steps a vector's program may take; each takes a handful.
+const VECTOR_STEPS: usize = 64;
+
+/// Every data op on every combination of pool values.
+pub fn vectors() -> Vec<Vector> {
+    let pool = pool();
+    let mut out = Vec::new();
+    for op in data_ops() {
+        let arity = match spec_of(&op).map(|s| s.pops.parse::<u32>()) {
+            Some(Ok(arity)) => arity,
+            _ => continue,
+        };
+        for combination in 0..pool.len().pow(arity) {
+            let mut inputs = Vec::with_capacity(arity as usize);
+            let mut rest = combination;
+            for _ in 0..arity {
+                inputs.push(pool[rest % pool.len()].clone());
+                rest /= pool.len();
+            }
+            let mut vector = Vector {
+                op: op.clone(),
+                inputs,
+                outcome: Outcome::OutOfSteps,
+            };
+            vector.outcome = difftest::run(&vector.program(), &[], VECTOR_STEPS).outcome;
+            out.push(vector);
+        }
+    }
+    out
+}
+
+pub fn vectors_jsonl() -> String {
+    vectors().iter().map(|v| v.to_json() + "\n").collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn spec_matches_the_interpreter() {
+        let names: HashSet<_> = SPEC.iter().map(|s| s.name).collect();
+        assert_eq!(names.len(), SPEC.len());
+        for op in data_ops() {
+            let spec = spec_of(&op).unwrap();
+            if let Some((pops, pushes)) = op.stack_effect() {
+                assert_eq!(
+                    (spec.pops, spec.pushes),
+                    (&*pops.to_string(), &*pushes.to_string())
+                );
+            }
+        }
+        assert!(spec_json().starts_with("[{\"name\":\"Push\""));
+
+        let vectors = vectors();
+        let find = |name: &str, inputs: &[&str]| {
+            vectors
+                .iter()
+                .find(|v| {
+                    v.op.name() == name
+                        && v.inputs
+                            .iter()
+                            .map(render)
+                            .eq(inputs.iter().map(|s| s.to_string()))
+                })
+                .map(Vector::to_json)
+        };
+        assert_eq!(
+            find("Add", &["Integer(1)", "Real(0.5)"]).unwrap(),
+            "{\"op\":\"Add\",\"inputs\":[\"Integer(1)\",\"Real(0.5)\"],\"returns\":\"Real(1.5)\"}"
+        );
+        assert!(find("Div", &["Integer(1)", "Integer(0)"])
+            .unwrap()
+            .ends_with("\"fails\":\"DivideByZero\"}"));
+        assert!(find("Add", &["Integer(9223372036854775807)", "Integer(1)"])
+            .unwrap()
+            .contains("Overflow"));
+        assert_eq!(vectors_jsonl().lines().count(), vectors.len());
+    }
+}
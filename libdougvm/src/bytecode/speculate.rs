@@ -0,0 +1,117 @@
+//! Speculative specialization. A `Speculate` op pairs a fast op with the
+//! generic op it replaces and a cheap type `Guard`. While the guard holds
+//! the fast op runs; when it fails the generic op runs instead, and a site
+//! that keeps failing deoptimizes for good, so a bad guess costs a few
+//! type checks rather than correctness.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::ops::{AddInt, LtInt, SubInt};
+use super::{Op, OpAction, OpError, Operation};
+use crate::datamodel::ValueType;
+use crate::CallStack;
+
+/// Guard failures after which a site stops speculating.
+pub const DEOPT_THRESHOLD: u32 = 8;
+
+#[derive(Clone)]
+pub enum Guard {
+    /// The top stack values have these types, top first.
+    Stack(Rc<[ValueType]>),
+    /// A local holds a value of this type.
+    Local(u8, ValueType),
+}
+
+impl Guard {
+    pub fn check(&self, m: &CallStack) -> bool {
+        match self {
+            Guard::Stack(types) => types
+                .iter()
+                .enumerate()
+                .all(|(depth, t)| m.peek(depth).is_some_and(|v| v.get_type() == *t)),
+            Guard::Local(index, t) => m.load(*index).is_ok_and(|v| v.get_type() == *t),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Speculate {
+    pub guard: Guard,
+    pub fast: Rc<Op>,
+    pub generic: Rc<Op>,
+    failures: Rc<Cell<u32>>,
+}
+
+impl Speculate {
+    pub fn new(guard: Guard, fast: Op, generic: Op) -> Speculate {
+        Speculate {
+            guard,
+            fast: Rc::new(fast),
+            generic: Rc::new(generic),
+            failures: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn is_deoptimized(&self) -> bool {
+        self.failures.get() >= DEOPT_THRESHOLD
+    }
+}
+
+impl Operation for Speculate {
+    fn exec(&self, m: &mut CallStack) -> Result<OpAction, OpError> {
+        if !self.is_deoptimized() {
+            if self.guard.check(m) {
+                return self.fast.exec(m);
+            }
+            self.failures.set(self.failures.get() + 1);
+        }
+        self.generic.exec(m)
+    }
+}
+
+/// Rewrites the integer-specializable ops in `ops` into guarded
+/// `Speculate` ops, leaving everything else (and the op layout) unchanged,
+/// so it is safe to use as a `TieringPolicy` promotion.
+pub fn specialize_ints(ops: &[Op]) -> Vec<Op> {
+    let ints: Rc<[ValueType]> = Rc::new([ValueType::Integer, ValueType::Integer]);
+    ops.iter()
+        .map(|op| {
+            let fast: Op = match op {
+                Op::Add(_) => AddInt.into(),
+                Op::Sub(_) => SubInt.into(),
+                Op::Lt(_) => LtInt.into(),
+                _ => return op.clone(),
+            };
+            Speculate::new(Guard::Stack(ints.clone()), fast, op.clone()).into()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Add;
+    use crate::datamodel::Value;
+
+    #[test]
+    fn deoptimizes_after_repeated_guard_failures() {
+        let op = match &specialize_ints(&[Add.into()])[0] {
+            Op::Speculate(s) => s.clone(),
+            _ => panic!("expected a Speculate op"),
+        };
+        let mut m = CallStack::new();
+        m.push(Value::Integer(1));
+        m.push(Value::Integer(2));
+        assert!(op.exec(&mut m).is_ok());
+        assert!(matches!(m.pop(), Ok(Value::Integer(3))));
+        for _ in 0..DEOPT_THRESHOLD {
+            assert!(!op.is_deoptimized());
+            m.push(Value::Real(1.0));
+            m.push(Value::Integer(2));
+            assert!(op.exec(&mut m).is_ok());
+            assert!(matches!(m.pop(), Ok(Value::Real(r)) if r == 3.0));
+        }
+        assert!(op.is_deoptimized());
+    }
+}
@@ -0,0 +1,291 @@
+//! Static checks on bytecode, for code that didn't come from the compiler
+//! (see `serialize::load`). A verified function can't run its cursor out of
+//! bounds: every jump lands on an op, and the last op can't fall through,
+//! so there is no implicit return off the end; a function with nothing to
+//! return ends in `Halt`. Nor can it load a local before storing it on
+//! some path, or call a constant function with fewer arguments than the
+//! callee's prologue stores.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use super::ops::{Call, Push, Store};
+use super::Op;
+use crate::datamodel::{Function, Identity, Value};
+
+pub enum VerifyError {
+    /// The jump at this op lands outside the function.
+    JumpOutOfRange(usize),
+    /// The cursor can run past the last op, or there are no ops.
+    FallsOffEnd,
+    /// The op here can read this local before it is stored.
+    LocalUnset(usize, u8),
+    /// The call here passes fewer arguments than its callee takes.
+    TooFewArgs(usize),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::JumpOutOfRange(at) => write!(f, "jump out of range at op {}", at),
+            VerifyError::FallsOffEnd => write!(f, "function can run off its end"),
+            VerifyError::LocalUnset(at, local) => {
+                write!(f, "local {} can be read unset at op {}", local, at)
+            }
+            VerifyError::TooFewArgs(at) => write!(f, "too few arguments for call at op {}", at),
+        }
+    }
+}
+
+/// Whether the cursor moves on to the next op after `op`, at least some of
+/// the time.
+fn falls_through(op: &Op) -> bool {
+    !matches!(
+        op,
+        Op::Return(_) | Op::Halt(_) | Op::Throw(_) | Op::Jump(_) | Op::SwitchStr(_) | Op::Switch(_)
+    )
+}
+
+/// Whether running `ops` can take the cursor just past the last op.
+pub fn reaches_end(ops: &[Op]) -> bool {
+    let jumps_to_end = ops.iter().enumerate().any(|(i, op)| {
+        let to_end = |offset: &i32| i as i64 + 1 + *offset as i64 == ops.len() as i64;
+        op.jump_offsets().iter().any(to_end)
+    });
+    ops.last().is_none_or(falls_through) || jumps_to_end
+}
+
+pub fn verify(func: &Function) -> Result<(), VerifyError> {
+    let ops = &func.ops;
+    for (i, op) in ops.iter().enumerate() {
+        for offset in op.jump_offsets() {
+            let target = i as i64 + 1 + offset as i64;
+            if target < 0 || target > ops.len() as i64 {
+                return Err(VerifyError::JumpOutOfRange(i));
+            }
+        }
+    }
+    if reaches_end(ops) {
+        return Err(VerifyError::FallsOffEnd);
+    }
+    check_locals(ops)?;
+    for (i, pair) in ops.windows(2).enumerate() {
+        if let [Op::Push(Push(Value::Function(callee))), Op::Call(Call(argc))] = pair {
+            if (*argc as usize) < params(callee) {
+                return Err(VerifyError::TooFewArgs(i + 1));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many arguments `func` takes, going by the `Store`s its prologue
+/// pops them into (see `ops::Call`).
+pub fn params(func: &Function) -> usize {
+    func.ops
+        .iter()
+        .take_while(|op| matches!(op, Op::Store(Store(_))))
+        .count()
+}
+
+/// The locals `op` reads.
+fn reads(op: &Op) -> Vec<u8> {
+    match op {
+        Op::Load(load) => vec![load.0],
+        Op::IncJumpLt(op) => vec![op.local, op.limit],
+        Op::AddImm(op) => vec![op.local],
+        Op::AddLocals(op) => vec![op.a, op.b],
+        Op::MakeClosure(op) => op.locals.to_vec(),
+        // a failed guard falls back rather than failing, so only the ops
+        // themselves count
+        Op::Speculate(s) => [reads(&s.fast), reads(&s.generic)].concat(),
+        _ => Vec::new(),
+    }
+}
+
+/// Locals stored on every path to an op, one bit each.
+#[derive(Clone, Copy, PartialEq)]
+struct Stored([u64; 4]);
+
+impl Stored {
+    fn has(&self, local: u8) -> bool {
+        self.0[local as usize / 64] & 1 << (local % 64) != 0
+    }
+
+    fn with(mut self, local: u8) -> Stored {
+        self.0[local as usize / 64] |= 1 << (local % 64);
+        self
+    }
+
+    fn meet(self, other: Stored) -> Stored {
+        Stored([0, 1, 2, 3].map(|i| self.0[i] & other.0[i]))
+    }
+}
+
+/// Checks every local is stored before it is read, on every path. Jumps
+/// must already be known to land in range.
+fn check_locals(ops: &[Op]) -> Result<(), VerifyError> {
+    // local 0 holds the module from the start
+    let entry = Stored([0; 4]).with(0);
+    let mut stored: Vec<Option<Stored>> = vec![None; ops.len()];
+    let mut pending = vec![(0, entry)];
+    while let Some((at, state)) = pending.pop() {
+        let Some(op) = ops.get(at) else { continue };
+        let state = match stored[at] {
+            Some(seen) if seen.meet(state) == seen => continue,
+            Some(seen) => seen.meet(state),
+            None => state,
+        };
+        stored[at] = Some(state);
+        if let Some(local) = reads(op).into_iter().find(|l| !state.has(*l)) {
+            return Err(VerifyError::LocalUnset(at, local));
+        }
+        let after = match op {
+            Op::Store(Store(local)) => state.with(*local),
+            _ => state,
+        };
+        for offset in op.jump_offsets() {
+            // a handler starts with the locals stored where it was
+            // installed, since stores before the throw only add to them
+            pending.push(((at as i64 + 1 + offset as i64) as usize, after));
+        }
+        if falls_through(op) {
+            pending.push((at + 1, after));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies `func` and every function it references through `Push`
+/// constants, reporting the first that fails.
+pub fn verify_all(func: &Function) -> Result<(), (Function, VerifyError)> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![func.clone()];
+    while let Some(func) = pending.pop() {
+        if !seen.insert(func.identity()) {
+            continue;
+        }
+        verify(&func).map_err(|e| (func.clone(), e))?;
+        for op in func.ops.iter() {
+            if let Op::Push(Push(Value::Function(f))) = op {
+                pending.push(f.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::OpError;
+    use crate::testing::function;
+    use crate::{VirtualMachine, VmError};
+
+    #[test]
+    fn functions_must_not_run_off_their_end() {
+        let falls_off = function(vec![Push(Value::Integer(1)).into(), Pop.into()]);
+        assert!(matches!(verify(&falls_off), Err(VerifyError::FallsOffEnd)));
+        let mut vm = VirtualMachine::new(falls_off);
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::FellOffEnd,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            verify(&function(vec![])),
+            Err(VerifyError::FallsOffEnd)
+        ));
+        // a branch to just past the end is as bad
+        let branch = function(vec![
+            Push(Value::None).into(),
+            JumpIf(1).into(),
+            Halt.into(),
+        ]);
+        assert!(matches!(verify(&branch), Err(VerifyError::FallsOffEnd)));
+        let branch = function(vec![
+            Push(Value::None).into(),
+            JumpIf(7).into(),
+            Halt.into(),
+        ]);
+        assert!(matches!(
+            verify(&branch),
+            Err(VerifyError::JumpOutOfRange(1))
+        ));
+
+        let halts = function(vec![
+            Push(Value::Integer(1)).into(),
+            Pop.into(),
+            Halt.into(),
+        ]);
+        let caller = function(vec![
+            Push(halts.into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        assert!(verify_all(&caller).is_ok());
+        assert!(matches!(
+            VirtualMachine::new(caller).run_until_exited(),
+            Ok(Value::None)
+        ));
+        let caller = function(vec![Push(function(vec![]).into()).into(), Jump(-2).into()]);
+        assert!(matches!(verify_all(&caller), Err((f, _)) if f.ops.is_empty()));
+    }
+
+    #[test]
+    fn locals_and_arguments_are_checked() {
+        // local 1 is only stored on one branch
+        let maybe = function(vec![
+            Push(Value::None).into(),
+            JumpIf(2).into(),
+            Push(Value::Integer(1)).into(),
+            Store(1).into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        assert!(matches!(verify(&maybe), Err(VerifyError::LocalUnset(4, 1))));
+        let module = function(vec![Load(0).into(), Return.into()]);
+        assert!(verify(&module).is_ok());
+        // a handler sees the locals stored before it was installed
+        let handled = function(vec![
+            PushHandler(3).into(),
+            Push(Value::Integer(1)).into(),
+            Store(1).into(),
+            Halt.into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        assert!(matches!(
+            verify(&handled),
+            Err(VerifyError::LocalUnset(4, 1))
+        ));
+
+        let add = function(vec![
+            Store(1).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        assert_eq!(params(&add), 2);
+        let short = function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(add.into()).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        assert!(matches!(verify(&short), Err(VerifyError::TooFewArgs(2))));
+        assert!(matches!(
+            VirtualMachine::verified(short),
+            Err((_, VerifyError::TooFewArgs(2)))
+        ));
+        for workload in crate::bench::workloads() {
+            assert!(VirtualMachine::verified(workload.main).is_ok());
+        }
+    }
+}
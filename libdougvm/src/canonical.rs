@@ -0,0 +1,296 @@
+//! A canonical byte encoding of plain data values, fixed across crate
+//! versions and platforms, for memoization keys, cache invalidation and
+//! deduplication across processes. Values that are equal as data encode
+//! to the same bytes:
+//!
+//! - table entries are sorted by key, and map entries by the encoding of
+//!   their key, so insertion order doesn't matter;
+//! - `-0.0` encodes as `0.0`, and every NaN as the one canonical NaN;
+//! - decimals drop trailing zeros, so `1.50` and `1.5` agree.
+//!
+//! Kinds stay distinct: `Integer(1)`, `Real(1.0)` and `Decimal(1)` all
+//! encode differently. Functions, iterators and host values have no
+//! encoding. Tables are keyed by `field_key`, itself stable.
+//!
+//! Every value is a kind byte, then its fields, all big-endian. Lengths
+//! are `u64`. The kind bytes below must never be reused or renumbered.
+
+use crate::datamodel::{Identity, Value};
+
+const NONE: u8 = 0;
+const INTEGER: u8 = 1;
+const REAL: u8 = 2;
+const DECIMAL: u8 = 3;
+const STR: u8 = 4;
+const BUFFER: u8 = 5;
+const TIMESTAMP: u8 = 6;
+const DURATION: u8 = 7;
+const TUPLE: u8 = 8;
+const LIST: u8 = 9;
+const TABLE: u8 = 10;
+const VARIANT: u8 = 11;
+const MAP: u8 = 12;
+
+/// Deepest nesting encoded; deeper values, like cyclic ones, have no
+/// encoding.
+pub const MAX_CANONICAL_DEPTH: usize = 256;
+
+/// Most values encoded, counting a shared value each time it appears;
+/// larger values have no encoding, so one that shares its parts over and
+/// over isn't unrolled into exponentially many bytes.
+pub const MAX_CANONICAL_NODES: usize = 1 << 20;
+
+fn len(out: &mut Vec<u8>, n: usize) {
+    out.extend((n as u64).to_be_bytes());
+}
+
+/// The identity of a container that could hold itself.
+fn container(val: &Value) -> Option<usize> {
+    match val {
+        Value::List(l) => Some(l.identity()),
+        Value::Tuple(t) => Some(t.identity()),
+        Value::Table(t) => Some(t.identity()),
+        Value::Map(m) => Some(m.identity()),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Path {
+    /// The containers the value being encoded is inside, outermost first,
+    /// so a value that holds itself is refused rather than unrolled to the
+    /// depth limit.
+    inside: Vec<usize>,
+    /// Values encoded so far.
+    nodes: usize,
+}
+
+fn encode(val: &Value, out: &mut Vec<u8>, depth: usize, path: &mut Path) -> Option<()> {
+    path.nodes += 1;
+    if depth > MAX_CANONICAL_DEPTH || path.nodes > MAX_CANONICAL_NODES {
+        return None;
+    }
+    let inside = container(val);
+    if let Some(id) = inside {
+        if path.inside.contains(&id) {
+            return None;
+        }
+        path.inside.push(id);
+    }
+    encode_one(val, out, depth, path)?;
+    if inside.is_some() {
+        path.inside.pop();
+    }
+    Some(())
+}
+
+fn encode_one(val: &Value, out: &mut Vec<u8>, depth: usize, path: &mut Path) -> Option<()> {
+    let seq = |items: &[Value], out: &mut Vec<u8>, path: &mut Path| {
+        len(out, items.len());
+        items
+            .iter()
+            .try_for_each(|v| encode(v, out, depth + 1, path))
+    };
+    match val {
+        Value::None => out.push(NONE),
+        Value::Integer(i) => {
+            out.push(INTEGER);
+            out.extend(i.to_be_bytes());
+        }
+        Value::Real(r) => {
+            let r = match *r {
+                r if r.is_nan() => f64::NAN,
+                // also matches -0.0
+                0.0 => 0.0,
+                r => r,
+            };
+            out.push(REAL);
+            out.extend(r.to_bits().to_be_bytes());
+        }
+        Value::Decimal(d) => {
+            let (mut units, mut scale) = (d.units(), d.scale());
+            while scale > 0 && units % 10 == 0 {
+                units /= 10;
+                scale -= 1;
+            }
+            out.push(DECIMAL);
+            out.extend(units.to_be_bytes());
+            out.push(scale);
+        }
+        Value::Str(s) => {
+            out.push(STR);
+            len(out, s.len());
+            out.extend(s.as_bytes());
+        }
+        Value::Buffer(b) => {
+            let bytes = b.to_vec();
+            out.push(BUFFER);
+            len(out, bytes.len());
+            out.extend(bytes);
+        }
+        Value::Timestamp(t) => {
+            out.push(TIMESTAMP);
+            out.extend(t.0.to_be_bytes());
+        }
+        Value::Duration(d) => {
+            out.push(DURATION);
+            out.extend(d.0.to_be_bytes());
+        }
+        Value::Tuple(t) => {
+            out.push(TUPLE);
+            let items: Vec<_> = (0..t.len()).filter_map(|i| t.get(i)).collect();
+            seq(&items, out, path)?;
+        }
+        Value::List(l) => {
+            out.push(LIST);
+            seq(&l.to_vec(), out, path)?;
+        }
+        Value::Table(t) => {
+            let mut entries = t.entries();
+            entries.sort_by_key(|(k, _)| *k);
+            out.push(TABLE);
+            len(out, entries.len());
+            for (k, v) in entries {
+                out.extend(k.to_be_bytes());
+                encode(&v, out, depth + 1, path)?;
+            }
+        }
+        Value::Map(m) => {
+            let mut entries = Vec::new();
+            for (k, v) in m.entries() {
+                let mut key = Vec::new();
+                encode(&k, &mut key, depth + 1, path)?;
+                entries.push((key, v));
+            }
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push(MAP);
+            len(out, entries.len());
+            for (k, v) in entries {
+                out.extend(k);
+                encode(&v, out, depth + 1, path)?;
+            }
+        }
+        Value::Variant(v) => {
+            out.push(VARIANT);
+            out.extend(v.tag().to_be_bytes());
+            encode(v.payload(), out, depth + 1, path)?;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// 64-bit FNV-1a, as `field_key` uses.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Value {
+    /// The canonical encoding, or `None` if the value isn't plain data or
+    /// is too large (see `MAX_CANONICAL_NODES`).
+    pub fn canonical_bytes(&self) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        encode(self, &mut out, 0, &mut Path::default())?;
+        Some(out)
+    }
+
+    /// A hash of the canonical encoding (64-bit FNV-1a), equally stable.
+    pub fn canonical_hash(&self) -> Option<u64> {
+        Some(fnv1a(&self.canonical_bytes()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datamodel::{field_key, Decimal, List, Map, Table, Value};
+
+    #[test]
+    fn equal_data_encodes_equally() {
+        let (a, b) = (Table::new(), Table::new());
+        a.set(field_key("x"), Value::Real(-0.0));
+        a.set(field_key("y"), Decimal::new(150, 2).unwrap().into());
+        b.set(field_key("y"), Decimal::new(15, 1).unwrap().into());
+        b.set(field_key("x"), Value::Real(0.0));
+        let (a, b): (Value, Value) = (a.into(), b.into());
+        assert_eq!(a.canonical_bytes(), b.canonical_bytes());
+        assert_ne!(
+            Value::Integer(1).canonical_hash(),
+            Value::Real(1.0).canonical_hash()
+        );
+        // pinned, so an accidental format change fails here
+        let list: Value = List::new(vec![Value::Integer(1), Value::Str("a".into())]).into();
+        assert_eq!(
+            list.canonical_bytes().unwrap(),
+            [
+                &[9][..],
+                &2u64.to_be_bytes(),
+                &[1],
+                &1i64.to_be_bytes(),
+                &[4],
+                &1u64.to_be_bytes(),
+                b"a"
+            ]
+            .concat()
+        );
+        assert_eq!(Value::None.canonical_hash(), Some(0xaf63bd4c8601b7df));
+        assert!(Value::NativeFn(|_| Value::None).canonical_hash().is_none());
+    }
+
+    #[test]
+    fn maps_encode_in_key_order() {
+        let (a, b) = (Map::new(), Map::new());
+        for (k, v) in [("b", 2), ("a", 1)] {
+            a.insert(Value::Str(k.into()), Value::Integer(v))
+                .ok()
+                .unwrap();
+        }
+        for (k, v) in [("a", 1), ("b", 2)] {
+            b.insert(Value::Str(k.into()), Value::Integer(v))
+                .ok()
+                .unwrap();
+        }
+        let (a, b): (Value, Value) = (a.into(), b.into());
+        assert_eq!(a.canonical_bytes(), b.canonical_bytes());
+        let one = Map::new();
+        one.insert(Value::Integer(7), Value::None).ok().unwrap();
+        let one: Value = one.into();
+        assert_eq!(
+            one.canonical_bytes().unwrap(),
+            [
+                &[12][..],
+                &1u64.to_be_bytes(),
+                &[1],
+                &7i64.to_be_bytes(),
+                &[0]
+            ]
+            .concat()
+        );
+        assert_ne!(a.canonical_hash(), one.canonical_hash());
+    }
+
+    #[test]
+    fn self_containing_values_are_refused() {
+        let list = List::new(Vec::new());
+        list.items.borrow_mut().push(list.clone().into());
+        list.items.borrow_mut().push(list.clone().into());
+        let val: Value = list.clone().into();
+        assert!(val.canonical_bytes().is_none());
+        list.items.borrow_mut().clear();
+        // shared but not cyclic is fine
+        let inner: Value = List::new(vec![Value::Integer(1)]).into();
+        let shared: Value = List::new(vec![inner.clone(), inner]).into();
+        assert!(shared.canonical_bytes().is_some());
+    }
+
+    #[test]
+    fn shared_values_are_capped() {
+        // 2^40 values once unshared
+        let mut val = Value::Integer(1);
+        for _ in 0..40 {
+            val = List::new(vec![val.clone(), val]).into();
+        }
+        assert!(val.canonical_hash().is_none());
+    }
+}
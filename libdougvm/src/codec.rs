@@ -0,0 +1,316 @@
+//! The binary encoding of plain data values shared by the `kv` store and
+//! the remote protocol (see `remote`).
+
+use crate::datamodel::{
+    Buffer, Decimal, Duration, Identity, List, Map, Str, Table, Timestamp, Tuple, Value, Variant,
+};
+
+pub(crate) fn write_chunk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+// One tag byte per value, followed by its fields. Integers and lengths are
+// little-endian.
+const NONE: u8 = 0;
+const INTEGER: u8 = 1;
+const REAL: u8 = 2;
+const STR: u8 = 3;
+const BUFFER: u8 = 4;
+const LIST: u8 = 5;
+const TUPLE: u8 = 6;
+const TABLE: u8 = 7;
+const VARIANT: u8 = 8;
+const TIMESTAMP: u8 = 9;
+const DURATION: u8 = 10;
+const DECIMAL: u8 = 11;
+const MAP: u8 = 12;
+
+/// Deepest nesting encoding and decoding will follow before giving up, so
+/// neither a hostile frame nor a deep value can exhaust the stack.
+pub const MAX_CODEC_DEPTH: usize = 256;
+
+/// Most values one encoding holds, counting a shared value each time it
+/// appears, so a value built by sharing its parts over and over can't
+/// take exponential time and space to encode.
+pub const MAX_CODEC_NODES: usize = 1 << 20;
+
+/// Appends the encoding of `val`, or `None` if it isn't plain data: if it
+/// holds anything else, contains itself, nests deeper than
+/// `MAX_CODEC_DEPTH` or holds more than `MAX_CODEC_NODES` values.
+pub(crate) fn encode(val: &Value, out: &mut Vec<u8>) -> Option<()> {
+    encode_at(val, out, &mut Path::default())
+}
+
+#[derive(Default)]
+struct Path {
+    /// The containers the value being encoded is inside, outermost first.
+    inside: Vec<usize>,
+    /// Values encoded so far.
+    nodes: usize,
+}
+
+/// The identity of a container that could hold itself.
+fn container(val: &Value) -> Option<usize> {
+    match val {
+        Value::List(l) => Some(l.identity()),
+        Value::Tuple(t) => Some(t.identity()),
+        Value::Table(t) => Some(t.identity()),
+        Value::Map(m) => Some(m.identity()),
+        _ => None,
+    }
+}
+
+fn encode_at(val: &Value, out: &mut Vec<u8>, path: &mut Path) -> Option<()> {
+    path.nodes += 1;
+    if path.inside.len() >= MAX_CODEC_DEPTH || path.nodes > MAX_CODEC_NODES {
+        return None;
+    }
+    let inside = container(val);
+    if let Some(id) = inside {
+        if path.inside.contains(&id) {
+            return None;
+        }
+        path.inside.push(id);
+    }
+    encode_one(val, out, path)?;
+    if inside.is_some() {
+        path.inside.pop();
+    }
+    Some(())
+}
+
+fn encode_one(val: &Value, out: &mut Vec<u8>, path: &mut Path) -> Option<()> {
+    match val {
+        Value::None => out.push(NONE),
+        Value::Integer(i) => {
+            out.push(INTEGER);
+            out.extend(i.to_le_bytes());
+        }
+        Value::Real(r) => {
+            out.push(REAL);
+            out.extend(r.to_le_bytes());
+        }
+        Value::Str(s) => {
+            out.push(STR);
+            write_chunk(out, s.as_bytes());
+        }
+        Value::Buffer(b) => {
+            out.push(BUFFER);
+            write_chunk(out, &b.to_vec());
+        }
+        Value::List(l) => {
+            out.push(LIST);
+            encode_seq(&l.to_vec(), out, path)?;
+        }
+        Value::Tuple(t) => {
+            out.push(TUPLE);
+            let items: Vec<_> = (0..t.len()).filter_map(|i| t.get(i)).collect();
+            encode_seq(&items, out, path)?;
+        }
+        Value::Table(t) => {
+            out.push(TABLE);
+            let entries = t.entries();
+            out.extend((entries.len() as u32).to_le_bytes());
+            for (k, v) in entries {
+                out.extend(k.to_le_bytes());
+                encode_at(&v, out, path)?;
+            }
+        }
+        Value::Map(m) => {
+            out.push(MAP);
+            let entries = m.entries();
+            out.extend((entries.len() as u32).to_le_bytes());
+            for (k, v) in entries {
+                encode_at(&k, out, path)?;
+                encode_at(&v, out, path)?;
+            }
+        }
+        Value::Variant(v) => {
+            out.push(VARIANT);
+            out.extend(v.tag().to_le_bytes());
+            encode_at(v.payload(), out, path)?;
+        }
+        Value::Timestamp(t) => {
+            out.push(TIMESTAMP);
+            out.extend(t.0.to_le_bytes());
+        }
+        Value::Duration(d) => {
+            out.push(DURATION);
+            out.extend(d.0.to_le_bytes());
+        }
+        Value::Decimal(d) => {
+            out.push(DECIMAL);
+            out.extend(d.units().to_le_bytes());
+            out.push(d.scale());
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn encode_seq(items: &[Value], out: &mut Vec<u8>, path: &mut Path) -> Option<()> {
+    out.extend((items.len() as u32).to_le_bytes());
+    for item in items {
+        encode_at(item, out, path)?;
+    }
+    Some(())
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.bytes.len() {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    pub(crate) fn chunk(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Decodes one value, or `None` if it is malformed or nested deeper than
+/// `MAX_CODEC_DEPTH`.
+pub(crate) fn decode_from(r: &mut Reader) -> Option<Value> {
+    decode_at(r, 0)
+}
+
+fn decode_at(r: &mut Reader, depth: usize) -> Option<Value> {
+    if depth > MAX_CODEC_DEPTH {
+        return None;
+    }
+    let depth = depth + 1;
+    Some(match r.u8()? {
+        NONE => Value::None,
+        INTEGER => Value::Integer(r.u64()? as i64),
+        REAL => Value::Real(f64::from_bits(r.u64()?)),
+        STR => Value::Str(Str::from(std::str::from_utf8(r.chunk()?).ok()?)),
+        BUFFER => Buffer::new(r.chunk()?.to_vec()).into(),
+        LIST => List::new(decode_seq(r, depth)?).into(),
+        TUPLE => Tuple::new(decode_seq(r, depth)?).into(),
+        TABLE => {
+            let table = Table::new();
+            for _ in 0..r.u32()? {
+                let key = r.u64()?;
+                table.set(key, decode_at(r, depth)?);
+            }
+            table.into()
+        }
+        MAP => {
+            let map = Map::new();
+            for _ in 0..r.u32()? {
+                let key = decode_at(r, depth)?;
+                map.insert(key, decode_at(r, depth)?).ok()?;
+            }
+            map.into()
+        }
+        VARIANT => {
+            let tag = r.u32()?;
+            Variant::new(tag, decode_at(r, depth)?).into()
+        }
+        TIMESTAMP => Timestamp(r.u64()? as i64).into(),
+        DURATION => Duration(r.u64()? as i64).into(),
+        DECIMAL => {
+            let units = i128::from_le_bytes(r.take(16)?.try_into().ok()?);
+            Decimal::new(units, r.u8()?)?.into()
+        }
+        _ => return None,
+    })
+}
+
+fn decode_seq(r: &mut Reader, depth: usize) -> Option<Vec<Value>> {
+    (0..r.u32()?).map(|_| decode_at(r, depth)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let table = Table::new();
+        table.set(7, Value::Str("t".into()));
+        let map = Map::new();
+        map.insert(Value::Integer(1), Value::Real(2.5))
+            .ok()
+            .unwrap();
+        let val: Value = List::new(vec![
+            Value::None,
+            Value::Integer(-3),
+            Value::Real(0.5),
+            Value::Str("s".into()),
+            Buffer::new(vec![1, 2]).into(),
+            Tuple::new(vec![Value::Integer(1)]).into(),
+            table.into(),
+            map.into(),
+            Variant::ok(Value::Integer(4)).into(),
+            Timestamp(5).into(),
+            Duration(-6).into(),
+            Decimal::new(125, 2).unwrap().into(),
+        ])
+        .into();
+        let mut out = Vec::new();
+        encode(&val, &mut out).unwrap();
+        let back = decode_from(&mut Reader::new(&out)).unwrap();
+        let mut again = Vec::new();
+        encode(&back, &mut again).unwrap();
+        assert_eq!(out, again);
+        assert!(encode(&Value::NativeFn(|_| Value::None), &mut Vec::new()).is_none());
+
+        // truncated, unknown and overly deep input is refused
+        assert!(decode_from(&mut Reader::new(&out[..out.len() - 1])).is_none());
+        assert!(decode_from(&mut Reader::new(&[99])).is_none());
+        let nested = |n: usize| [&[LIST, 1, 0, 0, 0].repeat(n)[..], &[NONE]].concat();
+        assert!(decode_from(&mut Reader::new(&nested(MAX_CODEC_DEPTH))).is_some());
+        assert!(decode_from(&mut Reader::new(&nested(MAX_CODEC_DEPTH + 1))).is_none());
+    }
+
+    #[test]
+    fn shared_values_are_capped() {
+        // 2^40 values once unshared
+        let mut val = Value::Integer(1);
+        for _ in 0..40 {
+            val = List::new(vec![val.clone(), val]).into();
+        }
+        assert!(encode(&val, &mut Vec::new()).is_none());
+        // shared a little is fine
+        let inner: Value = List::new(vec![Value::Integer(1)]).into();
+        let shared: Value = List::new(vec![inner.clone(), inner]).into();
+        let mut out = Vec::new();
+        encode(&shared, &mut out).unwrap();
+        let back = decode_from(&mut Reader::new(&out)).unwrap();
+        assert!(matches!(back, Value::List(l) if l.len() == 2));
+    }
+}
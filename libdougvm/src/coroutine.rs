@@ -0,0 +1,204 @@
+//! Coroutines: functions that can suspend themselves partway with `Yield`
+//! and be picked up again with `Resume`, for generators and other
+//! iterator-style code.
+//!
+//! A `Coroutine` owns the chain of call frames it was suspended with. The
+//! `Resume` op links that chain on top of the resumer's frames, pushing
+//! the resumed-with value onto the innermost frame: as the function's one
+//! argument the first time, and as the result of its `Yield` after that.
+//! `Yield` unlinks the chain again, up to the frame the coroutine started
+//! in, and the yielded value becomes the result of `Resume`. When the
+//! coroutine's function returns, its result is the result of `Resume`, and
+//! the coroutine is done. So is one an exception unwinds out of.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::datamodel::{Function, Identity};
+use crate::CallFrame;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status {
+    /// Not yet started, or stopped at a `Yield`.
+    Suspended,
+    /// Resumed, and not yet yielded or returned.
+    Running,
+    Done,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Suspended => "suspended",
+            Status::Running => "running",
+            Status::Done => "done",
+        }
+    }
+}
+
+enum State {
+    Suspended(Box<CallFrame>),
+    Running,
+    Done,
+}
+
+#[derive(Clone)]
+pub struct Coroutine {
+    state: Rc<RefCell<State>>,
+}
+
+impl Coroutine {
+    pub fn new(func: Function) -> Coroutine {
+        Coroutine {
+            state: Rc::new(RefCell::new(State::Suspended(Box::new(CallFrame::new(
+                func,
+            ))))),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match &*self.state.borrow() {
+            State::Suspended(_) => Status::Suspended,
+            State::Running => Status::Running,
+            State::Done => Status::Done,
+        }
+    }
+
+    /// Takes the frames of a suspended coroutine to run them.
+    pub(crate) fn take(&self) -> Option<Box<CallFrame>> {
+        let mut state = self.state.borrow_mut();
+        match std::mem::replace(&mut *state, State::Running) {
+            State::Suspended(frames) => Some(frames),
+            other => {
+                *state = other;
+                None
+            }
+        }
+    }
+
+    /// Hands back the frames of a coroutine that yielded.
+    pub(crate) fn suspend(&self, frames: Box<CallFrame>) {
+        *self.state.borrow_mut() = State::Suspended(frames);
+    }
+
+    pub(crate) fn finish(&self) {
+        *self.state.borrow_mut() = State::Done;
+    }
+}
+
+impl Identity for Coroutine {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.state).cast::<()>() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::OpError;
+    use crate::datamodel::Value;
+    use crate::testing::function;
+    use crate::{VirtualMachine, VmError};
+
+    #[test]
+    fn generators_yield_and_resume() {
+        // counts up from its argument, adding whatever it is resumed with
+        let counter = function(vec![
+            Store(1).into(),
+            Load(1).into(),
+            Yield.into(),
+            Load(1).into(),
+            Add.into(),
+            Store(1).into(),
+            Load(1).into(),
+            Yield.into(),
+            Pop.into(),
+            Push(Value::Str("done".into())).into(),
+            Return.into(),
+        ]);
+        let co = Coroutine::new(counter);
+        let resume = |val: i64| -> Vec<crate::bytecode::Op> {
+            vec![
+                Load(1).into(),
+                Push(Value::Integer(val)).into(),
+                Resume.into(),
+            ]
+        };
+        // [resume(co, 10), resume(co, 5), resume(co, 0)]
+        let mut ops = vec![Push(co.clone().into()).into(), Store(1).into()];
+        ops.extend(resume(10));
+        ops.extend(resume(5));
+        ops.extend(resume(0));
+        ops.extend([
+            Store(2).into(),
+            Store(3).into(),
+            Store(4).into(),
+            Load(4).into(),
+            Load(3).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(function(ops));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(25))));
+        assert!(co.status() == Status::Done);
+
+        // a done coroutine can't be resumed, and yield needs a coroutine
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(co.into()).into(),
+            Push(Value::None).into(),
+            Resume.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::NotResumable,
+                ..
+            })
+        ));
+        let mut vm = VirtualMachine::new(function(vec![Push(Value::None).into(), Yield.into()]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::NotInCoroutine,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn yields_cross_calls_and_errors_finish_the_coroutine() {
+        // the coroutine yields from a callee, then fails
+        let inner = function(vec![
+            Push(Value::Integer(7)).into(),
+            Yield.into(),
+            Neg.into(),
+            Return.into(),
+        ]);
+        let outer = function(vec![
+            Pop.into(),
+            Push(inner.into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let co = Coroutine::new(outer);
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(co.clone().into()).into(),
+            Push(Value::None).into(),
+            Resume.into(),
+            Store(1).into(),
+            PushHandler(4).into(),
+            Push(co.clone().into()).into(),
+            Push(Value::Str("x".into())).into(),
+            Resume.into(),
+            Return.into(),
+            Pop.into(),
+            Load(1).into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(7))));
+        assert!(co.status() == Status::Done);
+        assert_eq!(vm.usage().peak_frames, 3);
+    }
+}
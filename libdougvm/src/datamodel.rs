@@ -1,41 +1,253 @@
-use std::any::Any;
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::cell::RefCell;
 use std::rc::{Rc, Weak};
 
-use crate::bytecode::Op;
+use crate::bytecode::{Op, OpError};
+use crate::coroutine::Coroutine;
+use crate::gc::{self, Node};
+use crate::VmContext;
 
 pub type Integer = i64;
 pub type Real = f64;
+pub type Str = Rc<str>;
 pub type Unknown = Rc<dyn Any>;
 
 pub type NativeFn = fn(Vec<Value>) -> Value;
 
+/// A host function registered in a `NativeRegistry`. Unlike a `NativeFn` it
+/// may capture state, gets its arguments first argument first, and can
+/// fail: return `OpError::Uncaught` with an exception (e.g.
+/// `exception::native_error`) to throw one into the script.
+pub type HostFn = Box<dyn Fn(&mut VmContext, &[Value]) -> Result<Value, OpError>>;
+
+/// Host functions by name, called from bytecode by index with
+/// `ops::CallHost`. Indices are handed out in registration order, so
+/// bytecode referring to them (including serialized bytecode) expects
+/// functions registered in the same order.
+#[derive(Default)]
+pub struct NativeRegistry {
+    functions: Vec<(String, HostFn)>,
+    indices: HashMap<String, u32>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> NativeRegistry {
+        NativeRegistry::default()
+    }
+
+    /// Registers `f` as `name`, returning its index. Registering a name
+    /// again replaces the function but keeps the index.
+    pub fn register(
+        &mut self,
+        name: &str,
+        f: impl Fn(&mut VmContext, &[Value]) -> Result<Value, OpError> + 'static,
+    ) -> u32 {
+        if let Some(&index) = self.indices.get(name) {
+            self.functions[index as usize].1 = Box::new(f);
+            return index;
+        }
+        let index = self.functions.len() as u32;
+        self.functions.push((name.to_string(), Box::new(f)));
+        self.indices.insert(name.to_string(), index);
+        index
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<u32> {
+        self.indices.get(name).copied()
+    }
+
+    pub fn name(&self, index: u32) -> Option<&str> {
+        self.functions.get(index as usize).map(|(n, _)| n.as_str())
+    }
+
+    pub fn get(&self, index: u32) -> Option<&HostFn> {
+        self.functions.get(index as usize).map(|(_, f)| f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+}
+
 #[derive(Clone)]
 pub struct Buffer {
-    items: Rc<RefCell<Vec<u8>>>,
+    pub(crate) items: Rc<RefCell<Vec<u8>>>,
+}
+
+/// An exact base-10 number, `units * 10^-scale`. Arithmetic on decimals is
+/// exact except for division, which rounds; overflow is an error, never a
+/// silent loss of precision.
+#[derive(Clone, Copy)]
+pub struct Decimal {
+    units: i128,
+    scale: u8,
+}
+
+/// How a `Decimal` is rounded when digits have to be dropped.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rounding {
+    /// To nearest, ties to the even neighbour (banker's rounding).
+    HalfEven,
+    /// To nearest, ties away from zero.
+    HalfUp,
+    /// Toward zero (truncation).
+    Down,
+    /// Away from zero.
+    Up,
+    Floor,
+    Ceiling,
 }
 
+/// A signed span of time, in nanoseconds.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Duration(pub i64);
+
 #[derive(Clone)]
 pub struct Function {
     pub module: Tuple,
     pub ops: Rc<[Op]>,
 }
 
+/// A `Function` with the values it captured when it was made, which its
+/// ops read with `LoadUpvalue`. Captures are copies: a closure sees what
+/// a local held when `MakeClosure` ran, so closures made in a loop each
+/// keep their own iteration's value, and state they share goes in a
+/// container.
+#[derive(Clone)]
+pub struct Closure {
+    pub function: Function,
+    pub upvalues: Rc<[Value]>,
+}
+
+/// A named set of methods. Each implementing type registers a table of
+/// callables, one per method slot, looked up by the value's `TypeKey`.
+#[derive(Clone)]
+pub struct Interface {
+    inner: Rc<InterfaceInner>,
+}
+
+struct InterfaceInner {
+    name: String,
+    methods: Vec<String>,
+    impls: RefCell<Vec<(TypeKey, Rc<[Value]>)>>,
+}
+
+/// What an `Interface` implementation is registered against: a builtin
+/// value type, or the concrete Rust type behind an `Unknown`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TypeKey {
+    Builtin(ValueType),
+    Host(TypeId),
+}
+
+/// A lazy sequence: each call to `next` produces the following item, or
+/// `None` once exhausted. Natives use this to stream results instead of
+/// building whole lists.
+#[derive(Clone)]
+pub struct Iter {
+    next: Rc<RefCell<dyn FnMut() -> Option<Value>>>,
+}
+
 #[derive(Clone)]
 pub struct List {
-    items: Rc<RefCell<Vec<Value>>>,
+    pub(crate) items: Rc<RefCell<Vec<Value>>>,
+}
+
+/// A table's layout: the keys it holds, in slot order. Tables that gain the
+/// same keys in the same order share a `Shape`, so a shape (by pointer) can
+/// key caches of field slot lookups.
+pub struct Shape {
+    keys: Vec<u64>,
+    transitions: RefCell<Vec<(u64, Weak<Shape>)>>,
+    /// The shape this one extends, kept alive so the transition here
+    /// stays findable for as long as this shape is in use.
+    _parent: Option<Rc<Shape>>,
+    /// Operator methods of records with this shape, by `field_key` of the
+    /// method name.
+    methods: RefCell<HashMap<u64, Value>>,
 }
 
 #[derive(Clone)]
 pub struct Table {
-    items: Rc<RefCell<Vec<(u64, Value)>>>,
+    pub(crate) inner: Rc<RefCell<TableInner>>,
+}
+
+pub(crate) struct TableInner {
+    pub(crate) shape: Rc<Shape>,
+    pub(crate) values: Vec<Value>,
 }
 
+/// A fixed set of named fields. Unlike a `Table`, a record's fields are
+/// laid down when it is made and never added to, so records made by the
+/// same `NewRecord` share one `Shape` and every field access can be cached.
+#[derive(Clone)]
+pub struct Record {
+    pub(crate) shape: Rc<Shape>,
+    pub(crate) values: Rc<[RefCell<Value>]>,
+}
+
+/// An insertion-ordered dictionary. Keys are `None`, numbers, `Str`s and
+/// `Symbol`s. Numbers of one kind are the same key when they are equal, so
+/// `1.5` and `1.50` (as `Decimal`s) name one entry. Across kinds only
+/// integral values meet: `1`, `1.0` and `1.00d` all name the `Integer` key,
+/// even though `Eq` never finds a `Real` equal to a `Decimal`, while `1.5`
+/// and `1.5d` stay two entries. `Str`s compare by content and `Symbol`s by
+/// identity. Other values, and NaN, which isn't equal to itself, can't be
+/// keys.
+#[derive(Clone)]
+pub struct Map {
+    pub(crate) inner: Rc<RefCell<MapInner>>,
+}
+
+#[derive(Default)]
+pub(crate) struct MapInner {
+    index: HashMap<MapKey, usize>,
+    /// In insertion order; removing an entry leaves a hole here until the
+    /// holes are compacted away.
+    pub(crate) entries: Vec<Option<(Value, Value)>>,
+}
+
+/// What a `Map` hashes a key as.
+#[derive(PartialEq, Eq, Hash)]
+enum MapKey {
+    None,
+    Int(i64),
+    Real(u64),
+    Decimal(i128, u8),
+    Str(Str),
+    Symbol(usize),
+}
+
+/// An interned string. Symbols from the same `Interner` with the same name
+/// are the same symbol, so comparing two is a pointer comparison rather
+/// than a string one.
+#[derive(Clone)]
+pub struct Symbol {
+    name: Str,
+}
+
+/// The table a VM interns symbols in (see `VirtualMachine::intern`).
+/// Symbols from different tables never compare equal.
+#[derive(Default)]
+pub struct Interner {
+    names: HashSet<Str>,
+}
+
+/// An instant in UTC, as nanoseconds since the Unix epoch. That covers
+/// roughly the years 1678 to 2262.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Timestamp(pub i64);
+
 #[derive(Clone)]
 pub struct Tuple {
-    items: Rc<[RefCell<Value>]>,
+    pub(crate) items: Rc<[RefCell<Value>]>,
 }
 
 #[derive(Clone)]
@@ -43,6 +255,782 @@ pub struct TupleWeak {
     weakref: Weak<[RefCell<Value>]>,
 }
 
+/// Discriminant of a `Variant`; the compiler assigns tags per enum type.
+pub type Tag = u32;
+
+/// A tagged value of an algebraic data type. Multi-field variants carry a
+/// `Tuple` payload, unit variants carry `Value::None`.
+#[derive(Clone)]
+pub struct Variant {
+    tag: Tag,
+    pub(crate) payload: Rc<Value>,
+}
+
+impl Buffer {
+    pub fn new(items: Vec<u8>) -> Buffer {
+        Buffer {
+            items: Rc::new(RefCell::new(items)),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.items.borrow().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Most digits a `Decimal` may have after the point.
+pub const MAX_DECIMAL_SCALE: u8 = 38;
+
+/// Digits after the point that `Decimal` division rounds to, unless the
+/// operands already have more.
+pub const DECIMAL_DIV_SCALE: u8 = 18;
+
+fn pow10(exp: u8) -> Option<i128> {
+    10i128.checked_pow(exp as u32)
+}
+
+/// `n / d`, rounded as `mode` says.
+fn div_round(n: i128, d: i128, mode: Rounding) -> Option<i128> {
+    let (q, r) = (n.checked_div(d)?, n.checked_rem(d)?);
+    if r == 0 {
+        return Some(q);
+    }
+    let negative = (n < 0) != (d < 0);
+    let (twice, d) = (r.unsigned_abs() * 2, d.unsigned_abs());
+    let away = match mode {
+        Rounding::Down => false,
+        Rounding::Up => true,
+        Rounding::Floor => negative,
+        Rounding::Ceiling => !negative,
+        Rounding::HalfUp => twice >= d,
+        Rounding::HalfEven => twice > d || (twice == d && q % 2 != 0),
+    };
+    match (away, negative) {
+        (false, _) => Some(q),
+        (true, true) => q.checked_sub(1),
+        (true, false) => q.checked_add(1),
+    }
+}
+
+impl Decimal {
+    pub fn new(units: i128, scale: u8) -> Option<Decimal> {
+        (scale <= MAX_DECIMAL_SCALE).then_some(Decimal { units, scale })
+    }
+
+    pub fn units(&self) -> i128 {
+        self.units
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// This value with exactly `scale` digits after the point.
+    pub fn round(&self, scale: u8, mode: Rounding) -> Option<Decimal> {
+        let units = match scale.checked_sub(self.scale) {
+            Some(up) => self.units.checked_mul(pow10(up)?)?,
+            None => div_round(self.units, pow10(self.scale - scale)?, mode)?,
+        };
+        Decimal::new(units, scale)
+    }
+
+    /// Both operands' units at their common scale.
+    fn aligned(&self, other: &Decimal) -> Option<(i128, i128, u8)> {
+        let scale = self.scale.max(other.scale);
+        let a = self.round(scale, Rounding::Down)?.units;
+        let b = other.round(scale, Rounding::Down)?.units;
+        Some((a, b, scale))
+    }
+
+    pub fn checked_add(&self, other: &Decimal) -> Option<Decimal> {
+        let (a, b, scale) = self.aligned(other)?;
+        Decimal::new(a.checked_add(b)?, scale)
+    }
+
+    pub fn checked_sub(&self, other: &Decimal) -> Option<Decimal> {
+        let (a, b, scale) = self.aligned(other)?;
+        Decimal::new(a.checked_sub(b)?, scale)
+    }
+
+    pub fn checked_mul(&self, other: &Decimal) -> Option<Decimal> {
+        let scale = self.scale.checked_add(other.scale)?;
+        Decimal::new(self.units.checked_mul(other.units)?, scale)
+    }
+
+    /// The quotient with `scale` digits after the point; `None` on overflow
+    /// or division by zero.
+    pub fn checked_div(&self, other: &Decimal, scale: u8, mode: Rounding) -> Option<Decimal> {
+        // units * 10^(scale + other.scale - self.scale) / other.units
+        let shift = scale as i32 + other.scale as i32 - self.scale as i32;
+        let exp = pow10(u8::try_from(shift.unsigned_abs()).ok()?)?;
+        let q = match shift >= 0 {
+            true => div_round(self.units.checked_mul(exp)?, other.units, mode)?,
+            false => div_round(self.units, other.units.checked_mul(exp)?, mode)?,
+        };
+        Decimal::new(q, scale)
+    }
+
+    /// The remainder of truncating division, with the sign of `self`.
+    pub fn checked_rem(&self, other: &Decimal) -> Option<Decimal> {
+        let (a, b, scale) = self.aligned(other)?;
+        Decimal::new(a.checked_rem(b)?, scale)
+    }
+
+    pub fn checked_neg(&self) -> Option<Decimal> {
+        Decimal::new(self.units.checked_neg()?, self.scale)
+    }
+
+    /// Parses `[-+]digits[.digits]`.
+    pub fn parse(text: &str) -> Option<Decimal> {
+        let (negative, digits) = match text.as_bytes().first()? {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _ => (false, text),
+        };
+        let (whole, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && frac.is_empty() {
+            return None;
+        }
+        let mut units: i128 = 0;
+        for c in whole.bytes().chain(frac.bytes()) {
+            if !c.is_ascii_digit() {
+                return None;
+            }
+            units = units.checked_mul(10)?.checked_add((c - b'0') as i128)?;
+        }
+        let units = if negative { -units } else { units };
+        Decimal::new(units, u8::try_from(frac.len()).ok()?)
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(i: i64) -> Self {
+        Decimal {
+            units: i as i128,
+            scale: 0,
+        }
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        match self.aligned(other) {
+            Some((a, b, _)) => a.cmp(&b),
+            // rescaling overflowed, so the side with fewer digits after the
+            // point is the one with the larger magnitude
+            None => match self.scale < other.scale {
+                true => self.units.signum().cmp(&0),
+                false => 0.cmp(&other.units.signum()),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let digits = self.units.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, frac) = digits.split_at(digits.len() - scale);
+        let sign = if self.units < 0 { "-" } else { "" };
+        match scale {
+            0 => write!(f, "{}{}", sign, whole),
+            _ => write!(f, "{}{}.{}", sign, whole, frac),
+        }
+    }
+}
+
+impl Interface {
+    pub fn new(name: String, methods: Vec<String>) -> Interface {
+        Interface {
+            inner: Rc::new(InterfaceInner {
+                name,
+                methods,
+                impls: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn methods(&self) -> &[String] {
+        &self.inner.methods
+    }
+
+    /// Registers (or replaces) the method table for `key`. `table` must have
+    /// one callable per method, in the same order as `methods()`.
+    pub fn implement(&self, key: TypeKey, table: Vec<Value>) {
+        assert_eq!(
+            table.len(),
+            self.inner.methods.len(),
+            "method table for interface {} has the wrong length",
+            self.inner.name
+        );
+        let mut impls = self.inner.impls.borrow_mut();
+        impls.retain(|(k, _)| *k != key);
+        impls.push((key, table.into()));
+    }
+
+    pub fn table(&self, key: TypeKey) -> Option<Rc<[Value]>> {
+        let impls = self.inner.impls.borrow();
+        impls
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, t)| t.clone())
+    }
+
+    pub fn is_implemented_by(&self, val: &Value) -> bool {
+        self.table(val.type_key()).is_some()
+    }
+}
+
+impl Iter {
+    pub fn new(next: impl FnMut() -> Option<Value> + 'static) -> Iter {
+        Iter {
+            next: Rc::new(RefCell::new(next)),
+        }
+    }
+
+    pub fn next(&self) -> Option<Value> {
+        (self.next.borrow_mut())()
+    }
+
+    /// Iterates `items`, first to last.
+    pub fn over(items: Vec<Value>) -> Iter {
+        let mut items = items.into_iter();
+        Iter::new(move || items.next())
+    }
+}
+
+impl List {
+    pub fn new(items: Vec<Value>) -> List {
+        let items = Rc::new(RefCell::new(items));
+        gc::track(Node::List(Rc::downgrade(&items)));
+        List { items }
+    }
+
+    pub fn to_vec(&self) -> Vec<Value> {
+        self.items.borrow().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl MapKey {
+    /// Integral numbers are their `Integer`'s key, and decimals drop
+    /// trailing zeros, so equal numbers hash alike.
+    fn of(val: &Value) -> Result<MapKey, OpError> {
+        Ok(match val {
+            Value::None => MapKey::None,
+            Value::Integer(i) => MapKey::Int(*i),
+            Value::Real(r) if r.is_nan() => return Err(OpError::BadType(ValueType::Real)),
+            Value::Real(r) => match *r as i64 {
+                i if i as f64 == *r => MapKey::Int(i),
+                _ => MapKey::Real(r.to_bits()),
+            },
+            Value::Decimal(d) => {
+                let (mut units, mut scale) = (d.units(), d.scale());
+                while scale > 0 && units % 10 == 0 {
+                    units /= 10;
+                    scale -= 1;
+                }
+                match (scale, i64::try_from(units)) {
+                    (0, Ok(i)) => MapKey::Int(i),
+                    _ => MapKey::Decimal(units, scale),
+                }
+            }
+            Value::Str(s) => MapKey::Str(s.clone()),
+            Value::Symbol(s) => MapKey::Symbol(s.identity()),
+            other => return Err(OpError::BadType(other.get_type())),
+        })
+    }
+}
+
+impl Map {
+    pub fn new() -> Map {
+        let inner = Rc::new(RefCell::new(MapInner::default()));
+        gc::track(Node::Map(Rc::downgrade(&inner)));
+        Map { inner }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.borrow().index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries, in the order their keys were first inserted.
+    pub fn entries(&self) -> Vec<(Value, Value)> {
+        let inner = self.inner.borrow();
+        inner.entries.iter().flatten().cloned().collect()
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Option<Value>, OpError> {
+        let key = MapKey::of(key)?;
+        let inner = self.inner.borrow();
+        let entry = inner
+            .index
+            .get(&key)
+            .and_then(|&i| inner.entries[i].as_ref());
+        Ok(entry.map(|(_, val)| val.clone()))
+    }
+
+    pub fn contains(&self, key: &Value) -> Result<bool, OpError> {
+        let key = MapKey::of(key)?;
+        Ok(self.inner.borrow().index.contains_key(&key))
+    }
+
+    /// Sets the value under `key`. A key already present keeps its place
+    /// in the order, and the key it was first inserted with.
+    pub fn insert(&self, key: Value, val: Value) -> Result<(), OpError> {
+        let hashed = MapKey::of(&key)?;
+        let mut inner = self.inner.borrow_mut();
+        match inner.index.get(&hashed) {
+            Some(&i) => inner.entries[i].as_mut().unwrap().1 = val,
+            None => {
+                let i = inner.entries.len();
+                inner.entries.push(Some((key, val)));
+                inner.index.insert(hashed, i);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `a` and `b` would be the same key in a map; `false` if
+    /// either can't be one.
+    pub(crate) fn same_key(a: &Value, b: &Value) -> bool {
+        matches!((MapKey::of(a), MapKey::of(b)), (Ok(a), Ok(b)) if a == b)
+    }
+
+    /// An `Iter` over a snapshot of the entries, as `(key, value)` tuples.
+    pub fn iter(&self) -> Iter {
+        let mut entries = self.entries().into_iter();
+        Iter::new(move || {
+            let (key, val) = entries.next()?;
+            Some(Tuple::new(vec![key, val]).into())
+        })
+    }
+
+    /// Removes `key`'s entry, returning its value if it had one.
+    pub fn remove(&self, key: &Value) -> Result<Option<Value>, OpError> {
+        let key = MapKey::of(key)?;
+        let mut inner = self.inner.borrow_mut();
+        let removed = match inner.index.remove(&key) {
+            Some(i) => inner.entries[i].take().map(|(_, val)| val),
+            None => return Ok(None),
+        };
+        if inner.entries.len() > 2 * inner.index.len() + 8 {
+            inner.compact();
+        }
+        Ok(removed)
+    }
+}
+
+impl MapInner {
+    fn compact(&mut self) {
+        self.entries.retain(Option::is_some);
+        for (i, (key, _)) in self.entries.iter().flatten().enumerate() {
+            // every key in the map was hashable when it went in
+            if let Ok(key) = MapKey::of(key) {
+                self.index.insert(key, i);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static ROOT_SHAPE: Rc<Shape> = Rc::new(Shape {
+        keys: Vec::new(),
+        transitions: RefCell::new(Vec::new()),
+        _parent: None,
+        methods: RefCell::default(),
+    });
+}
+
+impl Shape {
+    pub fn root() -> Rc<Shape> {
+        ROOT_SHAPE.with(|s| s.clone())
+    }
+
+    pub fn keys(&self) -> &[u64] {
+        &self.keys
+    }
+
+    pub fn slot(&self, key: u64) -> Option<usize> {
+        self.keys.iter().position(|k| *k == key)
+    }
+
+    /// Gives records of this shape the method `field_key(name)`, which the
+    /// arithmetic and comparison ops call instead of failing on them (see
+    /// `bytecode::ops::arith`). Records with the same fields share a
+    /// shape, and so their methods; a shape no record or host holds is
+    /// dropped with its methods.
+    pub fn set_method(&self, key: u64, method: Value) {
+        self.methods.borrow_mut().insert(key, method);
+    }
+
+    pub fn method(&self, key: u64) -> Option<Value> {
+        self.methods.borrow().get(&key).cloned()
+    }
+
+    /// The shape with exactly `keys`, in order: the one a table gaining
+    /// those keys would reach.
+    pub fn of(keys: &[u64]) -> Rc<Shape> {
+        keys.iter()
+            .fold(Shape::root(), |shape, &key| shape.with_key(key))
+    }
+
+    /// Returns the shape reached by appending `key`, reusing an existing
+    /// transition if another table has already taken it.
+    fn with_key(self: &Rc<Shape>, key: u64) -> Rc<Shape> {
+        let mut transitions = self.transitions.borrow_mut();
+        transitions.retain(|(_, s)| s.strong_count() > 0);
+        if let Some(shape) = transitions
+            .iter()
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, s)| s.upgrade())
+        {
+            return shape;
+        }
+        let mut keys = self.keys.clone();
+        keys.push(key);
+        let shape = Rc::new(Shape {
+            keys,
+            transitions: RefCell::new(Vec::new()),
+            _parent: Some(self.clone()),
+            methods: RefCell::default(),
+        });
+        transitions.push((key, Rc::downgrade(&shape)));
+        shape
+    }
+}
+
+impl Table {
+    pub fn new() -> Table {
+        let inner = Rc::new(RefCell::new(TableInner {
+            shape: Shape::root(),
+            values: Vec::new(),
+        }));
+        gc::track(Node::Table(Rc::downgrade(&inner)));
+        Table { inner }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.borrow().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The table's own fields, in slot order.
+    pub fn entries(&self) -> Vec<(u64, Value)> {
+        let inner = self.inner.borrow();
+        let keys = inner.shape.keys.iter().copied();
+        keys.zip(inner.values.iter().cloned()).collect()
+    }
+
+    pub fn shape(&self) -> Rc<Shape> {
+        self.inner.borrow().shape.clone()
+    }
+
+    /// Reads a field of this table only, ignoring any prototype.
+    pub fn get(&self, key: u64) -> Option<Value> {
+        let inner = self.inner.borrow();
+        inner.shape.slot(key).map(|i| inner.values[i].clone())
+    }
+
+    /// Reads a slot directly; callers must have checked `shape()` first.
+    pub fn get_slot(&self, slot: usize) -> Option<Value> {
+        self.inner.borrow().values.get(slot).cloned()
+    }
+
+    /// Writes a slot directly; callers must have checked `shape()` first.
+    pub fn set_slot(&self, slot: usize, val: Value) {
+        self.inner.borrow_mut().values[slot] = val;
+    }
+
+    pub fn set(&self, key: u64, val: Value) {
+        let mut inner = self.inner.borrow_mut();
+        match inner.shape.slot(key) {
+            Some(i) => inner.values[i] = val,
+            None => {
+                inner.shape = inner.shape.with_key(key);
+                inner.values.push(val);
+            }
+        }
+    }
+
+    /// Reads a field, falling back along the `__proto__` chain when the
+    /// table doesn't have it. Tables without a `PROTO_KEY` field don't
+    /// delegate, so prototype mode is opted into per object.
+    pub fn lookup(&self, key: u64) -> Option<Value> {
+        let mut table = self.clone();
+        for _ in 0..MAX_PROTO_DEPTH {
+            if let Some(val) = table.get(key) {
+                return Some(val);
+            }
+            table = match table.get(PROTO_KEY) {
+                Some(Value::Table(proto)) => proto,
+                _ => return None,
+            };
+        }
+        None
+    }
+}
+
+impl Record {
+    /// A record with `values` in the slots of `shape`, or `None` if the
+    /// counts differ.
+    pub fn new(shape: Rc<Shape>, values: Vec<Value>) -> Option<Record> {
+        if values.len() != shape.keys.len() {
+            return None;
+        }
+        let values: Rc<[RefCell<Value>]> = values.into_iter().map(RefCell::new).collect();
+        gc::track(Node::Tuple(Rc::downgrade(&values)));
+        Some(Record { shape, values })
+    }
+
+    pub fn shape(&self) -> &Rc<Shape> {
+        &self.shape
+    }
+
+    pub fn get(&self, key: u64) -> Option<Value> {
+        self.shape.slot(key).map(|i| self.get_slot(i))
+    }
+
+    /// Writes a field, returning false if the record has no such field.
+    pub fn set(&self, key: u64, val: Value) -> bool {
+        match self.shape.slot(key) {
+            Some(i) => {
+                self.set_slot(i, val);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_slot(&self, slot: usize) -> Value {
+        self.values[slot].borrow().clone()
+    }
+
+    pub fn set_slot(&self, slot: usize, val: Value) {
+        *self.values[slot].borrow_mut() = val;
+    }
+
+    /// The fields, in slot order.
+    pub fn entries(&self) -> Vec<(u64, Value)> {
+        let keys = self.shape.keys.iter().copied();
+        keys.zip(self.values.iter().map(|v| v.borrow().clone()))
+            .collect()
+    }
+}
+
+impl Identity for Record {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.values).cast::<()>() as usize
+    }
+}
+
+impl Symbol {
+    pub fn name(&self) -> &Str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Rc::ptr_eq(&self.name, &other.name)
+    }
+}
+
+impl Identity for Symbol {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.name).cast::<()>() as usize
+    }
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(name) = self.names.get(name) {
+            return Symbol { name: name.clone() };
+        }
+        let name = Str::from(name);
+        self.names.insert(name.clone());
+        Symbol { name }
+    }
+
+    /// The symbol for `name`, if it has been interned.
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        let name = self.names.get(name)?.clone();
+        Some(Symbol { name })
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// Hashes a field name into a `Table` key (64-bit FNV-1a), so the compiler
+/// can resolve names ahead of time.
+pub const fn field_key(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+pub const PROTO_KEY: u64 = field_key("__proto__");
+
+/// Longest `__proto__` chain `Table::lookup` will follow, which also stops
+/// a cyclic chain from hanging the VM.
+pub const MAX_PROTO_DEPTH: usize = 64;
+
+impl Default for Table {
+    fn default() -> Self {
+        Table::new()
+    }
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Map::new()
+    }
+}
+
+impl Tuple {
+    pub fn new(items: Vec<Value>) -> Tuple {
+        let items: Rc<[RefCell<Value>]> = items.into_iter().map(RefCell::new).collect();
+        gc::track(Node::Tuple(Rc::downgrade(&items)));
+        Tuple { items }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value> {
+        self.items.get(index).map(|v| v.borrow().clone())
+    }
+
+    /// Replaces an item, for loaders that have to build a tuple before
+    /// everything it holds (a module whose functions refer back to it).
+    pub(crate) fn set(&self, index: usize, val: Value) {
+        *self.items[index].borrow_mut() = val;
+    }
+
+    pub fn downgrade(&self) -> TupleWeak {
+        TupleWeak {
+            weakref: Rc::downgrade(&self.items),
+        }
+    }
+}
+
+impl TupleWeak {
+    pub fn upgrade(&self) -> Option<Tuple> {
+        self.weakref.upgrade().map(|items| Tuple { items })
+    }
+}
+
+impl Variant {
+    pub fn new(tag: Tag, payload: Value) -> Variant {
+        Variant {
+            tag,
+            payload: Rc::new(payload),
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    pub fn payload(&self) -> &Value {
+        &self.payload
+    }
+
+    pub fn some(payload: Value) -> Variant {
+        Variant::new(SOME, payload)
+    }
+
+    pub fn none() -> Variant {
+        Variant::new(NONE, Value::None)
+    }
+
+    pub fn ok(payload: Value) -> Variant {
+        Variant::new(OK, payload)
+    }
+
+    pub fn err(payload: Value) -> Variant {
+        Variant::new(ERR, payload)
+    }
+}
+
+// Script-level Option and Result share tags, so that a single `Try` op can
+// propagate either: the success arms (`Some`, `Ok`) are tag 0 and the
+// failure arms (`None`, `Err`) are tag 1.
+pub const SOME: Tag = 0;
+pub const OK: Tag = 0;
+pub const NONE: Tag = 1;
+pub const ERR: Tag = 1;
+
+impl<T: Into<Value>, E: Into<Value>> From<Result<T, E>> for Variant {
+    fn from(t: Result<T, E>) -> Self {
+        match t {
+            Ok(t) => Variant::ok(t.into()),
+            Err(e) => Variant::err(e.into()),
+        }
+    }
+}
+
 macro_rules! create_value_enum {
     ($($n:ident),+) => {
         #[derive(Clone)]
@@ -61,7 +1049,7 @@ macro_rules! create_value_enum {
         }
 
         #[repr(u8)]
-        #[derive(PartialEq)]
+        #[derive(Clone, Copy, PartialEq)]
         pub enum ValueType {
             None,
             $($n),+
@@ -74,6 +1062,15 @@ macro_rules! create_value_enum {
                     $(ValueType::$n => stringify!($n)),+
                 }
             }
+
+            /// The inverse of `as_str`.
+            pub fn from_name(name: &str) -> Option<ValueType> {
+                match name {
+                    "None" => Some(ValueType::None),
+                    $(stringify!($n) => Some(ValueType::$n),)+
+                    _ => None,
+                }
+            }
         }
 
         $(create_value_enum!(conversion $n);)+
@@ -114,7 +1111,54 @@ macro_rules! create_value_enum {
 }
 
 create_value_enum! {
-    Integer, Real, Tuple, TupleWeak, Table, List, Buffer, Function, NativeFn, Unknown
+    Integer, Real, Decimal, Str, Symbol, Timestamp, Duration, Tuple, TupleWeak, Table, Record, Map, List, Buffer, Variant, Interface, Iter, Function, NativeFn, Coroutine, Unknown, Closure
+}
+
+impl Value {
+    /// `None` and numeric zeros are false; everything else is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::None => false,
+            Value::Integer(i) => *i != 0,
+            Value::Real(r) => *r != 0.0,
+            Value::Decimal(d) => d.units() != 0,
+            _ => true,
+        }
+    }
+
+    /// The iteration protocol, which `ForIter` loops follow. An `Iter`
+    /// iterates itself; the other iterables iterate a snapshot of their
+    /// items: a `List`'s or `Tuple`'s items, a `Map`'s entries (see
+    /// `Map::iter`), a `Str`'s characters as one-character `Str`s, and a
+    /// `Buffer`'s bytes as `Integer`s. `None` for values that aren't
+    /// iterable.
+    pub fn iter(&self) -> Option<Iter> {
+        Some(match self {
+            Value::Iter(it) => it.clone(),
+            Value::List(l) => Iter::over(l.to_vec()),
+            Value::Tuple(t) => Iter::over((0..t.len()).filter_map(|i| t.get(i)).collect()),
+            Value::Map(m) => m.iter(),
+            Value::Str(s) => {
+                let chars = s.chars().map(|c| Value::Str(c.to_string().into()));
+                Iter::over(chars.collect())
+            }
+            Value::Buffer(b) => {
+                let bytes = b
+                    .to_vec()
+                    .into_iter()
+                    .map(|byte| Value::Integer(byte as i64));
+                Iter::over(bytes.collect())
+            }
+            _ => return None,
+        })
+    }
+
+    pub fn type_key(&self) -> TypeKey {
+        match self {
+            Value::Unknown(u) => TypeKey::Host((**u).type_id()),
+            _ => TypeKey::Builtin(self.get_type()),
+        }
+    }
 }
 
 pub struct ValueTryIntoError {
@@ -132,6 +1176,60 @@ impl Identity for Unknown {
     }
 }
 
+impl Identity for Tuple {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.items).cast::<()>() as usize
+    }
+}
+
+impl Identity for Function {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.ops).cast::<()>() as usize
+    }
+}
+
+impl Identity for Closure {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.upvalues).cast::<()>() as usize
+    }
+}
+
+impl Identity for List {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.items).cast::<()>() as usize
+    }
+}
+
+impl Identity for Map {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.inner).cast::<()>() as usize
+    }
+}
+
+impl Identity for Table {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.inner).cast::<()>() as usize
+    }
+}
+
+impl Identity for Buffer {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.items).cast::<()>() as usize
+    }
+}
+
+impl Identity for Iter {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.next).cast::<()>() as usize
+    }
+}
+
+impl Identity for Interface {
+    fn identity(&self) -> usize {
+        Rc::as_ptr(&self.inner).cast::<()>() as usize
+    }
+}
+
 impl From<bool> for Value {
     fn from(t: bool) -> Self {
         match t {
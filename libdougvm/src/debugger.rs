@@ -0,0 +1,196 @@
+//! A step debugger. `Debugger` wraps a `VirtualMachine` and runs it an op
+//! at a time, pausing at breakpoints and after steps so the host can look
+//! at the call frames, locals and value stack in between.
+//!
+//! Locations are a function's identity (see `datamodel::Identity`) and an
+//! op index in it. A breakpoint pauses the VM before that op runs, however
+//! the VM got there: falling through, jumping, calling or unwinding to a
+//! handler. Resuming from a breakpoint runs its op rather than pausing
+//! again.
+//!
+//! Every call gets a frame while debugging; the leaf call fast path (see
+//! `leaf`) is turned off so callees can be stepped into. A `Store` of a
+//! call's result runs as part of the return (see `ReturnSlot`), so it can't
+//! be paused at.
+
+use std::collections::HashSet;
+
+use crate::datamodel::{Function, Identity, Value};
+use crate::{CallFrame, VirtualMachine, VmError, VmState};
+
+/// Why the debugger handed control back.
+pub enum Stop {
+    /// The VM is at a breakpoint, before its op runs.
+    Breakpoint,
+    /// The step finished.
+    Stepped,
+    /// The VM stopped as `run_until_blocked` would, by exiting, waiting on
+    /// a stream, or tripping its watchdog.
+    State(VmState),
+}
+
+pub struct Debugger {
+    vm: VirtualMachine,
+    breakpoints: HashSet<(usize, usize)>,
+    /// Whether the VM is paused at a breakpoint, so the next op runs.
+    at_breakpoint: bool,
+}
+
+impl Debugger {
+    pub fn new(mut vm: VirtualMachine) -> Debugger {
+        vm.set_leaf_calls(false);
+        Debugger {
+            vm,
+            breakpoints: HashSet::new(),
+            at_breakpoint: false,
+        }
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn into_inner(self) -> VirtualMachine {
+        self.vm
+    }
+
+    /// Returns whether the breakpoint is new.
+    pub fn set_breakpoint(&mut self, function_id: usize, op_index: usize) -> bool {
+        self.breakpoints.insert((function_id, op_index))
+    }
+
+    /// Returns whether there was such a breakpoint.
+    pub fn clear_breakpoint(&mut self, function_id: usize, op_index: usize) -> bool {
+        self.breakpoints.remove(&(function_id, op_index))
+    }
+
+    /// The innermost frame, or `None` once the VM has exited.
+    pub fn frame(&self) -> Option<&CallFrame> {
+        self.vm.frames().next()
+    }
+
+    /// The innermost frame's function and the op it runs next.
+    pub fn location(&self) -> Option<(&Function, usize)> {
+        self.frame().map(|f| (&f.function, f.cursor))
+    }
+
+    /// The innermost frame's locals, the module in local 0 included.
+    pub fn locals(&self) -> &[Value] {
+        self.frame().map_or(&[], |f| f.stack.locals())
+    }
+
+    /// The innermost frame's value stack, bottom first.
+    pub fn stack(&self) -> &[Value] {
+        self.frame().map_or(&[], |f| f.stack.values())
+    }
+
+    /// Frames in the call stack.
+    pub fn depth(&self) -> usize {
+        self.vm.depth
+    }
+
+    /// Runs until a breakpoint or the VM stops.
+    pub fn resume(&mut self) -> Result<Stop, VmError> {
+        self.run_while(|_| true)
+    }
+
+    /// Runs one op, following it into a callee.
+    pub fn step_into(&mut self) -> Result<Stop, VmError> {
+        self.run_while(|_| false)
+    }
+
+    /// Runs one op, or a whole call if the op makes one.
+    pub fn step_over(&mut self) -> Result<Stop, VmError> {
+        let depth = self.depth();
+        self.run_while(|d| d > depth)
+    }
+
+    /// Runs until the current frame returns or unwinds.
+    pub fn step_out(&mut self) -> Result<Stop, VmError> {
+        let depth = self.depth();
+        self.run_while(|d| d >= depth)
+    }
+
+    /// Runs ops until `more`, given the depth after each, returns false.
+    fn run_while(&mut self, more: impl Fn(usize) -> bool) -> Result<Stop, VmError> {
+        loop {
+            if !std::mem::take(&mut self.at_breakpoint) && self.is_at_breakpoint() {
+                self.at_breakpoint = true;
+                return Ok(Stop::Breakpoint);
+            }
+            match self.vm.run_op()? {
+                VmState::Running => {}
+                state => return Ok(Stop::State(state)),
+            }
+            if !more(self.depth()) {
+                return Ok(Stop::Stepped);
+            }
+        }
+    }
+
+    fn is_at_breakpoint(&self) -> bool {
+        match self.location() {
+            Some((function, cursor)) => self.breakpoints.contains(&(function.identity(), cursor)),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Add, Call, Push, Return, Store};
+    use crate::testing::function;
+
+    #[test]
+    fn breakpoints_and_steps() {
+        let add = function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(2)).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(add.clone().into()).into(),
+            Call(0).into(),
+            Store(1).into(),
+            Push(add.clone().into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let mut debugger = Debugger::new(crate::VirtualMachine::new(main.clone()));
+        assert!(debugger.set_breakpoint(add.identity(), 2));
+        assert!(debugger.set_breakpoint(main.identity(), 0));
+
+        // a breakpoint on the first op still pauses
+        assert!(matches!(debugger.resume(), Ok(Stop::Breakpoint)));
+        assert!(matches!(debugger.resume(), Ok(Stop::Breakpoint)));
+        assert_eq!(debugger.depth(), 2);
+        assert!(matches!(
+            debugger.stack(),
+            [Value::Integer(1), Value::Integer(2)]
+        ));
+
+        assert!(matches!(debugger.step_over(), Ok(Stop::Stepped)));
+        assert!(matches!(debugger.stack(), [Value::Integer(3)]));
+        // the result is stored on the way out
+        assert!(matches!(debugger.step_out(), Ok(Stop::Stepped)));
+        assert_eq!(debugger.location().unwrap().1, 3);
+        assert!(matches!(debugger.locals(), [_, Value::Integer(3)]));
+
+        // steps that run several ops still stop at breakpoints on the way
+        assert!(debugger.clear_breakpoint(add.identity(), 2));
+        assert!(matches!(debugger.step_over(), Ok(Stop::Stepped)));
+        assert!(matches!(debugger.step_into(), Ok(Stop::Stepped)));
+        assert_eq!(debugger.depth(), 2);
+        assert!(debugger.set_breakpoint(add.identity(), 2));
+        assert!(matches!(debugger.step_out(), Ok(Stop::Breakpoint)));
+        assert!(matches!(debugger.step_out(), Ok(Stop::Stepped)));
+        assert_eq!(debugger.depth(), 1);
+        assert!(matches!(
+            debugger.resume(),
+            Ok(Stop::State(VmState::Exited(Value::Integer(3))))
+        ));
+        assert!(debugger.frame().is_none());
+    }
+}
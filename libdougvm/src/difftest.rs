@@ -0,0 +1,281 @@
+//! Differential testing between tiers. The same program is run through the
+//! baseline interpreter and through each optimized tier with the same
+//! arguments, and the results and the trace of native calls are
+//! compared, so a miscompiling pass shows up as a `Divergence` instead of
+//! a wrong answer somewhere downstream.
+
+use std::mem::discriminant;
+
+use crate::bytecode::ops::{Call, Push, Return};
+use crate::bytecode::speculate::specialize_ints;
+use crate::bytecode::{OpAction, OpError};
+use crate::datamodel::{Function, Identity, Tuple, Value};
+use crate::optimize::constprop::propagate;
+use crate::optimize::escape::scalar_replace;
+use crate::optimize::fuse::fuse;
+use crate::optimize::inline::{inline, InlineConfig};
+use crate::optimize::ranges::narrow;
+use crate::{VirtualMachine, VmState};
+
+/// A way of turning a function into the form some tier would run.
+pub struct Tier {
+    pub name: &'static str,
+    pub build: fn(&Function) -> Function,
+}
+
+/// Every optimizing transform in the tree, alone and combined.
+pub fn tiers() -> Vec<Tier> {
+    vec![
+        Tier {
+            name: "constprop",
+            build: propagate,
+        },
+        Tier {
+            name: "inline",
+            build: |f| inline(f, &InlineConfig::default()),
+        },
+        Tier {
+            name: "escape",
+            build: scalar_replace,
+        },
+        Tier {
+            name: "speculate",
+            build: |f| Function {
+                module: f.module.clone(),
+                ops: specialize_ints(&f.ops).into(),
+            },
+        },
+        Tier {
+            name: "ranges",
+            build: narrow,
+        },
+        Tier {
+            name: "fuse",
+            build: fuse,
+        },
+        Tier {
+            name: "all",
+            build: |f| {
+                let f = scalar_replace(&inline(&propagate(f), &InlineConfig::default()));
+                let f = narrow(&f);
+                fuse(&Function {
+                    module: f.module.clone(),
+                    ops: specialize_ints(&f.ops).into(),
+                })
+            },
+        },
+    ]
+}
+
+pub enum Outcome {
+    Returned(Value),
+    Failed(OpError),
+    /// Ran out of steps; nothing is compared against such a run.
+    OutOfSteps,
+}
+
+pub struct Run {
+    pub outcome: Outcome,
+    /// Each native called, by address, with its arguments in call order.
+    pub natives: Vec<(usize, Vec<Value>)>,
+}
+
+/// Calls `func` with `args` (first argument first) on a fresh VM, giving
+/// up after `steps` ops.
+pub fn run(func: &Function, args: &[Value], steps: usize) -> Run {
+    let mut ops: Vec<_> = args.iter().map(|a| Push(a.clone()).into()).collect();
+    ops.push(Push(func.clone().into()).into());
+    ops.push(Call(args.len() as u8).into());
+    ops.push(Return.into());
+    let mut vm = VirtualMachine::new(Function {
+        module: Tuple::new(Vec::new()),
+        ops: ops.into(),
+    });
+    let mut natives = Vec::new();
+    let outcome = (|| {
+        for _ in 0..steps {
+            let action = match vm.step() {
+                Ok(action) => action,
+                Err(e) => return Outcome::Failed(e),
+            };
+            if let OpAction::CallNative(f, args) = &action {
+                natives.push((*f as usize, args.iter().rev().cloned().collect()));
+            }
+            match vm.process(action) {
+                Ok(VmState::Running) => {}
+                Ok(VmState::Exited(val)) => return Outcome::Returned(val),
+                // no host services streams here, so it would wait forever
+                Ok(VmState::WaitingForSink(_) | VmState::WaitingForSource(_)) => break,
+                Ok(VmState::Suspended(_)) => break,
+                Ok(VmState::HotLoop(_)) => return Outcome::Failed(OpError::HotLoop),
+                Ok(VmState::OutOfFuel) => return Outcome::Failed(OpError::OutOfFuel),
+                Err(e) => return Outcome::Failed(e),
+            }
+        }
+        Outcome::OutOfSteps
+    })();
+    Run { outcome, natives }
+}
+
+/// Structural equality, as far as it can be observed across tiers:
+/// functions are compared by type only, since tiers rebuild them.
+pub fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::None, Value::None) => true,
+        (Value::Integer(a), Value::Integer(b)) => a == b,
+        (Value::Real(a), Value::Real(b)) => a.to_bits() == b.to_bits() || a.is_nan() && b.is_nan(),
+        (Value::Decimal(a), Value::Decimal(b)) => a.units() == b.units() && a.scale() == b.scale(),
+        (Value::Str(a), Value::Str(b)) => a == b,
+        (Value::Timestamp(a), Value::Timestamp(b)) => a == b,
+        (Value::Duration(a), Value::Duration(b)) => a.0 == b.0,
+        (Value::Buffer(a), Value::Buffer(b)) => a.to_vec() == b.to_vec(),
+        (Value::Variant(a), Value::Variant(b)) => {
+            a.tag() == b.tag() && same(a.payload(), b.payload())
+        }
+        (Value::Tuple(a), Value::Tuple(b)) => {
+            a.len() == b.len() && (0..a.len()).all(|i| same(&a.get(i).unwrap(), &b.get(i).unwrap()))
+        }
+        (Value::List(a), Value::List(b)) => {
+            let (a, b) = (a.to_vec(), b.to_vec());
+            a.len() == b.len() && a.iter().zip(&b).all(|(a, b)| same(a, b))
+        }
+        (Value::Table(a), Value::Table(b)) => {
+            let (a, b) = (a.entries(), b.entries());
+            a.len() == b.len()
+                && a.iter()
+                    .zip(&b)
+                    .all(|(a, b)| a.0 == b.0 && same(&a.1, &b.1))
+        }
+        (Value::Map(a), Value::Map(b)) => {
+            let (a, b) = (a.entries(), b.entries());
+            a.len() == b.len()
+                && a.iter()
+                    .zip(&b)
+                    .all(|(a, b)| same(&a.0, &b.0) && same(&a.1, &b.1))
+        }
+        (Value::NativeFn(a), Value::NativeFn(b)) => *a as usize == *b as usize,
+        (Value::Unknown(a), Value::Unknown(b)) => a.identity() == b.identity(),
+        (a, b) => a.get_type() == b.get_type(),
+    }
+}
+
+fn agree(a: &Run, b: &Run) -> bool {
+    let outcomes = match (&a.outcome, &b.outcome) {
+        (Outcome::OutOfSteps, _) | (_, Outcome::OutOfSteps) => return true,
+        (Outcome::Returned(a), Outcome::Returned(b)) => same(a, b),
+        (Outcome::Failed(a), Outcome::Failed(b)) => discriminant(a) == discriminant(b),
+        _ => false,
+    };
+    let natives = a.natives.len() == b.natives.len()
+        && a.natives.iter().zip(&b.natives).all(|(a, b)| {
+            a.0 == b.0 && a.1.len() == b.1.len() && a.1.iter().zip(&b.1).all(|(a, b)| same(a, b))
+        });
+    outcomes && natives
+}
+
+/// A tier whose run disagreed with the baseline.
+pub struct Divergence {
+    pub tier: &'static str,
+    pub baseline: Run,
+    pub optimized: Run,
+}
+
+/// Runs `func` at baseline and through every tier, stopping at the first
+/// tier that disagrees.
+pub fn check(
+    func: &Function,
+    args: &[Value],
+    tiers: &[Tier],
+    steps: usize,
+) -> Result<(), Box<Divergence>> {
+    for tier in tiers {
+        let baseline = run(func, args, steps);
+        let optimized = run(&(tier.build)(func), args, steps);
+        if !agree(&baseline, &optimized) {
+            return Err(Box::new(Divergence {
+                tier: tier.name,
+                baseline,
+                optimized,
+            }));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::datamodel::field_key;
+    use crate::testing::function;
+
+    fn first(args: Vec<Value>) -> Value {
+        args.last().cloned().unwrap_or(Value::None)
+    }
+
+    #[test]
+    fn tiers_agree_with_baseline() {
+        // add(a, b) = a + b
+        let add = function(vec![
+            Store(1).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        // f(x) { t = {}; t.v = add(x, 2); first(t.v * add(3, 4)) / (x - x) }
+        let x = field_key("v");
+        let f = function(vec![
+            Store(1).into(),
+            NewTable.into(),
+            Store(2).into(),
+            Load(2).into(),
+            Load(1).into(),
+            Push(Value::Integer(2)).into(),
+            Push(add.clone().into()).into(),
+            Call(2).into(),
+            SetField::new(x).into(),
+            Load(2).into(),
+            GetField::new(x).into(),
+            Push(Value::Integer(3)).into(),
+            Push(Value::Integer(4)).into(),
+            Push(add.into()).into(),
+            Call(2).into(),
+            Mul.into(),
+            Push(Value::NativeFn(first)).into(),
+            Call(1).into(),
+            Load(1).into(),
+            Load(1).into(),
+            Sub.into(),
+            Div.into(),
+            Return.into(),
+        ]);
+        for arg in [Value::Integer(5), Value::Real(1.5)] {
+            let result = check(&f, &[arg], &tiers(), 10_000);
+            assert!(result.is_ok(), "{} diverged", result.err().unwrap().tier);
+        }
+        let run = run(&f, &[Value::Integer(5)], 10_000);
+        assert!(matches!(
+            run.outcome,
+            Outcome::Failed(OpError::DivideByZero)
+        ));
+        assert!(
+            matches!(&run.natives[..], [(_, args)] if matches!(args[..], [Value::Integer(49)]))
+        );
+    }
+
+    #[test]
+    fn reports_a_miscompiling_tier() {
+        let f = function(vec![Push(Value::Integer(1)).into(), Return.into()]);
+        let broken = Tier {
+            name: "broken",
+            build: |f| Function {
+                module: f.module.clone(),
+                ops: vec![Push(Value::Integer(2)).into(), Return.into()].into(),
+            },
+        };
+        let divergence = check(&f, &[], &[broken], 100).err().unwrap();
+        assert_eq!(divergence.tier, "broken");
+    }
+}
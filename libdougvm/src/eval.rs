@@ -0,0 +1,154 @@
+//! Interactive evaluation, one compiled line at a time, as a REPL needs.
+//!
+//! An `EvalSession` keeps a global frame whose locals outlive each line:
+//! every `Function` given to `eval` runs as the outermost frame with the
+//! locals the previous line left, so a line that stores local 3 defines a
+//! global that later lines load. Local 0 is the exception, holding the
+//! running line's own module as in any frame. The session's VM, with its
+//! host functions, modules and source maps, is shared by every line too.
+//!
+//! A line that fails keeps whatever it stored before failing, as it would
+//! in a script; the frames it was in are dropped, so the next line starts
+//! from the global frame again.
+
+use std::mem::swap;
+
+use crate::datamodel::{Function, Tuple, Value};
+use crate::{CallFrame, CallStack, VirtualMachine, VmConfig, VmError};
+
+pub struct EvalSession {
+    vm: VirtualMachine,
+    globals: CallStack,
+}
+
+impl EvalSession {
+    pub fn new() -> EvalSession {
+        EvalSession::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> EvalSession {
+        let mut globals = CallStack::new();
+        globals.set_local_reads(config.local_reads);
+        let empty = Function {
+            module: Tuple::new(Vec::new()),
+            ops: Vec::new().into(),
+        };
+        let mut vm = VirtualMachine::with_config(empty, config);
+        vm.keep_locals = true;
+        EvalSession { vm, globals }
+    }
+
+    /// The VM lines run in, for registering host functions and modules.
+    pub fn vm(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+
+    /// Runs `line` against the globals, returning what it returns.
+    pub fn eval(&mut self, line: Function) -> Result<Value, VmError> {
+        let mut frame = Box::new(CallFrame::new(line));
+        let module = frame.stack.locals()[0].clone();
+        swap(&mut frame.stack, &mut self.globals);
+        frame.stack.truncate(0);
+        frame.stack.store(0, module);
+        self.vm.start(frame);
+        let result = self.vm.run_until_exited();
+        let kept = self.vm.kept_locals.take();
+        if let Some(globals) = kept.or_else(|| self.vm.take_outermost_locals()) {
+            self.globals = globals;
+        }
+        self.vm.frame = None;
+        result
+    }
+
+    /// The global in local `index`, if a line has defined it.
+    pub fn global(&self, index: u8) -> Option<&Value> {
+        if !self.globals.is_stored(index) {
+            return None;
+        }
+        self.globals.locals().get(index as usize)
+    }
+
+    /// Defines a global before any line uses it, as a host binding.
+    pub fn set_global(&mut self, index: u8, val: Value) {
+        self.globals.store(index, val);
+    }
+}
+
+impl Default for EvalSession {
+    fn default() -> EvalSession {
+        EvalSession::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::{Op, OpError};
+
+    fn line(ops: Vec<Op>) -> Function {
+        Function {
+            module: Tuple::new(Vec::new()),
+            ops: ops.into(),
+        }
+    }
+
+    #[test]
+    fn globals_outlive_each_line() {
+        let mut session = EvalSession::new();
+        session.set_global(2, Value::Integer(10));
+        // x = 5; x
+        let define = line(vec![
+            Push(Value::Integer(5)).into(),
+            Store(1).into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        assert!(matches!(session.eval(define), Ok(Value::Integer(5))));
+        // x + y
+        let sum = line(vec![
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        assert!(matches!(session.eval(sum), Ok(Value::Integer(15))));
+
+        // x = 7; then a bad load fails the line but keeps the store
+        let fails = line(vec![
+            Push(Value::Integer(7)).into(),
+            Store(1).into(),
+            Load(9).into(),
+            Return.into(),
+        ]);
+        let err = session.eval(fails).err().unwrap();
+        assert!(matches!(err.error, OpError::LocalRead(9, _)));
+        assert!(matches!(session.global(1), Some(Value::Integer(7))));
+        assert!(session.global(9).is_none());
+        let read = line(vec![Load(1).into(), Return.into()]);
+        assert!(matches!(session.eval(read), Ok(Value::Integer(7))));
+    }
+
+    #[test]
+    fn tail_calls_keep_the_globals() {
+        let mut session = EvalSession::new();
+        session.vm().set_tail_calls(true);
+        session.vm().set_leaf_calls(false);
+        let define = line(vec![
+            Push(Value::Integer(42)).into(),
+            Store(3).into(),
+            Halt.into(),
+        ]);
+        assert!(session.eval(define).is_ok());
+        // f(7), whose frame would replace the globals if tail called
+        let f = line(vec![Store(1).into(), Load(1).into(), Return.into()]);
+        let call = line(vec![
+            Push(Value::Integer(7)).into(),
+            Push(f.into()).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        assert!(matches!(session.eval(call), Ok(Value::Integer(7))));
+        assert!(matches!(session.global(3), Some(Value::Integer(42))));
+    }
+}
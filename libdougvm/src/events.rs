@@ -0,0 +1,101 @@
+//! Host-to-script events. Scripts subscribe handler functions to event
+//! names through the `natives::events` natives; the host queues events
+//! with `Scheduler::emit`, and the scheduler dispatches them between
+//! slices, where no task is mid-op, by spawning each handler as a task
+//! called with the event's payload.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+
+use crate::datamodel::{Function, Value};
+
+pub type HandlerId = u64;
+
+struct Handler {
+    id: HandlerId,
+    func: Function,
+    priority: u8,
+}
+
+/// Subscriptions and the events waiting to be dispatched.
+#[derive(Default)]
+pub struct EventBus {
+    handlers: HashMap<String, Vec<Handler>>,
+    queue: VecDeque<(String, Value)>,
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self, id: HandlerId, name: String, func: Function, priority: u8) {
+        let handler = Handler { id, func, priority };
+        self.handlers.entry(name).or_default().push(handler);
+    }
+
+    pub fn unsubscribe(&mut self, id: HandlerId) -> bool {
+        let mut found = false;
+        for handlers in self.handlers.values_mut() {
+            let before = handlers.len();
+            handlers.retain(|h| h.id != id);
+            found |= handlers.len() != before;
+        }
+        self.handlers.retain(|_, handlers| !handlers.is_empty());
+        found
+    }
+
+    pub fn handlers(&self, name: &str) -> usize {
+        self.handlers.get(name).map_or(0, |h| h.len())
+    }
+
+    pub fn emit(&mut self, name: &str, payload: Value) {
+        self.queue.push_back((name.to_string(), payload));
+    }
+
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Empties the queue, returning a call for each handler of each event,
+    /// in the order the events were emitted and the handlers subscribed.
+    /// Events nobody handles are dropped.
+    pub fn drain(&mut self) -> Vec<(Function, Value, u8)> {
+        let mut calls = Vec::new();
+        for (name, payload) in self.queue.drain(..) {
+            for h in self.handlers.get(&name).into_iter().flatten() {
+                calls.push((h.func.clone(), payload.clone(), h.priority));
+            }
+        }
+        calls
+    }
+}
+
+pub(crate) enum Request {
+    Subscribe {
+        id: HandlerId,
+        name: String,
+        func: Function,
+    },
+    Unsubscribe(HandlerId),
+}
+
+thread_local! {
+    static REQUESTS: RefCell<Vec<Request>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<HandlerId> = const { Cell::new(0) };
+}
+
+/// Queues a subscription, returning the id it will have.
+pub(crate) fn request_subscribe(name: String, func: Function) -> HandlerId {
+    let id = NEXT_ID.with(|n| {
+        let id = n.get();
+        n.set(id + 1);
+        id
+    });
+    REQUESTS.with(|r| r.borrow_mut().push(Request::Subscribe { id, name, func }));
+    id
+}
+
+pub(crate) fn request_unsubscribe(id: HandlerId) {
+    REQUESTS.with(|r| r.borrow_mut().push(Request::Unsubscribe(id)));
+}
+
+pub(crate) fn take_requests() -> Vec<Request> {
+    REQUESTS.with(|r| std::mem::take(&mut *r.borrow_mut()))
+}
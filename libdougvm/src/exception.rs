@@ -0,0 +1,205 @@
+//! Script-level error values. An exception is a `Tuple` of
+//! `(class, message, data)`: a `Str` naming its class, a `Str` describing
+//! it, and any value the thrower attaches. Errors the VM raises itself are
+//! converted with `from_op_error` when they are thrown into a script's
+//! handler, so scripts can catch classes of failure rather than matching
+//! on messages.
+//!
+//! The standard classes form a small hierarchy under `Error`:
+//!
+//! ```text
+//! Error
+//! ├── TypeError        a value of the wrong type or tag
+//! ├── KeyError         a missing table field
+//! ├── IndexError       an index out of range
+//! ├── ArithmeticError  overflow or division by zero
+//! ├── ImportError      a missing module or an import cycle
+//! └── NativeError      a failure reported by a native
+//! ```
+//!
+//! Scripts may throw classes of their own; those count as subclasses of
+//! `Error` only.
+
+use crate::bytecode::{spec, OpError};
+use crate::datamodel::{Str, Tuple, Value};
+
+pub const ERROR: &str = "Error";
+pub const TYPE_ERROR: &str = "TypeError";
+pub const KEY_ERROR: &str = "KeyError";
+pub const INDEX_ERROR: &str = "IndexError";
+pub const ARITHMETIC_ERROR: &str = "ArithmeticError";
+pub const IMPORT_ERROR: &str = "ImportError";
+pub const NATIVE_ERROR: &str = "NativeError";
+
+/// The class `class` directly derives from, or `None` for `Error`.
+pub fn parent(class: &str) -> Option<&'static str> {
+    match class {
+        ERROR => None,
+        _ => Some(ERROR),
+    }
+}
+
+/// Whether `class` is `ancestor` or derives from it.
+pub fn is_a(class: &str, ancestor: &str) -> bool {
+    let mut class = Some(class);
+    while let Some(c) = class {
+        if c == ancestor {
+            return true;
+        }
+        class = parent(c);
+    }
+    false
+}
+
+pub fn exception(class: &str, message: &str, data: Value) -> Value {
+    Tuple::new(vec![
+        Value::Str(Str::from(class)),
+        Value::Str(Str::from(message)),
+        data,
+    ])
+    .into()
+}
+
+/// A `NativeError`, for a native or host function to throw.
+pub fn native_error(message: &str) -> Value {
+    exception(NATIVE_ERROR, message, Value::None)
+}
+
+/// The parts of an exception, or `None` if `val` isn't one.
+pub fn parse(val: &Value) -> Option<(Str, Str, Value)> {
+    match val {
+        Value::Tuple(t) if t.len() == 3 => match (t.get(0)?, t.get(1)?, t.get(2)?) {
+            (Value::Str(class), Value::Str(message), data) => Some((class, message, data)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The exception a script handler sees for `e`, or `None` for the errors
+/// that stop the VM on the host's behalf (`Interrupted`, `Blocked`,
+/// `HotLoop` and `OutOfFuel`), which scripts can't catch.
+pub fn from_op_error(e: &OpError) -> Option<Value> {
+    let (class, message, data) = match e {
+        OpError::StackEmpty => (ERROR, "stack is empty".to_string(), Value::None),
+        OpError::LocalRead(i, name) => (
+            ERROR,
+            match name {
+                Some(name) => format!("local {} ({}) was never set", i, name),
+                None => format!("local {} was never set", i),
+            },
+            Value::Integer(*i as i64),
+        ),
+        OpError::IndexRead(i) | OpError::IndexWrite(i) => (
+            INDEX_ERROR,
+            format!("index {} is out of range", i),
+            Value::Integer(*i),
+        ),
+        OpError::FieldRead(key) | OpError::FieldWrite(key) => (
+            KEY_ERROR,
+            format!("no field {:#x}", key),
+            Value::Integer(*key as i64),
+        ),
+        OpError::KeyRead(key) => (
+            KEY_ERROR,
+            format!("no key {}", spec::render(key)),
+            key.clone(),
+        ),
+        OpError::IntoType(_) => (
+            TYPE_ERROR,
+            "value has the wrong type".to_string(),
+            Value::None,
+        ),
+        OpError::BadType(t) => (
+            TYPE_ERROR,
+            format!("unexpected {}", t.as_str()),
+            Value::Str(Str::from(t.as_str())),
+        ),
+        OpError::BadTag { found, expected } => (
+            TYPE_ERROR,
+            format!("expected tag {}, found {}", expected, found),
+            Value::Integer(*found as i64),
+        ),
+        OpError::NotImplemented(t) => (
+            TYPE_ERROR,
+            format!("{} doesn't implement this", t.as_str()),
+            Value::Str(Str::from(t.as_str())),
+        ),
+        OpError::NoModule(name) => (
+            IMPORT_ERROR,
+            format!("no module {}", name),
+            Value::Str(name.clone()),
+        ),
+        OpError::ImportCycle(name) => (
+            IMPORT_ERROR,
+            format!("import cycle through module {}", name),
+            Value::Str(name.clone()),
+        ),
+        OpError::NoHostFn(i) => (
+            NATIVE_ERROR,
+            format!("no host function {}", i),
+            Value::Integer(*i as i64),
+        ),
+        OpError::TooManyResources(limit) => (
+            NATIVE_ERROR,
+            format!("more than {} resources open", limit),
+            Value::Integer(*limit as i64),
+        ),
+        OpError::UpvalueRead(i) => (
+            ERROR,
+            format!("no upvalue {}", i),
+            Value::Integer(*i as i64),
+        ),
+        OpError::StackOverflow(limit) => (
+            ERROR,
+            format!("call stack deeper than {} frames", limit),
+            Value::Integer(*limit as i64),
+        ),
+        OpError::NotResumable => (
+            ERROR,
+            "coroutine is running or done".to_string(),
+            Value::None,
+        ),
+        OpError::NotInCoroutine => (ERROR, "yield outside a coroutine".to_string(), Value::None),
+        OpError::FellOffEnd => (
+            ERROR,
+            "ran off the end of a function".to_string(),
+            Value::None,
+        ),
+        OpError::Overflow => (
+            ARITHMETIC_ERROR,
+            "integer overflow".to_string(),
+            Value::None,
+        ),
+        OpError::DivideByZero => (
+            ARITHMETIC_ERROR,
+            "division by zero".to_string(),
+            Value::None,
+        ),
+        // rethrown as it was
+        OpError::Uncaught(val) => return Some(val.clone()),
+        OpError::Interrupted | OpError::Blocked | OpError::HotLoop | OpError::OutOfFuel => {
+            return None
+        }
+    };
+    Some(exception(class, &message, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::ValueType;
+
+    #[test]
+    fn op_errors_map_into_the_hierarchy() {
+        let val = from_op_error(&OpError::BadType(ValueType::Str)).unwrap();
+        let (class, message, _) = parse(&val).unwrap();
+        assert!(is_a(&class, TYPE_ERROR) && is_a(&class, ERROR));
+        assert!(!is_a(&class, KEY_ERROR));
+        assert_eq!(&*message, "unexpected Str");
+        let val = from_op_error(&OpError::IndexRead(7)).unwrap();
+        assert!(matches!(parse(&val), Some((c, _, Value::Integer(7))) if &*c == INDEX_ERROR));
+        assert!(from_op_error(&OpError::Interrupted).is_none());
+        assert!(is_a("MyError", ERROR) && !is_a(ERROR, "MyError"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Finalizers for userdata: host values (`Value::Unknown`) that need
+//! cleanup, such as closing a file or freeing a GPU handle, once a script
+//! can no longer reach them.
+//!
+//! The rules:
+//!
+//! - Dropping the last reference to a `Userdata` queues its finalizer; it
+//!   never runs inside the drop, which may happen mid-op with the VM's
+//!   tables or tuples borrowed.
+//! - Queued finalizers run at safe points: after a native call or a return
+//!   in any VM on the thread, when a VM is dropped, and whenever the host
+//!   calls `run_pending`.
+//! - They run in the order they were queued. Userdata freed by a finalizer
+//!   is queued behind the rest and runs in the same pass.
+//! - A finalizer that reaches a safe point itself, e.g. by running a
+//!   script, doesn't start another pass: `run_pending` is a no-op while a
+//!   pass is running, so finalizers never nest.
+//! - Each finalizer runs at most once, and owns the value it finalizes.
+//!
+//! Values are reference counted, so userdata caught in a reference cycle
+//! (a table holding itself, say) is only finalized once `gc` breaks the
+//! cycle.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::datamodel::Value;
+use crate::resources::{self, Ticket};
+
+thread_local! {
+    static PENDING: RefCell<VecDeque<Box<dyn FnOnce()>>> = RefCell::new(VecDeque::new());
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A host value with a finalizer.
+pub struct Userdata<T: 'static> {
+    value: Option<T>,
+    finalizer: Option<Box<dyn FnOnce(T)>>,
+    /// Its entry in the ledger of the VM that opened it (see `resources`).
+    ticket: Option<Ticket>,
+}
+
+impl<T: 'static> Userdata<T> {
+    pub fn get(&self) -> &T {
+        // only `drop` takes the value
+        self.value.as_ref().unwrap()
+    }
+
+    /// The userdata `val` holds, if it holds a `Userdata<T>`.
+    pub fn from_value(val: &Value) -> Option<Rc<Userdata<T>>> {
+        match val {
+            Value::Unknown(u) => u.clone().downcast::<Userdata<T>>().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: 'static> Drop for Userdata<T> {
+    fn drop(&mut self) {
+        if let (Some(value), Some(finalizer)) = (self.value.take(), self.finalizer.take()) {
+            // it stays open until it is actually finalized
+            let ticket = self.ticket.take();
+            PENDING.with(|p| {
+                p.borrow_mut().push_back(Box::new(move || {
+                    finalizer(value);
+                    drop(ticket);
+                }))
+            });
+        }
+    }
+}
+
+/// Wraps `value` as userdata that `finalizer` cleans up.
+pub fn userdata<T: 'static>(value: T, finalizer: impl FnOnce(T) + 'static) -> Value {
+    let data: Rc<dyn Any> = Rc::new(Userdata {
+        value: Some(value),
+        finalizer: Some(Box::new(finalizer)),
+        ticket: resources::track(std::any::type_name::<T>()),
+    });
+    Value::Unknown(data)
+}
+
+/// Finalizers queued on this thread and not yet run.
+pub fn pending() -> usize {
+    PENDING.with(|p| p.borrow().len())
+}
+
+/// Runs the queued finalizers, returning how many ran; 0 if called from
+/// inside a finalizer.
+pub fn run_pending() -> usize {
+    if RUNNING.with(|r| r.replace(true)) {
+        return 0;
+    }
+    let mut ran = 0;
+    // the queue isn't borrowed while a finalizer runs, so it can queue more
+    while let Some(finalizer) = PENDING.with(|p| p.borrow_mut().pop_front()) {
+        finalizer();
+        ran += 1;
+    }
+    RUNNING.with(|r| r.set(false));
+    ran
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Push, Return, Store};
+    use crate::datamodel::{Function, Tuple};
+    use crate::VirtualMachine;
+
+    type Log = Rc<RefCell<Vec<&'static str>>>;
+
+    fn tracked(log: &Log, name: &'static str) -> Value {
+        let log = log.clone();
+        userdata(name, move |name| log.borrow_mut().push(name))
+    }
+
+    #[test]
+    fn finalizers_run_in_order_at_safe_points() {
+        let log = Log::default();
+        let (a, b) = (tracked(&log, "a"), tracked(&log, "b"));
+        assert_eq!(*Userdata::<&str>::from_value(&a).unwrap().get(), "a");
+        drop(a);
+        drop(b);
+        assert!(log.borrow().is_empty());
+        assert_eq!(pending(), 2);
+
+        // one finalizer freeing another, and trying to nest a pass
+        let inner = tracked(&log, "inner");
+        let outer = {
+            let log = log.clone();
+            userdata(inner, move |inner| {
+                drop(inner);
+                assert_eq!(run_pending(), 0);
+                log.borrow_mut().push("outer");
+            })
+        };
+        drop(outer);
+        assert_eq!(run_pending(), 4);
+        assert_eq!(*log.borrow(), ["a", "b", "outer", "inner"]);
+    }
+
+    #[test]
+    fn dropping_a_vm_finalizes_its_values() {
+        let log = Log::default();
+        let mut vm = VirtualMachine::new(Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(tracked(&log, "local")).into(),
+                Store(1).into(),
+                Push(Value::None).into(),
+                Return.into(),
+            ]
+            .into(),
+        });
+        assert!(vm.step().is_ok());
+        drop(vm);
+        assert_eq!(*log.borrow(), ["local"]);
+    }
+}
@@ -0,0 +1,210 @@
+//! Frozen values: a deep, immutable copy of a script value held in `Arc`s,
+//! so one segment can be read by VMs on any number of threads without
+//! copying it per worker. Scripts see a segment through a handle (see
+//! `natives::frozen`) and read it piece by piece; nested aggregates come
+//! back as further handles into the same segment, and only the leaves a
+//! script actually reads are turned back into ordinary values.
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::datamodel::{
+    Buffer, Decimal, Duration, List, Map, Str, Table, Tag, Timestamp, Tuple, Value, Variant,
+};
+
+/// A frozen value. Only data can be frozen: functions, iterators and
+/// host objects are rejected by `Frozen::freeze`.
+pub enum Frozen {
+    None,
+    Integer(i64),
+    Real(f64),
+    Decimal(Decimal),
+    Str(Arc<str>),
+    Timestamp(Timestamp),
+    Duration(Duration),
+    Buffer(Arc<[u8]>),
+    Tuple(Arc<[Frozen]>),
+    List(Arc<[Frozen]>),
+    Table(Arc<[(u64, Frozen)]>),
+    /// Entries in the map's insertion order.
+    Map(Arc<[(Frozen, Frozen)]>),
+    Variant(Tag, Arc<Frozen>),
+}
+
+/// Deepest nesting `Frozen::freeze` will follow, so a cyclic list errors
+/// out instead of overflowing the stack.
+pub const MAX_FREEZE_DEPTH: usize = 256;
+
+impl Frozen {
+    pub fn freeze(val: &Value) -> Option<Frozen> {
+        Frozen::freeze_at(val, 0)
+    }
+
+    fn freeze_at(val: &Value, depth: usize) -> Option<Frozen> {
+        if depth > MAX_FREEZE_DEPTH {
+            return None;
+        }
+        let seq = |items: Vec<Value>| -> Option<Arc<[Frozen]>> {
+            items
+                .iter()
+                .map(|v| Frozen::freeze_at(v, depth + 1))
+                .collect()
+        };
+        Some(match val {
+            Value::None => Frozen::None,
+            Value::Integer(i) => Frozen::Integer(*i),
+            Value::Real(r) => Frozen::Real(*r),
+            Value::Decimal(d) => Frozen::Decimal(*d),
+            Value::Str(s) => Frozen::Str(Arc::from(&**s)),
+            Value::Timestamp(t) => Frozen::Timestamp(*t),
+            Value::Duration(d) => Frozen::Duration(*d),
+            Value::Buffer(b) => Frozen::Buffer(b.to_vec().into()),
+            Value::Tuple(t) => Frozen::Tuple(seq((0..t.len()).filter_map(|i| t.get(i)).collect())?),
+            Value::List(l) => Frozen::List(seq(l.to_vec())?),
+            Value::Table(t) => {
+                let entries = t
+                    .entries()
+                    .into_iter()
+                    .map(|(k, v)| Some((k, Frozen::freeze_at(&v, depth + 1)?)));
+                Frozen::Table(entries.collect::<Option<_>>()?)
+            }
+            Value::Map(m) => {
+                let entries = m.entries().into_iter().map(|(k, v)| {
+                    Some((
+                        Frozen::freeze_at(&k, depth + 1)?,
+                        Frozen::freeze_at(&v, depth + 1)?,
+                    ))
+                });
+                Frozen::Map(entries.collect::<Option<_>>()?)
+            }
+            Value::Variant(v) => Frozen::Variant(
+                v.tag(),
+                Arc::new(Frozen::freeze_at(v.payload(), depth + 1)?),
+            ),
+            _ => return None,
+        })
+    }
+
+    /// A full, mutable copy as ordinary values.
+    pub fn thaw(&self) -> Value {
+        match self {
+            Frozen::None => Value::None,
+            Frozen::Integer(i) => Value::Integer(*i),
+            Frozen::Real(r) => Value::Real(*r),
+            Frozen::Decimal(d) => Value::Decimal(*d),
+            Frozen::Str(s) => Value::Str(Str::from(&**s)),
+            Frozen::Timestamp(t) => Value::Timestamp(*t),
+            Frozen::Duration(d) => Value::Duration(*d),
+            Frozen::Buffer(b) => Buffer::new(b.to_vec()).into(),
+            Frozen::Tuple(items) => Tuple::new(items.iter().map(Frozen::thaw).collect()).into(),
+            Frozen::List(items) => List::new(items.iter().map(Frozen::thaw).collect()).into(),
+            Frozen::Table(entries) => {
+                let table = Table::new();
+                for (k, v) in entries.iter() {
+                    table.set(*k, v.thaw());
+                }
+                table.into()
+            }
+            Frozen::Map(entries) => {
+                let map = Map::new();
+                for (k, v) in entries.iter() {
+                    // every key was hashable when it was frozen
+                    let _ = map.insert(k.thaw(), v.thaw());
+                }
+                map.into()
+            }
+            Frozen::Variant(tag, payload) => Variant::new(*tag, payload.thaw()).into(),
+        }
+    }
+}
+
+/// A shared handle to part of a frozen segment. Cloning it, or sending it
+/// to another thread, never copies the data.
+#[derive(Clone)]
+pub struct Segment {
+    root: Arc<Frozen>,
+    /// Path from the root to the frozen value this handle stands for.
+    path: Arc<[usize]>,
+}
+
+impl Segment {
+    pub fn freeze(val: &Value) -> Option<Segment> {
+        Some(Segment {
+            root: Arc::new(Frozen::freeze(val)?),
+            path: Arc::new([]),
+        })
+    }
+
+    pub fn get(&self) -> &Frozen {
+        let mut node = &*self.root;
+        for &i in self.path.iter() {
+            node = match node {
+                Frozen::Tuple(items) | Frozen::List(items) => &items[i],
+                Frozen::Table(entries) => &entries[i].1,
+                Frozen::Map(entries) => &entries[i].1,
+                Frozen::Variant(_, payload) => payload,
+                _ => unreachable!("segment paths only go through aggregates"),
+            };
+        }
+        node
+    }
+
+    fn child(&self, index: usize) -> Segment {
+        let mut path = self.path.to_vec();
+        path.push(index);
+        Segment {
+            root: self.root.clone(),
+            path: path.into(),
+        }
+    }
+
+    /// The child at `index` of a `Tuple` or `List`, by field key of a
+    /// `Table`, under a key of a `Map`, or the payload of a `Variant` (any
+    /// `index`).
+    pub fn lookup(&self, index: Lookup) -> Option<Segment> {
+        let found = match (self.get(), index) {
+            (Frozen::Tuple(items) | Frozen::List(items), Lookup::Index(i)) => {
+                usize::try_from(i).ok().filter(|&i| i < items.len())?
+            }
+            (Frozen::Table(entries), Lookup::Key(key)) => {
+                entries.iter().position(|(k, _)| *k == key)?
+            }
+            (Frozen::Map(entries), Lookup::Entry(key)) => entries
+                .iter()
+                .position(|(k, _)| Map::same_key(&k.thaw(), &key))?,
+            (Frozen::Variant(..), _) => 0,
+            _ => return None,
+        };
+        Some(self.child(found))
+    }
+
+    /// The value a script reads here: leaves are copied out, aggregates are
+    /// handed out as further handles.
+    pub fn to_value(&self) -> Value {
+        match self.get() {
+            Frozen::Tuple(_)
+            | Frozen::List(_)
+            | Frozen::Table(_)
+            | Frozen::Map(_)
+            | Frozen::Variant(..) => {
+                let handle: Rc<Segment> = Rc::new(self.clone());
+                Value::Unknown(handle)
+            }
+            leaf => leaf.thaw(),
+        }
+    }
+
+    pub fn from_value(val: &Value) -> Option<Segment> {
+        match val {
+            Value::Unknown(u) => u.downcast_ref::<Segment>().cloned(),
+            _ => None,
+        }
+    }
+}
+
+pub enum Lookup {
+    Index(i64),
+    Key(u64),
+    /// A map key.
+    Entry(Value),
+}
@@ -0,0 +1,333 @@
+//! A cycle collector for reference values. Values are reference counted,
+//! which frees everything except cycles: a table holding itself, or a
+//! module tuple holding functions whose module is that tuple. This finds
+//! and breaks those.
+//!
+//! Lists, maps, tables, tuples and records register themselves here when
+//! created, and a collection works over every one still alive, by trial
+//! deletion:
+//!
+//! 1. Each container starts with its strong count.
+//! 2. Every reference one container holds to another is subtracted, so
+//!    what is left counts references from outside the containers: the
+//!    VM's frames and stacks, the host, natives, closures.
+//! 3. Containers with outside references are roots. Everything reachable
+//!    from a root is marked live.
+//! 4. The rest are only reachable from each other, so they are cleared,
+//!    which drops the cycle and everything it held.
+//!
+//! There is no root set to keep up to date: any reference the collector
+//! can't see through (an `Iter`'s closure, a shared `Variant` payload, a
+//! constant in a function's ops) counts as an outside one, so it can only
+//! keep garbage alive, never free something reachable.
+//!
+//! Collections run when the host calls `collect`, and at op boundaries in
+//! any VM on the thread once enough containers have been created since the
+//! last one (see `set_threshold`).
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::datamodel::{MapInner, Shape, TableInner, Value};
+
+/// A container registered with the collector.
+pub(crate) enum Node {
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<MapInner>>),
+    Table(Weak<RefCell<TableInner>>),
+    Tuple(Weak<[RefCell<Value>]>),
+}
+
+enum Live {
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<MapInner>>),
+    Table(Rc<RefCell<TableInner>>),
+    Tuple(Rc<[RefCell<Value>]>),
+}
+
+/// The default `set_threshold`.
+pub const DEFAULT_THRESHOLD: usize = 10_000;
+
+thread_local! {
+    static NODES: RefCell<Vec<Node>> = const { RefCell::new(Vec::new()) };
+    static CREATED: Cell<usize> = const { Cell::new(0) };
+    static THRESHOLD: Cell<Option<usize>> = const { Cell::new(Some(DEFAULT_THRESHOLD)) };
+}
+
+pub(crate) fn track(node: Node) {
+    NODES.with(|n| n.borrow_mut().push(node));
+    CREATED.with(|c| c.set(c.get() + 1));
+}
+
+/// Sets how many containers may be created on this thread between
+/// automatic collections, or `None` to leave collecting to the host;
+/// returns the previous setting.
+pub fn set_threshold(threshold: Option<usize>) -> Option<usize> {
+    THRESHOLD.with(|t| t.replace(threshold))
+}
+
+/// Collects if the threshold has been reached; returns how many containers
+/// were freed.
+pub fn maybe_collect() -> usize {
+    let due = THRESHOLD
+        .with(|t| t.get())
+        .is_some_and(|t| CREATED.with(|c| c.get()) >= t);
+    match due {
+        true => collect(),
+        false => 0,
+    }
+}
+
+fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc).cast::<()>() as usize
+}
+
+impl Live {
+    fn address(&self) -> usize {
+        match self {
+            Live::List(rc) => address(rc),
+            Live::Map(rc) => address(rc),
+            Live::Table(rc) => address(rc),
+            Live::Tuple(rc) => address(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Live::List(rc) => Rc::strong_count(rc),
+            Live::Map(rc) => Rc::strong_count(rc),
+            Live::Table(rc) => Rc::strong_count(rc),
+            Live::Tuple(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    fn downgrade(&self) -> Node {
+        match self {
+            Live::List(rc) => Node::List(Rc::downgrade(rc)),
+            Live::Map(rc) => Node::Map(Rc::downgrade(rc)),
+            Live::Table(rc) => Node::Table(Rc::downgrade(rc)),
+            Live::Tuple(rc) => Node::Tuple(Rc::downgrade(rc)),
+        }
+    }
+
+    /// The containers this one references, or `None` if it is borrowed and
+    /// can't be looked into.
+    fn children(&self) -> Option<Vec<usize>> {
+        let mut out = Vec::new();
+        match self {
+            Live::List(rc) => rc.try_borrow().ok()?.iter().for_each(|v| refs(v, &mut out)),
+            // keys are scalars, which hold no containers
+            Live::Map(rc) => rc
+                .try_borrow()
+                .ok()?
+                .entries
+                .iter()
+                .flatten()
+                .for_each(|(_, v)| refs(v, &mut out)),
+            Live::Table(rc) => rc
+                .try_borrow()
+                .ok()?
+                .values
+                .iter()
+                .for_each(|v| refs(v, &mut out)),
+            Live::Tuple(rc) => {
+                for item in rc.iter() {
+                    refs(&*item.try_borrow().ok()?, &mut out);
+                }
+            }
+        }
+        Some(out)
+    }
+
+    /// Empties the container, returning what it held.
+    fn clear(&self) -> Vec<Value> {
+        match self {
+            Live::List(rc) => std::mem::take(&mut *rc.borrow_mut()),
+            Live::Map(rc) => {
+                let inner = std::mem::take(&mut *rc.borrow_mut());
+                inner
+                    .entries
+                    .into_iter()
+                    .flatten()
+                    .map(|(_, v)| v)
+                    .collect()
+            }
+            Live::Table(rc) => {
+                let mut inner = rc.borrow_mut();
+                inner.shape = Shape::root();
+                std::mem::take(&mut inner.values)
+            }
+            Live::Tuple(rc) => rc.iter().map(|item| item.replace(Value::None)).collect(),
+        }
+    }
+}
+
+/// The containers `val` holds a strong reference to, one entry per
+/// reference.
+fn refs(val: &Value, out: &mut Vec<usize>) {
+    match val {
+        Value::List(l) => out.push(address(&l.items)),
+        Value::Map(m) => out.push(address(&m.inner)),
+        Value::Table(t) => out.push(address(&t.inner)),
+        Value::Tuple(t) => out.push(address(&t.items)),
+        Value::Record(r) => out.push(address(&r.values)),
+        Value::Function(f) => out.push(address(&f.module.items)),
+        Value::Closure(c) => {
+            out.push(address(&c.function.module.items));
+            // as with a variant's payload, upvalues only it holds are its own
+            if Rc::strong_count(&c.upvalues) == 1 {
+                c.upvalues.iter().for_each(|v| refs(v, out));
+            }
+        }
+        // a payload only this variant holds belongs to its container
+        Value::Variant(v) if Rc::strong_count(&v.payload) == 1 => refs(&v.payload, out),
+        _ => {}
+    }
+}
+
+/// Frees the unreachable cycles among this thread's containers, returning
+/// how many containers were cleared.
+pub fn collect() -> usize {
+    CREATED.with(|c| c.set(0));
+    let nodes = NODES.with(|n| std::mem::take(&mut *n.borrow_mut()));
+    let live: Vec<Live> = nodes
+        .into_iter()
+        .filter_map(|node| match node {
+            Node::List(weak) => weak.upgrade().map(Live::List),
+            Node::Map(weak) => weak.upgrade().map(Live::Map),
+            Node::Table(weak) => weak.upgrade().map(Live::Table),
+            Node::Tuple(weak) => weak.upgrade().map(Live::Tuple),
+        })
+        .collect();
+    let index: HashMap<usize, usize> = live
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.address(), i))
+        .collect();
+
+    // outside references, less the one `live` holds
+    let mut outside: Vec<usize> = live.iter().map(|n| n.strong_count() - 1).collect();
+    let children: Vec<Option<Vec<usize>>> = live
+        .iter()
+        .map(|node| {
+            let children = node.children()?;
+            Some(
+                children
+                    .iter()
+                    .filter_map(|a| index.get(a).copied())
+                    .collect(),
+            )
+        })
+        .collect();
+    for child in children.iter().flatten().flatten() {
+        outside[*child] = outside[*child].saturating_sub(1);
+    }
+
+    let mut marked: Vec<bool> = children.iter().map(Option::is_none).collect();
+    let mut pending: Vec<usize> = (0..live.len())
+        .filter(|&i| marked[i] || outside[i] > 0)
+        .collect();
+    pending.iter().for_each(|&i| marked[i] = true);
+    while let Some(i) = pending.pop() {
+        for &child in children[i].iter().flatten() {
+            if !marked[child] {
+                marked[child] = true;
+                pending.push(child);
+            }
+        }
+    }
+
+    // drop what the garbage held only once nothing is borrowed
+    let mut freed = 0;
+    let mut held = Vec::new();
+    for (node, marked) in live.iter().zip(&marked) {
+        match marked {
+            true => NODES.with(|n| n.borrow_mut().push(node.downgrade())),
+            false => {
+                held.extend(node.clear());
+                freed += 1;
+            }
+        }
+    }
+    drop(live);
+    drop(held);
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Return;
+    use crate::datamodel::{Function, Table, Tuple, Variant};
+    use crate::finalize::{self, userdata};
+
+    fn finalized() -> (Value, Rc<Cell<bool>>) {
+        let done = Rc::new(Cell::new(false));
+        let flag = done.clone();
+        (userdata((), move |()| flag.set(true)), done)
+    }
+
+    #[test]
+    fn unreachable_cycles_are_freed() {
+        set_threshold(None);
+        collect();
+
+        // a table holding itself, through a variant
+        let (handle, done) = finalized();
+        let table = Table::new();
+        table.set(1, Variant::some(Value::Table(table.clone())).into());
+        table.set(2, handle);
+        // still held by the host
+        assert_eq!(collect(), 0);
+        assert_eq!(table.len(), 2);
+        drop(table);
+        assert_eq!(collect(), 1);
+        finalize::run_pending();
+        assert!(done.get());
+
+        // a module and its function, and a table it holds
+        let (handle, done) = finalized();
+        let module = Tuple::new(vec![Value::None, Value::None]);
+        let function = Function {
+            module: module.clone(),
+            ops: vec![Return.into()].into(),
+        };
+        let table = Table::new();
+        table.set(1, handle);
+        module.set(0, function.into());
+        module.set(1, table.into());
+        drop(module);
+        assert_eq!(collect(), 2);
+        finalize::run_pending();
+        assert!(done.get());
+
+        // a shared payload can't be seen through, so it keeps the cycle
+        let table = Table::new();
+        let shared = Variant::some(Value::Table(table.clone()));
+        table.set(1, shared.clone().into());
+        drop(table);
+        assert_eq!(collect(), 0);
+        drop(shared);
+        assert_eq!(collect(), 1);
+        set_threshold(Some(DEFAULT_THRESHOLD));
+    }
+
+    #[test]
+    fn collections_run_under_allocation_pressure() {
+        let previous = set_threshold(Some(3));
+        collect();
+        let (handle, done) = finalized();
+        let table = Table::new();
+        table.set(1, Value::Table(table.clone()));
+        table.set(2, handle);
+        drop(table);
+        Table::new();
+        assert_eq!(maybe_collect(), 0);
+        Table::new();
+        assert_eq!(maybe_collect(), 1);
+        finalize::run_pending();
+        assert!(done.get());
+        set_threshold(previous);
+    }
+}
@@ -0,0 +1,129 @@
+//! Task groups, for structured concurrency. A group is a script value
+//! that owns the tasks spawned into it: cancelling the group cancels them,
+//! and so does dropping the last reference to it, so a task can't outlive
+//! the code that started it. A cancelled task's frames are dropped along
+//! with any groups they held, which is how cancellation reaches
+//! grandchildren.
+//!
+//! Like timers and events, the natives (see `natives::group`) queue
+//! requests that the `Scheduler` picks up between slices.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::datamodel::{Function, Value};
+
+pub enum Outcome {
+    Running,
+    Returned(Value),
+    Failed,
+    Cancelled,
+}
+
+#[derive(Default)]
+pub struct TaskGroup {
+    children: RefCell<Vec<Outcome>>,
+    cancelled: Cell<bool>,
+}
+
+impl TaskGroup {
+    pub fn new() -> TaskGroup {
+        TaskGroup::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.children.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
+
+    /// Whether every child has finished, one way or another.
+    pub fn is_done(&self) -> bool {
+        let children = self.children.borrow();
+        !children.iter().any(|c| matches!(c, Outcome::Running))
+    }
+
+    /// Calls `f` with each child's outcome, in spawn order.
+    pub fn outcomes<T>(&self, f: impl FnMut(&Outcome) -> T) -> Vec<T> {
+        self.children.borrow().iter().map(f).collect()
+    }
+
+    /// Adds a running child, returning its index.
+    pub(crate) fn add(&self) -> usize {
+        let mut children = self.children.borrow_mut();
+        children.push(Outcome::Running);
+        children.len() - 1
+    }
+
+    pub(crate) fn finish(&self, index: usize, outcome: Outcome) {
+        self.children.borrow_mut()[index] = outcome;
+    }
+}
+
+pub(crate) struct Request {
+    pub group: Rc<TaskGroup>,
+    pub index: usize,
+    pub func: Function,
+}
+
+thread_local! {
+    static REQUESTS: RefCell<Vec<Request>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Queues `func` to be spawned as a child of `group`.
+pub(crate) fn request_spawn(group: Rc<TaskGroup>, func: Function) {
+    let index = group.add();
+    REQUESTS.with(|r| r.borrow_mut().push(Request { group, index, func }));
+}
+
+pub(crate) fn take_requests() -> Vec<Request> {
+    REQUESTS.with(|r| std::mem::take(&mut *r.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Return;
+    use crate::testing::function;
+
+    #[test]
+    fn children_finish_in_any_order() {
+        let func = function(vec![Return.into()]);
+        let group = Rc::new(TaskGroup::new());
+        assert!(group.is_empty() && group.is_done());
+        request_spawn(group.clone(), func.clone());
+        request_spawn(group.clone(), func);
+        assert_eq!(group.len(), 2);
+        assert!(!group.is_done());
+        let requests = take_requests();
+        let indices: Vec<_> = requests.iter().map(|r| r.index).collect();
+        assert_eq!(indices, [0, 1]);
+        assert!(requests.iter().all(|r| Rc::ptr_eq(&r.group, &group)));
+        assert!(take_requests().is_empty());
+
+        group.finish(1, Outcome::Returned(Value::Integer(2)));
+        assert!(!group.is_done());
+        group.finish(0, Outcome::Failed);
+        assert!(group.is_done());
+        let outcomes = group.outcomes(|outcome| match outcome {
+            Outcome::Returned(Value::Integer(i)) => *i,
+            Outcome::Failed => -1,
+            _ => 0,
+        });
+        assert_eq!(outcomes, [-1, 2]);
+
+        assert!(!group.is_cancelled());
+        group.cancel();
+        assert!(group.is_cancelled());
+    }
+}
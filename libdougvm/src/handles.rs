@@ -0,0 +1,93 @@
+//! Handle scopes: a shadow stack of the values host functions are holding
+//! while they run, so a collector can find every value the VM is using
+//! without guessing (see `VirtualMachine::roots`).
+//!
+//! The cycle collector in `gc` counts anything it can't see as a root, so
+//! it doesn't need these. A collector that moves values, or one that only
+//! trusts the roots it is given, does: values a host function keeps in
+//! Rust locals are invisible to it. Each host call runs in its own scope,
+//! which starts out holding the call's arguments; anything else the
+//! function holds on to across a call back into the VM belongs in the
+//! scope too, through `VmContext::root`. The scope, and every handle in
+//! it, is closed when the function returns.
+//!
+//! A `Handle` is a slot number rather than a reference, so whatever a
+//! collector does to the value in the slot, reading the handle afterwards
+//! gets the value as it is now.
+
+use crate::datamodel::Value;
+
+/// A value rooted in a handle scope. It is only meaningful until that
+/// scope closes: its slot may be handed out again after.
+#[derive(Clone, Copy)]
+pub struct Handle(usize);
+
+#[derive(Default)]
+pub struct ShadowStack {
+    values: Vec<Value>,
+    /// Where each open scope's values start, innermost last.
+    scopes: Vec<usize>,
+}
+
+impl ShadowStack {
+    pub fn open(&mut self) {
+        self.scopes.push(self.values.len());
+    }
+
+    /// Closes the innermost scope, dropping what it rooted.
+    pub fn close(&mut self) {
+        let start = self.scopes.pop().unwrap_or(0);
+        self.values.truncate(start);
+    }
+
+    pub fn root(&mut self, val: Value) -> Handle {
+        self.values.push(val);
+        Handle(self.values.len() - 1)
+    }
+
+    /// The value behind `handle`, or `None` if its slot is no longer in
+    /// use.
+    pub fn get(&self, handle: Handle) -> Option<&Value> {
+        self.values.get(handle.0)
+    }
+
+    /// Replaces the value behind `handle`; a no-op if its slot is no longer
+    /// in use.
+    pub fn set(&mut self, handle: Handle, val: Value) {
+        if let Some(slot) = self.values.get_mut(handle.0) {
+            *slot = val;
+        }
+    }
+
+    /// Every value rooted in an open scope, outermost first.
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// How many scopes are open.
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closing_a_scope_drops_only_its_values() {
+        let mut shadow = ShadowStack::default();
+        shadow.open();
+        let outer = shadow.root(Value::Integer(1));
+        shadow.open();
+        let inner = shadow.root(Value::Integer(2));
+        shadow.set(outer, Value::Integer(3));
+        assert_eq!(shadow.values().len(), 2);
+        shadow.close();
+        assert!(shadow.get(inner).is_none());
+        assert!(matches!(shadow.get(outer), Some(Value::Integer(3))));
+        shadow.close();
+        assert_eq!(shadow.depth(), 0);
+        assert!(shadow.values().is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+//! A fast path for leaf calls. A callee that makes no calls of its own can
+//! run to completion on a scratch stack the VM reuses, without a
+//! `CallFrame` being allocated and linked in, which is most of the cost of
+//! calling a small accessor.
+//!
+//! A leaf has at most `MAX_OPS` ops and `MAX_LOCALS` locals, and none of
+//! its ops calls, installs a handler, throws or jumps backwards, so it
+//! always finishes within its op count. There is no link step to classify
+//! functions ahead of time, so the VM classifies each callee on its first
+//! call and caches the result. If a leaf fails, the VM builds the frame it
+//! skipped, so the error's backtrace and any handler see the same state as
+//! in an ordinary call. It does the same when an operator on a record calls
+//! the record's method (see `ops::arith`), and carries on from there.
+
+use std::collections::HashMap;
+
+use crate::bytecode::{Op, OpAction, OpError, Operation};
+use crate::datamodel::{Function, Identity, Value};
+use crate::CallStack;
+
+pub const MAX_OPS: usize = 64;
+pub const MAX_LOCALS: usize = 16;
+
+/// Whether `func` can take the leaf path.
+pub fn is_leaf(func: &Function) -> bool {
+    let ops = &func.ops;
+    ops.len() <= MAX_OPS && ops.iter().all(is_leaf_op)
+}
+
+fn is_leaf_op(op: &Op) -> bool {
+    match op {
+        Op::Load(op) => (op.0 as usize) < MAX_LOCALS,
+        Op::Store(op) => (op.0 as usize) < MAX_LOCALS,
+        Op::Jump(_) | Op::JumpIf(_) | Op::JumpIfNot(_) => op.jump_offset().unwrap() >= 0,
+        Op::SwitchStr(_) | Op::Switch(_) => op.jump_offsets().iter().all(|offset| *offset >= 0),
+        Op::Push(_) | Op::Pop(_) | Op::Select(_) | Op::Return(_) | Op::Halt(_) | Op::Try(_) => true,
+        Op::Add(_) | Op::Sub(_) | Op::Mul(_) | Op::Div(_) | Op::Rem(_) | Op::Neg(_) => true,
+        Op::Eq(_) | Op::Ne(_) | Op::Lt(_) | Op::Le(_) | Op::Gt(_) | Op::Ge(_) => true,
+        Op::Same(_) | Op::DeepEq(_) | Op::Cmp(_) => true,
+        Op::AddInt(_) | Op::SubInt(_) | Op::LtInt(_) | Op::Speculate(_) => true,
+        Op::AddIntUnchecked(_) | Op::SubIntUnchecked(_) => true,
+        Op::AddImm(op) => (op.local as usize) < MAX_LOCALS,
+        Op::AddLocals(op) => (op.a.max(op.b) as usize) < MAX_LOCALS,
+        Op::MakeVariant(_) | Op::IsTag(_) | Op::GetTag(_) | Op::Unwrap(_) => true,
+        Op::Implements(_) | Op::NewTable(_) | Op::GetField(_) | Op::SetField(_) => true,
+        Op::NewRecord(_) => true,
+        Op::MakeClosure(op) => op.locals.iter().all(|&l| (l as usize) < MAX_LOCALS),
+        Op::NewMap(_) | Op::MapInsert(_) | Op::MapGet(_) | Op::MapDelete(_) => true,
+        Op::MapContains(_) | Op::MapIter(_) => true,
+        // loops, calls, and anything that can leave the frame other than by
+        // returning
+        Op::IncJumpLt(_) | Op::Call(_) | Op::Invoke(_) | Op::CallHost(_) => false,
+        Op::GetIter(_) | Op::ForIter(_) => false,
+        Op::PushHandler(_) | Op::PopHandler(_) | Op::Throw(_) => false,
+        Op::Enter(_) | Op::Exit(_) => false,
+        Op::Yield(_) | Op::Resume(_) | Op::LoadModule(_) | Op::Intern(_) => false,
+        // leaves run without the closure they may be called as
+        Op::LoadUpvalue(_) => false,
+    }
+}
+
+/// Leaf classifications by function. Entries hold their function so its
+/// identity can't be reused by another while cached.
+#[derive(Default)]
+pub(crate) struct LeafCache {
+    functions: HashMap<usize, (Function, bool)>,
+}
+
+impl LeafCache {
+    pub(crate) fn is_leaf(&mut self, func: &Function) -> bool {
+        let entry = self.functions.entry(func.identity());
+        entry.or_insert_with(|| (func.clone(), is_leaf(func))).1
+    }
+}
+
+/// Why a leaf call left the leaf path.
+pub(crate) enum Stop {
+    Error(OpError),
+    /// An operator called a record's method.
+    Call(OpAction),
+}
+
+/// A leaf call that stopped early, with the state to build its frame from.
+pub(crate) struct Failed {
+    pub stop: Stop,
+    /// Already advanced past the failing op, as a frame's would be.
+    pub cursor: usize,
+    pub steps: u64,
+}
+
+/// Runs leaf `func` on `stack`, which is cleared first. On success,
+/// returns its result and how many ops it ran.
+pub(crate) fn run(
+    func: &Function,
+    args: Vec<Value>,
+    stack: &mut CallStack,
+) -> Result<(Value, u64), Failed> {
+    stack.clear();
+    stack.store(0, func.module.clone().into());
+    for arg in args {
+        stack.push(arg);
+    }
+    let mut cursor = 0;
+    let mut steps = 0;
+    loop {
+        let op = match func.ops.get(cursor) {
+            Some(op) => op,
+            None => {
+                let stop = Stop::Error(OpError::FellOffEnd);
+                return Err(Failed {
+                    stop,
+                    cursor,
+                    steps,
+                });
+            }
+        };
+        cursor += 1;
+        steps += 1;
+        match op.exec(stack) {
+            Ok(OpAction::None) => {}
+            Ok(OpAction::Jump(offset)) => cursor = (cursor as isize + offset as isize) as usize,
+            Ok(OpAction::Return(val)) => return Ok((val, steps)),
+            // `is_leaf` rules out every other action
+            Ok(
+                action
+                @ (OpAction::Call(..) | OpAction::CallClosure(..) | OpAction::CallNative(..)),
+            ) => {
+                return Err(Failed {
+                    stop: Stop::Call(action),
+                    cursor,
+                    steps,
+                })
+            }
+            Ok(_) => unreachable!("leaf function left its frame"),
+            Err(error) => {
+                return Err(Failed {
+                    stop: Stop::Error(error),
+                    cursor,
+                    steps,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Add, Call, Jump, Load, Push, Return};
+    use crate::testing::function;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn only_straight_line_callees_are_leaves() {
+        assert!(is_leaf(&function(vec![Load(1).into(), Return.into()])));
+        assert!(is_leaf(&function(vec![Jump(0).into()])));
+        assert!(!is_leaf(&function(vec![Jump(-1).into()])));
+        assert!(!is_leaf(&function(vec![Call(0).into()])));
+        assert!(!is_leaf(&function(vec![Load(MAX_LOCALS as u8).into()])));
+        assert!(!is_leaf(&function(vec![
+            Push(Value::None).into();
+            MAX_OPS + 1
+        ])));
+    }
+
+    #[test]
+    fn leaf_calls_match_framed_calls() {
+        // add(2, 3); args arrive on the callee's stack
+        let add = function(vec![Add.into(), Return.into()]);
+        let main = function(vec![
+            Push(Value::Integer(2)).into(),
+            Push(Value::Integer(3)).into(),
+            Push(add.into()).into(),
+            Call(2).into(),
+            Return.into(),
+        ]);
+        for leaf_calls in [true, false] {
+            let mut vm = VirtualMachine::new(main.clone());
+            vm.set_leaf_calls(leaf_calls);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(5))));
+            let usage = vm.usage();
+            assert_eq!((usage.steps, usage.peak_frames), (7, 2));
+        }
+    }
+}
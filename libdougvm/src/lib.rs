@@ -1,138 +1,2478 @@
-use std::mem::swap;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::{swap, take};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
+pub mod bench;
 pub mod bytecode;
+pub mod canonical;
+pub(crate) mod codec;
+pub mod coroutine;
 pub mod datamodel;
+pub mod debugger;
+pub mod difftest;
+pub mod eval;
+pub mod events;
+pub mod exception;
+pub mod finalize;
+pub mod frozen;
+pub mod gc;
+pub mod group;
+pub mod handles;
+pub mod leaf;
+pub mod manifest;
+pub mod migrate;
+pub mod modules;
+pub mod natives;
+pub mod optimize;
+pub mod profiler;
+pub mod remote;
+pub mod resources;
+pub mod rpc;
+pub mod scheduler;
+pub mod schema;
+pub mod sourcemap;
+pub mod stdlib;
+pub mod stream;
+pub mod suspend;
+#[cfg(test)]
+mod testing;
+pub mod tiering;
+pub mod timer;
+pub mod transfer;
+pub mod usage;
+pub mod vfs;
+pub mod watchdog;
+
+use crate::bytecode::verify::{verify_all, VerifyError};
+use crate::bytecode::{ops, Op, OpAction, OpError, Operation};
+use crate::coroutine::Coroutine;
+use crate::datamodel::{
+    Function, Identity, Interner, NativeFn, NativeRegistry, Str, Symbol, Value,
+};
+use crate::handles::{Handle, ShadowStack};
+use crate::leaf::LeafCache;
+use crate::modules::{Import, ModuleTable};
+use crate::profiler::{OpTimings, Profile, Profiler, SampleHandle};
+use crate::resources::{Resource, Resources};
+use crate::sourcemap::{SourceMaps, Span};
+use crate::stream::{Stream, Wait};
+use crate::suspend::Token;
+use crate::tiering::{Tiering, TieringPolicy};
+use crate::usage::Usage;
+use crate::watchdog::{HotLoop, Watchdog};
+
+pub struct CallFrame {
+    pub parent: Option<Box<CallFrame>>,
+    pub function: Function,
+    pub cursor: usize,
+    pub stack: CallStack,
+    /// Installed exception handlers, innermost last.
+    pub handlers: Vec<Handler>,
+    /// Open resource scopes, innermost last.
+    pub scopes: Vec<Scope>,
+    /// Where the caller wants this frame's result.
+    pub ret: ReturnSlot,
+    /// Set on the frame a running coroutine started in, the innermost of
+    /// the resumer's frames being its parent.
+    pub coroutine: Option<Coroutine>,
+    /// Set on the frame running a module's top-level code, to the
+    /// module's name.
+    pub init: Option<Str>,
+}
+
+/// Where a call's result goes in the caller's frame.
+#[derive(Clone, Copy)]
+pub enum ReturnSlot {
+    /// On top of its stack.
+    Push,
+    /// Straight into a local, when the caller's next op would only store
+    /// it there; that `Store` is skipped rather than run.
+    Local(u8),
+}
+
+/// An exception handler installed by `ops::PushHandler`.
+#[derive(Clone, Copy)]
+pub struct Handler {
+    /// Where execution continues when it catches an exception.
+    pub target: usize,
+    /// The stack height to cut back to first.
+    pub stack: usize,
+}
+
+/// A resource scope opened by `ops::Enter`.
+#[derive(Clone)]
+pub struct Scope {
+    pub resource: Value,
+    pub release: NativeFn,
+    /// How many handlers the frame had installed when it was opened.
+    pub handlers: usize,
+}
+
+impl CallFrame {
+    pub fn new(function: Function) -> CallFrame {
+        let mut stack = CallStack::new();
+        stack.store(0, function.module.clone().into());
+        CallFrame {
+            parent: None,
+            function,
+            cursor: 0,
+            stack,
+            handlers: Vec::new(),
+            scopes: Vec::new(),
+            ret: ReturnSlot::Push,
+            coroutine: None,
+            init: None,
+        }
+    }
+
+    /// Where a call made by the op just run should put its result.
+    fn return_slot(&self) -> ReturnSlot {
+        match self.function.ops.get(self.cursor) {
+            Some(Op::Store(ops::Store(index))) => ReturnSlot::Local(*index),
+            _ => ReturnSlot::Push,
+        }
+    }
+
+    /// Whether a call made by the op just run can replace this frame: it
+    /// returns the call's result as is, and nothing happens as the frame
+    /// is left, as it would if it caught exceptions, held resource scopes,
+    /// ran a coroutine or initialized a module, or if `keep_locals` and it
+    /// is the outermost frame, whose locals the VM keeps.
+    fn in_tail_position(&self, keep_locals: bool) -> bool {
+        let returns = matches!(self.function.ops.get(self.cursor), Some(Op::Return(_)));
+        let scoped = !self.handlers.is_empty() || !self.scopes.is_empty();
+        let kept = keep_locals && self.parent.is_none();
+        returns && !scoped && !kept && self.coroutine.is_none() && self.init.is_none()
+    }
+
+    /// Takes a callee's result.
+    fn put_result(&mut self, slot: ReturnSlot, val: Value) {
+        match slot {
+            ReturnSlot::Push => self.push(val),
+            ReturnSlot::Local(index) => {
+                self.stack.store(index, val);
+                self.cursor += 1;
+            }
+        }
+    }
+
+    pub fn push(&mut self, val: Value) {
+        self.stack.push(val);
+    }
+
+    pub fn jump(&mut self, index: i32) {
+        self.cursor = (self.cursor as isize + index as isize) as usize;
+    }
+
+    pub fn exec(&mut self) -> Result<OpAction, OpError> {
+        // the op is borrowed from the function, not cloned, since running
+        // it only touches the stack
+        let op = match self.function.ops.get(self.cursor) {
+            Some(op) => op,
+            None => return Err(OpError::FellOffEnd),
+        };
+        self.cursor += 1;
+        op.exec(&mut self.stack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Jump;
+    use crate::datamodel::Tuple;
+    use crate::testing::function;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn frames_run_ops_in_place() {
+        use crate::bytecode::ops::Push;
+        use crate::datamodel::List;
+
+        let list = List::new(Vec::new());
+        let mut frame = CallFrame::new(function(vec![Push(list.clone().into()).into()]));
+        assert!(matches!(frame.exec(), Ok(OpAction::None)));
+        let pushed = frame.stack.pop().ok().unwrap();
+        assert!(matches!(&pushed, Value::List(l) if l.identity() == list.identity()));
+        drop(pushed);
+        // only `list` and the op itself hold it
+        assert_eq!(Rc::strong_count(&list.items), 2);
+        assert!(matches!(frame.exec(), Err(OpError::FellOffEnd)));
+        assert_eq!(frame.cursor, 1);
+    }
+
+    #[test]
+    fn it_works() {
+        let result = 2 + 2;
+        assert_eq!(result, 4);
+    }
+
+    #[test]
+    fn interrupt_stops_infinite_loop() {
+        let mut vm = VirtualMachine::new(function(vec![Jump(-1).into()]));
+        let handle = vm.interrupt_handle();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            handle.interrupt();
+        });
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::Interrupted,
+                ..
+            })
+        ));
+        assert_eq!(vm.backtrace(), "#0 at op 0\n");
+    }
+
+    #[test]
+    fn errors_carry_the_call_chain() {
+        use crate::bytecode::ops::*;
+
+        let callee = function(vec![Push(Value::Str("x".into())).into(), Neg.into()]);
+        let module = Tuple::new(vec![Value::None, callee.clone().into()]);
+        let callee = Function { module, ..callee };
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(1)).into(),
+            Pop.into(),
+            Push(callee.into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]));
+        let e = vm.run_until_exited().err().unwrap();
+        assert!(matches!(e.error, OpError::BadType(_)));
+        let trace = e.backtrace();
+        let lines: Vec<_> = trace.lines().collect();
+        assert_eq!(lines[0], "TypeError: unexpected Str in slot1 at op 1");
+        assert_eq!(lines[1], "#0 slot1 at op 1");
+        assert!(lines[2].starts_with("#1 fn_") && lines[2].ends_with(" at op 3"));
+    }
+
+    #[test]
+    fn handlers_catch_throws_and_errors() {
+        use crate::bytecode::ops::*;
+        use crate::exception;
+
+        // the stack is cut back to where the handler was installed
+        let mut vm = VirtualMachine::new(function(vec![
+            PushHandler(4).into(),
+            Push(Value::Integer(1)).into(),
+            Push(Value::Str("boom".into())).into(),
+            Throw.into(),
+            Return.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Str(s)) if &*s == "boom"));
+
+        // an error in a callee unwinds to the caller's handler
+        let callee = function(vec![Push(Value::Str("x".into())).into(), Neg.into()]);
+        let mut vm = VirtualMachine::new(function(vec![
+            PushHandler(3).into(),
+            Push(callee.into()).into(),
+            Call(0).into(),
+            Return.into(),
+            Return.into(),
+        ]));
+        let caught = vm.run_until_exited().ok().unwrap();
+        let (class, _, _) = exception::parse(&caught).unwrap();
+        assert_eq!(&*class, exception::TYPE_ERROR);
+        assert_eq!(vm.depth, 0);
+
+        // popped handlers no longer catch
+        let mut vm = VirtualMachine::new(function(vec![
+            PushHandler(2).into(),
+            PopHandler.into(),
+            Push(Value::Str("x".into())).into(),
+            Neg.into(),
+        ]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::BadType(_),
+                ..
+            })
+        ));
+
+        let thrown = exception::exception("MyError", "bad", Value::None);
+        let mut vm = VirtualMachine::new(function(vec![Push(thrown).into(), Throw.into()]));
+        let e = vm.run_until_exited().err().unwrap();
+        assert!(matches!(e.error, OpError::Uncaught(_)));
+        assert!(e.to_string().starts_with("MyError: bad in fn_"));
+    }
+
+    #[test]
+    fn errors_point_at_the_original_source() {
+        use crate::bytecode::ops::*;
+        use crate::sourcemap::{FunctionMap, Span, TextMap};
+
+        let span = |file: &str, line: u32| Span {
+            file: file.into(),
+            line,
+            column: 1,
+        };
+        let main = function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(0)).into(),
+            Div.into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(main.clone());
+        // the division was generated from the user's line 4
+        let map = FunctionMap::new(vec![(0, span("<eval>", 1)), (2, span("<eval>", 2))]);
+        vm.source_maps().set_function(&main, map);
+        let text = TextMap::new(vec![(2, 1, span("main.dg", 4))]);
+        vm.source_maps().set_text("<eval>".into(), text);
+        match vm.run_until_exited() {
+            Err(e) => {
+                assert!(e.frames[0].span == Some(span("main.dg", 4)));
+                assert!(e.backtrace().contains("at op 2 (main.dg:4:1)"));
+            }
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn host_functions_root_what_they_hold() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{List, NativeRegistry};
+
+        let mut registry = NativeRegistry::new();
+        let hold = registry.register("hold", |ctx, args| {
+            let list = ctx.root(List::new(args.to_vec()).into());
+            // the argument and the list, as a collector would find them
+            let roots = ctx.vm.roots();
+            assert!(matches!(roots[..], [.., Value::Integer(5), Value::List(_)]));
+            match ctx.handle(list) {
+                Value::List(l) => Ok(Value::Integer(l.len() as i64)),
+                _ => Ok(Value::None),
+            }
+        });
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(5)).into(),
+            CallHost {
+                index: hold,
+                argc: 1,
+            }
+            .into(),
+            Return.into(),
+        ]));
+        vm.set_registry(Rc::new(registry));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(1))));
+        assert_eq!(vm.shadow.depth(), 0);
+    }
+
+    #[test]
+    fn roots_include_captured_upvalues() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::NativeRegistry;
+
+        let mut registry = NativeRegistry::new();
+        let probe = registry.register("probe", |ctx, _| {
+            let found =
+                (ctx.vm.roots().iter()).any(|v| matches!(v, Value::Str(s) if &**s == "captured"));
+            Ok(found.into())
+        });
+        let inner = function(vec![
+            CallHost {
+                index: probe,
+                argc: 0,
+            }
+            .into(),
+            Return.into(),
+        ]);
+        // the closure holds the only copy once local 1 is overwritten
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Str("captured".into())).into(),
+            Store(1).into(),
+            Push(inner.into()).into(),
+            MakeClosure {
+                locals: Rc::from([1]),
+            }
+            .into(),
+            Push(Value::None).into(),
+            Store(1).into(),
+            Call(0).into(),
+            Return.into(),
+        ]));
+        vm.set_registry(Rc::new(registry));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(1))));
+    }
+
+    #[test]
+    fn host_functions_keep_state_and_throw() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::NativeRegistry;
+        use crate::exception;
+        use std::cell::Cell;
+
+        let mut registry = NativeRegistry::new();
+        let total = Rc::new(Cell::new(0));
+        let add = {
+            let total = total.clone();
+            registry.register("add", move |ctx, args| match args {
+                [Value::Integer(a), Value::Integer(b)] => {
+                    total.set(total.get() + a - b + ctx.depth() as i64);
+                    Ok(Value::Integer(total.get()))
+                }
+                _ => Err(OpError::Uncaught(exception::native_error(
+                    "add wants Integers",
+                ))),
+            })
+        };
+        assert_eq!(registry.index_of("add"), Some(add));
+        let registry = Rc::new(registry);
+
+        // add(10, 3), twice
+        let call = |a: i64| {
+            [
+                Push(Value::Integer(a)).into(),
+                Push(Value::Integer(3)).into(),
+                CallHost {
+                    index: add,
+                    argc: 2,
+                }
+                .into(),
+            ]
+        };
+        let mut ops: Vec<bytecode::Op> = call(10).into();
+        ops.push(Pop.into());
+        ops.extend(call(10));
+        ops.push(Return.into());
+        let mut vm = VirtualMachine::new(function(ops));
+        vm.set_registry(registry.clone());
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(16))));
+        assert_eq!(total.get(), 16);
+
+        let mut vm = VirtualMachine::new(function(vec![
+            PushHandler(2).into(),
+            Push(Value::None).into(),
+            CallHost {
+                index: add,
+                argc: 1,
+            }
+            .into(),
+            Return.into(),
+        ]));
+        vm.set_registry(registry);
+        let caught = vm.run_until_exited().ok().unwrap();
+        let (class, message, _) = exception::parse(&caught).unwrap();
+        assert_eq!(
+            (&*class, &*message),
+            (exception::NATIVE_ERROR, "add wants Integers")
+        );
+
+        let mut vm = VirtualMachine::new(function(vec![CallHost { index: 9, argc: 0 }.into()]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::NoHostFn(9),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn variant_tag_dispatch() {
+        use crate::bytecode::ops::*;
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(42)).into(),
+            MakeVariant(1).into(),
+            Store(1).into(),
+            Load(1).into(),
+            IsTag(0).into(),
+            JumpIf(3).into(),
+            Load(1).into(),
+            Unwrap(1).into(),
+            Return.into(),
+            Push(Value::None).into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(42))));
+    }
+
+    #[test]
+    fn fused_loop_matches_unfused() {
+        use crate::bytecode::ops::*;
+
+        // sum = 0; i = 0; while i < 10 { sum += i; i += 1 }
+        let prologue = || -> Vec<bytecode::Op> {
+            vec![
+                Push(Value::Integer(0)).into(),
+                Store(1).into(),
+                Push(Value::Integer(0)).into(),
+                Store(2).into(),
+                Push(Value::Integer(10)).into(),
+                Store(3).into(),
+            ]
+        };
+        let mut unfused = prologue();
+        unfused.extend::<[bytecode::Op; 12]>([
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Store(1).into(),
+            Load(2).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Store(2).into(),
+            Load(2).into(),
+            Load(3).into(),
+            Lt.into(),
+            JumpIf(-12).into(),
+        ]);
+        let mut fused = prologue();
+        fused.extend::<[bytecode::Op; 5]>([
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Store(1).into(),
+            IncJumpLt {
+                local: 2,
+                limit: 3,
+                offset: -5,
+            }
+            .into(),
+        ]);
+        for mut ops in [unfused, fused] {
+            ops.extend::<[bytecode::Op; 2]>([Load(1).into(), Return.into()]);
+            let mut vm = VirtualMachine::new(function(ops));
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(45))));
+        }
+    }
+
+    #[test]
+    fn select_picks_without_branching() {
+        use crate::bytecode::ops::*;
+
+        let program = |cond: i64| {
+            function(vec![
+                Push(Value::Integer(cond)).into(),
+                Push(Value::Integer(10)).into(),
+                Push(Value::Integer(20)).into(),
+                Select.into(),
+                Return.into(),
+            ])
+        };
+        let mut vm = VirtualMachine::new(program(1));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(10))));
+        let mut vm = VirtualMachine::new(program(0));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(20))));
+    }
+
+    #[test]
+    fn try_propagates_failure_arm() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{Variant, ERR};
+
+        let program =
+            |arg: Variant| function(vec![Push(arg.into()).into(), Try.into(), Return.into()]);
+        let mut vm = VirtualMachine::new(program(Variant::ok(Value::Integer(7))));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(7))));
+        let mut vm = VirtualMachine::new(program(Variant::err(Value::Integer(7))));
+        match vm.run_until_exited() {
+            Ok(Value::Variant(v)) => assert!(v.tag() == ERR),
+            _ => panic!("expected the Err arm to be returned"),
+        }
+    }
+
+    #[test]
+    fn interface_dispatch() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{Interface, TypeKey, ValueType};
+
+        let show = Interface::new("Show".into(), vec!["describe".into()]);
+        // describe(self) returns the receiver unchanged
+        let describe = function(vec![Return.into()]);
+        show.implement(TypeKey::Builtin(ValueType::Integer), vec![describe.into()]);
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(5)).into(),
+            Push(show.clone().into()).into(),
+            Invoke { method: 0, argc: 0 }.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(5))));
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Real(1.0)).into(),
+            Push(show.into()).into(),
+            Implements.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(0))));
+    }
+
+    #[test]
+    fn field_lookup_delegates_to_proto() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{field_key, Table, PROTO_KEY};
+
+        let base = Table::new();
+        base.set(field_key("x"), Value::Integer(1));
+        let obj = Table::new();
+        obj.set(PROTO_KEY, base.clone().into());
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(obj.clone().into()).into(),
+            GetField::new(field_key("x")).into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(1))));
+
+        obj.set(field_key("x"), Value::Integer(2));
+        assert!(matches!(
+            base.lookup(field_key("x")),
+            Some(Value::Integer(1))
+        ));
+        assert!(matches!(
+            obj.lookup(field_key("x")),
+            Some(Value::Integer(2))
+        ));
+        assert!(obj.lookup(field_key("y")).is_none());
+    }
+
+    #[test]
+    fn field_cache_tracks_shape_changes() {
+        use crate::bytecode::cache::FieldCache;
+        use crate::datamodel::{field_key, Table};
+
+        let (x, y) = (field_key("x"), field_key("y"));
+        let a = Table::new();
+        a.set(x, Value::Integer(1));
+        let b = Table::new();
+        b.set(y, Value::Integer(2));
+        b.set(x, Value::Integer(3));
+        let c = Table::new();
+        c.set(x, Value::Integer(4));
+        assert!(std::rc::Rc::ptr_eq(&a.shape(), &c.shape()));
+
+        let cache = FieldCache::new();
+        assert_eq!(cache.slot(&a, x), Some(0));
+        assert_eq!(cache.slot(&b, x), Some(1));
+        assert_eq!(cache.slot(&c, x), Some(0));
+        // a table gaining a field moves to a new shape and misses the cache
+        let cache = FieldCache::new();
+        assert_eq!(cache.slot(&a, y), None);
+        a.set(y, Value::None);
+        assert_eq!(cache.slot(&a, y), Some(1));
+    }
+
+    #[test]
+    fn records_have_fixed_fields() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{field_key, Shape, Table};
+
+        let (x, y) = (field_key("x"), field_key("y"));
+        let shape = Shape::of(&[x, y]);
+        // shared with tables that gain the same fields in the same order
+        let table = Table::new();
+        table.set(x, Value::None);
+        table.set(y, Value::None);
+        assert!(std::rc::Rc::ptr_eq(&shape, &table.shape()));
+
+        // { x: 1, y: 2 }, then r.y = r.x + 10
+        let set_y = vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(2)).into(),
+            NewRecord { shape }.into(),
+            Store(1).into(),
+            Load(1).into(),
+            Load(1).into(),
+            GetField::new(x).into(),
+            Push(Value::Integer(10)).into(),
+            Add.into(),
+            SetField::new(y).into(),
+            Load(1).into(),
+            GetField::new(y).into(),
+            Return.into(),
+        ];
+        let mut vm = VirtualMachine::new(function(set_y.clone()));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(11))));
+
+        // records can't gain fields
+        let mut ops = set_y;
+        ops[9] = SetField::new(field_key("z")).into();
+        let mut vm = VirtualMachine::new(function(ops));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::FieldWrite(key),
+                ..
+            }) if key == field_key("z")
+        ));
+    }
+
+    #[test]
+    fn operators_on_records_call_their_shapes_methods() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{field_key, Shape, ValueType};
+
+        let (x, y) = (field_key("vx"), field_key("vy"));
+        let shape = Shape::of(&[x, y]);
+        let field =
+            |local: u8, key: u64| -> [Op; 2] { [Load(local).into(), GetField::new(key).into()] };
+        // __add__(a, b) = { vx: a.vx + b.vx, vy: a.vy + b.vy }
+        let mut add = vec![Store(1).into(), Store(2).into()];
+        for key in [x, y] {
+            add.extend(field(1, key));
+            add.extend(field(2, key));
+            add.push(Add.into());
+        }
+        add.extend([
+            NewRecord {
+                shape: shape.clone(),
+            }
+            .into(),
+            Return.into(),
+        ]);
+        shape.set_method(field_key("__add__"), function(add).into());
+
+        // sum(a, b) = a + b, a leaf unless the add calls out
+        let sum = function(vec![
+            Store(1).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Load(2).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let vector = |vx: i64, vy: i64| -> [Op; 3] {
+            [
+                Push(Value::Integer(vx)).into(),
+                Push(Value::Integer(vy)).into(),
+                NewRecord {
+                    shape: shape.clone(),
+                }
+                .into(),
+            ]
+        };
+        let mut main = Vec::new();
+        main.extend(vector(1, 2));
+        main.extend(vector(10, 20));
+        main.extend([
+            Push(sum.into()).into(),
+            Call(2).into(),
+            GetField::new(y).into(),
+            Return.into(),
+        ]);
+        for leaf_calls in [true, false] {
+            let mut vm = VirtualMachine::new(function(main.clone()));
+            vm.set_leaf_calls(leaf_calls);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(22))));
+        }
+
+        // without a method, records are still the wrong type
+        main[6] = Sub.into();
+        main.truncate(7);
+        let mut vm = VirtualMachine::new(function(main));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::BadType(ValueType::Record),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn maps_key_by_equality() {
+        use crate::bytecode::ops::*;
+        use crate::datamodel::{Decimal, Map, ValueType};
+
+        let insert = |key: Value, val: Value| -> Vec<Op> {
+            vec![
+                Load(1).into(),
+                Push(key).into(),
+                Push(val).into(),
+                MapInsert.into(),
+            ]
+        };
+        // integral 1, 1.0 and 1.0d are one key; deleting it leaves "k"
+        let mut ops = vec![NewMap.into(), Store(1).into()];
+        ops.extend(insert(Value::Integer(1), Value::Str("a".into())));
+        ops.extend(insert(Value::Real(1.0), Value::Str("b".into())));
+        ops.extend(insert(Value::Str("k".into()), Value::Integer(2)));
+        ops.extend([
+            Load(1).into(),
+            Push(Decimal::new(10, 1).unwrap().into()).into(),
+            MapDelete.into(),
+            Load(1).into(),
+            Push(Value::Str("k".into())).into(),
+            MapGet.into(),
+            Add.into(),
+            Load(1).into(),
+            Push(Value::Integer(1)).into(),
+            MapContains.into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(function(ops.clone()));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(3))));
+
+        // the deleted key is missing, and tables can't be keys
+        let get = ops.len() - 9;
+        ops.truncate(get);
+        ops.extend([
+            Load(1).into(),
+            Push(Value::Integer(1)).into(),
+            MapGet.into(),
+        ]);
+        let mut vm = VirtualMachine::new(function(ops.clone()));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::KeyRead(Value::Integer(1)),
+                ..
+            })
+        ));
+        ops.truncate(get);
+        ops.extend([Load(1).into(), NewTable.into(), MapContains.into()]);
+        let mut vm = VirtualMachine::new(function(ops));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::BadType(ValueType::Table),
+                ..
+            })
+        ));
+        assert!(Map::new()
+            .insert(Value::Real(f64::NAN), Value::None)
+            .is_err());
+
+        // other numbers only meet their own kind: 1.5 and 1.5d are two keys
+        let map = Map::new();
+        for key in [
+            Value::Real(1.5),
+            Decimal::new(15, 1).unwrap().into(),
+            Decimal::new(150, 2).unwrap().into(),
+        ] {
+            map.insert(key, Value::None).ok().unwrap();
+        }
+        assert_eq!(map.len(), 2);
+
+        // iteration follows first insertion
+        let map = Map::new();
+        for (key, val) in [("x", 1), ("y", 2), ("x", 3)] {
+            assert!(map.insert(Value::Str(key.into()), val.into()).is_ok());
+        }
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(map.into()).into(),
+            MapIter.into(),
+            Return.into(),
+        ]));
+        let iter = match vm.run_until_exited() {
+            Ok(Value::Iter(iter)) => iter,
+            _ => panic!("expected an Iter"),
+        };
+        let pairs: Vec<_> = std::iter::from_fn(|| iter.next())
+            .map(|entry| match entry {
+                Value::Tuple(t) => match (t.get(0), t.get(1)) {
+                    (Some(Value::Str(k)), Some(Value::Integer(v))) => (k.to_string(), v),
+                    _ => panic!("expected a (Str, Integer) entry"),
+                },
+                _ => panic!("expected a Tuple"),
+            })
+            .collect();
+        assert_eq!(pairs, [("x".to_string(), 3), ("y".to_string(), 2)]);
+    }
+
+    #[test]
+    fn for_loops_run_over_any_iterable() {
+        use crate::bytecode::capacity::frame_info;
+        use crate::bytecode::ops::*;
+        use crate::datamodel::List;
+
+        // acc = 0; for x in <iterable>: acc += x; return acc
+        let sum = |iterable: Vec<Op>| {
+            let mut ops = vec![Push(Value::Integer(0)).into(), Store(1).into()];
+            ops.extend(iterable);
+            ops.extend([
+                GetIter.into(),
+                ForIter(4).into(),
+                Load(1).into(),
+                Add.into(),
+                Store(1).into(),
+                Jump(-5).into(),
+                Load(1).into(),
+                Return.into(),
+            ]);
+            function(ops)
+        };
+        let items = (1..=3).map(Value::Integer).collect();
+        let func = sum(vec![Push(List::new(items).into()).into()]);
+        // the iterator, an item and the sum
+        assert_eq!(frame_info(&func).stack, 3);
+        let mut vm = VirtualMachine::new(func);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(6))));
+
+        let mut vm = VirtualMachine::new(sum(vec![
+            Push(Value::Integer(0)).into(),
+            Push(Value::Integer(5)).into(),
+            Push(Value::NativeFn(natives::iter::range)).into(),
+            Call(2).into(),
+        ]));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(10))));
+
+        let mut vm = VirtualMachine::new(sum(vec![Push(Value::Integer(7)).into()]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::BadType(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn string_switches_jump_by_case() {
+        use crate::bytecode::compat::rewrite;
+        use crate::bytecode::ops::*;
+        use crate::bytecode::serialize::{load, save, NativeTable};
+        use crate::bytecode::verify::verify;
+
+        // match cmd { "start" => 1, "stop" => 2, _ => 0 }
+        let dispatch = |cmd: Value| {
+            let cases = [("start".into(), 0), ("stop".into(), 2)];
+            function(vec![
+                Push(cmd).into(),
+                SwitchStr::new(cases, 4).into(),
+                Push(Value::Integer(1)).into(),
+                Return.into(),
+                Push(Value::Integer(2)).into(),
+                Return.into(),
+                Push(Value::Integer(0)).into(),
+                Return.into(),
+            ])
+        };
+        let run = |func: Function| VirtualMachine::new(func).run_until_exited();
+        assert!(matches!(
+            run(dispatch(Value::Str("start".into()))),
+            Ok(Value::Integer(1))
+        ));
+        assert!(matches!(
+            run(dispatch(Value::Str("stop".into()))),
+            Ok(Value::Integer(2))
+        ));
+        assert!(matches!(
+            run(dispatch(Value::Str("halt".into()))),
+            Ok(Value::Integer(0))
+        ));
+        assert!(matches!(
+            run(dispatch(Value::Integer(1))),
+            Ok(Value::Integer(0))
+        ));
+
+        let func = dispatch(Value::Str("stop".into()));
+        assert!(verify(&func).is_ok());
+        let natives = NativeTable::new();
+        let loaded = load(&save(&func, &natives).ok().unwrap(), &natives)
+            .ok()
+            .unwrap();
+        assert!(matches!(run(loaded), Ok(Value::Integer(2))));
+        // every case is moved along when ops in front of its target grow
+        let grown = rewrite(&func, &|op| match op {
+            Op::Push(Push(Value::Integer(n))) => Some(vec![
+                Push(Value::Integer(*n)).into(),
+                Push(Value::Integer(0)).into(),
+                Add.into(),
+            ]),
+            _ => None,
+        });
+        assert!(matches!(run(grown), Ok(Value::Integer(2))));
+    }
+
+    #[test]
+    fn integer_switches_jump_by_table() {
+        use crate::bytecode::ops::*;
+        use crate::bytecode::serialize::{load, save, NativeTable};
+        use crate::bytecode::verify::verify;
+        use crate::datamodel::Variant;
+
+        // match n { 3 => 30, 4 => 40, 5 => 30, _ => 0 }
+        let dispatch = |n: Value| {
+            function(vec![
+                Push(n).into(),
+                Switch::new(3, vec![0, 2, 0], 4).into(),
+                Push(Value::Integer(30)).into(),
+                Return.into(),
+                Push(Value::Integer(40)).into(),
+                Return.into(),
+                Push(Value::Integer(0)).into(),
+                Return.into(),
+            ])
+        };
+        let run = |func: Function| match VirtualMachine::new(func).run_until_exited() {
+            Ok(Value::Integer(i)) => i,
+            _ => panic!("expected an Integer"),
+        };
+        let results: Vec<_> = [2, 3, 4, 5, 6, i64::MIN]
+            .into_iter()
+            .map(|n| run(dispatch(Value::Integer(n))))
+            .collect();
+        assert_eq!(results, [0, 30, 40, 30, 0, 0]);
+        // enum tags dispatch the same way; anything else takes the default
+        assert_eq!(run(dispatch(Variant::new(4, Value::None).into())), 40);
+        assert_eq!(run(dispatch(Value::Real(4.0))), 0);
+
+        let func = dispatch(Value::Integer(4));
+        assert!(verify(&func).is_ok());
+        let natives = NativeTable::new();
+        let loaded = load(&save(&func, &natives).ok().unwrap(), &natives)
+            .ok()
+            .unwrap();
+        assert_eq!(run(loaded), 40);
+    }
+
+    #[test]
+    fn closures_capture_locals_by_value() {
+        use crate::bytecode::ops::*;
+        use crate::bytecode::serialize::{load, save, NativeTable};
+        use crate::bytecode::verify::verify;
+
+        // |x| x + captured
+        let adder = function(vec![
+            Store(1).into(),
+            Load(1).into(),
+            LoadUpvalue(0).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let make = |adder: &Function| -> [Op; 2] {
+            [
+                Push(adder.clone().into()).into(),
+                MakeClosure {
+                    locals: Rc::from([1]),
+                }
+                .into(),
+            ]
+        };
+        // n = 5; a = closure; n = 7; b = closure; a(10) + b(10)
+        let mut ops = vec![Push(Value::Integer(5)).into(), Store(1).into()];
+        ops.extend(make(&adder));
+        ops.extend([
+            Store(2).into(),
+            Push(Value::Integer(7)).into(),
+            Store(1).into(),
+        ]);
+        ops.extend(make(&adder));
+        ops.extend([
+            Store(3).into(),
+            Push(Value::Integer(10)).into(),
+            Load(2).into(),
+            Call(1).into(),
+            Push(Value::Integer(10)).into(),
+            Load(3).into(),
+            Call(1).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let main = function(ops);
+        assert!(verify(&main).is_ok());
+        let result = VirtualMachine::new(main.clone()).run_until_exited();
+        assert!(matches!(result, Ok(Value::Integer(32))));
+        let natives = NativeTable::new();
+        let loaded = load(&save(&main, &natives).ok().unwrap(), &natives)
+            .ok()
+            .unwrap();
+        let result = VirtualMachine::new(loaded).run_until_exited();
+        assert!(matches!(result, Ok(Value::Integer(32))));
+
+        // a plain call has no upvalues to load
+        let result = VirtualMachine::with_args(adder, vec![Value::Integer(1)]).run_until_exited();
+        assert!(matches!(
+            result.err().unwrap().error,
+            OpError::UpvalueRead(0)
+        ));
+    }
+
+    #[test]
+    fn symbols_compare_by_identity() {
+        use crate::bytecode::ops::*;
+
+        // compares the value it is run with against an interned name
+        let is_interned = |name: &str| {
+            function(vec![
+                Push(Value::Str(name.into())).into(),
+                Intern.into(),
+                Eq.into(),
+                Return.into(),
+            ])
+        };
+        let run = |func: Function, lhs: fn(&mut VirtualMachine) -> Value| {
+            let mut vm = VirtualMachine::new(func);
+            let lhs = lhs(&mut vm);
+            vm.frame.as_mut().unwrap().push(lhs);
+            match vm.run_until_exited() {
+                Ok(Value::Integer(i)) => i,
+                _ => panic!("expected an Integer"),
+            }
+        };
+        assert_eq!(run(is_interned("a"), |vm| vm.intern("a").into()), 1);
+        assert_eq!(run(is_interned("b"), |vm| vm.intern("a").into()), 0);
+        assert_eq!(run(is_interned("a"), |_| Value::Str("a".into())), 0);
+        // another VM's symbols are its own
+        let other = |_: &mut VirtualMachine| {
+            let mut other = VirtualMachine::new(function(vec![]));
+            other.intern("a").into()
+        };
+        assert_eq!(run(is_interned("a"), other), 0);
+
+        let mut vm = VirtualMachine::new(function(vec![]));
+        let a = vm.intern("a");
+        assert!(a == vm.intern("a") && a != vm.intern("b"));
+        assert_eq!(vm.symbols().len(), 2);
+    }
+
+    #[test]
+    fn hot_function_is_promoted() {
+        use crate::datamodel::Identity;
+        use crate::tiering::ThresholdPolicy;
+
+        let callee = function(vec![]);
+        let optimized = function(vec![]);
+        let replacement = optimized.clone();
+        let mut vm = VirtualMachine::new(function(vec![]));
+        // it inspects the frames each call pushes
+        vm.set_leaf_calls(false);
+        vm.set_tiering_policy(Box::new(ThresholdPolicy {
+            invocations: 3,
+            back_edges: u64::MAX,
+            optimize: move |_: &Function| Some(replacement.clone()),
+        }));
+        let mut ran = Vec::new();
+        for _ in 0..4 {
+            assert!(vm
+                .process(OpAction::Call(callee.clone(), Vec::new()))
+                .is_ok());
+            ran.push(vm.frames().next().unwrap().function.identity());
+        }
+        assert_eq!(ran[1], callee.identity());
+        assert_eq!(ran[2], optimized.identity());
+        assert_eq!(ran[3], optimized.identity());
+    }
+
+    #[test]
+    fn stored_results_skip_the_store() {
+        use crate::bytecode::ops::{Call, Load, Push, Return, Store};
+
+        let callee = function(vec![Push(Value::Integer(7)).into(), Return.into()]);
+        let main = function(vec![
+            Push(callee.into()).into(),
+            Call(0).into(),
+            Store(1).into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        for leaf_calls in [true, false] {
+            let mut vm = VirtualMachine::new(main.clone());
+            vm.set_leaf_calls(leaf_calls);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(7))));
+            assert_eq!(vm.usage().steps, 6);
+        }
+    }
+
+    #[test]
+    fn unset_locals_are_strict_or_lenient() {
+        use crate::bytecode::ops::{Load, Pop, Push, Return, Store};
+
+        // local 2 is stored, so local 1 is a gap below it
+        let main = function(vec![
+            Push(Value::Integer(5)).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(main.clone());
+        vm.set_local_names(&main, vec!["module".into(), "total".into()]);
+        let e = vm.run_until_exited().err().unwrap();
+        assert!(matches!(&e.error, OpError::LocalRead(1, Some(name)) if &**name == "total"));
+        assert!(e
+            .to_string()
+            .starts_with("Error: local 1 (total) was never set"));
+
+        let mut vm = VirtualMachine::new(main);
+        vm.set_local_reads(LocalReads::Lenient);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::None)));
+
+        // far past anything stored, too
+        let main = function(vec![
+            Load(200).into(),
+            Pop.into(),
+            Push(Value::None).into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(main.clone());
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::LocalRead(200, None),
+                ..
+            })
+        ));
+        let mut vm = VirtualMachine::new(main);
+        vm.set_local_reads(LocalReads::Lenient);
+        assert!(vm.run_until_exited().is_ok());
+    }
+
+    #[test]
+    fn lenient_reads_survive_failed_leaf_calls() {
+        use crate::bytecode::ops::*;
+
+        let fails = function(vec![
+            Push(Value::Str("x".into())).into(),
+            Neg.into(),
+            Return.into(),
+        ]);
+        let reads = function(vec![Load(5).into(), Return.into()]);
+        // try { fails() } catch {}; reads()
+        let main = function(vec![
+            PushHandler(3).into(),
+            Push(fails.into()).into(),
+            Call(0).into(),
+            PopHandler.into(),
+            Pop.into(),
+            Push(reads.into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(main);
+        vm.set_local_reads(LocalReads::Lenient);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::None)));
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_space() {
+        use crate::bytecode::ops::{Call, JumpIf, Load, Push, Return, Store, Sub};
+
+        // count(f, n) = n == 0 ? 0 : f(f, n - 1), with the call in tail
+        // position
+        let count = function(vec![
+            Store(1).into(),
+            Store(2).into(),
+            Load(2).into(),
+            JumpIf(2).into(),
+            Push(Value::Integer(0)).into(),
+            Return.into(),
+            Load(1).into(),
+            Load(2).into(),
+            Push(Value::Integer(1)).into(),
+            Sub.into(),
+            Load(1).into(),
+            Call(2).into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(count.clone().into()).into(),
+            Push(Value::Integer(5_000)).into(),
+            Push(count.into()).into(),
+            Call(2).into(),
+            Return.into(),
+        ]);
+        for tail_calls in [true, false] {
+            let mut vm = VirtualMachine::new(main.clone());
+            vm.set_tail_calls(tail_calls);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(0))));
+            let frames = vm.usage().peak_frames;
+            // main's own call is a tail call too
+            assert_eq!(frames, if tail_calls { 1 } else { 5_002 });
+        }
+    }
+
+    #[test]
+    fn runaway_recursion_overflows() {
+        use crate::bytecode::ops::{Call, Load, PopHandler, Push, PushHandler, Return, Store};
+
+        // f(f) = f(f), with a handler in main
+        let f = function(vec![
+            Store(1).into(),
+            Load(1).into(),
+            Load(1).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            PushHandler(4).into(),
+            Push(f.clone().into()).into(),
+            Push(f.clone().into()).into(),
+            Call(1).into(),
+            PopHandler.into(),
+            Return.into(),
+        ]);
+        let config = VmConfig {
+            max_call_depth: 50,
+            ..VmConfig::default()
+        };
+        let mut vm = VirtualMachine::with_config(main.clone(), config);
+        let caught = vm.run_until_exited().ok().unwrap();
+        assert!(matches!(
+            exception::parse(&caught),
+            Some((class, _, Value::Integer(50))) if &*class == exception::ERROR
+        ));
+        assert_eq!(vm.usage().peak_frames, 50);
+
+        // without a handler it stops the VM
+        let mut vm = VirtualMachine::with_args(f.clone(), vec![f.into()]);
+        vm.set_max_call_depth(10);
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::StackOverflow(10),
+                ..
+            })
+        ));
+    }
+
+    thread_local! {
+        static RELEASED: std::cell::RefCell<Vec<i64>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn release(args: Vec<Value>) -> Value {
+        if let Value::Integer(i) = args[0] {
+            RELEASED.with(|r| r.borrow_mut().push(i));
+        }
+        Value::None
+    }
+
+    fn released() -> Vec<i64> {
+        RELEASED.with(|r| r.take())
+    }
+
+    #[test]
+    fn scopes_release_however_they_are_left() {
+        use crate::bytecode::ops::*;
+
+        let enter = |i| -> Vec<Op> {
+            vec![
+                Push(Value::Integer(i)).into(),
+                Push(Value::NativeFn(release)).into(),
+                Enter.into(),
+                Pop.into(),
+            ]
+        };
+        // by Exit, and by returning, innermost first
+        let mut ops = [enter(1), enter(2), enter(3)].concat();
+        ops.extend([Exit.into(), Push(Value::None).into(), Return.into()]);
+        let mut vm = VirtualMachine::new(function(ops));
+        assert!(vm.run_until_exited().is_ok());
+        assert_eq!(released(), [3, 2, 1]);
+
+        // by an error unwinding out of a callee
+        let mut callee = enter(4);
+        callee.extend([Push(Value::Str("x".into())).into(), Neg.into()]);
+        let mut vm = VirtualMachine::new(function(vec![
+            PushHandler(3).into(),
+            Push(function(callee).into()).into(),
+            Call(0).into(),
+            Return.into(),
+            Return.into(),
+        ]));
+        assert!(vm.run_until_exited().is_ok());
+        assert_eq!(released(), [4]);
+
+        // a handler inside the scope catching doesn't leave it
+        let mut ops = enter(5);
+        ops.extend([
+            PushHandler(3).into(),
+            Push(Value::Str("x".into())).into(),
+            Throw.into(),
+            Return.into(),
+        ]);
+        ops.extend(enter(6));
+        ops.extend([Exit.into(), Return.into()]);
+        let mut vm = VirtualMachine::new(function(ops));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Str(s)) if &*s == "x"));
+        assert_eq!(released(), [6, 5]);
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::None).into(),
+            Push(Value::Integer(0)).into(),
+            Enter.into(),
+        ]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::BadType(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn step_n_runs_a_slice() {
+        use crate::bytecode::ops::{Add, Push, Return};
+
+        let mut vm = VirtualMachine::new(function(vec![Jump(-1).into()]));
+        assert!(matches!(vm.step_n(100), Ok(VmState::Running)));
+        assert_eq!(vm.usage().steps, 100);
+        // the interrupt is seen at the start of the next slice
+        vm.interrupt_handle().interrupt();
+        assert!(matches!(
+            vm.step_n(100),
+            Err(VmError {
+                error: OpError::Interrupted,
+                ..
+            })
+        ));
+        assert_eq!(vm.usage().steps, 100);
+
+        let mut vm = VirtualMachine::new(function(vec![Jump(-1).into()]));
+        vm.set_fuel(Some(30));
+        assert!(matches!(vm.step_n(20), Ok(VmState::Running)));
+        assert!(matches!(vm.step_n(20), Ok(VmState::OutOfFuel)));
+        assert_eq!((vm.usage().steps, vm.fuel()), (30, Some(0)));
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(2)).into(),
+            Add.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(
+            vm.step_n(100),
+            Ok(VmState::Exited(Value::Integer(3)))
+        ));
+        assert_eq!(vm.usage().steps, 4);
+    }
+
+    #[test]
+    fn fuel_pauses_and_resumes() {
+        use crate::bytecode::ops::{Add, Push, Return};
+
+        let mut vm = VirtualMachine::new(function(vec![Jump(-1).into()]));
+        assert!(matches!(vm.run_with_fuel(10), Ok(VmState::OutOfFuel)));
+        assert!(matches!(vm.run_until_blocked(), Ok(VmState::OutOfFuel)));
+        assert_eq!((vm.usage().steps, vm.fuel()), (10, Some(0)));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::OutOfFuel,
+                ..
+            })
+        ));
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(2)).into(),
+            Add.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_with_fuel(2), Ok(VmState::OutOfFuel)));
+        assert_eq!(vm.frames().next().unwrap().cursor, 2);
+        assert!(matches!(
+            vm.run_with_fuel(5),
+            Ok(VmState::Exited(Value::Integer(3)))
+        ));
+        assert_eq!(vm.fuel(), Some(3));
+    }
+
+    #[test]
+    fn leaf_calls_stay_within_fuel_and_slices() {
+        use crate::bytecode::ops::{Call, Jump, Pop, Push, Return};
+
+        let mut ops = Vec::new();
+        for _ in 0..19 {
+            ops.push(Push(Value::Integer(1)).into());
+            ops.push(Pop.into());
+        }
+        ops.push(Push(Value::None).into());
+        ops.push(Return.into());
+        // loop { callee() }, with a 40 op leaf
+        let main = function(vec![
+            Push(function(ops).into()).into(),
+            Call(0).into(),
+            Pop.into(),
+            Jump(-4).into(),
+        ]);
+        let mut vm = VirtualMachine::new(main.clone());
+        assert!(matches!(vm.run_with_fuel(5), Ok(VmState::OutOfFuel)));
+        assert_eq!(vm.usage().steps, 5);
+        assert!(matches!(vm.run_with_fuel(100), Ok(VmState::OutOfFuel)));
+        assert_eq!(vm.usage().steps, 105);
+
+        let mut vm = VirtualMachine::new(main);
+        assert!(matches!(vm.step_n(10), Ok(VmState::Running)));
+        assert_eq!(vm.usage().steps, 10);
+        assert!(matches!(vm.step_n(1000), Ok(VmState::Running)));
+        assert_eq!(vm.usage().steps, 1010);
+    }
+
+    #[test]
+    fn failed_leaf_calls_check_the_depth() {
+        use crate::bytecode::ops::{Call, Neg, Push, Return};
+
+        let fails = function(vec![
+            Push(Value::Str("x".into())).into(),
+            Neg.into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(fails.into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        for leaf_calls in [true, false] {
+            let mut vm = VirtualMachine::new(main.clone());
+            vm.set_leaf_calls(leaf_calls);
+            vm.set_max_call_depth(1);
+            let e = vm.run_until_exited().err().unwrap();
+            assert!(matches!(e.error, OpError::StackOverflow(1)));
+        }
+    }
+
+    #[test]
+    fn usage_is_accounted() {
+        use crate::bytecode::ops::{Call, Pop, Push, Return};
+
+        fn nop(_args: Vec<Value>) -> Value {
+            Value::None
+        }
+
+        let callee = function(vec![
+            Push(Value::NativeFn(nop)).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::NativeFn(nop)).into(),
+            Call(0).into(),
+            Pop.into(),
+            Push(callee.into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(main);
+        assert!(vm.run_until_exited().is_ok());
+        let usage = vm.take_usage();
+        assert_eq!(usage.steps, 10);
+        assert_eq!(usage.native_calls_to(nop), 2);
+        assert_eq!(usage.peak_frames, 2);
+        // main's module local and Integer, plus the callee's module local
+        // and the native it pushed
+        assert_eq!(usage.peak_values, 4);
+        assert_eq!(vm.usage().steps, 0);
+    }
+}
+
+/// What loading a local that was never stored does.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalReads {
+    /// Fails with `OpError::LocalRead`, so a compiler bug surfaces where
+    /// it happens.
+    Strict,
+    /// Loads `None`.
+    Lenient,
+}
+
+pub struct CallStack {
+    stack: Vec<Value>,
+    locals: Vec<Value>,
+    /// Which locals have been stored, one bit each.
+    stored: [u64; 4],
+    reads: LocalReads,
+    /// What the running closure captured, if the frame runs one.
+    upvalues: Option<Rc<[Value]>>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            stack: Vec::new(),
+            locals: Vec::new(),
+            stored: [0; 4],
+            reads: LocalReads::Strict,
+            upvalues: None,
+        }
+    }
+
+    pub fn set_local_reads(&mut self, reads: LocalReads) {
+        self.reads = reads;
+    }
+
+    fn is_stored(&self, index: u8) -> bool {
+        self.stored[index as usize / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn load(&self, index: u8) -> Result<&Value, OpError> {
+        match (self.is_stored(index), self.reads) {
+            (true, _) => Ok(&self.locals[index as usize]),
+            (false, LocalReads::Lenient) => Ok(&Value::None),
+            (false, LocalReads::Strict) => Err(OpError::LocalRead(index, None)),
+        }
+    }
+
+    fn get_mut_or_resize(&mut self, index: u8) -> &mut Value {
+        self.stored[index as usize / 64] |= 1 << (index % 64);
+        let index = index as usize;
+        if index >= self.locals.len() {
+            self.locals.resize_with(index + 1, || Value::None);
+        }
+        unsafe { self.locals.get_unchecked_mut(index) }
+    }
+
+    pub fn store(&mut self, index: u8, val: Value) {
+        let out = self.get_mut_or_resize(index);
+        *out = val;
+    }
+
+    /// Upvalue `index` of the running closure (see `ops::LoadUpvalue`).
+    pub fn upvalue(&self, index: u8) -> Result<&Value, OpError> {
+        (self.upvalues.as_ref())
+            .and_then(|upvalues| upvalues.get(index as usize))
+            .ok_or(OpError::UpvalueRead(index))
+    }
+
+    pub fn set_upvalues(&mut self, upvalues: Rc<[Value]>) {
+        self.upvalues = Some(upvalues);
+    }
+
+    pub fn swap(&mut self, index: u8, val: &mut Value) {
+        let out = self.get_mut_or_resize(index);
+        swap(out, val);
+    }
+
+    pub fn push(&mut self, val: Value) {
+        self.stack.push(val);
+    }
+
+    pub fn pop(&mut self) -> Result<Value, OpError> {
+        self.stack.pop().ok_or(OpError::StackEmpty)
+    }
+
+    /// The locals, up to the highest stored; gaps hold `None`.
+    pub fn locals(&self) -> &[Value] {
+        &self.locals
+    }
+
+    /// The stack's values, bottom first.
+    pub fn values(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Empties the stack and locals, keeping their allocations.
+    pub fn clear(&mut self) {
+        self.stack.clear();
+        self.locals.clear();
+        self.stored = [0; 4];
+        self.upvalues = None;
+    }
+
+    /// Drops stack values down to `len` of them.
+    pub fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
+    }
+
+    /// How many values the stack and locals hold.
+    pub fn size(&self) -> usize {
+        self.stack.len() + self.locals.len()
+    }
+
+    /// Looks at a stack value without popping it; depth 0 is the top.
+    pub fn peek(&self, depth: usize) -> Option<&Value> {
+        let len = self.stack.len();
+        depth
+            .checked_add(1)
+            .and_then(|d| len.checked_sub(d))
+            .map(|i| &self.stack[i])
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        CallStack::new()
+    }
+}
+
+/// A thread-safe flag that asks a running `VirtualMachine` to stop at the
+/// next op boundary, e.g. from a SIGINT handler installed by the host.
+#[derive(Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Clears the flag, returning whether an interrupt was pending.
+    fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::SeqCst)
+    }
+}
+
+pub struct VirtualMachine {
+    frame: Option<Box<CallFrame>>,
+    interrupt: InterruptHandle,
+    tiering: Option<Tiering>,
+    usage: Usage,
+    /// Frames in the call stack, and values held by all but the innermost.
+    depth: usize,
+    suspended: usize,
+    /// A native call waiting on a stream, retried by the next `step`.
+    blocked: Option<OpAction>,
+    /// A suspended native call waiting on `resume`; see `suspend`.
+    awaiting: Option<Token>,
+    watchdog: Option<Watchdog>,
+    registry: Option<Rc<NativeRegistry>>,
+    resources: Rc<Resources>,
+    resource_limit: Option<usize>,
+    /// Leaf calls are on unless turned off; see `leaf`.
+    leaf_calls: bool,
+    /// Tail calls reuse the caller's place in the chain when turned on;
+    /// see `CallFrame::in_tail_position`.
+    tail_calls: bool,
+    max_call_depth: usize,
+    leaves: LeafCache,
+    /// The stack leaf calls run on, kept for its allocation.
+    scratch: CallStack,
+    /// Ops left to run, if metered.
+    fuel: Option<u64>,
+    /// Where the running `step_n` slice stops, in `usage.steps`.
+    slice_end: Option<u64>,
+    local_reads: LocalReads,
+    /// Local names by function, holding the function so its identity
+    /// isn't reused.
+    local_names: HashMap<usize, (Function, Vec<Str>)>,
+    modules: ModuleTable,
+    sampling: Option<(SampleHandle, Profile)>,
+    op_timings: Option<OpTimings>,
+    profiler: Option<Profiler>,
+    symbols: Interner,
+    /// Values the host functions in flight are holding; see `handles`.
+    shadow: ShadowStack,
+    source_maps: SourceMaps,
+    /// Whether the outermost frame's locals are kept when it returns, in
+    /// `kept_locals`; see `eval`.
+    keep_locals: bool,
+    kept_locals: Option<CallStack>,
+}
+
+/// Frames a call stack may hold by default; see `VmConfig`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Settings for a new `VirtualMachine`. Each has a setter on the VM as
+/// well, for changing it later.
+#[derive(Clone)]
+pub struct VmConfig {
+    /// Most frames the call stack may hold; a call past it fails with
+    /// `OpError::StackOverflow`, which scripts can catch. Leaf calls (see
+    /// `leaf`) take no frame and don't count.
+    pub max_call_depth: usize,
+    pub leaf_calls: bool,
+    pub tail_calls: bool,
+    pub local_reads: LocalReads,
+    pub fuel: Option<u64>,
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            leaf_calls: true,
+            tail_calls: false,
+            local_reads: LocalReads::Strict,
+            fuel: None,
+        }
+    }
+}
+
+impl VirtualMachine {
+    /// A VM for `func` with the default `VmConfig`.
+    pub fn new(func: Function) -> VirtualMachine {
+        VirtualMachine::with_config(func, VmConfig::default())
+    }
+
+    pub fn with_config(func: Function, config: VmConfig) -> VirtualMachine {
+        let mut frame = Box::new(CallFrame::new(func));
+        frame.stack.set_local_reads(config.local_reads);
+        let mut scratch = CallStack::new();
+        scratch.set_local_reads(config.local_reads);
+        VirtualMachine {
+            frame: Some(frame),
+            interrupt: InterruptHandle::default(),
+            tiering: None,
+            usage: Usage::default(),
+            depth: 1,
+            suspended: 0,
+            blocked: None,
+            awaiting: None,
+            watchdog: None,
+            registry: None,
+            resources: Rc::default(),
+            resource_limit: None,
+            leaf_calls: config.leaf_calls,
+            tail_calls: config.tail_calls,
+            max_call_depth: config.max_call_depth,
+            leaves: LeafCache::default(),
+            scratch,
+            fuel: config.fuel,
+            slice_end: None,
+            local_reads: config.local_reads,
+            local_names: HashMap::new(),
+            modules: ModuleTable::new(),
+            sampling: None,
+            op_timings: None,
+            profiler: None,
+            symbols: Interner::new(),
+            shadow: ShadowStack::default(),
+            source_maps: SourceMaps::default(),
+            keep_locals: false,
+            kept_locals: None,
+        }
+    }
+
+    /// A VM that calls `func` with `args`, given first argument first.
+    pub fn with_args(func: Function, args: Vec<Value>) -> VirtualMachine {
+        let mut vm = VirtualMachine::new(func);
+        let frame = vm.frame.as_mut().unwrap();
+        // see `ops::Call` for why the first argument goes on top
+        for arg in args.into_iter().rev() {
+            frame.push(arg);
+        }
+        vm
+    }
+
+    /// A VM for `func`, if it and every function it references pass
+    /// `verify`; otherwise the first function that fails, and why. Use
+    /// this for code that didn't come straight from the compiler.
+    pub fn verified(func: Function) -> Result<VirtualMachine, (Function, VerifyError)> {
+        verify_all(&func)?;
+        Ok(VirtualMachine::new(func))
+    }
+
+    /// Enables hotness counting; `policy` is consulted as functions warm up.
+    pub fn set_tiering_policy(&mut self, policy: Box<dyn TieringPolicy>) {
+        self.tiering = Some(Tiering::new(policy));
+    }
+
+    pub fn tiering(&self) -> Option<&Tiering> {
+        self.tiering.as_ref()
+    }
+
+    /// The host functions `ops::CallHost` calls.
+    pub fn set_registry(&mut self, registry: Rc<NativeRegistry>) {
+        self.registry = Some(registry);
+    }
+
+    /// The userdata this VM's calls opened that hasn't been finalized yet
+    /// (see `resources`), oldest first.
+    pub fn open_resources(&self) -> Vec<Resource> {
+        self.resources.open()
+    }
+
+    /// Caps the resources open at once. A call that leaves more open
+    /// fails with `OpError::TooManyResources`, which scripts can catch.
+    pub fn set_resource_limit(&mut self, limit: Option<usize>) {
+        self.resource_limit = limit;
+    }
+
+    /// Runs `call` with this VM's ledger current, then records where the
+    /// resources it opened came from and checks the cap.
+    fn tracked<T>(&mut self, call: impl FnOnce(&mut Self) -> T) -> (T, Result<(), OpError>) {
+        let mark = self.resources.mark();
+        let previous = resources::set_current(Some(self.resources.clone()));
+        let result = call(self);
+        resources::set_current(previous);
+        if self.resources.mark() > mark {
+            let call_site = render_frames(&self.frame_infos(true));
+            self.resources.annotate_since(mark, &call_site);
+        }
+        let check = match self.resource_limit {
+            Some(limit) if self.resources.len() > limit => Err(OpError::TooManyResources(limit)),
+            _ => Ok(()),
+        };
+        (result, check)
+    }
+
+    /// Turns the leaf call fast path (see `leaf`) on or off. While it is
+    /// off, every call gets a frame, so `frames` shows leaf callees too.
+    pub fn set_leaf_calls(&mut self, on: bool) {
+        self.leaf_calls = on;
+    }
+
+    /// Turns tail calls on or off (they are off by default). While on, a
+    /// call made just before the caller returns replaces the caller's
+    /// frame rather than adding one, so recursion in tail position runs in
+    /// constant space. The caller's `Return` never runs, and replaced
+    /// callers are missing from `frames`, backtraces and the debugger.
+    pub fn set_tail_calls(&mut self, on: bool) {
+        self.tail_calls = on;
+    }
+
+    /// See `VmConfig::max_call_depth`. Frames already active are kept if
+    /// there are more.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Enables hot-loop detection (see `watchdog`).
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Tells the watchdog, if any, that the script is making progress.
+    pub fn progress(&mut self) {
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.progress();
+        }
+    }
+
+    /// The symbol for `name` in this VM's intern table, the one
+    /// `ops::Intern` pushes, for host code to compare against or embed as
+    /// a constant.
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        self.symbols.intern(name)
+    }
+
+    pub fn symbols(&self) -> &Interner {
+        &self.symbols
+    }
 
-use crate::bytecode::{OpAction, OpError, Operation};
-use crate::datamodel::{Function, Value};
+    /// The modules `ops::LoadModule` imports from.
+    pub fn modules(&mut self) -> &mut ModuleTable {
+        &mut self.modules
+    }
 
-pub struct CallFrame {
-    pub parent: Option<Box<CallFrame>>,
-    pub function: Function,
-    pub cursor: usize,
-    pub stack: CallStack,
-}
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
 
-impl CallFrame {
-    pub fn new(function: Function) -> CallFrame {
-        let mut stack = CallStack::new();
-        stack.store(0, function.module.clone().into());
-        CallFrame {
-            parent: None,
-            function,
-            cursor: 0,
-            stack,
+    /// Sets what loading a never-stored local does in this VM, including
+    /// in the frames already active. Strict by default.
+    pub fn set_local_reads(&mut self, reads: LocalReads) {
+        self.local_reads = reads;
+        self.scratch.set_local_reads(reads);
+        let mut frame = self.frame.as_deref_mut();
+        while let Some(f) = frame {
+            f.stack.set_local_reads(reads);
+            frame = f.parent.as_deref_mut();
         }
     }
 
-    pub fn push(&mut self, val: Value) {
-        self.stack.push(val);
+    /// Names `func`'s locals, by index, for `OpError::LocalRead` to report.
+    /// Compilers that keep debug metadata pass it on here.
+    pub fn set_local_names(&mut self, func: &Function, names: Vec<Str>) {
+        self.local_names
+            .insert(func.identity(), (func.clone(), names));
     }
 
-    pub fn jump(&mut self, index: i32) {
-        self.cursor = (self.cursor as isize + index as isize) as usize;
+    /// Where `VmError` frames and `backtrace` find the source spans of ops.
+    pub fn source_maps(&mut self) -> &mut SourceMaps {
+        &mut self.source_maps
     }
 
-    pub fn exec(&mut self) -> Result<OpAction, OpError> {
-        let op = match self.function.ops.get(self.cursor) {
-            Some(op) => op.clone(),
-            None => return Ok(OpAction::Return(Value::None)),
+    /// Fills in the name of the local a `LocalRead` in `func` failed on.
+    fn name_local(&self, e: OpError, func: &Function) -> OpError {
+        match e {
+            OpError::LocalRead(i, None) => {
+                let names = self.local_names.get(&func.identity());
+                let name = names.and_then(|(_, names)| names.get(i as usize)).cloned();
+                OpError::LocalRead(i, name)
+            }
+            e => e,
+        }
+    }
+
+    /// Meters the VM: each op burns one unit of fuel, and once it is gone
+    /// `process` returns `VmState::OutOfFuel` instead of `Running`. A leaf
+    /// call (see `leaf`) is only made when the fuel left covers all of its
+    /// ops, so it never overruns. `None` turns metering off.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// The fuel left, if metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// How many ops can run before the fuel or the running `step_n` slice
+    /// runs out.
+    fn room(&self) -> u64 {
+        let slice = self
+            .slice_end
+            .map_or(u64::MAX, |end| end.saturating_sub(self.usage.steps));
+        slice.min(self.fuel.unwrap_or(u64::MAX))
+    }
+
+    fn burn(&mut self, ops: u64) {
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel = fuel.saturating_sub(ops);
+        }
+    }
+
+    /// Returns the usage so far and starts counting from zero, e.g. after
+    /// each run a host bills for.
+    pub fn take_usage(&mut self) -> Usage {
+        std::mem::take(&mut self.usage)
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Turns on sampling (see `profiler`), returning the handle samples
+    /// are requested through.
+    pub fn sample_handle(&mut self) -> SampleHandle {
+        let (handle, _) = self
+            .sampling
+            .get_or_insert_with(|| (SampleHandle::default(), Profile::default()));
+        handle.clone()
+    }
+
+    /// The samples taken so far, if sampling is on.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.sampling.as_ref().map(|(_, profile)| profile)
+    }
+
+    /// Turns timing of every op on or off (see `profiler::OpTimings`).
+    /// Timing an op covers the call or return it makes, not the callee.
+    pub fn set_op_timing(&mut self, on: bool) {
+        self.op_timings = match on {
+            true => Some(self.op_timings.take().unwrap_or_default()),
+            false => None,
         };
-        self.cursor += 1;
-        op.exec(&mut self.stack)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
+    pub fn op_timings(&self) -> Option<&OpTimings> {
+        self.op_timings.as_ref()
     }
-}
 
-pub struct CallStack {
-    stack: Vec<Value>,
-    locals: Vec<Value>,
-}
+    /// Returns the op timings so far and starts counting from zero.
+    pub fn take_op_timings(&mut self) -> Option<OpTimings> {
+        self.op_timings.as_mut().map(std::mem::take)
+    }
 
-impl CallStack {
-    pub fn new() -> CallStack {
-        CallStack {
-            stack: Vec::new(),
-            locals: Vec::new(),
+    /// Returns the samples so far and starts a new profile.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        let (_, profile) = self.sampling.as_mut()?;
+        Some(std::mem::take(profile))
+    }
+
+    /// Turns the per-function `Profiler` on or off. Turned on mid-run, it
+    /// counts the frames already active as called from then.
+    pub fn set_profiling(&mut self, on: bool) {
+        if !on {
+            self.profiler = None;
+        } else if self.profiler.is_none() {
+            let mut profiler = Profiler::default();
+            let frames: Vec<&CallFrame> = self.frames().collect();
+            for frame in frames.into_iter().rev() {
+                profiler.enter(&frame.function);
+            }
+            self.profiler = Some(profiler);
         }
     }
 
-    pub fn load(&self, index: u8) -> Result<&Value, OpError> {
-        self.locals
-            .get(index as usize)
-            .ok_or(OpError::LocalRead(index))
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
     }
 
-    fn get_mut_or_resize(&mut self, index: u8) -> &mut Value {
-        let index = index as usize;
-        if index >= self.locals.len() {
-            self.locals.resize_with(index + 1, || Value::None);
+    /// Every value the VM's execution holds right now: each active frame's
+    /// stack, locals and resource scopes, and the handle scopes of the host
+    /// functions in flight. A precise collector treats these as its roots.
+    pub fn roots(&self) -> Vec<Value> {
+        let mut roots = Vec::new();
+        for frame in self.frames() {
+            roots.extend(frame.stack.values().iter().cloned());
+            roots.extend(frame.stack.locals().iter().cloned());
+            if let Some(upvalues) = frame.stack.upvalues.as_ref() {
+                roots.extend(upvalues.iter().cloned());
+            }
+            roots.extend(frame.scopes.iter().map(|s| s.resource.clone()));
         }
-        unsafe { self.locals.get_unchecked_mut(index) }
+        roots.extend(self.shadow.values().iter().cloned());
+        roots
     }
 
-    pub fn store(&mut self, index: u8, val: Value) {
-        let out = self.get_mut_or_resize(index);
-        *out = val;
+    /// Makes `frame` the whole call stack, dropping any frames a failed
+    /// run left behind.
+    pub(crate) fn start(&mut self, frame: Box<CallFrame>) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.clear_active();
+            profiler.enter(&frame.function);
+        }
+        self.frame = Some(frame);
+        self.depth = 1;
+        self.suspended = 0;
+        self.blocked = None;
+        self.awaiting = None;
+        finalize::run_pending();
     }
 
-    pub fn swap(&mut self, index: u8, val: &mut Value) {
-        let out = self.get_mut_or_resize(index);
-        swap(out, val);
+    /// Takes the outermost frame's locals, leaving it with none.
+    pub(crate) fn take_outermost_locals(&mut self) -> Option<CallStack> {
+        let mut frame = self.frame.as_deref_mut()?;
+        while frame.parent.is_some() {
+            frame = frame.parent.as_deref_mut().unwrap();
+        }
+        Some(take(&mut frame.stack))
     }
 
-    pub fn push(&mut self, val: Value) {
-        self.stack.push(val);
+    /// Iterates over the active call frames, innermost first.
+    pub fn frames(&self) -> impl Iterator<Item = &CallFrame> {
+        std::iter::successors(self.frame.as_deref(), |f| f.parent.as_deref())
     }
 
-    pub fn pop(&mut self) -> Result<Value, OpError> {
-        self.stack.pop().ok_or(OpError::StackEmpty)
+    /// Renders the active call frames as a stack trace, innermost first.
+    pub fn backtrace(&self) -> String {
+        let mut out = String::new();
+        for (depth, frame) in self.frames().enumerate() {
+            // parent frames have already advanced past their call op
+            let at = match depth {
+                0 => frame.cursor,
+                _ => frame.cursor - 1,
+            };
+            out.push_str(&format!("#{} at op {}", depth, at));
+            if let Some(span) = self.source_maps.locate(&frame.function, at) {
+                out.push_str(&format!(" ({})", span));
+            }
+            out.push('\n');
+        }
+        out
     }
-}
 
-pub struct VirtualMachine {
-    frame: Option<Box<CallFrame>>,
-}
+    /// Runs until the outermost frame returns. Errors carry the call frames
+    /// active when they were raised. If interrupted, returns
+    /// `OpError::Interrupted` and leaves the frames in place so the host can
+    /// inspect them (see `backtrace`) before discarding the VM. A script
+    /// that waits on a stream stops with `OpError::Blocked`; hosts using
+    /// streams run it with `run_until_blocked` instead.
+    pub fn run_until_exited(&mut self) -> Result<Value, VmError> {
+        let start = Instant::now();
+        let result = self.run();
+        self.usage.wall_time += start.elapsed();
+        result
+    }
 
-impl VirtualMachine {
-    pub fn new(func: Function) -> VirtualMachine {
-        VirtualMachine {
-            frame: Some(Box::new(CallFrame::new(func))),
+    fn run(&mut self) -> Result<Value, VmError> {
+        match self.run_until_blocked()? {
+            VmState::Exited(val) => Ok(val),
+            VmState::HotLoop(_) => Err(self.error(OpError::HotLoop, false)),
+            VmState::OutOfFuel => Err(self.error(OpError::OutOfFuel, false)),
+            _ => Err(self.error(OpError::Blocked, false)),
         }
     }
 
-    pub fn run_until_exited(&mut self) -> Result<Value, OpError> {
+    /// Runs until the outermost frame returns or the script waits on a
+    /// stream. After servicing the stream, call this again to resume; the
+    /// same goes for a `HotLoop` pause the host chooses to ignore.
+    pub fn run_until_blocked(&mut self) -> Result<VmState, VmError> {
         loop {
-            let action = self.step()?;
-            match self.process(action)? {
+            match self.run_op()? {
                 VmState::Running => continue,
-                VmState::Exited(val) => return Ok(val),
+                state => return Ok(state),
             }
         }
     }
 
+    /// Whether a native call is waiting on `resume`.
+    pub fn is_suspended(&self) -> bool {
+        self.awaiting.is_some()
+    }
+
+    /// Hands a suspended native call its result (see `suspend`), which the
+    /// script sees as the native's return value when the VM next runs.
+    /// Returns `false`, dropping `val`, if no call is suspended.
+    pub fn resume(&mut self, val: Value) -> bool {
+        if self.awaiting.take().is_none() {
+            return false;
+        }
+        self.frame.as_mut().unwrap().push(val);
+        finalize::run_pending();
+        true
+    }
+
+    /// Like `run_until_blocked`, metered with `fuel` (see `set_fuel`). When
+    /// it returns `VmState::OutOfFuel`, call this again with more to resume.
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<VmState, VmError> {
+        self.fuel = Some(fuel);
+        self.run_until_blocked()
+    }
+
+    /// Runs up to `n` ops in one loop, checking for an interrupt, a sample
+    /// request and fuel only before the first, for hosts that run a slice
+    /// of the script each time round their own event loop. Returns
+    /// `VmState::Running` if all `n` ran and the script hasn't finished or
+    /// blocked. Fuel is still counted exactly: no more ops run than are
+    /// left. With op timing on, each op is timed and checked as usual.
+    pub fn step_n(&mut self, n: u64) -> Result<VmState, VmError> {
+        let end = self.usage.steps.saturating_add(n);
+        self.slice_end = Some(end);
+        let state = self.step_until(end);
+        self.slice_end = None;
+        state
+    }
+
+    fn step_until(&mut self, end: u64) -> Result<VmState, VmError> {
+        if self.op_timings.is_some() {
+            while self.usage.steps < end {
+                match self.run_op()? {
+                    VmState::Running => continue,
+                    state => return Ok(state),
+                }
+            }
+            return Ok(VmState::Running);
+        }
+        if let Some(state) = self.check_in()? {
+            return Ok(state);
+        }
+        while self.usage.steps < end && self.fuel != Some(0) {
+            let action = self.step().map_err(|e| self.error(e, true))?;
+            match self.process(action).map_err(|e| self.error(e, true))? {
+                VmState::Running => continue,
+                state => return Ok(state),
+            }
+        }
+        match self.fuel {
+            Some(0) => Ok(VmState::OutOfFuel),
+            _ => Ok(VmState::Running),
+        }
+    }
+
+    /// The checks made between ops: fails if interrupted, takes a sample if
+    /// one was asked for, and returns `OutOfFuel` if there is none left.
+    fn check_in(&mut self) -> Result<Option<VmState>, VmError> {
+        if self.interrupt.take() {
+            return Err(self.error(OpError::Interrupted, false));
+        }
+        if let Some((handle, profile)) = self.sampling.as_mut() {
+            if handle.take_request() {
+                let frames = std::iter::successors(self.frame.as_deref(), |f| f.parent.as_deref());
+                handle.publish(profile.record(frames));
+            }
+        }
+        Ok(match self.fuel {
+            Some(0) => Some(VmState::OutOfFuel),
+            _ => None,
+        })
+    }
+
+    /// Steps and processes one op, unless interrupted.
+    pub(crate) fn run_op(&mut self) -> Result<VmState, VmError> {
+        if let Some(state) = self.check_in()? {
+            return Ok(state);
+        }
+        let waiting = self.blocked.is_some() || self.awaiting.is_some();
+        let timed = match (&self.op_timings, waiting, &self.frame) {
+            (Some(_), false, Some(frame)) => frame.function.ops.get(frame.cursor).map(Op::name),
+            _ => None,
+        };
+        let start = timed.as_ref().map(|_| Instant::now());
+        let action = self.step().map_err(|e| self.error(e, true));
+        let state = action.and_then(|a| self.process(a).map_err(|e| self.error(e, true)));
+        if let (Some(op), Some(start), Some(timings)) = (timed, start, self.op_timings.as_mut()) {
+            timings.record(op, start.elapsed());
+        }
+        state
+    }
+
     pub fn step(&mut self) -> Result<OpAction, OpError> {
+        // nothing runs until the suspended call has its result
+        if let Some(token) = self.awaiting {
+            return Ok(OpAction::Suspend(token));
+        }
+        self.usage.steps += 1;
+        self.burn(1);
+        if let Some(action) = self.blocked.take() {
+            return Ok(action);
+        }
         let frame = self.frame.as_mut().unwrap();
-        frame.exec()
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.record(self.depth - 1, &frame.function, frame.cursor);
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.op(&frame.function);
+        }
+        let result = frame.exec();
+        let frame = self.frame.as_ref().unwrap();
+        match result.map_err(|e| self.name_local(e, &frame.function)) {
+            // with a handler to catch it, an error becomes an exception
+            Err(e) if self.is_catching() => match exception::from_op_error(&e) {
+                Some(val) => Ok(OpAction::Throw(val)),
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    fn enter(&mut self, mut callee: Box<CallFrame>) {
+        self.suspended += self.frame.as_ref().map_or(0, |f| f.stack.size());
+        self.depth += 1;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(&callee.function);
+        }
+        swap(&mut self.frame, &mut callee.parent);
+        self.frame = Some(callee);
+    }
+
+    fn leaf_call(&mut self, func: Function, args: Vec<Value>) -> Result<VmState, OpError> {
+        let mut scratch = std::mem::take(&mut self.scratch);
+        // a failed call keeps `scratch` as its frame, leaving this in its place
+        self.scratch.set_local_reads(self.local_reads);
+        let start = self.profiler.as_ref().map(|_| Instant::now());
+        match leaf::run(&func, args, &mut scratch) {
+            Ok((val, steps)) => {
+                self.usage.steps += steps;
+                self.burn(steps);
+                if let (Some(profiler), Some(start)) = (self.profiler.as_mut(), start) {
+                    profiler.leaf(&func, steps, start.elapsed());
+                }
+                let frame = self.frame.as_mut().unwrap();
+                let live = self.suspended + frame.stack.size() + scratch.size();
+                self.usage.observe(live, self.depth + 1);
+                frame.put_result(frame.return_slot(), val);
+                self.scratch = scratch;
+                Ok(VmState::Running)
+            }
+            Err(failed) => {
+                self.usage.steps += failed.steps;
+                self.burn(failed.steps);
+                // build the frame the call would have had
+                let stop = match failed.stop {
+                    leaf::Stop::Error(error) => Err(self.name_local(error, &func)),
+                    leaf::Stop::Call(action) => Ok(action),
+                };
+                if self.depth >= self.max_call_depth {
+                    return self.raise(OpError::StackOverflow(self.max_call_depth));
+                }
+                let mut callee = Box::new(CallFrame::new(func));
+                callee.cursor = failed.cursor;
+                callee.stack = scratch;
+                self.enter(callee);
+                match stop {
+                    Ok(action) => self.process(action),
+                    Err(error) => self.raise(error),
+                }
+            }
+        }
+    }
+
+    /// Throws `e` into the script if a handler will catch it.
+    fn raise(&mut self, e: OpError) -> Result<VmState, OpError> {
+        match self.is_catching() {
+            true => match exception::from_op_error(&e) {
+                Some(val) => self.process(OpAction::Throw(val)),
+                None => Err(e),
+            },
+            false => Err(e),
+        }
+    }
+
+    /// Releases the innermost frame's scopes that were opened with at least
+    /// `handlers` handlers installed, innermost first.
+    fn release_scopes(&mut self, handlers: usize) {
+        let scopes = &mut self.frame.as_mut().unwrap().scopes;
+        let from = scopes.partition_point(|s| s.handlers < handlers);
+        let released: Vec<_> = scopes.drain(from..).rev().collect();
+        for scope in released {
+            self.release(scope);
+        }
+    }
+
+    fn release(&mut self, scope: Scope) {
+        self.usage.count_native(scope.release);
+        (scope.release)(vec![scope.resource]);
+        finalize::run_pending();
+    }
+
+    /// Whether any active frame has a handler installed.
+    fn is_catching(&self) -> bool {
+        self.frames().any(|f| !f.handlers.is_empty())
+    }
+
+    /// Wraps `e` with the active frames. `in_op` is whether the innermost
+    /// frame's last op raised it, rather than it arising between ops.
+    pub(crate) fn error(&self, e: OpError, in_op: bool) -> VmError {
+        VmError {
+            error: e,
+            frames: self.frame_infos(in_op),
+        }
+    }
+
+    fn frame_infos(&self, in_op: bool) -> Vec<FrameInfo> {
+        let frames = self.frames().enumerate().map(|(depth, frame)| {
+            // parent frames have already advanced past their call op
+            let cursor = match (depth, in_op) {
+                (0, false) => frame.cursor,
+                _ => frame.cursor.saturating_sub(1),
+            };
+            FrameInfo {
+                function: frame.function.clone(),
+                cursor,
+                span: self.source_maps.locate(&frame.function, cursor),
+            }
+        });
+        frames.collect()
+    }
+
+    fn hot_loop(&self, iterations: u64) -> HotLoop {
+        let frame = self.frame.as_ref().unwrap();
+        HotLoop {
+            function: frame.function.clone(),
+            cursor: frame.cursor,
+            iterations,
+            backtrace: self.backtrace(),
+            locals: frame.stack.locals().to_vec(),
+            stack: frame.stack.values().to_vec(),
+            trace: self.watchdog.as_ref().map_or(Vec::new(), |w| w.trace()),
+        }
     }
 
     pub fn process(&mut self, action: OpAction) -> Result<VmState, OpError> {
+        let state = self.process_action(action)?;
+        gc::maybe_collect();
+        match state {
+            VmState::Running if self.fuel == Some(0) => Ok(VmState::OutOfFuel),
+            state => Ok(state),
+        }
+    }
+
+    fn process_action(&mut self, action: OpAction) -> Result<VmState, OpError> {
+        // a closure is called as its function, with its upvalues
+        let (action, upvalues) = match action {
+            OpAction::CallClosure(closure, args) => (
+                OpAction::Call(closure.function, args),
+                Some(closure.upvalues),
+            ),
+            action => (action, None),
+        };
         match action {
             OpAction::None => (),
             OpAction::Jump(dest) => {
                 let frame = self.frame.as_mut().unwrap();
+                if let (Some(tiering), true) = (self.tiering.as_mut(), dest < 0) {
+                    tiering.back_edge(&frame.function);
+                }
                 frame.jump(dest);
+                let tripped = match (self.watchdog.as_mut(), dest < 0) {
+                    (Some(watchdog), true) => watchdog.back_edge(&frame.function, frame.cursor),
+                    _ => None,
+                };
+                if let Some(iterations) = tripped {
+                    return Ok(VmState::HotLoop(Box::new(self.hot_loop(iterations))));
+                }
             }
             OpAction::Call(func, args) => {
+                let func = match self.tiering.as_mut() {
+                    Some(tiering) => tiering.invoke(func),
+                    None => func,
+                };
+                // a leaf runs all at once, so only when every op it has fits
+                let fits = func.ops.len() as u64 <= self.room();
+                if upvalues.is_none() && self.leaf_calls && fits && self.leaves.is_leaf(&func) {
+                    return self.leaf_call(func, args);
+                }
                 let mut callee = Box::new(CallFrame::new(func));
+                callee.stack.set_local_reads(self.local_reads);
+                callee.stack.upvalues = upvalues;
                 // NOTE: for expr `Call(A, B, C)`, args is reversed: `[C, B, A]`
                 // so now the order that they will be popped off the stack is
                 // (A, B, C), which is how the stage0 compiler expects them.
@@ -140,34 +2480,416 @@ impl VirtualMachine {
                 for arg in args.into_iter() {
                     callee.push(arg);
                 }
-                swap(&mut self.frame, &mut callee.parent);
-                self.frame = Some(callee);
+                let caller = self.frame.as_mut().unwrap();
+                if self.tail_calls && caller.in_tail_position(self.keep_locals) {
+                    // the callee returns straight to the caller's caller
+                    callee.ret = caller.ret;
+                    callee.parent = caller.parent.take();
+                    if let Some(profiler) = self.profiler.as_mut() {
+                        profiler.exit();
+                        profiler.enter(&callee.function);
+                    }
+                    self.frame = Some(callee);
+                } else if self.depth >= self.max_call_depth {
+                    return self.raise(OpError::StackOverflow(self.max_call_depth));
+                } else {
+                    callee.ret = caller.return_slot();
+                    self.enter(callee);
+                }
             }
+            OpAction::CallClosure(..) => unreachable!("closure calls are made above"),
             OpAction::CallNative(func, args) => {
+                self.usage.count_native(func);
+                let (val, check) = self.tracked(|_| func(args));
+                if let Some((wait, args)) = stream::take_blocked() {
+                    self.blocked = Some(OpAction::CallNative(func, args));
+                    return Ok(match wait {
+                        Wait::Sink(stream) => VmState::WaitingForSink(stream),
+                        Wait::Source(stream) => VmState::WaitingForSource(stream),
+                    });
+                }
+                if let Some(token) = suspend::take_suspended() {
+                    drop(val);
+                    return self.process_action(OpAction::Suspend(token));
+                }
+                if watchdog::take_progress() {
+                    self.progress();
+                }
+                if let Err(e) = check {
+                    drop(val);
+                    return self.raise(e);
+                }
+                let frame = self.frame.as_mut().unwrap();
+                frame.push(val);
+                finalize::run_pending();
+            }
+            OpAction::CallHost(index, args) => {
+                let registry = self.registry.clone();
+                let f = match registry.as_ref().and_then(|r| r.get(index)) {
+                    Some(f) => f,
+                    None => return self.raise(OpError::NoHostFn(index)),
+                };
+                self.shadow.open();
+                for arg in &args {
+                    self.shadow.root(arg.clone());
+                }
+                let (result, check) = self.tracked(|vm| f(&mut VmContext { vm }, &args));
+                self.shadow.close();
+                match result.and_then(|val| check.map(|()| val)) {
+                    Ok(val) => self.frame.as_mut().unwrap().push(val),
+                    Err(e) => return self.raise(e),
+                }
+                finalize::run_pending();
+            }
+            OpAction::PushHandler(offset) => {
                 let frame = self.frame.as_mut().unwrap();
-                frame.push(func(args));
+                frame.handlers.push(Handler {
+                    target: (frame.cursor as isize + offset as isize) as usize,
+                    stack: frame.stack.values().len(),
+                });
+            }
+            OpAction::PopHandler => {
+                self.frame.as_mut().unwrap().handlers.pop();
+            }
+            OpAction::Enter(resource, release) => {
+                let frame = self.frame.as_mut().unwrap();
+                frame.scopes.push(Scope {
+                    resource,
+                    release,
+                    handlers: frame.handlers.len(),
+                });
+            }
+            OpAction::Exit => {
+                if let Some(scope) = self.frame.as_mut().unwrap().scopes.pop() {
+                    self.release(scope);
+                }
+            }
+            OpAction::Throw(val) => {
+                // leave the frames in place for the backtrace if nothing
+                // will catch it
+                if !self.is_catching() {
+                    return Err(OpError::Uncaught(val));
+                }
+                loop {
+                    let frame = self.frame.as_mut().unwrap();
+                    if let Some(handler) = frame.handlers.pop() {
+                        // scopes opened inside the handler's code are left
+                        let installed = frame.handlers.len() + 1;
+                        self.release_scopes(installed);
+                        let frame = self.frame.as_mut().unwrap();
+                        frame.stack.truncate(handler.stack);
+                        frame.push(val);
+                        frame.cursor = handler.target;
+                        break;
+                    }
+                    self.release_scopes(0);
+                    let frame = self.frame.as_mut().unwrap();
+                    if let Some(co) = frame.coroutine.take() {
+                        co.finish();
+                    }
+                    if let Some(name) = frame.init.take() {
+                        self.modules.failed(&name);
+                    }
+                    let parent = frame.parent.take().unwrap();
+                    self.depth -= 1;
+                    self.suspended -= parent.stack.size();
+                    if let Some(profiler) = self.profiler.as_mut() {
+                        profiler.exit();
+                    }
+                    self.frame = Some(parent);
+                }
+            }
+            OpAction::Resume(co, val) => {
+                let mut top = match co.take() {
+                    Some(top) => top,
+                    None => return self.raise(OpError::NotResumable),
+                };
+                let (frames, below) = chain_size(&top);
+                if self.depth + frames > self.max_call_depth {
+                    co.suspend(top);
+                    return self.raise(OpError::StackOverflow(self.max_call_depth));
+                }
+                let resumer = self.frame.take().unwrap();
+                self.depth += frames;
+                self.suspended += resumer.stack.size() + below;
+                top.push(val);
+                if let Some(profiler) = self.profiler.as_mut() {
+                    let frames: Vec<&CallFrame> =
+                        std::iter::successors(Some(&*top), |f| f.parent.as_deref()).collect();
+                    for frame in frames.into_iter().rev() {
+                        profiler.enter(&frame.function);
+                    }
+                }
+                let mut base = &mut *top;
+                while base.parent.is_some() {
+                    base.stack.set_local_reads(self.local_reads);
+                    base = base.parent.as_deref_mut().unwrap();
+                }
+                base.stack.set_local_reads(self.local_reads);
+                base.ret = resumer.return_slot();
+                base.coroutine = Some(co);
+                base.parent = Some(resumer);
+                self.frame = Some(top);
+            }
+            OpAction::Yield(val) => {
+                if !self.frames().any(|f| f.coroutine.is_some()) {
+                    return self.raise(OpError::NotInCoroutine);
+                }
+                let mut top = self.frame.take().unwrap();
+                let mut base = &mut *top;
+                while base.coroutine.is_none() {
+                    base = base.parent.as_deref_mut().unwrap();
+                }
+                let co = base.coroutine.take().unwrap();
+                let ret = base.ret;
+                let mut resumer = base.parent.take().unwrap();
+                let (frames, below) = chain_size(&top);
+                self.depth -= frames;
+                self.suspended -= resumer.stack.size() + below;
+                if let Some(profiler) = self.profiler.as_mut() {
+                    for _ in 0..frames {
+                        profiler.exit();
+                    }
+                }
+                co.suspend(top);
+                resumer.put_result(ret, val);
+                self.frame = Some(resumer);
+            }
+            OpAction::LoadModule(name) => match self.modules.import(&name) {
+                Ok(Import::Loaded(val)) => self.frame.as_mut().unwrap().push(val),
+                Ok(Import::Init(_)) if self.depth >= self.max_call_depth => {
+                    self.modules.failed(&name);
+                    return self.raise(OpError::StackOverflow(self.max_call_depth));
+                }
+                Ok(Import::Init(init)) => {
+                    let mut frame = Box::new(CallFrame::new(init));
+                    frame.stack.set_local_reads(self.local_reads);
+                    frame.ret = self.frame.as_ref().unwrap().return_slot();
+                    frame.init = Some(name);
+                    self.enter(frame);
+                }
+                Err(e) => return self.raise(e),
+            },
+            OpAction::Suspend(token) => {
+                self.awaiting = Some(token);
+                return Ok(VmState::Suspended(token));
+            }
+            OpAction::Intern(name) => {
+                let symbol = self.symbols.intern(&name);
+                self.frame.as_mut().unwrap().push(symbol.into());
             }
             OpAction::Return(val) => {
+                self.release_scopes(0);
                 let frame = self.frame.as_mut().unwrap();
+                if let Some(co) = frame.coroutine.take() {
+                    co.finish();
+                }
+                if let Some(name) = frame.init.take() {
+                    self.modules.loaded(&name, val.clone());
+                }
                 let mut parent = None;
                 swap(&mut frame.parent, &mut parent);
+                self.depth -= 1;
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.exit();
+                }
                 match parent {
                     Some(mut parent) => {
-                        parent.push(val);
+                        self.suspended -= parent.stack.size();
+                        parent.put_result(frame.ret, val);
                         self.frame = Some(parent);
+                        finalize::run_pending();
                     }
                     None => {
+                        if self.keep_locals {
+                            self.kept_locals = Some(take(&mut frame.stack));
+                        }
                         self.frame = None;
                         return Ok(VmState::Exited(val));
                     }
                 }
             }
         }
+        let live = self.frame.as_ref().map_or(0, |f| f.stack.size());
+        self.usage.observe(self.suspended + live, self.depth);
         Ok(VmState::Running)
     }
 }
 
+/// How many frames a chain has, and how many values all but the first of
+/// them hold.
+fn chain_size(top: &CallFrame) -> (usize, usize) {
+    let below = std::iter::successors(top.parent.as_deref(), |f| f.parent.as_deref());
+    below.fold((1, 0), |(frames, values), f| {
+        (frames + 1, values + f.stack.size())
+    })
+}
+
+/// What a host function in a `NativeRegistry` sees of the VM calling it.
+pub struct VmContext<'a> {
+    vm: &'a mut VirtualMachine,
+}
+
+impl VmContext<'_> {
+    /// Frames in the call stack, including the caller's.
+    pub fn depth(&self) -> usize {
+        self.vm.depth
+    }
+
+    pub fn backtrace(&self) -> String {
+        self.vm.backtrace()
+    }
+
+    pub fn usage(&self) -> &Usage {
+        self.vm.usage()
+    }
+
+    /// Signals progress to the VM's watchdog (see `watchdog`).
+    pub fn progress(&mut self) {
+        self.vm.progress();
+    }
+
+    /// Roots `val` in this call's handle scope (see `handles`), for a
+    /// value the function holds across a call back into the VM.
+    pub fn root(&mut self, val: Value) -> Handle {
+        self.vm.shadow.root(val)
+    }
+
+    /// The value behind a handle this call rooted.
+    pub fn handle(&self, handle: Handle) -> Value {
+        self.vm.shadow.get(handle).cloned().unwrap_or(Value::None)
+    }
+
+    /// Calls a script function or native with `args`, first argument
+    /// first. Until host functions can re-enter the VM, a script function
+    /// runs to completion in a VM of its own, with this one's host
+    /// functions but none of its frames; its errors come back as they are.
+    pub fn call(&mut self, callee: &Value, args: Vec<Value>) -> Result<Value, OpError> {
+        match callee {
+            Value::NativeFn(f) => Ok(f(args.into_iter().rev().collect())),
+            Value::Function(func) => self.run_nested(VirtualMachine::with_args(func.clone(), args)),
+            Value::Closure(closure) => {
+                let mut vm = VirtualMachine::with_args(closure.function.clone(), args);
+                let frame = vm.frame.as_mut().unwrap();
+                frame.stack.set_upvalues(closure.upvalues.clone());
+                self.run_nested(vm)
+            }
+            other => Err(OpError::BadType(other.get_type())),
+        }
+    }
+
+    /// Runs `nested` to completion under this VM's limits: its interrupt,
+    /// a watchdog like its own and what is left of its fuel and call
+    /// depth. The ops `nested` runs are charged to this VM.
+    fn run_nested(&mut self, mut nested: VirtualMachine) -> Result<Value, OpError> {
+        let vm = &mut *self.vm;
+        nested.registry = vm.registry.clone();
+        nested.interrupt = vm.interrupt.clone();
+        nested.watchdog = vm.watchdog.as_ref().map(Watchdog::fresh);
+        nested.fuel = vm.fuel;
+        nested.max_call_depth = vm.max_call_depth.saturating_sub(vm.depth).max(1);
+        let result = nested.run_until_exited();
+        vm.fuel = nested.fuel;
+        vm.usage.steps += nested.usage.steps;
+        result.map_err(|e| e.error)
+    }
+}
+
+impl Drop for VirtualMachine {
+    /// Finalizes userdata only the VM held (see `finalize`).
+    fn drop(&mut self) {
+        self.frame = None;
+        self.blocked = None;
+        self.watchdog = None;
+        finalize::run_pending();
+    }
+}
+
 pub enum VmState {
     Running,
     Exited(Value),
+    /// The script is writing to a full stream.
+    WaitingForSink(Rc<Stream>),
+    /// The script is reading from an empty, open stream.
+    WaitingForSource(Rc<Stream>),
+    /// The watchdog found a loop spinning without progress.
+    HotLoop(Box<HotLoop>),
+    /// The fuel ran out (see `set_fuel`); the next op hasn't run.
+    OutOfFuel,
+    /// A native is waiting on the host (see `suspend`); the VM goes no
+    /// further until `resume` gives it the result.
+    Suspended(Token),
+}
+
+/// A frame in a `VmError`'s backtrace.
+#[derive(Clone)]
+pub struct FrameInfo {
+    pub function: Function,
+    /// The failing op in the innermost frame, and the call being made in
+    /// the others.
+    pub cursor: usize,
+    /// Where in the original source that op came from, if the function has
+    /// a source map (see `sourcemap`).
+    pub span: Option<Span>,
+}
+
+impl FrameInfo {
+    /// The function's slot in its module, e.g. `slot2`, or `fn_` and its
+    /// identity if it isn't in one (as in `graph::call_graph_dot`).
+    pub fn name(&self) -> String {
+        let module = &self.function.module;
+        let slot = (0..module.len()).find(|i| match module.get(*i) {
+            Some(Value::Function(f)) => f.identity() == self.function.identity(),
+            _ => false,
+        });
+        match slot {
+            Some(i) => format!("slot{}", i),
+            None => format!("fn_{:x}", self.function.identity()),
+        }
+    }
+}
+
+/// One line per frame, innermost first.
+fn render_frames(frames: &[FrameInfo]) -> String {
+    let mut out = String::new();
+    for (depth, frame) in frames.iter().enumerate() {
+        out.push_str(&format!(
+            "#{} {} at op {}",
+            depth,
+            frame.name(),
+            frame.cursor
+        ));
+        if let Some(span) = &frame.span {
+            out.push_str(&format!(" ({})", span));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// An `OpError` and the call frames active when it was raised, innermost
+/// first.
+pub struct VmError {
+    pub error: OpError,
+    pub frames: Vec<FrameInfo>,
+}
+
+impl VmError {
+    /// The error followed by one line per frame, innermost first.
+    pub fn backtrace(&self) -> String {
+        format!("{}\n{}", self, render_frames(&self.frames))
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match exception::from_op_error(&self.error).and_then(|e| exception::parse(&e)) {
+            Some((class, message, _)) => write!(f, "{}: {}", class, message),
+            None => write!(f, "{}", bytecode::spec::error_name(&self.error)),
+        }?;
+        match self.frames.first() {
+            Some(frame) => write!(f, " in {} at op {}", frame.name(), frame.cursor),
+            None => Ok(()),
+        }
+    }
 }
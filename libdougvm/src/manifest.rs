@@ -0,0 +1,550 @@
+//! Package manifests, declaring the modules a script depends on and the
+//! versions it works with, and a resolver that finds those packages on a
+//! `Vfs`, checks them and registers them with a `ModuleTable`, so shared
+//! script libraries can be installed once and imported by name.
+//!
+//! A manifest is a line per entry; blank lines and `#` comments are
+//! skipped:
+//!
+//! ```text
+//! package app 0.3.0
+//! require strings 1.2
+//! require net 0.4.1
+//! ```
+//!
+//! Package names are letters, digits, `_` and `-`. A requirement accepts
+//! its version or any later compatible one: the same major version, or
+//! for `0.x` versions the same minor, as `^` does for Cargo. Missing
+//! version parts are zero.
+//!
+//! The resolver looks for a package under each of its roots in turn, as
+//! `<root>/<name>/<version>/`, holding the package's own manifest in
+//! `package.manifest` and its compiled top-level code in `package.dgbc`.
+//! Each package gets the highest version the first requirement to reach
+//! it allows; a later requirement that version doesn't satisfy is a
+//! conflict rather than a reason to pick again.
+//!
+//! A lockfile (see `Lock`) records a hash of each package's code, so
+//! linking fails if a package has changed since it was locked.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use crate::bytecode::serialize::{load, LoadError, NativeTable};
+use crate::bytecode::verify::{verify_all, VerifyError};
+use crate::modules::ModuleTable;
+use crate::vfs::Vfs;
+
+/// The manifest file in a package's directory.
+pub const MANIFEST_FILE: &str = "package.manifest";
+/// The compiled code in a package's directory.
+pub const CODE_FILE: &str = "package.dgbc";
+
+/// Whether `name` can name a package, and so safely be part of a path.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && (name.bytes()).all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    /// Parses `1`, `1.2` or `1.2.3`.
+    pub fn parse(text: &str) -> Option<Version> {
+        let parts: Vec<u32> = (text.split('.'))
+            .map(|part| part.parse().ok())
+            .collect::<Option<_>>()?;
+        let (major, minor, patch) = match parts[..] {
+            [major] => (major, 0, 0),
+            [major, minor] => (major, minor, 0),
+            [major, minor, patch] => (major, minor, patch),
+            _ => return None,
+        };
+        Some(Version {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// The versions of a dependency a package works with.
+#[derive(Clone, Copy)]
+pub struct Requirement {
+    pub min: Version,
+}
+
+impl Requirement {
+    pub fn matches(&self, version: &Version) -> bool {
+        let min = &self.min;
+        let compatible = match min.major {
+            0 => version.major == 0 && version.minor == min.minor,
+            major => version.major == major,
+        };
+        compatible && *version >= *min
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "^{}", self.min)
+    }
+}
+
+#[derive(Clone)]
+pub struct Manifest {
+    pub name: String,
+    pub version: Version,
+    /// Dependencies, in the order the manifest lists them.
+    pub requires: Vec<(String, Requirement)>,
+}
+
+/// Where, and how, a manifest failed to parse.
+pub struct ManifestError {
+    /// From 1; 0 for problems with the manifest as a whole.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.message),
+            line => write!(f, "line {}: {}", line, self.message),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+        let mut package = None;
+        let mut requires = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ManifestError {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap().trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (name, version) = match words[..] {
+                [] => continue,
+                [_, name, version] => {
+                    let parsed = Version::parse(version);
+                    (
+                        name,
+                        parsed.ok_or_else(|| error(format!("bad version {}", version)))?,
+                    )
+                }
+                _ => return Err(error(format!("expected 3 words, found {}", words.len()))),
+            };
+            if !is_valid_name(name) {
+                return Err(error(format!("bad package name {}", name)));
+            }
+            match words[0] {
+                "package" if package.is_some() => {
+                    return Err(error("a second package line".to_string()))
+                }
+                "package" => package = Some((name.to_string(), version)),
+                "require" if requires.iter().any(|(n, _)| n == name) => {
+                    return Err(error(format!("{} is required twice", name)))
+                }
+                "require" => requires.push((name.to_string(), Requirement { min: version })),
+                other => return Err(error(format!("unknown entry {}", other))),
+            }
+        }
+        let (name, version) = package.ok_or_else(|| ManifestError {
+            line: 0,
+            message: "no package line".to_string(),
+        })?;
+        Ok(Manifest {
+            name,
+            version,
+            requires,
+        })
+    }
+}
+
+/// The hashes of the packages an app was linked against. A lockfile has a
+/// line per package, `lock <name> <version> <hash>`, with the hash being
+/// `code_hash` of its code in hex; blank lines and `#` comments are
+/// skipped. `Display` writes one back out.
+#[derive(Clone, Default)]
+pub struct Lock {
+    hashes: BTreeMap<(String, Version), u64>,
+}
+
+/// The hash a lockfile records for a package's code: 64-bit FNV-1a, which
+/// catches a package changed since it was locked.
+pub fn code_hash(code: &[u8]) -> u64 {
+    code.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Lock {
+    pub fn parse(text: &str) -> Result<Lock, ManifestError> {
+        let mut lock = Lock::default();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ManifestError {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap().trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (name, version, hash) = match words[..] {
+                [] => continue,
+                ["lock", name, version, hash] => (name, version, hash),
+                [other, ..] if other != "lock" => {
+                    return Err(error(format!("unknown entry {}", other)))
+                }
+                _ => return Err(error(format!("expected 4 words, found {}", words.len()))),
+            };
+            if !is_valid_name(name) {
+                return Err(error(format!("bad package name {}", name)));
+            }
+            let version =
+                Version::parse(version).ok_or_else(|| error(format!("bad version {}", version)))?;
+            let hash =
+                u64::from_str_radix(hash, 16).map_err(|_| error(format!("bad hash {}", hash)))?;
+            let old = lock.hashes.insert((name.to_string(), version), hash);
+            if old.is_some() {
+                return Err(error(format!("{} {} is locked twice", name, version)));
+            }
+        }
+        Ok(lock)
+    }
+
+    /// The hash recorded for `name` at `version`, if any.
+    pub fn get(&self, name: &str, version: &Version) -> Option<u64> {
+        self.hashes.get(&(name.to_string(), *version)).copied()
+    }
+
+    pub fn insert(&mut self, name: &str, version: Version, hash: u64) {
+        self.hashes.insert((name.to_string(), version), hash);
+    }
+}
+
+impl fmt::Display for Lock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ((name, version), hash) in &self.hashes {
+            writeln!(f, "lock {} {} {:016x}", name, version, hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// A dependency the resolver found.
+#[derive(Clone)]
+pub struct Package {
+    pub manifest: Manifest,
+    /// The directory holding it.
+    pub dir: String,
+}
+
+pub enum ResolveError {
+    /// No root has a version of the package the requirement allows.
+    Missing {
+        name: String,
+        requirement: Requirement,
+    },
+    /// Another requirement already chose a version this one doesn't allow.
+    Conflict {
+        name: String,
+        chosen: Version,
+        requirement: Requirement,
+    },
+    /// Packages requiring each other, the first repeated at the end.
+    Cycle(Vec<String>),
+    /// The manifest at this path names a different package or version than
+    /// the directory it is in.
+    Mismatch(String),
+    /// The code at this path doesn't have the hash the lockfile records.
+    Changed(String),
+    Manifest(String, ManifestError),
+    Io(String, io::Error),
+    Load(String, LoadError),
+    Invalid(String, VerifyError),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::Missing { name, requirement } => {
+                write!(f, "no version of {} matches {}", name, requirement)
+            }
+            ResolveError::Conflict {
+                name,
+                chosen,
+                requirement,
+            } => write!(
+                f,
+                "{} {} was chosen, but {} is required",
+                name, chosen, requirement
+            ),
+            ResolveError::Cycle(names) => write!(f, "dependency cycle: {}", names.join(" -> ")),
+            ResolveError::Mismatch(path) => write!(f, "{}: doesn't match its directory", path),
+            ResolveError::Changed(path) => write!(f, "{}: changed since it was locked", path),
+            ResolveError::Manifest(path, e) => write!(f, "{}: {}", path, e),
+            ResolveError::Io(path, e) => write!(f, "{}: {}", path, e),
+            ResolveError::Load(path, e) => write!(f, "{}: {}", path, e),
+            ResolveError::Invalid(path, e) => write!(f, "{}: {}", path, e),
+        }
+    }
+}
+
+pub struct Resolver {
+    vfs: Rc<dyn Vfs>,
+    roots: Vec<String>,
+    lock: Lock,
+}
+
+impl Resolver {
+    pub fn new(vfs: Rc<dyn Vfs>) -> Resolver {
+        Resolver {
+            vfs,
+            roots: Vec::new(),
+            lock: Lock::default(),
+        }
+    }
+
+    /// Checks the code `link` loads against `lock`. Packages it doesn't
+    /// record are linked unchecked.
+    pub fn set_lock(&mut self, lock: Lock) {
+        self.lock = lock;
+    }
+
+    /// Adds a directory to search for packages, after those added before.
+    pub fn add_root(&mut self, root: &str) {
+        self.roots.push(root.trim_end_matches('/').to_string());
+    }
+
+    /// The highest version of `name` that `requirement` allows; an earlier
+    /// root wins between equal versions.
+    fn locate(&self, name: &str, requirement: &Requirement) -> Result<Package, ResolveError> {
+        let mut best: Option<(Version, String)> = None;
+        for root in &self.roots {
+            let entries = self.vfs.list(&format!("{}/{}", root, name));
+            for entry in entries.unwrap_or_default() {
+                let version = match Version::parse(&entry) {
+                    Some(v) if requirement.matches(&v) => v,
+                    _ => continue,
+                };
+                if best.as_ref().is_none_or(|(b, _)| version > *b) {
+                    best = Some((version, format!("{}/{}/{}", root, name, entry)));
+                }
+            }
+        }
+        let (version, dir) = best.ok_or_else(|| ResolveError::Missing {
+            name: name.to_string(),
+            requirement: *requirement,
+        })?;
+        let path = format!("{}/{}", dir, MANIFEST_FILE);
+        let bytes = (self.vfs.read(&path)).map_err(|e| ResolveError::Io(path.clone(), e))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let manifest = match Manifest::parse(&text) {
+            Ok(m) if m.name == name && m.version == version => m,
+            Ok(_) => return Err(ResolveError::Mismatch(path)),
+            Err(e) => return Err(ResolveError::Manifest(path, e)),
+        };
+        Ok(Package { manifest, dir })
+    }
+
+    /// Every package `root` depends on, directly or not, each after the
+    /// packages it depends on.
+    pub fn resolve(&self, root: &Manifest) -> Result<Vec<Package>, ResolveError> {
+        let mut chosen = HashMap::new();
+        let mut order = Vec::new();
+        self.visit(root, &mut Vec::new(), &mut chosen, &mut order)?;
+        Ok(order)
+    }
+
+    fn visit(
+        &self,
+        manifest: &Manifest,
+        path: &mut Vec<String>,
+        chosen: &mut HashMap<String, Version>,
+        order: &mut Vec<Package>,
+    ) -> Result<(), ResolveError> {
+        path.push(manifest.name.clone());
+        for (name, requirement) in &manifest.requires {
+            if let Some(at) = path.iter().position(|n| n == name) {
+                let mut cycle = path[at..].to_vec();
+                cycle.push(name.clone());
+                return Err(ResolveError::Cycle(cycle));
+            }
+            match chosen.get(name) {
+                Some(version) if requirement.matches(version) => {}
+                Some(version) => {
+                    return Err(ResolveError::Conflict {
+                        name: name.clone(),
+                        chosen: *version,
+                        requirement: *requirement,
+                    })
+                }
+                None => {
+                    let package = self.locate(name, requirement)?;
+                    self.visit(&package.manifest, path, chosen, order)?;
+                    chosen.insert(name.clone(), package.manifest.version);
+                    order.push(package);
+                }
+            }
+        }
+        path.pop();
+        Ok(())
+    }
+
+    /// Resolves `root`'s dependencies, then loads and verifies each one's
+    /// code and registers it with `modules` under its package name, in
+    /// dependency order. Nothing is registered if any of them fails.
+    pub fn link(
+        &self,
+        root: &Manifest,
+        modules: &mut ModuleTable,
+        natives: &NativeTable,
+    ) -> Result<Vec<Package>, ResolveError> {
+        let packages = self.resolve(root)?;
+        let mut loaded = Vec::with_capacity(packages.len());
+        for package in &packages {
+            let path = format!("{}/{}", package.dir, CODE_FILE);
+            let bytes = (self.vfs.read(&path)).map_err(|e| ResolveError::Io(path.clone(), e))?;
+            let manifest = &package.manifest;
+            match self.lock.get(&manifest.name, &manifest.version) {
+                Some(hash) if hash != code_hash(&bytes) => return Err(ResolveError::Changed(path)),
+                _ => {}
+            }
+            let init = load(&bytes, natives).map_err(|e| ResolveError::Load(path.clone(), e))?;
+            verify_all(&init).map_err(|(_, e)| ResolveError::Invalid(path.clone(), e))?;
+            loaded.push(init);
+        }
+        for (package, init) in packages.iter().zip(loaded) {
+            modules.register(&package.manifest.name, init);
+        }
+        Ok(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::serialize::save;
+    use crate::datamodel::{Str, Value};
+    use crate::testing::function;
+    use crate::vfs::MemoryFs;
+    use crate::VirtualMachine;
+
+    /// Installs a package whose module's value is `value`.
+    fn install(fs: &MemoryFs, name: &str, version: &str, requires: &[&str], value: i64) {
+        let dir = format!("lib/{}/{}", name, version);
+        let mut manifest = format!("package {} {}\n", name, version);
+        requires
+            .iter()
+            .for_each(|r| manifest += &format!("require {}\n", r));
+        fs.insert(&format!("{}/{}", dir, MANIFEST_FILE), manifest.as_bytes());
+        let init = function(vec![Push(Value::Integer(value)).into(), Return.into()]);
+        let code = save(&init, &NativeTable::new()).ok().unwrap();
+        fs.insert(&format!("{}/{}", dir, CODE_FILE), &code);
+    }
+
+    fn resolver(fs: MemoryFs) -> Resolver {
+        let mut resolver = Resolver::new(Rc::new(fs));
+        resolver.add_root("lib/");
+        resolver
+    }
+
+    fn app(text: &str) -> Manifest {
+        Manifest::parse(text).ok().unwrap()
+    }
+
+    #[test]
+    fn dependencies_link_in_order() {
+        let fs = MemoryFs::new();
+        install(&fs, "text", "1.2.0", &[], 120);
+        install(&fs, "text", "1.4.1", &[], 141);
+        install(&fs, "text", "2.0.0", &[], 200);
+        install(&fs, "net", "0.4.0", &["text 1.3"], 40);
+        install(&fs, "net", "0.5.0", &["text 1.3"], 50);
+        let resolver = resolver(fs);
+        let root =
+            app("# an app\npackage app 0.1.0\n\nrequire net 0.4\nrequire text 1.0 # shared\n");
+
+        let mut vm = VirtualMachine::new(function(vec![
+            LoadModule(Str::from("net")).into(),
+            Return.into(),
+        ]));
+        let packages = resolver.link(&root, vm.modules(), &NativeTable::new());
+        let names: Vec<_> = (packages.ok().unwrap().iter())
+            .map(|p| format!("{} {}", p.manifest.name, p.manifest.version))
+            .collect();
+        assert_eq!(names, ["text 1.4.1", "net 0.4.0"]);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(40))));
+        assert!(matches!(vm.modules().get("net"), Some(Value::Integer(40))));
+    }
+
+    #[test]
+    fn unresolvable_dependencies_are_errors() {
+        let fs = MemoryFs::new();
+        install(&fs, "text", "1.2.0", &[], 0);
+        install(&fs, "net", "0.4.0", &["text 1.3"], 0);
+        install(&fs, "a", "1.0.0", &["b 1"], 0);
+        install(&fs, "b", "1.0.0", &["a 1"], 0);
+        let resolver = resolver(fs);
+        let resolve = |text: &str| resolver.resolve(&app(text)).err().unwrap();
+
+        assert!(matches!(
+            resolve("package app 1.0\nrequire net 0.4"),
+            ResolveError::Missing { name, .. } if name == "text"
+        ));
+        assert!(matches!(
+            resolve("package app 1.0\nrequire text 1.0\nrequire net 0.4"),
+            ResolveError::Conflict { chosen, .. } if chosen.to_string() == "1.2.0"
+        ));
+        match resolve("package app 1.0\nrequire a 1.0") {
+            ResolveError::Cycle(names) => assert_eq!(names, ["a", "b", "a"]),
+            e => panic!("expected a cycle, got {}", e),
+        }
+        let e = Manifest::parse("package app 1.0\nrequire text one")
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "line 2: bad version one");
+        assert!(Manifest::parse("require text 1.0").is_err());
+        assert!(Manifest::parse("package app 1.0\nrequire a 1\nrequire a 2").is_err());
+        let e = Manifest::parse("package app 1.0\nrequire ../../etc 1")
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "line 2: bad package name ../../etc");
+    }
+
+    #[test]
+    fn locked_packages_must_be_unchanged() {
+        let fs = MemoryFs::new();
+        install(&fs, "text", "1.2.0", &[], 12);
+        let code = fs.read("lib/text/1.2.0/package.dgbc").unwrap();
+        let mut lock = Lock::default();
+        lock.insert("text", Version::parse("1.2").unwrap(), code_hash(&code));
+        let lock = Lock::parse(&format!("# locked\n{}", lock)).ok().unwrap();
+        let root = app("package app 1.0\nrequire text 1");
+        let link = |fs: MemoryFs| {
+            let mut resolver = resolver(fs);
+            resolver.set_lock(lock.clone());
+            resolver.link(&root, &mut ModuleTable::new(), &NativeTable::new())
+        };
+        assert!(link(fs).is_ok());
+        let changed = MemoryFs::new();
+        install(&changed, "text", "1.2.0", &[], 13);
+        assert!(matches!(link(changed), Err(ResolveError::Changed(_))));
+        assert!(Lock::parse("lock text 1.2 xyz").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! Versioned records and migrations, so data a script saved (e.g. in the
+//! `kv` store) still loads after the script changes the shape of it.
+//!
+//! A versioned record is a `Tuple` of `(kind, version, data)`: a `Str`
+//! naming what the data is, an `Integer` version, and the data itself.
+//! The host or the script registers one migration per kind and version,
+//! each turning version `n` data into version `n + 1`; `upgrade` applies
+//! them in turn until none is left, so a kind's current version is one
+//! past its newest migration.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::datamodel::{Function, Str, Tuple, Value};
+use crate::VirtualMachine;
+
+pub enum Migration {
+    Host(Rc<dyn Fn(Value) -> Result<Value, String>>),
+    /// Called with the old data, returning the new.
+    Script(Function),
+}
+
+impl Migration {
+    fn apply(&self, data: Value) -> Result<Value, String> {
+        match self {
+            Migration::Host(f) => f(data),
+            Migration::Script(func) => VirtualMachine::with_args(func.clone(), vec![data])
+                .run_until_exited()
+                .map_err(|_| "migration failed".to_string()),
+        }
+    }
+}
+
+/// The parts of a versioned record, or `None` if `val` isn't one.
+pub fn parse_record(val: &Value) -> Option<(Str, i64, Value)> {
+    match val {
+        Value::Tuple(t) if t.len() == 3 => match (t.get(0)?, t.get(1)?, t.get(2)?) {
+            (Value::Str(kind), Value::Integer(version), data) => Some((kind, version, data)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn record(kind: &str, version: i64, data: Value) -> Value {
+    Tuple::new(vec![
+        Value::Str(Str::from(kind)),
+        Value::Integer(version),
+        data,
+    ])
+    .into()
+}
+
+#[derive(Default)]
+pub struct Migrations {
+    steps: HashMap<(String, i64), Rc<Migration>>,
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations::default()
+    }
+
+    /// Registers how to turn `kind` data from version `from` into
+    /// `from + 1`, replacing any earlier migration for that step.
+    pub fn register(&mut self, kind: &str, from: i64, migration: Migration) {
+        self.steps
+            .insert((kind.to_string(), from), Rc::new(migration));
+    }
+
+    fn step(&self, kind: &str, version: i64) -> Option<Rc<Migration>> {
+        self.steps.get(&(kind.to_string(), version)).cloned()
+    }
+
+    /// Brings a versioned record up to its kind's current version. Values
+    /// that aren't versioned records are returned unchanged.
+    pub fn upgrade(&self, val: Value) -> Result<Value, String> {
+        let (kind, mut version, mut data) = match parse_record(&val) {
+            Some(parts) => parts,
+            None => return Ok(val),
+        };
+        if self.step(&kind, version).is_none() {
+            return Ok(val);
+        }
+        while let Some(migration) = self.step(&kind, version) {
+            data = migration
+                .apply(data)
+                .map_err(|e| format!("{} v{}: {}", kind, version, e))?;
+            version += 1;
+        }
+        Ok(record(&kind, version, data))
+    }
+}
+
+thread_local! {
+    static MIGRATIONS: RefCell<Migrations> = RefCell::new(Migrations::new());
+}
+
+/// Registers a migration used by `upgrade` and the natives on this thread.
+pub fn register(kind: &str, from: i64, migration: Migration) {
+    MIGRATIONS.with(|m| m.borrow_mut().register(kind, from, migration));
+}
+
+/// `Migrations::upgrade` with this thread's migrations. Migrations may
+/// register further migrations, but those only apply from the next call.
+pub fn upgrade(val: Value) -> Result<Value, String> {
+    let steps = MIGRATIONS.with(|m| Migrations {
+        steps: m.borrow().steps.clone(),
+    });
+    steps.upgrade(val)
+}
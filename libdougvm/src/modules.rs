@@ -0,0 +1,175 @@
+//! Modules a script can import by name, so a program can be split over
+//! several files. The host registers each module's top-level code with a
+//! VM's `ModuleTable`; the first `LoadModule` of a name runs that code in
+//! a frame of its own, and its result, usually a `Table` of the module's
+//! exports, becomes the module's value. Later imports push that value
+//! without running anything.
+//!
+//! A module imported again while its top-level code is still running is
+//! an import cycle, raised as `OpError::ImportCycle`. If the top-level
+//! code throws, the module goes back to unloaded, so catching the error
+//! and importing again reruns it.
+
+use std::collections::HashMap;
+
+use crate::bytecode::OpError;
+use crate::datamodel::{Function, Str, Value};
+
+enum Module {
+    Unloaded(Function),
+    Loading(Function),
+    Loaded(Value),
+}
+
+/// What an import needs to do.
+pub(crate) enum Import {
+    Loaded(Value),
+    /// Run the module's top-level code.
+    Init(Function),
+}
+
+#[derive(Default)]
+pub struct ModuleTable {
+    modules: HashMap<Str, Module>,
+}
+
+impl ModuleTable {
+    pub fn new() -> ModuleTable {
+        ModuleTable::default()
+    }
+
+    /// Registers `init` as the top-level code of the module `name`,
+    /// replacing and unloading any module of that name. Returns whether the
+    /// name is new.
+    pub fn register(&mut self, name: &str, init: Function) -> bool {
+        self.modules
+            .insert(Str::from(name), Module::Unloaded(init))
+            .is_none()
+    }
+
+    /// The value of a module that has been loaded.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.modules.get(name)? {
+            Module::Loaded(val) => Some(val.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub(crate) fn import(&mut self, name: &Str) -> Result<Import, OpError> {
+        let module = self
+            .modules
+            .get_mut(name)
+            .ok_or_else(|| OpError::NoModule(name.clone()))?;
+        match module {
+            Module::Loaded(val) => Ok(Import::Loaded(val.clone())),
+            Module::Loading(_) => Err(OpError::ImportCycle(name.clone())),
+            Module::Unloaded(init) => {
+                let init = init.clone();
+                *module = Module::Loading(init.clone());
+                Ok(Import::Init(init))
+            }
+        }
+    }
+
+    /// Records the result of a module's top-level code.
+    pub(crate) fn loaded(&mut self, name: &Str, val: Value) {
+        if let Some(module) = self.modules.get_mut(name) {
+            *module = Module::Loaded(val);
+        }
+    }
+
+    /// Puts a module whose top-level code threw back to unloaded.
+    pub(crate) fn failed(&mut self, name: &Str) {
+        if let Some(module) = self.modules.get_mut(name) {
+            if let Module::Loading(init) = module {
+                *module = Module::Unloaded(init.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::Op;
+    use crate::datamodel::field_key;
+    use crate::testing::function;
+    use crate::{VirtualMachine, VmError};
+
+    fn import(name: &str) -> Op {
+        LoadModule(Str::from(name)).into()
+    }
+
+    #[test]
+    fn modules_load_once_on_first_import() {
+        let key = field_key("answer");
+        // imports counter, then exports { answer: 40 + 2 }
+        let math = function(vec![
+            import("counter"),
+            Store(1).into(),
+            NewTable.into(),
+            Store(2).into(),
+            Load(2).into(),
+            Push(Value::Integer(40)).into(),
+            Push(Value::Integer(2)).into(),
+            Add.into(),
+            SetField::new(key).into(),
+            Load(2).into(),
+            Return.into(),
+        ]);
+        let counter = function(vec![NewTable.into(), Return.into()]);
+        let main = function(vec![
+            import("math"),
+            Pop.into(),
+            import("math"),
+            GetField::new(key).into(),
+            Return.into(),
+        ]);
+        let mut vm = VirtualMachine::new(main);
+        assert!(vm.modules().register("math", math));
+        assert!(vm.modules().register("counter", counter));
+        assert!(!vm.modules().is_loaded("math"));
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(42))));
+        assert!(vm.modules().is_loaded("math") && vm.modules().is_loaded("counter"));
+        assert_eq!(vm.usage().peak_frames, 3);
+    }
+
+    #[test]
+    fn cycles_and_missing_modules_are_errors() {
+        let mut vm = VirtualMachine::new(function(vec![import("a"), Return.into()]));
+        vm.modules()
+            .register("a", function(vec![import("b"), Return.into()]));
+        vm.modules()
+            .register("b", function(vec![import("a"), Return.into()]));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::ImportCycle(name),
+                ..
+            }) if &*name == "a"
+        ));
+
+        // a failed load can be caught, and leaves the module unloaded
+        let mut vm = VirtualMachine::new(function(vec![
+            PushHandler(2).into(),
+            import("broken"),
+            Return.into(),
+            Pop.into(),
+            import("fine"),
+            Return.into(),
+        ]));
+        vm.modules()
+            .register("broken", function(vec![import("missing"), Return.into()]));
+        vm.modules().register(
+            "fine",
+            function(vec![Push(Value::Integer(1)).into(), Return.into()]),
+        );
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(1))));
+        assert!(!vm.modules().is_loaded("broken"));
+    }
+}
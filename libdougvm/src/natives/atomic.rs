@@ -0,0 +1,154 @@
+//! Atomic integer cells for state shared between threads. A cell is an
+//! `Arc`, so the host can hand clones of it to VMs on other threads (see
+//! `AtomicCell::to_value`) and scripts coordinate through it directly
+//! instead of round-tripping messages. All operations are sequentially
+//! consistent.
+
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use super::{call_order, int_arg};
+use crate::datamodel::Value;
+
+#[derive(Clone, Default)]
+pub struct AtomicCell(Arc<AtomicI64>);
+
+impl AtomicCell {
+    pub fn new(initial: i64) -> AtomicCell {
+        AtomicCell(Arc::new(AtomicI64::new(initial)))
+    }
+
+    /// A script value sharing this cell.
+    pub fn to_value(&self) -> Value {
+        let cell: Rc<AtomicCell> = Rc::new(self.clone());
+        Value::Unknown(cell)
+    }
+
+    /// The cell behind a script value, to send to another thread.
+    pub fn from_value(val: &Value) -> Option<AtomicCell> {
+        match val {
+            Value::Unknown(u) => u.downcast_ref::<AtomicCell>().cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn load(&self) -> i64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn store(&self, val: i64) {
+        self.0.store(val, Ordering::SeqCst)
+    }
+
+    /// Stores `new` if the cell holds `expected`, returning what it held.
+    pub fn compare_and_swap(&self, expected: i64, new: i64) -> i64 {
+        match self
+            .0
+            .compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(old) | Err(old) => old,
+        }
+    }
+
+    /// Adds `n` (wrapping), returning the previous value.
+    pub fn fetch_add(&self, n: i64) -> i64 {
+        self.0.fetch_add(n, Ordering::SeqCst)
+    }
+}
+
+fn cell_arg(args: &[Value]) -> Option<AtomicCell> {
+    AtomicCell::from_value(args.first()?)
+}
+
+/// `atomic(initial?)`: a new cell holding `initial`, or 0.
+pub fn atomic(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match args.first() {
+        None => AtomicCell::new(0).to_value(),
+        Some(_) => match int_arg(&args, 0) {
+            Some(initial) => AtomicCell::new(initial).to_value(),
+            None => Value::None,
+        },
+    }
+}
+
+/// `atomic_load(cell)`: the cell's value.
+pub fn atomic_load(args: Vec<Value>) -> Value {
+    match cell_arg(&call_order(args)) {
+        Some(cell) => Value::Integer(cell.load()),
+        None => Value::None,
+    }
+}
+
+/// `atomic_store(cell, n)`: sets the cell's value.
+pub fn atomic_store(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    if let (Some(cell), Some(n)) = (cell_arg(&args), int_arg(&args, 1)) {
+        cell.store(n);
+    }
+    Value::None
+}
+
+/// `atomic_cas(cell, expected, new)`: stores `new` if the cell holds
+/// `expected`. Returns the value the cell held, so the swap happened iff
+/// that equals `expected`.
+pub fn atomic_cas(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (cell_arg(&args), int_arg(&args, 1), int_arg(&args, 2)) {
+        (Some(cell), Some(expected), Some(new)) => {
+            Value::Integer(cell.compare_and_swap(expected, new))
+        }
+        _ => Value::None,
+    }
+}
+
+/// `atomic_fetch_add(cell, n)`: adds `n` and returns the previous value.
+pub fn atomic_fetch_add(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (cell_arg(&args), int_arg(&args, 1)) {
+        (Some(cell), Some(n)) => Value::Integer(cell.fetch_add(n)),
+        _ => Value::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn threads_share_a_cell() {
+        let cell = AtomicCell::new(0);
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    // each thread sees the cell as its own script value
+                    let val = cell.to_value();
+                    for _ in 0..1000 {
+                        atomic_fetch_add(vec![Value::Integer(1), val.clone()]);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let val = cell.to_value();
+        assert!(matches!(
+            atomic_load(vec![val.clone()]),
+            Value::Integer(4000)
+        ));
+        let cas = |expected, new| {
+            atomic_cas(vec![
+                Value::Integer(new),
+                Value::Integer(expected),
+                val.clone(),
+            ])
+        };
+        assert!(matches!(cas(1, 5), Value::Integer(4000)));
+        assert!(matches!(cas(4000, 5), Value::Integer(4000)));
+        assert_eq!(cell.load(), 5);
+    }
+}
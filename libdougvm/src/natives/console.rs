@@ -0,0 +1,194 @@
+//! Console natives. Scripts never touch the process's stdin, stdout or
+//! stderr directly: every read and write goes through the `Console` the
+//! host has registered for the thread, so an embedder can capture, redirect
+//! or deny a script's console I/O around each VM it runs.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+use super::{bytes_arg, call_order};
+use crate::datamodel::{Str, Value, Variant};
+
+/// The streams console natives read from and write to.
+pub struct Console {
+    pub stdin: Box<dyn BufRead>,
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+}
+
+impl Console {
+    /// The process's own streams, as a standalone interpreter would use.
+    pub fn process() -> Console {
+        Console {
+            stdin: Box::new(BufReader::new(io::stdin())),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        }
+    }
+
+    /// Streams that fail every read and write.
+    pub fn denied() -> Console {
+        Console {
+            stdin: Box::new(Denied),
+            stdout: Box::new(Denied),
+            stderr: Box::new(Denied),
+        }
+    }
+}
+
+struct Denied;
+
+fn denied() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "console access denied")
+}
+
+impl Read for Denied {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(denied())
+    }
+}
+
+impl BufRead for Denied {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Err(denied())
+    }
+
+    fn consume(&mut self, _amt: usize) {}
+}
+
+impl Write for Denied {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(denied())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(denied())
+    }
+}
+
+/// An in-memory output stream whose contents the host can read back, e.g.
+/// to capture what a script printed.
+#[derive(Clone, Default)]
+pub struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CONSOLE: RefCell<Console> = RefCell::new(Console::process());
+}
+
+/// Registers the console for natives on this thread, returning the
+/// previous one so the host can put it back after the VM is done.
+pub fn set_console(console: Console) -> Console {
+    CONSOLE.with(|c| std::mem::replace(&mut *c.borrow_mut(), console))
+}
+
+fn io_result(result: io::Result<()>) -> Value {
+    match result {
+        Ok(()) => Variant::ok(Value::None).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e.to_string().as_str()))).into(),
+    }
+}
+
+fn write_to(args: Vec<Value>, newline: bool, out: fn(&mut Console) -> &mut dyn Write) -> Value {
+    let mut bytes = match bytes_arg(&call_order(args), 0) {
+        Some(bytes) => bytes,
+        None => return Value::None,
+    };
+    if newline {
+        bytes.push(b'\n');
+    }
+    CONSOLE.with(|c| {
+        let mut console = c.borrow_mut();
+        let out = out(&mut console);
+        io_result(out.write_all(&bytes).and_then(|()| out.flush()))
+    })
+}
+
+/// `print(text)`: writes a `Str` or `Buffer` to stdout. `Ok(None)`, or
+/// `Err(reason)` if the console refused it.
+pub fn print(args: Vec<Value>) -> Value {
+    write_to(args, false, |c| &mut c.stdout)
+}
+
+/// `println(text)`: as `print`, followed by a newline.
+pub fn println(args: Vec<Value>) -> Value {
+    write_to(args, true, |c| &mut c.stdout)
+}
+
+/// `eprint(text)`: as `print`, to stderr.
+pub fn eprint(args: Vec<Value>) -> Value {
+    write_to(args, false, |c| &mut c.stderr)
+}
+
+/// `eprintln(text)`: as `println`, to stderr.
+pub fn eprintln(args: Vec<Value>) -> Value {
+    write_to(args, true, |c| &mut c.stderr)
+}
+
+/// `read_line()`: `Ok(Some(line))` without its line ending, `Ok(None)` at
+/// the end of input, or `Err(reason)`.
+pub fn read_line(_args: Vec<Value>) -> Value {
+    let mut line = String::new();
+    let read = CONSOLE.with(|c| c.borrow_mut().stdin.read_line(&mut line));
+    match read {
+        Ok(0) => Variant::ok(Variant::none().into()).into(),
+        Ok(_) => {
+            let trimmed = line.strip_suffix('\n').unwrap_or(&line);
+            let trimmed = trimmed.strip_suffix('\r').unwrap_or(trimmed);
+            Variant::ok(Variant::some(Value::Str(Str::from(trimmed))).into()).into()
+        }
+        Err(e) => Variant::err(Value::Str(Str::from(e.to_string().as_str()))).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn console_is_redirected_per_thread() {
+        let out = Captured::default();
+        let previous = set_console(Console {
+            stdin: Box::new(&b"first\r\nsecond"[..]),
+            stdout: Box::new(out.clone()),
+            stderr: Box::new(Denied),
+        });
+        let text = || vec![Value::Str(Str::from("hi"))];
+        assert!(
+            matches!(println(text()), Value::Variant(v) if v.tag() == Variant::ok(Value::None).tag())
+        );
+        assert!(
+            matches!(eprint(text()), Value::Variant(v) if v.tag() == Variant::err(Value::None).tag())
+        );
+        assert_eq!(out.take(), b"hi\n");
+        for _ in 0..3 {
+            assert!(matches!(read_line(Vec::new()), Value::Variant(_)));
+        }
+        set_console(Console::denied());
+        assert!(
+            matches!(print(text()), Value::Variant(v) if v.tag() == Variant::err(Value::None).tag())
+        );
+        set_console(previous);
+    }
+}
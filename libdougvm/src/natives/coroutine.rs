@@ -0,0 +1,22 @@
+//! Natives for making and inspecting coroutines (see `coroutine`); they
+//! are run with the `Resume` op.
+
+use crate::coroutine::Coroutine;
+use crate::datamodel::{Str, Value};
+
+/// `coroutine(f)`: a suspended coroutine that runs `f`, which takes the
+/// value of the first resume as its one argument.
+pub fn coroutine(args: Vec<Value>) -> Value {
+    match args.first() {
+        Some(Value::Function(f)) => Coroutine::new(f.clone()).into(),
+        _ => Value::None,
+    }
+}
+
+/// `coroutine_status(c)`: `"suspended"`, `"running"` or `"done"`.
+pub fn coroutine_status(args: Vec<Value>) -> Value {
+    match args.first() {
+        Some(Value::Coroutine(c)) => Value::Str(Str::from(c.status().as_str())),
+        _ => Value::None,
+    }
+}
@@ -0,0 +1,157 @@
+//! `Decimal` natives: conversion to and from text, and the operations that
+//! need an explicit precision and rounding mode. Rounding modes are named
+//! `"half_even"`, `"half_up"`, `"down"`, `"up"`, `"floor"` and `"ceiling"`.
+
+use super::{call_order, int_arg, str_arg};
+use crate::datamodel::{Decimal, Rounding, Str, Value, Variant};
+
+fn decimal_arg(args: &[Value], index: usize) -> Option<Decimal> {
+    match args.get(index) {
+        Some(Value::Decimal(d)) => Some(*d),
+        Some(Value::Integer(i)) => Some((*i).into()),
+        _ => None,
+    }
+}
+
+fn scale_arg(args: &[Value], index: usize) -> Option<u8> {
+    u8::try_from(int_arg(args, index)?).ok()
+}
+
+fn rounding_arg(args: &[Value], index: usize) -> Option<Rounding> {
+    Some(match &*str_arg(args, index)? {
+        "half_even" => Rounding::HalfEven,
+        "half_up" => Rounding::HalfUp,
+        "down" => Rounding::Down,
+        "up" => Rounding::Up,
+        "floor" => Rounding::Floor,
+        "ceiling" => Rounding::Ceiling,
+        _ => return None,
+    })
+}
+
+fn result(d: Option<Decimal>, error: &str) -> Value {
+    match d {
+        Some(d) => Variant::ok(d.into()).into(),
+        None => Variant::err(Value::Str(Str::from(error))).into(),
+    }
+}
+
+/// `decimal(x)`: parses a `Str` like `"-12.50"`, or converts an `Integer`.
+pub fn decimal(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let parsed = match args.first() {
+        Some(Value::Str(s)) => Decimal::parse(s),
+        _ => decimal_arg(&args, 0),
+    };
+    parsed.map_or(Value::None, Value::Decimal)
+}
+
+/// `decimal_str(d)`: the digits of `d`, keeping its trailing zeros.
+pub fn decimal_str(args: Vec<Value>) -> Value {
+    match decimal_arg(&call_order(args), 0) {
+        Some(d) => Value::Str(Str::from(d.to_string())),
+        None => Value::None,
+    }
+}
+
+/// `decimal_round(d, places, mode)`: `Ok(d)` rounded to `places` digits
+/// after the point, or `Err(message)` if that overflows.
+pub fn decimal_round(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (
+        decimal_arg(&args, 0),
+        scale_arg(&args, 1),
+        rounding_arg(&args, 2),
+    ) {
+        (Some(d), Some(scale), Some(mode)) => result(d.round(scale, mode), "decimal overflow"),
+        _ => Value::None,
+    }
+}
+
+/// `decimal_div(a, b, places, mode)`: `Ok(a / b)` with `places` digits
+/// after the point, or `Err(message)` on overflow or division by zero.
+pub fn decimal_div(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (a, b) = match (decimal_arg(&args, 0), decimal_arg(&args, 1)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Value::None,
+    };
+    match (scale_arg(&args, 2), rounding_arg(&args, 3)) {
+        (Some(_), Some(_)) if b.units() == 0 => result(None, "division by zero"),
+        (Some(scale), Some(mode)) => result(a.checked_div(&b, scale, mode), "decimal overflow"),
+        _ => Value::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Add, Div, Mul};
+    use crate::bytecode::Operation;
+    use crate::datamodel::OK;
+    use crate::CallStack;
+
+    fn dec(s: &str) -> Value {
+        decimal(vec![Value::Str(s.into())])
+    }
+
+    fn text(val: Value) -> String {
+        match decimal_str(vec![val]) {
+            Value::Str(s) => s.to_string(),
+            _ => panic!("expected a Decimal"),
+        }
+    }
+
+    fn ok(val: Value) -> Value {
+        match val {
+            Value::Variant(v) if v.tag() == OK => v.payload().clone(),
+            _ => panic!("expected Ok"),
+        }
+    }
+
+    fn binary(op: impl Operation, a: Value, b: Value) -> Value {
+        let mut m = CallStack::new();
+        m.push(a);
+        m.push(b);
+        assert!(op.exec(&mut m).is_ok());
+        m.pop().ok().unwrap()
+    }
+
+    #[test]
+    fn arithmetic_is_exact() {
+        assert_eq!(text(binary(Add, dec("0.1"), dec("0.2"))), "0.3");
+        assert_eq!(text(binary(Mul, dec("19.99"), Value::Integer(3))), "59.97");
+        assert_eq!(
+            text(binary(Div, dec("1"), dec("3"))),
+            "0.333333333333333333"
+        );
+        assert!(matches!(
+            decimal(vec![Value::Str("1.2.3".into())]),
+            Value::None
+        ));
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let round = |s: &str, mode: &str| {
+            // natives get their arguments last-first
+            let args = vec![Value::Str(mode.into()), Value::Integer(0), dec(s)];
+            text(ok(decimal_round(args)))
+        };
+        assert_eq!(round("2.5", "half_even"), "2");
+        assert_eq!(round("3.5", "half_even"), "4");
+        assert_eq!(round("2.5", "half_up"), "3");
+        assert_eq!(round("-2.5", "half_up"), "-3");
+        assert_eq!(round("-2.1", "floor"), "-3");
+        assert_eq!(round("-2.9", "down"), "-2");
+        assert_eq!(round("2.1", "up"), "3");
+        assert_eq!(round("2.1", "ceiling"), "3");
+        let div = decimal_div(vec![
+            Value::Str("half_up".into()),
+            Value::Integer(2),
+            dec("3"),
+            dec("2"),
+        ]);
+        assert_eq!(text(ok(div)), "0.67");
+    }
+}
@@ -0,0 +1,47 @@
+//! Checksums and digests (feature `digest`). Each takes a `Buffer` (or a
+//! `Str`, as UTF-8) and returns the digest as a `Buffer`; combine with
+//! `encoding::hex_encode` for the usual printable form.
+
+use sha2::{Digest, Sha256};
+
+use super::{bytes_arg, bytes_value, call_order};
+use crate::datamodel::Value;
+
+fn digest_with(args: Vec<Value>, f: impl FnOnce(&[u8]) -> Vec<u8>) -> Value {
+    bytes_arg(&call_order(args), 0)
+        .map(|bytes| bytes_value(f(&bytes)))
+        .into()
+}
+
+/// `crc32(bytes)`: IEEE CRC-32, as 4 big-endian bytes.
+pub fn crc32(args: Vec<Value>) -> Value {
+    digest_with(args, |bytes| crc32fast::hash(bytes).to_be_bytes().to_vec())
+}
+
+/// `sha256(bytes)`: the 32-byte SHA-256 digest.
+pub fn sha256(args: Vec<Value>) -> Value {
+    digest_with(args, |bytes| Sha256::digest(bytes).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::natives::encoding::hex_encode;
+
+    fn hex(v: Value) -> String {
+        match hex_encode(vec![v]) {
+            Value::Str(s) => s.to_string(),
+            _ => panic!("expected a Str"),
+        }
+    }
+
+    #[test]
+    fn known_digests() {
+        let input = || Value::Str("abc".into());
+        assert_eq!(hex(crc32(vec![input()])), "352441c2");
+        assert_eq!(
+            hex(sha256(vec![input()])),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
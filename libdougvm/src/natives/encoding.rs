@@ -0,0 +1,183 @@
+//! Text encodings of binary data. Encoders take a `Buffer` (or a `Str`,
+//! as UTF-8) and return a `Str`; decoders take a `Str` and return a
+//! `Buffer`, or `None` if the input is malformed.
+
+use super::{bytes_arg, bytes_value, call_order, str_arg};
+use crate::datamodel::{Str, Value};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn encode_with(args: Vec<Value>, f: impl FnOnce(&[u8]) -> String) -> Value {
+    match bytes_arg(&call_order(args), 0) {
+        Some(bytes) => Value::Str(Str::from(f(&bytes))),
+        None => Value::None,
+    }
+}
+
+fn decode_with(args: Vec<Value>, f: impl FnOnce(&str) -> Option<Vec<u8>>) -> Value {
+    str_arg(&call_order(args), 0)
+        .and_then(|s| f(&s))
+        .map(bytes_value)
+        .into()
+}
+
+/// `base64_encode(bytes)`: standard alphabet, with padding.
+pub fn base64_encode(args: Vec<Value>) -> Value {
+    encode_with(args, |bytes| {
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                match i <= chunk.len() {
+                    true => out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                    false => out.push('='),
+                }
+            }
+        }
+        out
+    })
+}
+
+/// `base64_decode(s)`: standard alphabet; padding is optional.
+pub fn base64_decode(args: Vec<Value>) -> Value {
+    decode_with(args, |s| {
+        let s = s.trim_end_matches('=').as_bytes();
+        let mut out = Vec::with_capacity(s.len() * 3 / 4);
+        for chunk in s.chunks(4) {
+            if chunk.len() == 1 {
+                return None;
+            }
+            let mut n = 0u32;
+            for (i, c) in chunk.iter().enumerate() {
+                let digit = BASE64.iter().position(|b| b == c)? as u32;
+                n |= digit << (18 - 6 * i);
+            }
+            for i in 0..chunk.len() - 1 {
+                out.push((n >> (16 - 8 * i)) as u8);
+            }
+        }
+        Some(out)
+    })
+}
+
+/// `hex_encode(bytes)`: lowercase, two digits per byte.
+pub fn hex_encode(args: Vec<Value>) -> Value {
+    encode_with(args, |bytes| {
+        let mut out = String::with_capacity(bytes.len() * 2);
+        for b in bytes {
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0xf) as usize] as char);
+        }
+        out
+    })
+}
+
+/// `hex_decode(s)`: accepts either case.
+pub fn hex_decode(args: Vec<Value>) -> Value {
+    decode_with(args, |s| {
+        let s = s.as_bytes();
+        if s.len() % 2 != 0 {
+            return None;
+        }
+        s.chunks(2)
+            .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+            .collect()
+    })
+}
+
+/// `url_encode(bytes)`: percent-encodes everything except the RFC 3986
+/// unreserved characters.
+pub fn url_encode(args: Vec<Value>) -> Value {
+    encode_with(args, |bytes| {
+        let mut out = String::with_capacity(bytes.len());
+        for b in bytes {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    out.push(*b as char)
+                }
+                _ => {
+                    out.push('%');
+                    out.push(HEX[(b >> 4) as usize].to_ascii_uppercase() as char);
+                    out.push(HEX[(b & 0xf) as usize].to_ascii_uppercase() as char);
+                }
+            }
+        }
+        out
+    })
+}
+
+/// `url_decode(s)`: decodes `%XX` escapes; other characters pass through.
+pub fn url_decode(args: Vec<Value>) -> Value {
+    decode_with(args, |s| {
+        let s = s.as_bytes();
+        let mut out = Vec::with_capacity(s.len());
+        let mut i = 0;
+        while i < s.len() {
+            match s[i] {
+                b'%' => {
+                    let pair = s.get(i + 1..i + 3)?;
+                    out.push(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?);
+                    i += 3;
+                }
+                b => {
+                    out.push(b);
+                    i += 1;
+                }
+            }
+        }
+        Some(out)
+    })
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    fn as_str(v: Value) -> String {
+        match v {
+            Value::Str(s) => s.to_string(),
+            _ => panic!("expected a Str"),
+        }
+    }
+
+    fn as_bytes(v: Value) -> Vec<u8> {
+        match v {
+            Value::Buffer(b) => b.to_vec(),
+            _ => panic!("expected a Buffer"),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for (plain, b64) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+        ] {
+            assert_eq!(as_str(base64_encode(vec![s(plain)])), b64);
+            assert_eq!(as_bytes(base64_decode(vec![s(b64)])), plain.as_bytes());
+        }
+        assert!(matches!(base64_decode(vec![s("Z")]), Value::None));
+        assert_eq!(as_str(hex_encode(vec![s("\x01\u{7f}")])), "017f");
+        assert_eq!(as_bytes(hex_decode(vec![s("017F")])), [1, 0x7f]);
+        assert_eq!(as_str(url_encode(vec![s("a b/é")])), "a%20b%2F%C3%A9");
+        assert_eq!(
+            as_bytes(url_decode(vec![s("a%20b%2F%C3%A9")])),
+            "a b/é".as_bytes()
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Event subscription natives. Handlers run as new scheduler tasks, called
+//! with the event's payload, when the host emits a matching event (see
+//! `Scheduler::emit`).
+
+use super::{call_order, int_arg, str_arg};
+use crate::datamodel::Value;
+use crate::events::{request_subscribe, request_unsubscribe};
+
+/// `on(name, fn)`: calls `fn(payload)` for every `name` event. Returns the
+/// subscription's id.
+pub fn on(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (str_arg(&args, 0), args.get(1)) {
+        (Some(name), Some(Value::Function(func))) => {
+            Value::Integer(request_subscribe(name.to_string(), func.clone()) as i64)
+        }
+        _ => Value::None,
+    }
+}
+
+/// `off(id)`: removes a subscription made by `on`.
+pub fn off(args: Vec<Value>) -> Value {
+    if let Some(id) = int_arg(&call_order(args), 0) {
+        request_unsubscribe(id as u64);
+    }
+    Value::None
+}
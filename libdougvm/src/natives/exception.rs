@@ -0,0 +1,30 @@
+//! Exception natives (see `exception`).
+
+use super::{call_order, str_arg};
+use crate::datamodel::Value;
+use crate::exception::{self, exception, parse};
+
+/// `error(class, message, data)`: a new exception, for the script to throw.
+/// `data` may be left off.
+pub fn error(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (str_arg(&args, 0), str_arg(&args, 1)) {
+        (Some(class), Some(message)) => exception(
+            &class,
+            &message,
+            args.get(2).cloned().unwrap_or(Value::None),
+        ),
+        _ => Value::None,
+    }
+}
+
+/// `error_is(e, class)`: whether `e` is an exception of `class` or one of
+/// its subclasses.
+pub fn error_is(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (args.first().and_then(parse), str_arg(&args, 1)) {
+        (Some((found, _, _)), Some(class)) => exception::is_a(&found, &class).into(),
+        (None, Some(_)) => false.into(),
+        _ => Value::None,
+    }
+}
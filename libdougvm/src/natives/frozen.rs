@@ -0,0 +1,112 @@
+//! Natives for reading frozen segments (see `frozen`), which the host
+//! shares between VMs with `Segment::to_value`.
+
+use super::{call_order, str_arg};
+use crate::datamodel::{field_key, Value};
+use crate::frozen::{Frozen, Lookup, Segment};
+
+fn segment_arg(args: &[Value]) -> Option<Segment> {
+    Segment::from_value(args.first()?)
+}
+
+/// `frozen_get(segment, key)`: the item at an `Integer` index of a frozen
+/// tuple or list, a field (named by `Str`) of a frozen table, the value
+/// under `key` in a frozen map, or a frozen variant's payload. `None` if
+/// there is no such item.
+pub fn frozen_get(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let segment = match segment_arg(&args) {
+        Some(s) => s,
+        None => return Value::None,
+    };
+    let lookup = match (segment.get(), args.get(1), str_arg(&args, 1)) {
+        (Frozen::Map(_), Some(key), _) => Lookup::Entry(key.clone()),
+        (_, Some(Value::Integer(i)), _) => Lookup::Index(*i),
+        (_, _, Some(name)) => Lookup::Key(field_key(&name)),
+        _ => Lookup::Index(0),
+    };
+    segment
+        .lookup(lookup)
+        .map_or(Value::None, |child| child.to_value())
+}
+
+/// `frozen_len(segment)`: the number of items or fields.
+pub fn frozen_len(args: Vec<Value>) -> Value {
+    let len = match segment_arg(&call_order(args)).as_ref().map(Segment::get) {
+        Some(Frozen::Tuple(items) | Frozen::List(items)) => items.len(),
+        Some(Frozen::Table(entries)) => entries.len(),
+        Some(Frozen::Map(entries)) => entries.len(),
+        _ => return Value::None,
+    };
+    Value::Integer(len as i64)
+}
+
+/// `thaw(segment)`: a mutable copy of the frozen value.
+pub fn thaw(args: Vec<Value>) -> Value {
+    match segment_arg(&call_order(args)) {
+        Some(segment) => segment.get().thaw(),
+        None => Value::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::{List, Map, Table};
+    use std::thread;
+
+    #[test]
+    fn segments_are_read_across_threads() {
+        let table = Table::new();
+        table.set(
+            field_key("rates"),
+            List::new(vec![Value::Integer(3), Value::Real(0.5)]).into(),
+        );
+        let segment = Segment::freeze(&table.into()).unwrap();
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let segment = segment.clone();
+                thread::spawn(move || {
+                    let root = segment.to_value();
+                    let rates = frozen_get(vec![Value::Str("rates".into()), root]);
+                    assert!(matches!(frozen_len(vec![rates.clone()]), Value::Integer(2)));
+                    match frozen_get(vec![Value::Integer(0), rates]) {
+                        Value::Integer(i) => i,
+                        _ => panic!("expected an Integer"),
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), 3);
+        }
+        let thawed = thaw(vec![segment.to_value()]);
+        assert!(matches!(thawed, Value::Table(t) if t.len() == 1));
+        assert!(Segment::freeze(&Value::NativeFn(thaw)).is_none());
+    }
+
+    #[test]
+    fn maps_freeze_and_thaw_in_order() {
+        let map = Map::new();
+        for (k, v) in [(Value::Str("b".into()), 2), (Value::Integer(1), 1)] {
+            map.insert(k, Value::Integer(v)).ok().unwrap();
+        }
+        let segment = Segment::freeze(&map.into()).unwrap();
+        let root = segment.to_value();
+        assert!(matches!(frozen_len(vec![root.clone()]), Value::Integer(2)));
+        let get = |key: Value| frozen_get(vec![key, root.clone()]);
+        assert!(matches!(get(Value::Str("b".into())), Value::Integer(2)));
+        // looked up as a map key, not an index
+        assert!(matches!(get(Value::Integer(1)), Value::Integer(1)));
+        assert!(matches!(get(Value::Real(1.0)), Value::Integer(1)));
+        assert!(matches!(get(Value::Integer(0)), Value::None));
+        let thawed = match thaw(vec![root]) {
+            Value::Map(m) => m.entries(),
+            _ => panic!("expected a Map"),
+        };
+        assert!(matches!(
+            thawed[..],
+            [(Value::Str(_), Value::Integer(2)), (Value::Integer(1), _)]
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+//! File natives, over the thread's `Vfs` (see `vfs`). All of them fail in
+//! deterministic mode.
+
+use super::{bytes_arg, bytes_value, call_order, is_deterministic, nondeterministic, str_arg};
+use crate::datamodel::{List, Str, Value, Variant};
+use crate::vfs::vfs;
+
+fn io_err(e: std::io::Error) -> Value {
+    Variant::err(Value::Str(Str::from(e.to_string()))).into()
+}
+
+/// Runs `op` on the path argument, wrapping what it returns in a `Variant`.
+fn with_path(args: Vec<Value>, op: impl FnOnce(&str, &[Value]) -> std::io::Result<Value>) -> Value {
+    let args = call_order(args);
+    let path = match str_arg(&args, 0) {
+        Some(path) => path,
+        None => return Value::None,
+    };
+    if is_deterministic() {
+        return nondeterministic();
+    }
+    match op(&path, &args) {
+        Ok(val) => Variant::ok(val).into(),
+        Err(e) => io_err(e),
+    }
+}
+
+/// `read_file(path)`: `Ok(text)`, or `Err` if it can't be read or isn't
+/// UTF-8.
+pub fn read_file(args: Vec<Value>) -> Value {
+    with_path(args, |path, _| {
+        let bytes = vfs().read(path)?;
+        match String::from_utf8(bytes) {
+            Ok(text) => Ok(Value::Str(Str::from(text))),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file is not UTF-8",
+            )),
+        }
+    })
+}
+
+/// `read_bytes(path)`: `Ok(buffer)` or `Err`.
+pub fn read_bytes(args: Vec<Value>) -> Value {
+    with_path(args, |path, _| Ok(bytes_value(vfs().read(path)?)))
+}
+
+fn data_arg(args: &[Value]) -> std::io::Result<Vec<u8>> {
+    bytes_arg(args, 1).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "expected a Str or Buffer")
+    })
+}
+
+/// `write_file(path, data)`: replaces the file with a `Str` or `Buffer`.
+/// `Ok(None)` or `Err`.
+pub fn write_file(args: Vec<Value>) -> Value {
+    with_path(args, |path, args| {
+        vfs().write(path, &data_arg(args)?)?;
+        Ok(Value::None)
+    })
+}
+
+/// `append_file(path, data)`: as `write_file`, adding to the end.
+pub fn append_file(args: Vec<Value>) -> Value {
+    with_path(args, |path, args| {
+        vfs().append(path, &data_arg(args)?)?;
+        Ok(Value::None)
+    })
+}
+
+/// `remove_file(path)`: `Ok(None)` or `Err`.
+pub fn remove_file(args: Vec<Value>) -> Value {
+    with_path(args, |path, _| {
+        vfs().remove(path)?;
+        Ok(Value::None)
+    })
+}
+
+/// `list_dir(path)`: `Ok(names)`, a sorted `List` of `Str`s, or `Err`.
+pub fn list_dir(args: Vec<Value>) -> Value {
+    with_path(args, |path, _| {
+        let names = vfs().list(path)?;
+        let names = names.iter().map(|n| Value::Str(Str::from(n.as_str())));
+        Ok(List::new(names.collect()).into())
+    })
+}
+
+/// `file_exists(path)`: `Ok(true)` if there is a file or directory there.
+pub fn file_exists(args: Vec<Value>) -> Value {
+    with_path(args, |path, _| Ok(vfs().exists(path).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{set_vfs, MemoryFs, ReadOnly, Vfs};
+    use std::rc::Rc;
+
+    fn ok(val: Value) -> Option<Value> {
+        match val {
+            Value::Variant(v) if v.tag() == Variant::ok(Value::None).tag() => {
+                Some(v.payload().clone())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn natives_use_the_registered_vfs() {
+        let files = Rc::new(MemoryFs::new());
+        files.insert("in/config", b"debug");
+        let previous = set_vfs(files.clone());
+        let path = |p: &str| Value::Str(Str::from(p));
+        assert!(
+            matches!(ok(read_file(vec![path("in/config")])), Some(Value::Str(s)) if &*s == "debug")
+        );
+        assert!(ok(write_file(vec![path("out"), path("out/log")])).is_some());
+        assert!(ok(append_file(vec![path("!"), path("out/log")])).is_some());
+        assert_eq!(files.read("out/log").unwrap(), b"out!");
+        assert!(matches!(ok(list_dir(vec![path("/")])), Some(Value::List(l)) if l.len() == 2));
+        set_vfs(Rc::new(ReadOnly(files)));
+        assert!(ok(remove_file(vec![path("out/log")])).is_none());
+        set_vfs(previous);
+    }
+}
@@ -0,0 +1,121 @@
+//! Task group natives (see `group`).
+
+use std::rc::Rc;
+
+use super::call_order;
+use crate::datamodel::{List, Str, Value, Variant};
+use crate::group::{request_spawn, Outcome, TaskGroup};
+
+fn group_arg(args: &[Value], index: usize) -> Option<Rc<TaskGroup>> {
+    match args.get(index) {
+        Some(Value::Unknown(u)) => u.clone().downcast::<TaskGroup>().ok(),
+        _ => None,
+    }
+}
+
+/// `task_group()`: a new, empty group.
+pub fn task_group(_args: Vec<Value>) -> Value {
+    let group: Rc<TaskGroup> = Rc::new(TaskGroup::new());
+    Value::Unknown(group)
+}
+
+/// `group_spawn(group, fn)`: starts `fn()` as a task owned by `group`.
+/// Returns its index in the group, or `None` if the group was cancelled.
+pub fn group_spawn(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (group_arg(&args, 0), args.get(1)) {
+        (Some(group), Some(Value::Function(func))) if !group.is_cancelled() => {
+            let index = group.len();
+            request_spawn(group, func.clone());
+            Value::Integer(index as i64)
+        }
+        _ => Value::None,
+    }
+}
+
+/// `group_cancel(group)`: cancels every task in the group that hasn't
+/// finished yet.
+pub fn group_cancel(args: Vec<Value>) -> Value {
+    if let Some(group) = group_arg(&call_order(args), 0) {
+        group.cancel();
+    }
+    Value::None
+}
+
+/// `group_poll(group)`: `Some(results)` once every task has finished,
+/// otherwise `None`. Each result is `Ok(value)` or `Err(reason)`.
+pub fn group_poll(args: Vec<Value>) -> Value {
+    let group = match group_arg(&call_order(args), 0) {
+        Some(group) => group,
+        None => return Value::None,
+    };
+    if !group.is_done() {
+        return Variant::none().into();
+    }
+    let err = |reason: &str| Variant::err(Value::Str(Str::from(reason))).into();
+    let results = group.outcomes(|outcome| match outcome {
+        Outcome::Returned(val) => Variant::ok(val.clone()).into(),
+        Outcome::Failed => err("failed"),
+        Outcome::Cancelled | Outcome::Running => err("cancelled"),
+    });
+    Variant::some(List::new(results).into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Return;
+    use crate::datamodel::{ERR, OK};
+    use crate::group::take_requests;
+    use crate::testing::function;
+
+    fn finished(poll: Value) -> Option<Vec<Value>> {
+        match poll {
+            Value::Variant(v) => match v.payload() {
+                Value::List(l) => Some(l.to_vec()),
+                _ => None,
+            },
+            _ => panic!("expected an Option"),
+        }
+    }
+
+    #[test]
+    fn groups_spawn_poll_and_cancel() {
+        let func: Value = function(vec![Return.into()]).into();
+        let group = task_group(Vec::new());
+        // natives get their arguments last-first
+        let spawn = |func: &Value| group_spawn(vec![func.clone(), group.clone()]);
+        assert!(matches!(spawn(&func), Value::Integer(0)));
+        assert!(matches!(spawn(&func), Value::Integer(1)));
+        assert!(matches!(spawn(&Value::Integer(1)), Value::None));
+        assert!(matches!(
+            group_spawn(vec![func.clone(), Value::None]),
+            Value::None
+        ));
+        let requests = take_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(finished(group_poll(vec![group.clone()])).is_none());
+
+        requests[0]
+            .group
+            .finish(0, Outcome::Returned(Value::Integer(5)));
+        group_cancel(vec![group.clone()]);
+        assert!(matches!(spawn(&func), Value::None));
+        assert!(take_requests().is_empty());
+        // the scheduler finishes what a cancelled group still owns
+        requests[1].group.finish(1, Outcome::Cancelled);
+        let results = finished(group_poll(vec![group])).unwrap();
+        let tags: Vec<_> = results
+            .iter()
+            .map(|r| match r {
+                Value::Variant(v) => v.tag(),
+                _ => panic!("expected a Result"),
+            })
+            .collect();
+        assert_eq!(tags, [OK, ERR]);
+        assert!(
+            matches!(&results[0], Value::Variant(v) if matches!(v.payload(), Value::Integer(5)))
+        );
+        assert!(matches!(group_poll(vec![Value::None]), Value::None));
+    }
+}
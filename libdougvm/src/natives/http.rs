@@ -0,0 +1,580 @@
+//! HTTP client natives (feature `http`). Nothing is reachable until the
+//! host grants it with `allow_hosts`, since natives have no other way to
+//! learn what a script may touch.
+//!
+//! Requests run on a background thread: `http_get`/`http_post` return a
+//! pending request handle (an `Unknown`), which `http_poll` checks without
+//! blocking and `http_wait` waits on for a bounded time. A completed request is `Ok((status, headers,
+//! body))`, with headers a `List` of `(name, value)` `Tuple`s and the body a
+//! `Buffer`, or `Err(message)`. Non-2xx statuses are still `Ok`.
+//! Redirects aren't followed, since the host they lead to may not be
+//! granted: a redirect is an `Ok` 3xx whose `Location` header the script
+//! can request in turn.
+//!
+//! `sse_connect` opens a server-sent event stream, read on a background
+//! thread. Events are `(event, data)` `Tuple`s of `Str`s, delivered by
+//! polling with `sse_poll`, by iterating the ones already in with
+//! `sse_events`, or by awaiting each with `sse_next`, which suspends the
+//! VM (see `suspend`) until the host resumes it with `sse_resume`. There
+//! is no WebSocket client: scripts that need pushed messages use SSE.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use super::{bytes_arg, bytes_value, call_order, int_arg, is_deterministic, str_arg};
+use crate::datamodel::{Iter, List, Str, Tuple, Value, Variant};
+use crate::suspend::{suspend, Token};
+use url::Url;
+
+thread_local! {
+    static ALLOWED_HOSTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// Event streams a suspended `sse_next` is waiting on, by token.
+    static AWAITED: RefCell<HashMap<Token, Rc<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// Grants scripts on this thread access to the given hosts (matched
+/// exactly against the URL's host). `"*"` allows any host.
+pub fn allow_hosts(hosts: Vec<String>) {
+    ALLOWED_HOSTS.with(|allowed| *allowed.borrow_mut() = hosts);
+}
+
+/// `url` parsed, if its host has been granted. The request is then made
+/// to this same `Url`, so the host checked is the host reached.
+fn granted(url: &str) -> Option<Url> {
+    if is_deterministic() {
+        return None;
+    }
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str().filter(|h| !h.is_empty())?;
+    let allowed =
+        ALLOWED_HOSTS.with(|allowed| allowed.borrow().iter().any(|a| a == "*" || a == host));
+    allowed.then_some(url)
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Pending {
+    rx: Receiver<Result<Response, String>>,
+    result: RefCell<Option<Result<Response, String>>>,
+}
+
+impl Pending {
+    fn result_value(&self) -> Value {
+        match self.result.borrow().as_ref() {
+            Some(Ok(resp)) => {
+                let headers = resp.headers.iter().map(|(k, v)| {
+                    let pair = vec![str_value(k), str_value(v)];
+                    Tuple::new(pair).into()
+                });
+                let parts = vec![
+                    Value::Integer(resp.status as i64),
+                    List::new(headers.collect()).into(),
+                    bytes_value(resp.body.clone()),
+                ];
+                Variant::ok(Tuple::new(parts).into()).into()
+            }
+            Some(Err(e)) => Variant::err(str_value(e)).into(),
+            None => Value::None,
+        }
+    }
+}
+
+fn str_value(s: &str) -> Value {
+    Value::Str(Str::from(s))
+}
+
+fn headers_arg(args: &[Value], index: usize) -> Option<Vec<(String, String)>> {
+    match args.get(index) {
+        None | Some(Value::None) => Some(Vec::new()),
+        Some(Value::List(list)) => list
+            .to_vec()
+            .into_iter()
+            .map(|pair| match pair {
+                Value::Tuple(t) => match (t.get(0), t.get(1)) {
+                    (Some(Value::Str(k)), Some(Value::Str(v))) => {
+                        Some((k.to_string(), v.to_string()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// An agent that stops at redirects, so every host reached is checked.
+fn agent() -> ureq::AgentBuilder {
+    ureq::AgentBuilder::new().redirects(0)
+}
+
+fn execute(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> Result<Response, String> {
+    let mut agent = agent();
+    if let Some(timeout) = timeout {
+        agent = agent.timeout(timeout);
+    }
+    let mut req = agent.build().request_url(method, url);
+    for (k, v) in headers {
+        req = req.set(k, v);
+    }
+    let resp = match body {
+        Some(body) => req.send_bytes(body),
+        None => req.call(),
+    };
+    let resp = match resp {
+        Ok(resp) | Err(ureq::Error::Status(_, resp)) => resp,
+        Err(e) => return Err(e.to_string()),
+    };
+    let status = resp.status();
+    let headers = resp
+        .headers_names()
+        .into_iter()
+        .filter_map(|name| {
+            let value = resp.header(&name)?.to_string();
+            Some((name, value))
+        })
+        .collect();
+    let mut body = Vec::new();
+    resp.into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn start(method: &'static str, args: Vec<Value>, has_body: bool) -> Value {
+    let args = call_order(args);
+    let url = match str_arg(&args, 0) {
+        Some(url) => url.to_string(),
+        None => return Value::None,
+    };
+    let headers = match headers_arg(&args, 1) {
+        Some(headers) => headers,
+        None => return Value::None,
+    };
+    let body = match has_body {
+        true => match bytes_arg(&args, 2) {
+            Some(body) => Some(body),
+            None => return Value::None,
+        },
+        false => None,
+    };
+    let timeout_index = if has_body { 3 } else { 2 };
+    let timeout = int_arg(&args, timeout_index).map(|ms| Duration::from_millis(ms.max(0) as u64));
+
+    let (tx, rx) = channel();
+    match granted(&url) {
+        Some(url) => {
+            thread::spawn(move || {
+                let _ = tx.send(execute(method, &url, &headers, body.as_deref(), timeout));
+            });
+        }
+        None => {
+            let _ = tx.send(Err(format!("network access to {} was not granted", url)));
+        }
+    }
+    let pending: Rc<Pending> = Rc::new(Pending {
+        rx,
+        result: RefCell::new(None),
+    });
+    Value::Unknown(pending)
+}
+
+/// `http_get(url, headers?, timeout_ms?)`: starts a GET request.
+pub fn http_get(args: Vec<Value>) -> Value {
+    start("GET", args, false)
+}
+
+/// `http_post(url, headers, body, timeout_ms?)`: starts a POST request
+/// with a `Buffer` or `Str` body.
+pub fn http_post(args: Vec<Value>) -> Value {
+    start("POST", args, true)
+}
+
+fn pending_arg(args: &[Value]) -> Option<&Pending> {
+    match args.first() {
+        Some(Value::Unknown(u)) => u.downcast_ref::<Pending>(),
+        _ => None,
+    }
+}
+
+/// `http_poll(request)`: `Some(result)` once the request has completed,
+/// otherwise `None`.
+pub fn http_poll(args: Vec<Value>) -> Value {
+    let pending = match pending_arg(&args) {
+        Some(p) => p,
+        None => return Value::None,
+    };
+    if pending.result.borrow().is_none() {
+        let result = match pending.rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return Variant::none().into(),
+            Err(TryRecvError::Disconnected) => Err("request thread panicked".to_string()),
+        };
+        *pending.result.borrow_mut() = Some(result);
+    }
+    Variant::some(pending.result_value()).into()
+}
+
+/// How long `http_wait` waits when not told.
+pub const DEFAULT_WAIT_MS: i64 = 30_000;
+
+/// `http_wait(request, timeout_ms?)`: waits up to `timeout_ms` (default
+/// `DEFAULT_WAIT_MS`) for the request to complete and returns its result,
+/// or `Err("request still pending")` if it hasn't yet; the request carries
+/// on, and a later `http_wait` or `http_poll` gets its result.
+pub fn http_wait(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let pending = match pending_arg(&args) {
+        Some(p) => p,
+        None => return Value::None,
+    };
+    if pending.result.borrow().is_none() {
+        let ms = int_arg(&args, 1).unwrap_or(DEFAULT_WAIT_MS).max(0) as u64;
+        let result = match pending.rx.recv_timeout(Duration::from_millis(ms)) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                return Variant::err(str_value("request still pending")).into()
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("request thread panicked".to_string()),
+        };
+        *pending.result.borrow_mut() = Some(result);
+    }
+    pending.result_value()
+}
+
+struct Event {
+    event: String,
+    data: String,
+}
+
+impl Event {
+    fn into_value(self) -> Value {
+        Tuple::new(vec![str_value(&self.event), str_value(&self.data)]).into()
+    }
+}
+
+/// Parses an event stream, sending each dispatched event until the input
+/// ends or the receiver hangs up.
+fn read_events(input: impl BufRead, tx: &Sender<Result<Event, String>>) -> Result<(), String> {
+    let mut event = String::new();
+    let mut data: Option<String> = None;
+    for line in input.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.is_empty() {
+            if let Some(data) = data.take() {
+                let event = match event.is_empty() {
+                    true => "message".to_string(),
+                    false => std::mem::take(&mut event),
+                };
+                if tx.send(Ok(Event { event, data })).is_err() {
+                    return Ok(());
+                }
+            }
+            event.clear();
+            continue;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+        match field {
+            "event" => event = value.to_string(),
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            },
+            // comments, ids and retry hints aren't surfaced
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+struct EventStream {
+    rx: Receiver<Result<Event, String>>,
+}
+
+/// `sse_connect(url, headers?)`: opens a server-sent event stream.
+pub fn sse_connect(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let url = match str_arg(&args, 0) {
+        Some(url) => url.to_string(),
+        None => return Value::None,
+    };
+    let mut headers = match headers_arg(&args, 1) {
+        Some(headers) => headers,
+        None => return Value::None,
+    };
+    headers.push(("Accept".to_string(), "text/event-stream".to_string()));
+    let (tx, rx) = channel();
+    match granted(&url) {
+        Some(url) => {
+            thread::spawn(move || {
+                let mut req = agent().build().request_url("GET", &url);
+                for (k, v) in &headers {
+                    req = req.set(k, v);
+                }
+                let result = match req.call() {
+                    Ok(resp) => read_events(BufReader::new(resp.into_reader()), &tx),
+                    Err(e) => Err(e.to_string()),
+                };
+                let reason = match result {
+                    Ok(()) => "stream closed".to_string(),
+                    Err(e) => e,
+                };
+                let _ = tx.send(Err(reason));
+            });
+        }
+        None => {
+            let _ = tx.send(Err(format!("network access to {} was not granted", url)));
+        }
+    }
+    let stream: Rc<EventStream> = Rc::new(EventStream { rx });
+    Value::Unknown(stream)
+}
+
+fn stream_arg(args: &[Value]) -> Option<&EventStream> {
+    match args.first() {
+        Some(Value::Unknown(u)) => u.downcast_ref::<EventStream>(),
+        _ => None,
+    }
+}
+
+/// `sse_poll(stream)`: `Some(event)` if one has arrived, `None` if not yet,
+/// or `Err(message)` once the stream has closed.
+pub fn sse_poll(args: Vec<Value>) -> Value {
+    let stream = match stream_arg(&args) {
+        Some(s) => s,
+        None => return Value::None,
+    };
+    match stream.rx.try_recv() {
+        Ok(Ok(event)) => Variant::some(event.into_value()).into(),
+        Ok(Err(e)) => Variant::err(str_value(&e)).into(),
+        Err(TryRecvError::Empty) => Variant::none().into(),
+        Err(TryRecvError::Disconnected) => Variant::err(str_value("stream closed")).into(),
+    }
+}
+
+/// `sse_events(stream)`: an `Iter` over the events that have arrived so
+/// far, ending at the first one that hasn't (use `sse_next` to wait).
+pub fn sse_events(args: Vec<Value>) -> Value {
+    let stream = match args.first() {
+        Some(Value::Unknown(u)) if u.is::<EventStream>() => u.clone(),
+        _ => return Value::None,
+    };
+    Iter::new(move || {
+        let stream = stream.downcast_ref::<EventStream>()?;
+        match stream.rx.try_recv() {
+            Ok(Ok(event)) => Some(event.into_value()),
+            _ => None,
+        }
+    })
+    .into()
+}
+
+/// A `sse_next` result: `Some(event)`, or `None` once the stream closed.
+fn next_value(event: Result<Event, String>) -> Value {
+    match event {
+        Ok(event) => Variant::some(event.into_value()).into(),
+        Err(_) => Variant::none().into(),
+    }
+}
+
+/// `sse_next(stream)`: `Some(event)`, or `None` once the stream has
+/// closed. If no event has arrived yet, the VM suspends with a token for
+/// the host to pass to `sse_resume`.
+pub fn sse_next(args: Vec<Value>) -> Value {
+    let handle = match args.first() {
+        Some(Value::Unknown(u)) if u.is::<EventStream>() => u.clone(),
+        _ => return Value::None,
+    };
+    let stream = handle.downcast_ref::<EventStream>().unwrap();
+    match stream.rx.try_recv() {
+        Ok(event) => next_value(event),
+        Err(TryRecvError::Disconnected) => Variant::none().into(),
+        Err(TryRecvError::Empty) => {
+            let token = Rc::as_ptr(&handle).cast::<()>() as usize as Token;
+            AWAITED.with(|awaited| awaited.borrow_mut().insert(token, handle));
+            suspend(token);
+            Value::None
+        }
+    }
+}
+
+/// For a host whose VM suspended in `sse_next` with `token`: waits up to
+/// `timeout` for that stream's next event and returns the value to
+/// `VirtualMachine::resume` with, or `None` if nothing arrived in time or
+/// `token` isn't one `sse_next` gave out.
+pub fn sse_resume(token: Token, timeout: Duration) -> Option<Value> {
+    let handle = AWAITED.with(|awaited| awaited.borrow().get(&token).cloned())?;
+    let stream = handle.downcast_ref::<EventStream>()?;
+    let val = match stream.rx.recv_timeout(timeout) {
+        Ok(event) => next_value(event),
+        Err(RecvTimeoutError::Disconnected) => Variant::none().into(),
+        Err(RecvTimeoutError::Timeout) => return None,
+    };
+    AWAITED.with(|awaited| awaited.borrow_mut().remove(&token));
+    Some(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::{ERR, OK};
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    /// Answers one request with `response`.
+    fn serve_once(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(response);
+        });
+        format!("http://{}/missing", addr)
+    }
+
+    fn response_of(result: Variant) -> Tuple {
+        assert!(result.tag() == OK);
+        match result.payload() {
+            Value::Tuple(t) => t.clone(),
+            _ => panic!("expected a Tuple"),
+        }
+    }
+
+    fn wait(url: &str) -> Variant {
+        let req = http_get(vec![Value::Str(url.into())]);
+        match http_wait(vec![req]) {
+            Value::Variant(v) => v,
+            _ => panic!("expected a Variant"),
+        }
+    }
+
+    #[test]
+    fn parses_event_stream() {
+        let text = ": comment\ndata: a\ndata: b\n\nevent: tick\ndata:1\n\n";
+        let (tx, rx) = channel();
+        read_events(text.as_bytes(), &tx).unwrap();
+        drop(tx);
+        let events: Vec<_> = rx.iter().map(|e| e.unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((&*events[0].event, &*events[0].data), ("message", "a\nb"));
+        assert_eq!((&*events[1].event, &*events[1].data), ("tick", "1"));
+    }
+
+    #[test]
+    fn scripts_await_events_without_blocking() {
+        use crate::bytecode::ops::{Call, Push, Return};
+        use crate::datamodel::{Function, SOME};
+        use crate::{VirtualMachine, VmState};
+
+        let (tx, rx) = channel();
+        let stream = Value::Unknown(Rc::new(EventStream { rx }));
+        let mut vm = VirtualMachine::new(Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(stream.clone()).into(),
+                Push(Value::NativeFn(sse_next)).into(),
+                Call(1).into(),
+                Return.into(),
+            ]
+            .into(),
+        });
+        let token = match vm.run_until_blocked() {
+            Ok(VmState::Suspended(token)) => token,
+            _ => panic!("expected the VM to suspend"),
+        };
+        assert!(sse_resume(token, Duration::ZERO).is_none());
+        let event = |data: &str| Event {
+            event: "message".to_string(),
+            data: data.to_string(),
+        };
+        tx.send(Ok(event("a"))).unwrap();
+        assert!(vm.resume(sse_resume(token, Duration::from_secs(1)).unwrap()));
+        match vm.run_until_blocked() {
+            Ok(VmState::Exited(Value::Variant(v))) => assert!(v.tag() == SOME),
+            _ => panic!("expected Some(event)"),
+        }
+        // iterating takes only what is already there
+        tx.send(Ok(event("b"))).unwrap();
+        let iter: Iter = sse_events(vec![stream]).try_into().ok().unwrap();
+        assert!(iter.next().is_some());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn requires_grant() {
+        allow_hosts(Vec::new());
+        assert!(wait("http://127.0.0.1/").tag() == ERR);
+        // the host ureq would reach is evil.com, whatever follows the `\`
+        allow_hosts(vec!["allowed.com".to_string()]);
+        assert!(granted("http://evil.com\\@allowed.com/").is_none());
+        assert!(granted("http://user@allowed.com:8080/x").is_some());
+    }
+
+    #[test]
+    fn get_local_server() {
+        allow_hosts(vec!["127.0.0.1".to_string()]);
+        let not_found = b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\nno";
+        let resp = response_of(wait(&serve_once(not_found)));
+        assert!(matches!(resp.get(0), Some(Value::Integer(404))));
+        assert!(matches!(resp.get(2), Some(Value::Buffer(b)) if b.to_vec() == b"no"));
+    }
+
+    #[test]
+    fn redirects_are_not_followed() {
+        allow_hosts(vec!["127.0.0.1".to_string()]);
+        // localhost isn't granted, so following this would escape the grant
+        let redirect = b"HTTP/1.1 302 Found\r\nLocation: http://localhost:1/\r\n\
+            Content-Length: 0\r\n\r\n";
+        let resp = response_of(wait(&serve_once(redirect)));
+        assert!(matches!(resp.get(0), Some(Value::Integer(302))));
+    }
+
+    #[test]
+    fn waiting_gives_up_without_losing_the_request() {
+        allow_hosts(vec!["127.0.0.1".to_string()]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/slow", listener.local_addr().unwrap());
+        let req = http_get(vec![Value::Str(url.as_str().into())]);
+        // natives get their arguments last first
+        let waited = http_wait(vec![Value::Integer(50), req.clone()]);
+        assert!(matches!(waited, Value::Variant(v) if v.tag() == ERR));
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        drop(stream);
+        let resp = match http_wait(vec![req]) {
+            Value::Variant(v) => response_of(v),
+            _ => panic!("expected a Variant"),
+        };
+        assert!(matches!(resp.get(0), Some(Value::Integer(200))));
+    }
+}
@@ -0,0 +1,87 @@
+//! Natives for driving `Iter` values.
+
+use crate::datamodel::{Iter, Value, Variant};
+use crate::natives::call_order;
+
+/// `next(iter)`: advances an `Iter`, returning `Some(item)` or `None` (see
+/// the Option tags in `datamodel`).
+pub fn next(args: Vec<Value>) -> Value {
+    match args.first() {
+        Some(Value::Iter(it)) => match it.next() {
+            Some(val) => Variant::some(val).into(),
+            None => Variant::none().into(),
+        },
+        _ => Value::None,
+    }
+}
+
+/// `range(start, stop, step = 1)`: an `Iter` over the `Integer`s from
+/// `start` up to but not including `stop`, or down to it for a negative
+/// step.
+pub fn range(args: Vec<Value>) -> Value {
+    let (start, stop, step) = match call_order(args)[..] {
+        [Value::Integer(start), Value::Integer(stop)] => (start, stop, 1),
+        [Value::Integer(start), Value::Integer(stop), Value::Integer(step)] if step != 0 => {
+            (start, stop, step)
+        }
+        _ => return Value::None,
+    };
+    let mut next = Some(start);
+    Iter::new(move || {
+        let i = next.filter(|&i| if step > 0 { i < stop } else { i > stop })?;
+        next = i.checked_add(step);
+        Some(Value::Integer(i))
+    })
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(args: Vec<i64>) -> Vec<Value> {
+        args.into_iter().map(Value::Integer).collect()
+    }
+
+    fn collect(val: Value) -> Vec<i64> {
+        let it = match val {
+            Value::Iter(it) => it,
+            _ => panic!("expected an Iter"),
+        };
+        std::iter::from_fn(|| it.next())
+            .map(|v| match v {
+                Value::Integer(i) => i,
+                _ => panic!("expected an Integer"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranges_count_either_way() {
+        // natives get their arguments last-first
+        assert_eq!(collect(range(ints(vec![3, 0]))), [0, 1, 2]);
+        assert_eq!(collect(range(ints(vec![-2, 0, 5]))), [5, 3, 1]);
+        assert!(collect(range(ints(vec![3, 3]))).is_empty());
+        // stepping past i64::MAX ends the range rather than overflowing
+        assert_eq!(
+            collect(range(ints(vec![2, i64::MAX, i64::MAX - 1]))),
+            [i64::MAX - 1]
+        );
+        assert!(matches!(range(ints(vec![0, 3, 0])), Value::None));
+        assert!(matches!(range(ints(vec![3])), Value::None));
+    }
+
+    #[test]
+    fn next_advances_until_none() {
+        let it = range(ints(vec![2, 0]));
+        let tags: Vec<_> = (0..3)
+            .map(|_| match next(vec![it.clone()]) {
+                Value::Variant(v) => v.tag(),
+                _ => panic!("expected an Option"),
+            })
+            .collect();
+        let (some, none) = (Variant::some(Value::None), Variant::none());
+        assert_eq!(tags, [some.tag(), some.tag(), none.tag()]);
+        assert!(matches!(next(ints(vec![1])), Value::None));
+    }
+}
@@ -0,0 +1,271 @@
+//! A persistent key-value store for scripts (feature `kv`). Keys are
+//! `Str`s; values are plain data (`None`, numbers, `Str`, `Buffer`,
+//! `List`, `Tuple`, `Table`, `Variant`, `Timestamp` and `Duration`),
+//! stored in this module's own encoding. Functions, natives and host
+//! values can't be stored.
+//!
+//! The store is an append-only log, kept in the thread's `Vfs` and
+//! replayed into memory on open, so every `kv_put` is durable once it
+//! returns.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+use std::rc::Rc;
+
+use super::{call_order, is_deterministic, nondeterministic, str_arg};
+use crate::codec::{decode_from, encode, write_chunk, Reader};
+use crate::datamodel::{Iter, Str, Tuple, Value, Variant};
+use crate::migrate;
+use crate::vfs::{vfs, Vfs};
+
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+
+pub struct Store {
+    vfs: Rc<dyn Vfs>,
+    path: String,
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl Store {
+    pub fn open(path: &str) -> io::Result<Store> {
+        let vfs = vfs();
+        let log = match vfs.read(path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                vfs.append(path, &[])?;
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let mut entries = BTreeMap::new();
+        let mut r = Reader::new(&log);
+        while !r.is_empty() {
+            let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt store");
+            let op = r.u8().ok_or_else(corrupt)?;
+            let key = r.chunk().ok_or_else(corrupt)?;
+            let key = String::from_utf8(key.to_vec()).map_err(|_| corrupt())?;
+            match op {
+                PUT => {
+                    let val = r.chunk().ok_or_else(corrupt)?;
+                    entries.insert(key, val.to_vec());
+                }
+                DELETE => {
+                    entries.remove(&key);
+                }
+                _ => return Err(corrupt()),
+            }
+        }
+        Ok(Store {
+            vfs,
+            path: path.to_string(),
+            entries,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        decode(self.entries.get(key)?)
+    }
+
+    /// Fails with `InvalidInput` if the value can't be encoded.
+    pub fn put(&mut self, key: &str, val: &Value) -> io::Result<()> {
+        let mut encoded = Vec::new();
+        if encode(val, &mut encoded).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a {} can't be stored", val.get_type().as_str()),
+            ));
+        }
+        let mut record = vec![PUT];
+        write_chunk(&mut record, key.as_bytes());
+        write_chunk(&mut record, &encoded);
+        self.vfs.append(&self.path, &record)?;
+        self.entries.insert(key.to_string(), encoded);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        if self.entries.remove(key).is_some() {
+            let mut record = vec![DELETE];
+            write_chunk(&mut record, key.as_bytes());
+            self.vfs.append(&self.path, &record)?;
+        }
+        Ok(())
+    }
+
+    /// The first entry whose key starts with `prefix` and sorts after
+    /// `after` (exclusive).
+    fn next_with_prefix(&self, prefix: &str, after: Option<&str>) -> Option<(String, Value)> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Included(prefix),
+        };
+        let (k, v) = self
+            .entries
+            .range::<str, _>((start, Bound::Unbounded))
+            .next()?;
+        match k.starts_with(prefix) {
+            true => Some((k.clone(), decode(v)?)),
+            false => None,
+        }
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<Value> {
+    let mut r = Reader::new(bytes);
+    let val = decode_from(&mut r)?;
+    match r.is_empty() {
+        true => Some(val),
+        false => None,
+    }
+}
+
+type Handle = RefCell<Store>;
+
+fn store_arg(args: &[Value]) -> Option<Rc<Handle>> {
+    match args.first() {
+        Some(Value::Unknown(u)) => u.clone().downcast::<Handle>().ok(),
+        _ => None,
+    }
+}
+
+fn io_result(result: io::Result<()>) -> Value {
+    match result {
+        Ok(()) => Variant::ok(Value::None).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e.to_string()))).into(),
+    }
+}
+
+/// `kv_open(path)`: `Ok(store)`, creating the file if needed, or
+/// `Err(message)`.
+pub fn kv_open(args: Vec<Value>) -> Value {
+    let path = match str_arg(&call_order(args), 0) {
+        Some(path) => path,
+        None => return Value::None,
+    };
+    if is_deterministic() {
+        return nondeterministic();
+    }
+    match Store::open(&path) {
+        Ok(store) => {
+            let handle: Rc<Handle> = Rc::new(RefCell::new(store));
+            Variant::ok(Value::Unknown(handle)).into()
+        }
+        Err(e) => Variant::err(Value::Str(Str::from(e.to_string()))).into(),
+    }
+}
+
+/// `kv_get(store, key)`: `Some(value)` or `None`. Versioned records are
+/// upgraded as they are read (see `migrate`), and a failed migration
+/// returns `Err(reason)` instead.
+pub fn kv_get(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (store, key) = match (store_arg(&args), str_arg(&args, 1)) {
+        (Some(store), Some(key)) => (store, key),
+        _ => return Value::None,
+    };
+    let found = store.borrow().get(&key);
+    match found.map(migrate::upgrade) {
+        Some(Ok(val)) => Variant::some(val).into(),
+        Some(Err(e)) => Variant::err(Value::Str(Str::from(e))).into(),
+        None => Variant::none().into(),
+    }
+}
+
+/// `kv_put(store, key, value)`: `Ok(None)` or `Err(message)`.
+pub fn kv_put(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (store, key, val) = match (store_arg(&args), str_arg(&args, 1), args.get(2)) {
+        (Some(store), Some(key), Some(val)) => (store, key, val),
+        _ => return Value::None,
+    };
+    let result = store.borrow_mut().put(&key, val);
+    io_result(result)
+}
+
+/// `kv_delete(store, key)`: `Ok(None)` or `Err(message)`.
+pub fn kv_delete(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (store, key) = match (store_arg(&args), str_arg(&args, 1)) {
+        (Some(store), Some(key)) => (store, key),
+        _ => return Value::None,
+    };
+    let result = store.borrow_mut().delete(&key);
+    io_result(result)
+}
+
+/// `kv_scan(store, prefix)`: an `Iter` of `(key, value)` `Tuple`s whose key
+/// starts with `prefix`, in key order. Entries written during the scan are
+/// seen if they sort after the current position.
+pub fn kv_scan(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (store, prefix) = match (store_arg(&args), str_arg(&args, 1)) {
+        (Some(store), Some(prefix)) => (store, prefix),
+        _ => return Value::None,
+    };
+    let mut last: Option<String> = None;
+    Iter::new(move || {
+        let (key, val) = store.borrow().next_with_prefix(&prefix, last.as_deref())?;
+        let item = Tuple::new(vec![Value::Str(Str::from(key.as_str())), val]);
+        last = Some(key);
+        Some(item.into())
+    })
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::{field_key, List, Table};
+
+    #[test]
+    fn persists_across_opens() {
+        let path = std::env::temp_dir().join(format!("doug-kv-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        {
+            let mut store = Store::open(path).unwrap();
+            let table = Table::new();
+            table.set(field_key("n"), Value::Integer(3));
+            let list = List::new(vec![Value::Str("x".into()), table.into()]);
+            store.put("a/1", &list.into()).unwrap();
+            store.put("a/2", &Value::Real(0.5)).unwrap();
+            store.put("b", &Value::None).unwrap();
+            store.delete("a/2").unwrap();
+            assert!(store.put("f", &Value::NativeFn(kv_open)).is_err());
+        }
+        let store = Store::open(path).unwrap();
+        let list = match store.get("a/1") {
+            Some(Value::List(l)) => l.to_vec(),
+            _ => panic!("expected a List"),
+        };
+        assert!(
+            matches!(&list[1], Value::Table(t) if matches!(t.get(field_key("n")), Some(Value::Integer(3))))
+        );
+        assert!(store.get("a/2").is_none());
+        assert_eq!(store.next_with_prefix("a/", None).unwrap().0, "a/1");
+        assert!(store.next_with_prefix("a/", Some("a/1")).is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn self_containing_and_deep_values_are_refused() {
+        let previous = crate::vfs::set_vfs(Rc::new(crate::vfs::MemoryFs::new()));
+        let mut store = Store::open("kv").unwrap();
+        let list = List::new(Vec::new());
+        list.items.borrow_mut().push(list.clone().into());
+        assert!(store.put("loop", &list.clone().into()).is_err());
+        list.items.borrow_mut().clear();
+
+        let deep = (0..1000).fold(Value::None, |v, _| List::new(vec![v]).into());
+        assert!(store.put("deep", &deep).is_err());
+        // shared, but not cyclic, is fine
+        let shared: Value = List::new(vec![Value::Integer(1)]).into();
+        assert!(store
+            .put("shared", &List::new(vec![shared.clone(), shared]).into())
+            .is_ok());
+        crate::vfs::set_vfs(previous);
+    }
+}
@@ -0,0 +1,93 @@
+//! Versioned record natives (see `migrate`).
+
+use super::{call_order, int_arg, str_arg};
+use crate::datamodel::{Str, Value, Variant};
+use crate::migrate::{self, record, Migration};
+
+/// `versioned(kind, version, data)`: a versioned record.
+pub fn versioned(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (str_arg(&args, 0), int_arg(&args, 1), args.get(2)) {
+        (Some(kind), Some(version), Some(data)) => record(&kind, version, data.clone()),
+        _ => Value::None,
+    }
+}
+
+/// `migration(kind, from, fn)`: registers `fn(data)` as the way from
+/// version `from` of `kind` to the next.
+pub fn migration(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    if let (Some(kind), Some(from), Some(Value::Function(func))) =
+        (str_arg(&args, 0), int_arg(&args, 1), args.get(2))
+    {
+        migrate::register(&kind, from, Migration::Script(func.clone()));
+    }
+    Value::None
+}
+
+/// `upgrade(record)`: `Ok(record)` at its kind's current version, or
+/// `Err(reason)` if a migration failed.
+pub fn upgrade(args: Vec<Value>) -> Value {
+    let val = match call_order(args).into_iter().next() {
+        Some(val) => val,
+        None => return Value::None,
+    };
+    match migrate::upgrade(val) {
+        Ok(val) => Variant::ok(val).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e))).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Push, Return, Sub};
+    use crate::migrate::parse_record;
+    use crate::testing::function;
+    use std::rc::Rc;
+
+    #[test]
+    fn upgrades_through_every_version() {
+        // v1 -> v2 in Rust: data * 2; v2 -> v3 in script: data - 100
+        migrate::register(
+            "score",
+            1,
+            Migration::Host(Rc::new(|data| match data {
+                Value::Integer(i) => Ok(Value::Integer(i * 2)),
+                _ => Err("not an Integer".to_string()),
+            })),
+        );
+        let script = function(vec![
+            Push(Value::Integer(100)).into(),
+            Sub.into(),
+            Return.into(),
+        ]);
+        migration(vec![
+            script.into(),
+            Value::Integer(2),
+            Value::Str("score".into()),
+        ]);
+        let old = versioned(vec![
+            Value::Integer(7),
+            Value::Integer(1),
+            Value::Str("score".into()),
+        ]);
+        let upgraded = match upgrade(vec![old]) {
+            Value::Variant(v) => v.payload().clone(),
+            _ => panic!("expected a Variant"),
+        };
+        assert!(
+            matches!(parse_record(&upgraded), Some((k, 3, Value::Integer(-86))) if &*k == "score")
+        );
+        let bad = versioned(vec![
+            Value::None,
+            Value::Integer(1),
+            Value::Str("score".into()),
+        ]);
+        assert!(matches!(migrate::upgrade(bad), Err(e) if e == "score v1: not an Integer"));
+        assert!(matches!(
+            migrate::upgrade(Value::Integer(1)),
+            Ok(Value::Integer(1))
+        ));
+    }
+}
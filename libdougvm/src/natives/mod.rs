@@ -0,0 +1,99 @@
+//! Native functions, grouped by topic. Natives report bad arguments by
+//! returning `Value::None`.
+
+use std::cell::Cell;
+
+use crate::datamodel::{Buffer, Str, Value, Variant};
+
+pub mod atomic;
+pub mod console;
+pub mod coroutine;
+#[cfg(feature = "csv")]
+pub mod csv;
+pub mod decimal;
+#[cfg(feature = "digest")]
+pub mod digest;
+pub mod encoding;
+pub mod events;
+pub mod exception;
+pub mod frozen;
+pub mod fs;
+pub mod group;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod iter;
+#[cfg(feature = "kv")]
+pub mod kv;
+pub mod migrate;
+pub mod pack;
+pub mod rpc;
+pub mod schema;
+pub mod scratch;
+pub mod sql;
+pub mod stream;
+pub mod string;
+pub mod time;
+pub mod timer;
+#[cfg(feature = "toml")]
+pub mod toml;
+#[cfg(feature = "unicode")]
+pub mod unicode;
+pub mod watchdog;
+#[cfg(feature = "yaml")]
+pub mod yaml;
+
+thread_local! {
+    static DETERMINISTIC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Switches the natives on this thread in or out of deterministic mode. In
+/// it, `time::now` reads a virtual clock the host sets (see
+/// `time::set_clock`) and natives that touch files, the network or a
+/// database fail as if access had been denied, so a script's results
+/// depend only on its inputs.
+pub fn set_deterministic(on: bool) {
+    DETERMINISTIC.with(|d| d.set(on));
+}
+
+pub fn is_deterministic() -> bool {
+    DETERMINISTIC.with(|d| d.get())
+}
+
+/// The `Err` that I/O natives return in deterministic mode.
+pub(crate) fn nondeterministic() -> Value {
+    Variant::err(Value::Str(Str::from("not available in deterministic mode"))).into()
+}
+
+/// Natives receive their arguments in reverse call order (see
+/// `bytecode::ops::Call`); this puts them back in call order.
+pub(crate) fn call_order(mut args: Vec<Value>) -> Vec<Value> {
+    args.reverse();
+    args
+}
+
+pub(crate) fn str_arg(args: &[Value], index: usize) -> Option<Str> {
+    match args.get(index) {
+        Some(Value::Str(s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+pub(crate) fn int_arg(args: &[Value], index: usize) -> Option<i64> {
+    match args.get(index) {
+        Some(Value::Integer(i)) => Some(*i),
+        _ => None,
+    }
+}
+
+/// Accepts either a `Buffer` or a `Str` (as its UTF-8 bytes).
+pub(crate) fn bytes_arg(args: &[Value], index: usize) -> Option<Vec<u8>> {
+    match args.get(index) {
+        Some(Value::Buffer(b)) => Some(b.to_vec()),
+        Some(Value::Str(s)) => Some(s.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+pub(crate) fn bytes_value(bytes: Vec<u8>) -> Value {
+    Value::Buffer(Buffer::new(bytes))
+}
@@ -0,0 +1,248 @@
+//! Binary packing natives. A format string is an optional byte order
+//! (`<` little, `>` or `!` big, `=` native; little if omitted) followed by
+//! fields, each an optional repeat count and a code:
+//!
+//! | code | type | code | type |
+//! |------|------|------|------|
+//! | `b`  | i8   | `B`  | u8   |
+//! | `h`  | i16  | `H`  | u16  |
+//! | `i`  | i32  | `I`  | u32  |
+//! | `q`  | i64  | `Q`  | u64  |
+//! | `f`  | f32  | `d`  | f64  |
+//!
+//! Integer fields pack from and unpack to `Integer`, float fields `Real`.
+
+use super::{bytes_arg, bytes_value, call_order, str_arg};
+use crate::datamodel::{List, Value};
+
+#[derive(Clone, Copy)]
+enum Field {
+    Int { size: usize, signed: bool },
+    Float { size: usize },
+}
+
+impl Field {
+    fn size(self) -> usize {
+        match self {
+            Field::Int { size, .. } | Field::Float { size } => size,
+        }
+    }
+}
+
+/// Expands a format into its byte order and one `Field` per value, or
+/// `None` if it has more than `limit` fields, which can't match the input
+/// anyway and would otherwise let a repeat count allocate without bound.
+fn parse_format(fmt: &str, limit: usize) -> Option<(bool, Vec<Field>)> {
+    let mut chars = fmt.chars().peekable();
+    let big_endian = match chars.peek() {
+        Some('<') => {
+            chars.next();
+            false
+        }
+        Some('>') | Some('!') => {
+            chars.next();
+            true
+        }
+        Some('=') => {
+            chars.next();
+            cfg!(target_endian = "big")
+        }
+        _ => false,
+    };
+    let mut fields = Vec::new();
+    let mut count: Option<usize> = None;
+    for c in chars {
+        if let Some(d) = c.to_digit(10) {
+            count = Some(
+                count
+                    .unwrap_or(0)
+                    .checked_mul(10)?
+                    .checked_add(d as usize)?,
+            );
+            continue;
+        }
+        let field = match c {
+            'b' | 'B' => Field::Int {
+                size: 1,
+                signed: c == 'b',
+            },
+            'h' | 'H' => Field::Int {
+                size: 2,
+                signed: c == 'h',
+            },
+            'i' | 'I' => Field::Int {
+                size: 4,
+                signed: c == 'i',
+            },
+            'q' | 'Q' => Field::Int {
+                size: 8,
+                signed: c == 'q',
+            },
+            'f' => Field::Float { size: 4 },
+            'd' => Field::Float { size: 8 },
+            c if c.is_whitespace() && count.is_none() => continue,
+            _ => return None,
+        };
+        let count = count.take().unwrap_or(1);
+        if count > limit - fields.len() {
+            return None;
+        }
+        fields.extend(std::iter::repeat_n(field, count));
+    }
+    match count {
+        Some(_) => None,
+        None => Some((big_endian, fields)),
+    }
+}
+
+fn pack_field(out: &mut Vec<u8>, field: Field, big_endian: bool, val: &Value) -> Option<()> {
+    let mut bytes = match (field, val) {
+        (Field::Int { size, signed }, Value::Integer(i)) => {
+            let bits = size as u32 * 8;
+            let fits = match (signed, bits) {
+                (_, 64) => signed || *i >= 0,
+                (true, _) => (-(1i64 << (bits - 1))..1i64 << (bits - 1)).contains(i),
+                (false, _) => (0..1i64 << bits).contains(i),
+            };
+            if !fits {
+                return None;
+            }
+            i.to_le_bytes()[..size].to_vec()
+        }
+        (Field::Float { size: 4 }, Value::Real(r)) => (*r as f32).to_le_bytes().to_vec(),
+        (Field::Float { .. }, Value::Real(r)) => r.to_le_bytes().to_vec(),
+        _ => return None,
+    };
+    if big_endian {
+        bytes.reverse();
+    }
+    out.extend(bytes);
+    Some(())
+}
+
+fn unpack_field(bytes: &[u8], field: Field, big_endian: bool) -> Value {
+    let mut le = [0u8; 8];
+    le[..bytes.len()].copy_from_slice(bytes);
+    if big_endian {
+        le[..bytes.len()].reverse();
+    }
+    match field {
+        Field::Int { size, signed } => {
+            let shift = 64 - size as u32 * 8;
+            let raw = u64::from_le_bytes(le) << shift;
+            match signed {
+                true => Value::Integer((raw as i64) >> shift),
+                // u64 fields above i64::MAX wrap; there is no wider Integer
+                false => Value::Integer((raw >> shift) as i64),
+            }
+        }
+        Field::Float { size: 4 } => {
+            let f = f32::from_le_bytes([le[0], le[1], le[2], le[3]]);
+            Value::Real(f as f64)
+        }
+        Field::Float { .. } => Value::Real(f64::from_le_bytes(le)),
+    }
+}
+
+/// `pack(fmt, values)`: packs a `List` of numbers into a `Buffer`. Returns
+/// `None` if the list doesn't match the format or a value is out of range.
+pub fn pack(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (fmt, values) = match (str_arg(&args, 0), args.get(1)) {
+        (Some(fmt), Some(Value::List(values))) => (fmt, values.to_vec()),
+        _ => return Value::None,
+    };
+    let (big_endian, fields) = match parse_format(&fmt, values.len()) {
+        Some(f) if f.1.len() == values.len() => f,
+        _ => return Value::None,
+    };
+    let mut out = Vec::new();
+    for (field, val) in fields.into_iter().zip(values.iter()) {
+        if pack_field(&mut out, field, big_endian, val).is_none() {
+            return Value::None;
+        }
+    }
+    bytes_value(out)
+}
+
+/// `unpack(fmt, bytes)`: the inverse of `pack`. The input must be exactly
+/// as long as the format requires.
+pub fn unpack(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (fmt, bytes) = match (str_arg(&args, 0), bytes_arg(&args, 1)) {
+        (Some(fmt), Some(bytes)) => (fmt, bytes),
+        _ => return Value::None,
+    };
+    // every field takes at least a byte
+    let (big_endian, fields) = match parse_format(&fmt, bytes.len()) {
+        Some(f) if f.1.iter().map(|f| f.size()).sum::<usize>() == bytes.len() => f,
+        _ => return Value::None,
+    };
+    let mut pos = 0;
+    let mut values = Vec::with_capacity(fields.len());
+    for field in fields {
+        values.push(unpack_field(
+            &bytes[pos..pos + field.size()],
+            field,
+            big_endian,
+        ));
+        pos += field.size();
+    }
+    List::new(values).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    fn ints(items: &[i64]) -> Value {
+        List::new(items.iter().map(|i| Value::Integer(*i)).collect()).into()
+    }
+
+    fn as_bytes(v: Value) -> Vec<u8> {
+        match v {
+            Value::Buffer(b) => b.to_vec(),
+            _ => panic!("expected a Buffer"),
+        }
+    }
+
+    #[test]
+    fn pack_unpack() {
+        // natives take their arguments reversed
+        let packed = as_bytes(pack(vec![ints(&[1, -2, 3]), s(">H b I")]));
+        assert_eq!(packed, [0, 1, 0xfe, 0, 0, 0, 3]);
+        let unpacked = match unpack(vec![bytes_value(packed), s(">HbI")]) {
+            Value::List(l) => l.to_vec(),
+            _ => panic!("expected a List"),
+        };
+        assert!(matches!(
+            unpacked[..],
+            [Value::Integer(1), Value::Integer(-2), Value::Integer(3)]
+        ));
+        assert!(matches!(pack(vec![ints(&[256]), s("B")]), Value::None));
+        assert_eq!(as_bytes(pack(vec![ints(&[1, 2]), s("<2h")])), [1, 0, 2, 0]);
+        let real = List::new(vec![Value::Real(1.5)]).into();
+        assert_eq!(as_bytes(pack(vec![real, s("<f")])), 1.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn huge_repeat_counts_are_rejected() {
+        assert!(matches!(
+            pack(vec![ints(&[1]), s("99999999999i")]),
+            Value::None
+        ));
+        let bytes = bytes_value(vec![0; 4]);
+        assert!(matches!(
+            unpack(vec![bytes, s("99999999999i")]),
+            Value::None
+        ));
+        assert!(matches!(
+            unpack(vec![bytes_value(vec![0; 4]), s("4B")]),
+            Value::List(_)
+        ));
+    }
+}
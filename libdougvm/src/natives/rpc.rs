@@ -0,0 +1,80 @@
+//! The RPC native (see `rpc`).
+
+use super::{call_order, str_arg};
+use crate::datamodel::{Str, Value, Variant};
+use crate::rpc::service;
+
+/// `rpc_call(service, function, args)`: calls an exported function with
+/// the items of a `List` or `Tuple`. `Ok(result)` or `Err(reason)`.
+pub fn rpc_call(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let call_args = match args.get(2) {
+        Some(Value::List(l)) => l.to_vec(),
+        Some(Value::Tuple(t)) => (0..t.len()).filter_map(|i| t.get(i)).collect(),
+        _ => return Value::None,
+    };
+    let (name, function) = match (str_arg(&args, 0), str_arg(&args, 1)) {
+        (Some(name), Some(function)) => (name, function),
+        _ => return Value::None,
+    };
+    let result = match service(&name) {
+        Some(service) => service.call(&function, &call_args),
+        None => Err(format!("no service {}", name)),
+    };
+    match result {
+        Ok(val) => Variant::ok(val).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e))).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Add, Return};
+    use crate::datamodel::{Function, List};
+    use crate::rpc::{register, unregister, Exports, Remote, Service};
+    use crate::schema::Schema;
+    use crate::testing::function;
+    use std::rc::Rc;
+
+    fn add() -> Function {
+        function(vec![Add.into(), Return.into()])
+    }
+
+    fn call(service: &str, function: &str, args: Vec<Value>) -> Option<Value> {
+        let name = |s: &str| Value::Str(Str::from(s));
+        match rpc_call(vec![List::new(args).into(), name(function), name(service)]) {
+            Value::Variant(v) if v.tag() == Variant::ok(Value::None).tag() => {
+                Some(v.payload().clone())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn calls_local_and_remote_services() {
+        let mut exports = Exports::new();
+        exports.export("add", add());
+        exports.set_schema("add", Schema::Tuple(vec![Schema::Number, Schema::Number]));
+        register("local", Service::Local(Rc::new(exports)));
+        register(
+            "remote",
+            Service::Remote(Remote::spawn(|| {
+                let mut exports = Exports::new();
+                exports.export("add", add());
+                exports
+            })),
+        );
+        for service in ["local", "remote"] {
+            let sum = call(service, "add", vec![Value::Integer(2), Value::Integer(3)]);
+            assert!(matches!(sum, Some(Value::Integer(5))));
+            assert!(call(service, "missing", Vec::new()).is_none());
+            assert!(call(service, "add", vec![Value::NativeFn(rpc_call)]).is_none());
+        }
+        let text = Value::Str(Str::from("2"));
+        assert!(call("local", "add", vec![text, Value::Integer(3)]).is_none());
+        unregister("local");
+        assert!(call("local", "add", Vec::new()).is_none());
+        unregister("remote");
+    }
+}
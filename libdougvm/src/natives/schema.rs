@@ -0,0 +1,81 @@
+//! The schema validation native (see `schema`).
+
+use super::call_order;
+use crate::datamodel::{Str, Value, Variant};
+use crate::schema::Schema;
+
+/// `validate(value, schema)`: `Ok(value)` if it matches `schema`, written
+/// as script data, otherwise `Err(reason)` naming where it first differs.
+pub fn validate(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (val, schema) = match (args.first(), args.get(1)) {
+        (Some(val), Some(schema)) => (val, schema),
+        _ => return Value::None,
+    };
+    let err = |reason: String| Variant::err(Value::Str(Str::from(reason))).into();
+    match Schema::from_value(schema) {
+        Ok(schema) => match schema.validate(val) {
+            Ok(()) => Variant::ok(val.clone()).into(),
+            Err(e) => err(e.to_string()),
+        },
+        Err(reason) => err(format!("bad schema: {}", reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::{field_key, List, Table, ValueType};
+    use crate::schema::{Field, Schema};
+
+    fn reason(val: Value) -> Option<String> {
+        match val {
+            Value::Variant(v) => match v.payload() {
+                Value::Str(s) if v.tag() == Variant::err(Value::None).tag() => Some(s.to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn reports_the_failing_path() {
+        let item = Table::new();
+        item.set(field_key("name"), Value::Integer(3));
+        let order = Table::new();
+        order.set(
+            field_key("items"),
+            List::new(vec![Table::new().into(), item.into()]).into(),
+        );
+        let order: Value = order.into();
+
+        let schema = Schema::Table {
+            fields: vec![Field::new(
+                "items",
+                Schema::List(Box::new(Schema::Table {
+                    fields: vec![Field::new(
+                        "name",
+                        Schema::Optional(Box::new(Schema::Type(ValueType::Str))),
+                    )],
+                    closed: false,
+                })),
+            )],
+            closed: true,
+        };
+        let e = schema.validate(&order).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "$.items[1].name: expected Str, found Integer"
+        );
+
+        let script_schema = Table::new();
+        script_schema.set(
+            field_key("items"),
+            List::new(vec![Value::Str("Table".into())]).into(),
+        );
+        assert!(reason(validate(vec![script_schema.clone().into(), order.clone()])).is_none());
+        script_schema.set(field_key("total"), Value::Str("Number".into()));
+        let missing = reason(validate(vec![script_schema.into(), order])).unwrap();
+        assert_eq!(missing, format!("$.#{:016x}: missing", field_key("total")));
+    }
+}
@@ -0,0 +1,247 @@
+//! Scratch storage: a flat set of named files a script may use for
+//! intermediate data, bounded in total bytes and file count. It lives in a
+//! `Vfs` of the host's choosing (in memory unless set otherwise), apart
+//! from the filesystem the file natives see, so even a script with no
+//! file access can spill data without reaching the real filesystem.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::rc::Rc;
+
+use super::{bytes_arg, bytes_value, call_order, str_arg};
+use crate::datamodel::{List, Str, Tuple, Value, Variant};
+use crate::vfs::{MemoryFs, Vfs};
+
+pub struct Scratch {
+    vfs: Rc<dyn Vfs>,
+    max_bytes: u64,
+    max_files: usize,
+    /// Size of every file, so quota checks don't touch `vfs`.
+    sizes: RefCell<BTreeMap<String, u64>>,
+}
+
+fn invalid_name(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("bad scratch file name: {:?}", name),
+    )
+}
+
+fn quota_exceeded() -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, "scratch quota exceeded")
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    match name {
+        "" | "." | ".." => Err(invalid_name(name)),
+        // the vfs splits paths on either slash
+        name if name.contains(['/', '\\']) => Err(invalid_name(name)),
+        _ => Ok(()),
+    }
+}
+
+impl Scratch {
+    /// Scratch storage in the root of `vfs`, counting the files already
+    /// there against the quota.
+    pub fn new(vfs: Rc<dyn Vfs>, max_bytes: u64, max_files: usize) -> io::Result<Scratch> {
+        let mut sizes = BTreeMap::new();
+        for name in vfs.list("/")? {
+            let len = vfs.read(&name)?.len() as u64;
+            sizes.insert(name, len);
+        }
+        Ok(Scratch {
+            vfs,
+            max_bytes,
+            max_files,
+            sizes: RefCell::new(sizes),
+        })
+    }
+
+    pub fn in_memory(max_bytes: u64, max_files: usize) -> Scratch {
+        Scratch {
+            vfs: Rc::new(MemoryFs::new()),
+            max_bytes,
+            max_files,
+            sizes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Bytes and files in use.
+    pub fn usage(&self) -> (u64, usize) {
+        let sizes = self.sizes.borrow();
+        (sizes.values().sum(), sizes.len())
+    }
+
+    /// Checks that `name` may grow to `new_len` bytes.
+    fn reserve(&self, name: &str, new_len: u64) -> io::Result<()> {
+        check_name(name)?;
+        let (bytes, files) = self.usage();
+        let old_len = self.sizes.borrow().get(name).copied();
+        let files = files + old_len.is_none() as usize;
+        let bytes = bytes - old_len.unwrap_or(0) + new_len;
+        match bytes <= self.max_bytes && files <= self.max_files {
+            true => Ok(()),
+            false => Err(quota_exceeded()),
+        }
+    }
+
+    pub fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        check_name(name)?;
+        self.vfs.read(name)
+    }
+
+    pub fn write(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        self.reserve(name, bytes.len() as u64)?;
+        self.vfs.write(name, bytes)?;
+        self.sizes
+            .borrow_mut()
+            .insert(name.to_string(), bytes.len() as u64);
+        Ok(())
+    }
+
+    pub fn append(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let old_len = self.sizes.borrow().get(name).copied().unwrap_or(0);
+        let new_len = old_len + bytes.len() as u64;
+        self.reserve(name, new_len)?;
+        self.vfs.append(name, bytes)?;
+        self.sizes.borrow_mut().insert(name.to_string(), new_len);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        check_name(name)?;
+        self.vfs.remove(name)?;
+        self.sizes.borrow_mut().remove(name);
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.sizes.borrow().keys().cloned().collect()
+    }
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Option<Rc<Scratch>>> = const { RefCell::new(None) };
+}
+
+/// Gives scripts on this thread scratch storage, or takes it away with
+/// `None`; returns the previous one. There is none until this is called.
+pub fn set_scratch(scratch: Option<Rc<Scratch>>) -> Option<Rc<Scratch>> {
+    SCRATCH.with(|s| std::mem::replace(&mut *s.borrow_mut(), scratch))
+}
+
+fn with_scratch(op: impl FnOnce(&Scratch) -> io::Result<Value>) -> Value {
+    let scratch = match SCRATCH.with(|s| s.borrow().clone()) {
+        Some(scratch) => scratch,
+        None => return Variant::err(Value::Str(Str::from("no scratch storage"))).into(),
+    };
+    match op(&scratch) {
+        Ok(val) => Variant::ok(val).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e.to_string()))).into(),
+    }
+}
+
+/// `scratch_read(name)`: `Ok(buffer)` or `Err`.
+pub fn scratch_read(args: Vec<Value>) -> Value {
+    match str_arg(&call_order(args), 0) {
+        Some(name) => with_scratch(|s| Ok(bytes_value(s.read(&name)?))),
+        None => Value::None,
+    }
+}
+
+/// `scratch_write(name, data)`: replaces the file with a `Str` or
+/// `Buffer`. `Ok(None)`, or `Err` if it would exceed the quota.
+pub fn scratch_write(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (str_arg(&args, 0), bytes_arg(&args, 1)) {
+        (Some(name), Some(bytes)) => with_scratch(|s| s.write(&name, &bytes).map(|()| Value::None)),
+        _ => Value::None,
+    }
+}
+
+/// `scratch_append(name, data)`: as `scratch_write`, adding to the end.
+pub fn scratch_append(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (str_arg(&args, 0), bytes_arg(&args, 1)) {
+        (Some(name), Some(bytes)) => {
+            with_scratch(|s| s.append(&name, &bytes).map(|()| Value::None))
+        }
+        _ => Value::None,
+    }
+}
+
+/// `scratch_remove(name)`: `Ok(None)` or `Err`.
+pub fn scratch_remove(args: Vec<Value>) -> Value {
+    match str_arg(&call_order(args), 0) {
+        Some(name) => with_scratch(|s| s.remove(&name).map(|()| Value::None)),
+        None => Value::None,
+    }
+}
+
+/// `scratch_list()`: `Ok(names)`, a sorted `List` of `Str`s.
+pub fn scratch_list(_args: Vec<Value>) -> Value {
+    with_scratch(|s| {
+        let names = s.names().into_iter().map(|n| Value::Str(Str::from(n)));
+        Ok(List::new(names.collect()).into())
+    })
+}
+
+/// `scratch_usage()`: `Ok((bytes, files))` in use.
+pub fn scratch_usage(_args: Vec<Value>) -> Value {
+    with_scratch(|s| {
+        let (bytes, files) = s.usage();
+        Ok(Tuple::new(vec![
+            Value::Integer(bytes as i64),
+            Value::Integer(files as i64),
+        ])
+        .into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_in_the_root() {
+        let scratch = Scratch::in_memory(64, 8);
+        for name in ["", ".", "..", "a/b", "../a", "a\\b", "..\\a", "\\a"] {
+            let e = scratch.write(name, b"x").err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert!(scratch.read(name).is_err());
+        }
+        assert_eq!(scratch.usage(), (0, 0));
+        scratch.write("a.b", b"x").unwrap();
+    }
+
+    #[test]
+    fn quota_is_enforced() {
+        let scratch = Scratch::in_memory(8, 2);
+        scratch.write("a", b"1234").unwrap();
+        scratch.append("a", b"56").unwrap();
+        assert!(scratch.append("a", b"789").is_err());
+        scratch.write("b", b"xy").unwrap();
+        assert!(scratch.write("c", b"").is_err());
+        assert!(scratch.write("../etc", b"").is_err());
+        // rewriting a file only counts its new size
+        scratch.write("a", b"123456").unwrap();
+        assert_eq!(scratch.usage(), (8, 2));
+        scratch.remove("b").unwrap();
+        assert_eq!(scratch.read("a").unwrap(), b"123456");
+
+        let previous = set_scratch(Some(Rc::new(scratch)));
+        let name = Value::Str(Str::from("c"));
+        assert!(matches!(
+            scratch_write(vec![name.clone(), name.clone()]),
+            Value::Variant(_)
+        ));
+        assert!(
+            matches!(scratch_list(Vec::new()), Value::Variant(v) if matches!(v.payload(), Value::List(l) if l.len() == 2))
+        );
+        set_scratch(None);
+        let missing = scratch_read(vec![name]);
+        assert!(matches!(missing, Value::Variant(v) if v.tag() == Variant::err(Value::None).tag()));
+        set_scratch(previous);
+    }
+}
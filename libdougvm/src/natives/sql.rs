@@ -0,0 +1,124 @@
+//! Database access through a host-provided driver. The VM ships no
+//! database client; the host implements `SqlDriver` for whatever it uses
+//! and installs it with `set_driver`. Rows reach scripts as `Table`s keyed
+//! by `field_key` of each column name.
+
+use std::cell::RefCell;
+
+use super::{call_order, is_deterministic, nondeterministic, str_arg};
+use crate::datamodel::{field_key, List, Str, Table, Value, Variant};
+
+pub struct Rows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+pub trait SqlDriver {
+    fn query(&mut self, sql: &str, params: &[Value]) -> Result<Rows, String>;
+
+    /// Runs a statement, returning how many rows it affected.
+    fn execute(&mut self, sql: &str, params: &[Value]) -> Result<u64, String>;
+}
+
+thread_local! {
+    static DRIVER: RefCell<Option<Box<dyn SqlDriver>>> = const { RefCell::new(None) };
+}
+
+/// Installs the driver used by natives on this thread, returning the
+/// previous one.
+pub fn set_driver(driver: Option<Box<dyn SqlDriver>>) -> Option<Box<dyn SqlDriver>> {
+    DRIVER.with(|d| std::mem::replace(&mut *d.borrow_mut(), driver))
+}
+
+fn with_driver(
+    args: Vec<Value>,
+    f: impl FnOnce(&mut dyn SqlDriver, &str, &[Value]) -> Result<Value, String>,
+) -> Value {
+    let args = call_order(args);
+    let sql = match str_arg(&args, 0) {
+        Some(sql) => sql,
+        None => return Value::None,
+    };
+    let params = match args.get(1) {
+        None | Some(Value::None) => Vec::new(),
+        Some(Value::List(l)) => l.to_vec(),
+        Some(_) => return Value::None,
+    };
+    if is_deterministic() {
+        return nondeterministic();
+    }
+    let result = DRIVER.with(|d| match d.borrow_mut().as_deref_mut() {
+        Some(driver) => f(driver, &sql, &params),
+        None => Err("no database driver is installed".to_string()),
+    });
+    match result {
+        Ok(val) => Variant::ok(val).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e))).into(),
+    }
+}
+
+/// `sql_query(sql, params?)`: `Ok(rows)` as a `List` of `Table`s, or
+/// `Err(message)`.
+pub fn sql_query(args: Vec<Value>) -> Value {
+    with_driver(args, |driver, sql, params| {
+        let rows = driver.query(sql, params)?;
+        let keys: Vec<u64> = rows.columns.iter().map(|c| field_key(c)).collect();
+        let records = rows.rows.into_iter().map(|row| {
+            let record = Table::new();
+            for (key, val) in keys.iter().zip(row) {
+                record.set(*key, val);
+            }
+            record.into()
+        });
+        Ok(List::new(records.collect()).into())
+    })
+}
+
+/// `sql_execute(sql, params?)`: `Ok(affected_rows)` or `Err(message)`.
+pub fn sql_execute(args: Vec<Value>) -> Value {
+    with_driver(args, |driver, sql, params| {
+        let affected = driver.execute(sql, params)?;
+        Ok(Value::Integer(affected as i64))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::OK;
+
+    struct Echo;
+
+    impl SqlDriver for Echo {
+        fn query(&mut self, _sql: &str, params: &[Value]) -> Result<Rows, String> {
+            Ok(Rows {
+                columns: vec!["id".to_string()],
+                rows: params.iter().map(|p| vec![p.clone()]).collect(),
+            })
+        }
+
+        fn execute(&mut self, sql: &str, _params: &[Value]) -> Result<u64, String> {
+            Err(format!("read-only: {}", sql))
+        }
+    }
+
+    #[test]
+    fn rows_become_tables() {
+        set_driver(Some(Box::new(Echo)));
+        let params = List::new(vec![Value::Integer(7)]).into();
+        let rows = match sql_query(vec![params, Value::Str("select".into())]) {
+            Value::Variant(v) if v.tag() == OK => v.payload().clone(),
+            _ => panic!("expected Ok"),
+        };
+        let rows = match rows {
+            Value::List(l) => l.to_vec(),
+            _ => panic!("expected a List"),
+        };
+        assert!(
+            matches!(&rows[..], [Value::Table(t)] if matches!(t.get(field_key("id")), Some(Value::Integer(7))))
+        );
+        let result = sql_execute(vec![Value::Str("delete".into())]);
+        assert!(matches!(result, Value::Variant(v) if v.tag() != OK));
+        set_driver(None);
+    }
+}
@@ -0,0 +1,116 @@
+//! Stream natives (see `stream`).
+
+use super::{call_order, int_arg};
+use crate::datamodel::{Str, Value, Variant};
+use crate::stream::{block, Stream, Wait};
+
+/// `stream(capacity)`: a new stream, for the host to pick up from the
+/// script's results.
+pub fn stream(args: Vec<Value>) -> Value {
+    match int_arg(&call_order(args), 0) {
+        Some(capacity) if capacity > 0 => Stream::new(capacity as usize).to_value(),
+        _ => Value::None,
+    }
+}
+
+/// `stream_write(stream, value)`: `Ok(None)` once `value` is buffered,
+/// waiting while the stream is full, or `Err` if it has been closed.
+pub fn stream_write(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (stream, val) = match (args.first().and_then(Stream::from_value), args.get(1)) {
+        (Some(stream), Some(val)) => (stream, val.clone()),
+        _ => return Value::None,
+    };
+    if stream.is_closed() {
+        return Variant::err(Value::Str(Str::from("stream closed"))).into();
+    }
+    match stream.push(val) {
+        Ok(()) => Variant::ok(Value::None).into(),
+        Err(_) => {
+            block(Wait::Sink(stream), call_order(args));
+            Value::None
+        }
+    }
+}
+
+/// `stream_read(stream)`: `Some(value)`, waiting while the stream is empty,
+/// or `None` once it is closed and empty.
+pub fn stream_read(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let stream = match args.first().and_then(Stream::from_value) {
+        Some(stream) => stream,
+        None => return Value::None,
+    };
+    match stream.pop() {
+        Some(val) => Variant::some(val).into(),
+        None if stream.is_closed() => Variant::none().into(),
+        None => {
+            block(Wait::Source(stream), call_order(args));
+            Value::None
+        }
+    }
+}
+
+/// `stream_close(stream)`: see `Stream::close`.
+pub fn stream_close(args: Vec<Value>) -> Value {
+    if let Some(stream) = call_order(args).first().and_then(Stream::from_value) {
+        stream.close();
+    }
+    Value::None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Call, Pop, Push, Return};
+    use crate::bytecode::Op;
+    use crate::datamodel::{Function, Tuple};
+    use crate::{VirtualMachine, VmState};
+
+    #[test]
+    fn full_stream_suspends_writer() {
+        let out = Stream::new(2);
+        let mut ops: Vec<Op> = Vec::new();
+        for i in 0..3 {
+            ops.push(Push(out.to_value()).into());
+            ops.push(Push(Value::Integer(i)).into());
+            ops.push(Push(Value::NativeFn(stream_write)).into());
+            ops.push(Call(2).into());
+            ops.push(Pop.into());
+        }
+        ops.push(Push(Value::None).into());
+        ops.push(Return.into());
+        let mut vm = VirtualMachine::new(Function {
+            module: Tuple::new(Vec::new()),
+            ops: ops.into(),
+        });
+        assert!(matches!(
+            vm.run_until_blocked(),
+            Ok(VmState::WaitingForSink(_))
+        ));
+        assert_eq!(out.len(), 2);
+        assert!(matches!(
+            out.drain()[..],
+            [Value::Integer(0), Value::Integer(1)]
+        ));
+        assert!(matches!(vm.run_until_blocked(), Ok(VmState::Exited(_))));
+        assert!(matches!(out.pop(), Some(Value::Integer(2))));
+    }
+
+    #[test]
+    fn reads_wait_until_closed() {
+        let input = Stream::new(1);
+        let read = || stream_read(vec![input.to_value()]);
+        assert!(matches!(read(), Value::None));
+        assert!(matches!(
+            crate::stream::take_blocked(),
+            Some((Wait::Source(_), _))
+        ));
+        assert!(input.push(Value::Integer(7)).is_ok());
+        assert!(input.push(Value::Integer(8)).is_err());
+        assert!(matches!(read(), Value::Variant(_)));
+        input.close();
+        assert!(matches!(read(), Value::Variant(_)));
+        assert!(crate::stream::take_blocked().is_none());
+    }
+}
@@ -0,0 +1,119 @@
+//! String scanning natives. Each returns an `Iter` that walks the input
+//! on demand, so scanning a large string never materializes every match.
+
+use super::{call_order, int_arg, str_arg};
+use crate::datamodel::{Iter, Str, Value};
+
+/// `find_all(haystack, needle)`: yields the byte offset of each
+/// non-overlapping occurrence of `needle`.
+pub fn find_all(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (haystack, needle) = match (str_arg(&args, 0), str_arg(&args, 1)) {
+        (Some(h), Some(n)) if !n.is_empty() => (h, n),
+        _ => return Value::None,
+    };
+    let mut pos = 0;
+    Iter::new(move || {
+        let found = pos + haystack.get(pos..)?.find(&*needle)?;
+        pos = found + needle.len();
+        Some(Value::Integer(found as i64))
+    })
+    .into()
+}
+
+/// `splitn(s, sep, n)`: yields at most `n` pieces of `s` split on `sep`,
+/// the last piece holding the unsplit remainder. A negative `n` means no
+/// limit.
+pub fn splitn(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let (s, sep, n) = match (str_arg(&args, 0), str_arg(&args, 1), int_arg(&args, 2)) {
+        (Some(s), Some(sep), Some(n)) if !sep.is_empty() => (s, sep, n),
+        _ => return Value::None,
+    };
+    let mut remaining = if n < 0 { usize::MAX } else { n as usize };
+    let mut pos = Some(0);
+    Iter::new(move || {
+        let start = pos?;
+        if remaining == 0 {
+            return None;
+        }
+        remaining -= 1;
+        let rest = &s[start..];
+        let end = match rest.find(&*sep) {
+            Some(i) if remaining > 0 => i,
+            _ => {
+                pos = None;
+                return Some(substr(rest));
+            }
+        };
+        pos = Some(start + end + sep.len());
+        Some(substr(&rest[..end]))
+    })
+    .into()
+}
+
+/// `lines(s)`: yields each line of `s` without its `\n` or `\r\n`
+/// terminator.
+pub fn lines(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let s = match str_arg(&args, 0) {
+        Some(s) => s,
+        None => return Value::None,
+    };
+    let mut pos = 0;
+    Iter::new(move || {
+        let rest = s.get(pos..).filter(|r| !r.is_empty())?;
+        let (line, advance) = match rest.find('\n') {
+            Some(i) => (&rest[..i], i + 1),
+            None => (rest, rest.len()),
+        };
+        pos += advance;
+        Some(substr(line.strip_suffix('\r').unwrap_or(line)))
+    })
+    .into()
+}
+
+fn substr(s: &str) -> Value {
+    Value::Str(Str::from(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(it: Value) -> Vec<String> {
+        let it = match it {
+            Value::Iter(it) => it,
+            _ => panic!("expected an Iter"),
+        };
+        std::iter::from_fn(|| it.next())
+            .map(|v| match v {
+                Value::Str(s) => s.to_string(),
+                Value::Integer(i) => i.to_string(),
+                _ => panic!("unexpected item"),
+            })
+            .collect()
+    }
+
+    fn s(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    #[test]
+    fn scanning() {
+        // natives take their arguments reversed
+        assert_eq!(collect(find_all(vec![s("ab"), s("xxabyab")])), ["2", "5"]);
+        assert_eq!(
+            collect(splitn(vec![Value::Integer(2), s(","), s("a,b,c")])),
+            ["a", "b,c"]
+        );
+        assert_eq!(
+            collect(splitn(vec![Value::Integer(-1), s(","), s("a,,b")])),
+            ["a", "", "b"]
+        );
+        assert_eq!(
+            collect(lines(vec![s("one\r\ntwo\n\nthree")])),
+            ["one", "two", "", "three"]
+        );
+    }
+}
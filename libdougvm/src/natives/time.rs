@@ -0,0 +1,322 @@
+//! Date and time natives over `Timestamp` and `Duration` values. All
+//! calendar math is proleptic Gregorian in UTC; offsets in parsed RFC 3339
+//! strings are applied, but formatting always produces `Z`.
+
+use std::cell::Cell;
+use std::time::SystemTime;
+
+use super::{call_order, int_arg, is_deterministic, str_arg};
+use crate::datamodel::{Duration, Str, Timestamp, Tuple, Value};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+const SECS_PER_DAY: i64 = 86_400;
+
+fn timestamp_arg(args: &[Value], index: usize) -> Option<Timestamp> {
+    match args.get(index) {
+        Some(Value::Timestamp(t)) => Some(*t),
+        _ => None,
+    }
+}
+
+fn duration_arg(args: &[Value], index: usize) -> Option<Duration> {
+    match args.get(index) {
+        Some(Value::Duration(d)) => Some(*d),
+        _ => None,
+    }
+}
+
+thread_local! {
+    static CLOCK: Cell<Timestamp> = const { Cell::new(Timestamp(0)) };
+}
+
+/// Sets what `now` returns on this thread in deterministic mode; the host
+/// advances it, e.g. once per simulation tick.
+pub fn set_clock(now: Timestamp) {
+    CLOCK.with(|c| c.set(now));
+}
+
+/// `now()`: the current system time, or the virtual clock in deterministic
+/// mode.
+pub fn now(_args: Vec<Value>) -> Value {
+    if is_deterministic() {
+        return CLOCK.with(|c| c.get()).into();
+    }
+    let nanos = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    };
+    Timestamp(nanos).into()
+}
+
+/// `seconds(n)`: a `Duration` of `n` whole seconds.
+pub fn seconds(args: Vec<Value>) -> Value {
+    int_arg(&call_order(args), 0)
+        .and_then(|n| n.checked_mul(NANOS_PER_SEC))
+        .map(Duration)
+        .into()
+}
+
+/// `millis(n)`: a `Duration` of `n` milliseconds.
+pub fn millis(args: Vec<Value>) -> Value {
+    int_arg(&call_order(args), 0)
+        .and_then(|n| n.checked_mul(1_000_000))
+        .map(Duration)
+        .into()
+}
+
+/// `total_seconds(d)`: a `Duration` as a `Real` number of seconds.
+pub fn total_seconds(args: Vec<Value>) -> Value {
+    duration_arg(&call_order(args), 0)
+        .map(|d| Value::Real(d.0 as f64 / NANOS_PER_SEC as f64))
+        .into()
+}
+
+/// `add(t, d)`: shifts a `Timestamp` by a `Duration`. Also adds two
+/// `Duration`s.
+pub fn add(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    let d = match duration_arg(&args, 1) {
+        Some(d) => d,
+        None => return Value::None,
+    };
+    match args.first() {
+        Some(Value::Timestamp(t)) => t.0.checked_add(d.0).map(Timestamp).into(),
+        Some(Value::Duration(a)) => a.0.checked_add(d.0).map(Duration).into(),
+        _ => Value::None,
+    }
+}
+
+/// `diff(a, b)`: the `Duration` from `Timestamp` `b` to `a`.
+pub fn diff(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (timestamp_arg(&args, 0), timestamp_arg(&args, 1)) {
+        (Some(a), Some(b)) => a.0.checked_sub(b.0).map(Duration).into(),
+        _ => Value::None,
+    }
+}
+
+/// `components(t)`: a `Tuple` of (year, month, day, hour, minute, second,
+/// nanosecond, weekday), with months and days starting at 1 and weekday 0
+/// being Monday.
+pub fn components(args: Vec<Value>) -> Value {
+    let t = match timestamp_arg(&call_order(args), 0) {
+        Some(t) => t,
+        None => return Value::None,
+    };
+    let secs = t.0.div_euclid(NANOS_PER_SEC);
+    let nanos = t.0.rem_euclid(NANOS_PER_SEC);
+    let days = secs.div_euclid(SECS_PER_DAY);
+    let time = secs.rem_euclid(SECS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    // 1970-01-01 was a Thursday
+    let weekday = (days + 3).rem_euclid(7);
+    let parts = [
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        nanos,
+        weekday,
+    ];
+    Tuple::new(parts.iter().map(|i| Value::Integer(*i)).collect()).into()
+}
+
+/// `parse_rfc3339(s)`: e.g. `"2022-03-17T12:30:00.5+01:00"`. Returns `None`
+/// if `s` is not valid RFC 3339.
+pub fn parse_rfc3339(args: Vec<Value>) -> Value {
+    str_arg(&call_order(args), 0)
+        .and_then(|s| parse(&s))
+        .map(Timestamp)
+        .into()
+}
+
+/// `format_rfc3339(t)`: formats in UTC, with fractional seconds only when
+/// they are non-zero.
+pub fn format_rfc3339(args: Vec<Value>) -> Value {
+    let t = match timestamp_arg(&call_order(args), 0) {
+        Some(t) => t,
+        None => return Value::None,
+    };
+    let secs = t.0.div_euclid(NANOS_PER_SEC);
+    let nanos = t.0.rem_euclid(NANOS_PER_SEC);
+    let time = secs.rem_euclid(SECS_PER_DAY);
+    let (y, mo, d) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+    let mut out = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        y,
+        mo,
+        d,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    if nanos != 0 {
+        let frac = format!("{:09}", nanos);
+        out.push('.');
+        out.push_str(frac.trim_end_matches('0'));
+    }
+    out.push('Z');
+    Value::Str(Str::from(out))
+}
+
+/// Parses RFC 3339 into nanoseconds since the epoch.
+pub(crate) fn parse(s: &str) -> Option<i64> {
+    let b = s.as_bytes();
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = b.get(range)?;
+        digits.iter().try_fold(0i64, |n, c| match c {
+            b'0'..=b'9' => Some(n * 10 + (c - b'0') as i64),
+            _ => None,
+        })
+    };
+    let sep = |i: usize, allowed: &[u8]| b.get(i).filter(|c| allowed.contains(c)).is_some();
+    if !(sep(4, b"-") && sep(7, b"-") && sep(10, b"Tt ") && sep(13, b":") && sep(16, b":")) {
+        return None;
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    // allow a leap second, which folds into the following second
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut i = 19;
+    let mut nanos = 0;
+    if sep(i, b".") {
+        let start = i + 1;
+        i = start;
+        while b.get(i).filter(|c| c.is_ascii_digit()).is_some() {
+            i += 1;
+        }
+        if i == start {
+            return None;
+        }
+        // digits past nanosecond precision are truncated
+        let digits = (i - start).min(9);
+        nanos = num(start..start + digits)? * 10i64.pow(9 - digits as u32);
+    }
+    let offset = match b.get(i)? {
+        b'Z' | b'z' if i + 1 == b.len() => 0,
+        b'+' | b'-' if i + 6 == b.len() && sep(i + 3, b":") => {
+            let (h, m) = (num(i + 1..i + 3)?, num(i + 4..i + 6)?);
+            if h > 23 || m > 59 {
+                return None;
+            }
+            let offset = h * 3600 + m * 60;
+            if b[i] == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+    let secs = days_from_civil(year, month, day) * SECS_PER_DAY + hour * 3600 + minute * 60
+        - offset
+        + second;
+    secs.checked_mul(NANOS_PER_SEC)?.checked_add(nanos)
+}
+
+fn is_leap(y: i64) -> bool {
+    y % 4 == 0 && (y % 100 != 0 || y % 400 == 0)
+}
+
+fn days_in_month(y: i64, m: i64) -> i64 {
+    match m {
+        2 if is_leap(y) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Conversions between a day count since 1970-01-01 and a civil date, after
+// Howard Hinnant's `days_from_civil`/`civil_from_days`.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(s: &str) -> Option<i64> {
+        match parse_rfc3339(vec![Value::Str(s.into())]) {
+            Value::Timestamp(t) => Some(t.0),
+            _ => None,
+        }
+    }
+
+    fn formatted(nanos: i64) -> String {
+        match format_rfc3339(vec![Timestamp(nanos).into()]) {
+            Value::Str(s) => s.to_string(),
+            _ => panic!("expected a Str"),
+        }
+    }
+
+    #[test]
+    fn rfc3339_round_trip() {
+        assert_eq!(parsed("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parsed("1970-01-01T01:00:00+01:00"), Some(0));
+        assert_eq!(
+            parsed("2000-02-29T12:00:00.25Z"),
+            Some(951_825_600_250_000_000)
+        );
+        assert_eq!(parsed("2001-02-29T00:00:00Z"), None);
+        assert_eq!(parsed("2001-01-01 00:00:00"), None);
+        assert_eq!(
+            formatted(951_825_600_250_000_000),
+            "2000-02-29T12:00:00.25Z"
+        );
+        assert_eq!(formatted(-1_000_000_000), "1969-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn calendar_components() {
+        let t = Timestamp(951_825_600_250_000_000).into();
+        let parts = match components(vec![t]) {
+            Value::Tuple(t) => t,
+            _ => panic!("expected a Tuple"),
+        };
+        let parts: Vec<i64> = (0..parts.len())
+            .map(|i| match parts.get(i) {
+                Some(Value::Integer(n)) => n,
+                _ => panic!("expected an Integer"),
+            })
+            .collect();
+        // a Tuesday
+        assert_eq!(parts, [2000, 2, 29, 12, 0, 0, 250_000_000, 1]);
+    }
+
+    #[test]
+    fn deterministic_mode_reads_virtual_clock() {
+        crate::natives::set_deterministic(true);
+        set_clock(Timestamp(42));
+        assert!(matches!(now(Vec::new()), Value::Timestamp(Timestamp(42))));
+        crate::natives::set_deterministic(false);
+        assert!(matches!(now(Vec::new()), Value::Timestamp(t) if t.0 > 42));
+    }
+}
@@ -0,0 +1,95 @@
+//! Timer natives. Callbacks run as new scheduler tasks once the host has
+//! advanced time past their deadline (see `Scheduler::advance_time`).
+
+use super::{call_order, int_arg};
+use crate::datamodel::{Function, Value};
+use crate::timer::{request_cancel, request_start};
+
+fn millis_arg(args: &[Value], index: usize) -> Option<u64> {
+    match args.get(index) {
+        Some(Value::Duration(d)) => u64::try_from(d.0 / 1_000_000).ok(),
+        _ => u64::try_from(int_arg(args, index)?).ok(),
+    }
+}
+
+fn function_arg(args: &[Value], index: usize) -> Option<Function> {
+    match args.get(index) {
+        Some(Value::Function(f)) => Some(f.clone()),
+        _ => None,
+    }
+}
+
+/// `after(ms, fn)`: calls `fn()` once, `ms` milliseconds (an `Integer` or
+/// a `Duration`) from now. Returns the timer's id.
+pub fn after(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (millis_arg(&args, 0), function_arg(&args, 1)) {
+        (Some(ms), Some(func)) => Value::Integer(request_start(ms, None, func) as i64),
+        _ => Value::None,
+    }
+}
+
+/// `every(ms, fn)`: calls `fn()` every `ms` milliseconds until cancelled.
+/// Returns the timer's id.
+pub fn every(args: Vec<Value>) -> Value {
+    let args = call_order(args);
+    match (millis_arg(&args, 0), function_arg(&args, 1)) {
+        (Some(ms), Some(func)) if ms > 0 => {
+            Value::Integer(request_start(ms, Some(ms), func) as i64)
+        }
+        _ => Value::None,
+    }
+}
+
+/// `cancel_timer(id)`: stops a timer; callbacks already due still run.
+pub fn cancel_timer(args: Vec<Value>) -> Value {
+    if let Some(id) = int_arg(&call_order(args), 0) {
+        request_cancel(id as u64);
+    }
+    Value::None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Return;
+    use crate::datamodel::Duration;
+    use crate::testing::function;
+    use crate::timer::{take_requests, Request};
+
+    #[test]
+    fn timers_are_requested() {
+        let func: Value = function(vec![Return.into()]).into();
+        // natives get their arguments last-first
+        let once = after(vec![func.clone(), Value::Integer(10)]);
+        let tick = every(vec![func.clone(), Value::Duration(Duration(4_000_000))]);
+        assert!(matches!(
+            every(vec![func.clone(), Value::Integer(0)]),
+            Value::None
+        ));
+        assert!(matches!(after(vec![func, Value::Integer(-1)]), Value::None));
+        assert!(matches!(
+            after(vec![Value::None, Value::Integer(1)]),
+            Value::None
+        ));
+        let (once, tick) = match (once, tick) {
+            (Value::Integer(once), Value::Integer(tick)) => (once as u64, tick as u64),
+            _ => panic!("expected timer ids"),
+        };
+        cancel_timer(vec![Value::Integer(once as i64)]);
+        match &take_requests()[..] {
+            [Request::Start {
+                id: a,
+                delay: 10,
+                interval: None,
+                ..
+            }, Request::Start {
+                id: b,
+                delay: 4,
+                interval: Some(4),
+                ..
+            }, Request::Cancel(c)] => assert_eq!((*a, *b, *c), (once, tick, once)),
+            _ => panic!("expected two starts and a cancel"),
+        }
+    }
+}
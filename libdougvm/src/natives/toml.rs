@@ -0,0 +1,82 @@
+//! TOML parsing (feature `toml`). Documents become nested `Table`s keyed
+//! by `field_key` of each name, so scripts read them with ordinary field
+//! access. Arrays become `List`s, booleans `Integer` 0 or 1, and offset
+//! date-times `Timestamp`s; local dates and times stay `Str`.
+
+use super::{call_order, str_arg};
+use crate::datamodel::{field_key, List, Str, Table, Timestamp, Value, Variant};
+use crate::natives::time;
+
+fn convert(val: toml::Value) -> Value {
+    match val {
+        toml::Value::String(s) => Value::Str(Str::from(s)),
+        toml::Value::Integer(i) => Value::Integer(i),
+        toml::Value::Float(f) => Value::Real(f),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(dt) => {
+            let s = dt.to_string();
+            match time::parse(&s) {
+                Some(nanos) => Timestamp(nanos).into(),
+                None => Value::Str(Str::from(s)),
+            }
+        }
+        toml::Value::Array(items) => List::new(items.into_iter().map(convert).collect()).into(),
+        toml::Value::Table(table) => convert_table(table),
+    }
+}
+
+fn convert_table(table: toml::Table) -> Value {
+    let out = Table::new();
+    for (k, v) in table {
+        out.set(field_key(&k), convert(v));
+    }
+    out.into()
+}
+
+/// `parse_toml(text)`: `Ok(table)`, or `Err(message)` if `text` is not valid
+/// TOML.
+pub fn parse_toml(args: Vec<Value>) -> Value {
+    let text = match str_arg(&call_order(args), 0) {
+        Some(text) => text,
+        None => return Value::None,
+    };
+    match text.parse::<toml::Table>() {
+        Ok(table) => Variant::ok(convert_table(table)).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e.to_string()))).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::OK;
+
+    #[test]
+    fn parses_document() {
+        let text = "[server]\nport = 8080\nstarted = 1979-05-27T07:32:00Z\n";
+        let doc = match parse_toml(vec![Value::Str(text.into())]) {
+            Value::Variant(v) if v.tag() == OK => v.payload().clone(),
+            _ => panic!("expected Ok"),
+        };
+        let server = match doc {
+            Value::Table(t) => t.get(field_key("server")),
+            _ => panic!("expected a Table"),
+        };
+        let server = match server {
+            Some(Value::Table(t)) => t,
+            _ => panic!("expected a Table"),
+        };
+        assert!(matches!(
+            server.get(field_key("port")),
+            Some(Value::Integer(8080))
+        ));
+        assert!(matches!(
+            server.get(field_key("started")),
+            Some(Value::Timestamp(_))
+        ));
+        assert!(matches!(
+            parse_toml(vec![Value::Str("= 1".into())]),
+            Value::Variant(v) if v.tag() != OK
+        ));
+    }
+}
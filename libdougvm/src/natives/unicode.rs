@@ -0,0 +1,82 @@
+//! Unicode-aware string natives (feature `unicode`). Byte-wise comparisons
+//! of international text give wrong answers whenever the same text can be
+//! encoded more than one way, so scripts should normalize or case-fold
+//! before comparing.
+
+use caseless::default_case_fold_str;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::{call_order, str_arg};
+use crate::datamodel::{Iter, Str, Value};
+
+fn map_str(args: Vec<Value>, f: impl FnOnce(&str) -> String) -> Value {
+    match str_arg(&call_order(args), 0) {
+        Some(s) => Value::Str(Str::from(f(&s))),
+        None => Value::None,
+    }
+}
+
+/// `nfc(s)`: canonical composition.
+pub fn nfc(args: Vec<Value>) -> Value {
+    map_str(args, |s| s.nfc().collect())
+}
+
+/// `nfd(s)`: canonical decomposition.
+pub fn nfd(args: Vec<Value>) -> Value {
+    map_str(args, |s| s.nfd().collect())
+}
+
+/// `casefold(s)`: locale-independent full case folding, e.g. `"Straße"`
+/// and `"STRASSE"` both fold to `"strasse"`.
+pub fn casefold(args: Vec<Value>) -> Value {
+    map_str(args, default_case_fold_str)
+}
+
+/// `graphemes(s)`: yields each extended grapheme cluster of `s`.
+pub fn graphemes(args: Vec<Value>) -> Value {
+    let s = match str_arg(&call_order(args), 0) {
+        Some(s) => s,
+        None => return Value::None,
+    };
+    let mut pos = 0;
+    Iter::new(move || {
+        let g = s[pos..].graphemes(true).next()?;
+        pos += g.len();
+        Some(Value::Str(Str::from(g)))
+    })
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    fn as_str(v: Value) -> String {
+        match v {
+            Value::Str(s) => s.to_string(),
+            _ => panic!("expected a Str"),
+        }
+    }
+
+    #[test]
+    fn normalize_and_fold() {
+        assert_eq!(as_str(nfc(vec![s("e\u{301}")])), "\u{e9}");
+        assert_eq!(as_str(nfd(vec![s("\u{e9}")])), "e\u{301}");
+        assert_eq!(
+            as_str(casefold(vec![s("Straße")])),
+            as_str(casefold(vec![s("STRASSE")]))
+        );
+        let it = match graphemes(vec![s("e\u{301}x")]) {
+            Value::Iter(it) => it,
+            _ => panic!("expected an Iter"),
+        };
+        assert_eq!(as_str(it.next().unwrap()), "e\u{301}");
+        assert_eq!(as_str(it.next().unwrap()), "x");
+        assert!(it.next().is_none());
+    }
+}
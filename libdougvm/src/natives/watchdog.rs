@@ -0,0 +1,11 @@
+//! Watchdog natives (see `watchdog`).
+
+use crate::datamodel::Value;
+use crate::watchdog;
+
+/// `progress()`: tells the watchdog the script is doing real work, e.g.
+/// once per item in a long loop that would otherwise look stuck.
+pub fn progress(_args: Vec<Value>) -> Value {
+    watchdog::progress();
+    Value::None
+}
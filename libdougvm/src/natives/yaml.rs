@@ -0,0 +1,77 @@
+//! YAML parsing (feature `yaml`), with the same mapping as `natives::toml`:
+//! mappings become `Table`s keyed by `field_key`, sequences `List`s, and
+//! null `None`. Mapping keys must be strings.
+
+use serde_yaml::Value as Yaml;
+
+use super::{call_order, str_arg};
+use crate::datamodel::{field_key, List, Str, Table, Value, Variant};
+
+fn convert(val: Yaml) -> Result<Value, String> {
+    Ok(match val {
+        Yaml::Null => Value::None,
+        Yaml::Bool(b) => b.into(),
+        Yaml::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Yaml::String(s) => Value::Str(Str::from(s)),
+        Yaml::Sequence(items) => {
+            let items: Result<Vec<_>, _> = items.into_iter().map(convert).collect();
+            List::new(items?).into()
+        }
+        Yaml::Mapping(mapping) => {
+            let out = Table::new();
+            for (k, v) in mapping {
+                let k = match k {
+                    Yaml::String(k) => k,
+                    _ => return Err("mapping keys must be strings".to_string()),
+                };
+                out.set(field_key(&k), convert(v)?);
+            }
+            out.into()
+        }
+        Yaml::Tagged(tagged) => convert(tagged.value)?,
+    })
+}
+
+/// `parse_yaml(text)`: `Ok(value)`, or `Err(message)` if `text` is not
+/// valid YAML or can't be represented.
+pub fn parse_yaml(args: Vec<Value>) -> Value {
+    let text = match str_arg(&call_order(args), 0) {
+        Some(text) => text,
+        None => return Value::None,
+    };
+    let result = serde_yaml::from_str(&text)
+        .map_err(|e| e.to_string())
+        .and_then(convert);
+    match result {
+        Ok(val) => Variant::ok(val).into(),
+        Err(e) => Variant::err(Value::Str(Str::from(e))).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::OK;
+
+    #[test]
+    fn parses_document() {
+        let text = "name: demo\nports: [80, 443]\n";
+        let doc = match parse_yaml(vec![Value::Str(text.into())]) {
+            Value::Variant(v) if v.tag() == OK => v.payload().clone(),
+            _ => panic!("expected Ok"),
+        };
+        let doc = match doc {
+            Value::Table(t) => t,
+            _ => panic!("expected a Table"),
+        };
+        assert!(matches!(doc.get(field_key("name")), Some(Value::Str(s)) if &*s == "demo"));
+        assert!(matches!(doc.get(field_key("ports")), Some(Value::List(l)) if l.len() == 2));
+        assert!(matches!(
+            parse_yaml(vec![Value::Str("{1: 2}".into())]),
+            Value::Variant(v) if v.tag() != OK
+        ));
+    }
+}
@@ -0,0 +1,265 @@
+//! Compile-time evaluation of expressions the source marks as constant,
+//! such as buffer sizes and lookup tables, so their values are built once
+//! by the compiler and loaded from the constant pool at startup rather
+//! than recomputed by every run.
+//!
+//! The compiler emits each annotated expression as a function of no
+//! arguments, called in place by `Push(f), Call(0)`, and marks `f` with
+//! `ConstEval::mark`. `ConstEval::function` then runs each marked call it
+//! finds in a VM of its own and replaces the call with a `Push` of the
+//! result. Unlike `constprop`, which only folds what it can prove pure and
+//! leaves the rest, an annotated expression must evaluate: one that fails,
+//! or that uses anything outside the compile-time subset, is an error for
+//! the compiler to report.
+//!
+//! The subset is every op except those reaching the host or the running
+//! VM (`CallHost`, `LoadModule`, `Intern`, coroutines and resource scopes),
+//! with no native functions among the constants. A result that is a
+//! container is shared by every run of its `Push`, so it is only right
+//! for results the program doesn't mutate, which is what a constant is.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::ops::{Call, Push};
+use crate::bytecode::{relocate, Op};
+use crate::datamodel::{Function, Identity, Value, ValueType};
+use crate::{VirtualMachine, VmConfig, VmError};
+
+/// Ops a constant expression may run, including its callees, before it
+/// fails with `OpError::OutOfFuel`.
+pub const CONST_FUEL: u64 = 1_000_000;
+
+pub enum ConstError {
+    /// Op `at` of `func`, reached from a marked expression, is outside the
+    /// compile-time subset.
+    Unsupported { func: Function, at: usize },
+    /// A constant of this type, in a function a marked expression reaches,
+    /// can't be used at compile time.
+    Native(ValueType),
+    /// The expression failed while evaluating.
+    Failed(VmError),
+    /// The expression evaluated to a value of this type, which can't be a
+    /// constant.
+    Unembeddable(ValueType),
+}
+
+fn is_const_op(op: &Op) -> bool {
+    !matches!(
+        op,
+        Op::CallHost(_)
+            | Op::LoadModule(_)
+            | Op::Intern(_)
+            | Op::Yield(_)
+            | Op::Resume(_)
+            | Op::Enter(_)
+            | Op::Exit(_)
+    )
+}
+
+/// Values that belong to a running VM, or to the host, rather than to the
+/// program.
+fn is_native(val: &Value) -> bool {
+    matches!(
+        val,
+        Value::NativeFn(_) | Value::Iter(_) | Value::Coroutine(_) | Value::Unknown(_)
+    )
+}
+
+#[derive(Default)]
+pub struct ConstEval {
+    marked: HashMap<usize, Function>,
+    /// Functions already rewritten, by identity of the original.
+    done: HashMap<usize, Function>,
+    /// Results of marked expressions already evaluated.
+    values: HashMap<usize, Value>,
+}
+
+impl ConstEval {
+    pub fn new() -> ConstEval {
+        ConstEval::default()
+    }
+
+    /// Marks `expr` as a constant expression, to be evaluated wherever it
+    /// is called with `Call(0)`.
+    pub fn mark(&mut self, expr: &Function) {
+        self.marked.insert(expr.identity(), expr.clone());
+    }
+
+    /// Rewrites `func` and, first, every function it references, replacing
+    /// each call of a marked expression with its value.
+    pub fn function(&mut self, func: &Function) -> Result<Function, ConstError> {
+        if let Some(done) = self.done.get(&func.identity()) {
+            return Ok(done.clone());
+        }
+        // recursion sees the original, which still evaluates correctly
+        self.done.insert(func.identity(), func.clone());
+        let rewritten = self.rewrite(func)?;
+        self.done.insert(func.identity(), rewritten.clone());
+        Ok(rewritten)
+    }
+
+    fn rewrite(&mut self, func: &Function) -> Result<Function, ConstError> {
+        let ops = &func.ops;
+        let mut targets = vec![false; ops.len() + 1];
+        for (i, op) in ops.iter().enumerate() {
+            for offset in op.jump_offsets() {
+                let t = (i as i64 + 1 + offset as i64).clamp(0, ops.len() as i64);
+                targets[t as usize] = true;
+            }
+        }
+        let mut out = Vec::with_capacity(ops.len());
+        let mut map = Vec::with_capacity(ops.len() + 1);
+        let mut i = 0;
+        while i < ops.len() {
+            map.push(out.len());
+            if let (Op::Push(Push(Value::Function(f))), Some(Op::Call(Call(0)))) =
+                (&ops[i], ops.get(i + 1))
+            {
+                // a jump straight to the call could bring another callee
+                if self.marked.contains_key(&f.identity()) && !targets[i + 1] {
+                    let val = self.evaluate(f)?;
+                    out.push(Push(val).into());
+                    map.push(out.len() - 1);
+                    i += 2;
+                    continue;
+                }
+            }
+            out.push(match &ops[i] {
+                Op::Push(Push(Value::Function(f))) => Push(self.function(f)?.into()).into(),
+                op => op.clone(),
+            });
+            i += 1;
+        }
+        map.push(out.len());
+        relocate(ops, &map, &mut out);
+        Ok(Function {
+            module: func.module.clone(),
+            ops: out.into(),
+        })
+    }
+
+    fn evaluate(&mut self, expr: &Function) -> Result<Value, ConstError> {
+        if let Some(val) = self.values.get(&expr.identity()) {
+            return Ok(val.clone());
+        }
+        check(expr, &mut HashSet::new())?;
+        // marked expressions inside this one are evaluated first, so each
+        // is only evaluated once
+        let expr_now = self.function(expr)?;
+        let config = VmConfig {
+            fuel: Some(CONST_FUEL),
+            ..VmConfig::default()
+        };
+        let val = VirtualMachine::with_config(expr_now, config)
+            .run_until_exited()
+            .map_err(ConstError::Failed)?;
+        if is_native(&val) || matches!(val, Value::Function(_) | Value::Closure(_)) {
+            return Err(ConstError::Unembeddable(val.get_type()));
+        }
+        self.values.insert(expr.identity(), val.clone());
+        Ok(val)
+    }
+}
+
+/// Checks that `func`, and every function it can reach through its
+/// constants, stays within the compile-time subset.
+fn check(func: &Function, seen: &mut HashSet<usize>) -> Result<(), ConstError> {
+    if !seen.insert(func.identity()) {
+        return Ok(());
+    }
+    let module = &func.module;
+    let constants = (0..module.len()).filter_map(|i| module.get(i));
+    let pushed = func.ops.iter().filter_map(|op| match op {
+        Op::Push(Push(val)) => Some(val.clone()),
+        _ => None,
+    });
+    for val in constants.chain(pushed).collect::<Vec<_>>() {
+        match val {
+            Value::Function(f) => check(&f, seen)?,
+            val if is_native(&val) => return Err(ConstError::Native(val.get_type())),
+            _ => {}
+        }
+    }
+    match func.ops.iter().position(|op| !is_const_op(op)) {
+        Some(at) => Err(ConstError::Unsupported {
+            func: func.clone(),
+            at,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::OpError;
+    use crate::testing::function;
+
+    #[test]
+    fn marked_expressions_become_constants() {
+        // a lookup table of squares, built by a loop
+        let squares = function(vec![
+            NewMap.into(),
+            Store(1).into(),
+            Push(Value::Integer(0)).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Load(2).into(),
+            Load(2).into(),
+            Load(2).into(),
+            Mul.into(),
+            MapInsert.into(),
+            Load(2).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Store(2).into(),
+            Load(2).into(),
+            Push(Value::Integer(4)).into(),
+            Lt.into(),
+            JumpIf(-14).into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(squares.clone().into()).into(),
+            Call(0).into(),
+            Push(Value::Integer(3)).into(),
+            MapGet.into(),
+            Return.into(),
+        ]);
+        let mut consts = ConstEval::new();
+        consts.mark(&squares);
+        let rewritten = consts.function(&main).ok().unwrap();
+        match &rewritten.ops[..] {
+            [Op::Push(Push(Value::Map(m))), Op::Push(_), Op::MapGet(_), Op::Return(_)] => {
+                assert_eq!(m.len(), 4)
+            }
+            _ => panic!("the table wasn't evaluated"),
+        }
+        let mut vm = VirtualMachine::new(rewritten);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(9))));
+
+        // unmarked calls are left alone
+        let left = ConstEval::new().function(&main).ok().unwrap();
+        assert!(matches!(left.ops[1], Op::Call(_)));
+    }
+
+    #[test]
+    fn expressions_outside_the_subset_are_errors() {
+        let host = function(vec![CallHost { index: 0, argc: 0 }.into(), Return.into()]);
+        let failing = function(vec![Push(Value::Integer(1)).into(), Neg.into(), Neg.into()]);
+        let mut consts = ConstEval::new();
+        consts.mark(&host);
+        consts.mark(&failing);
+        let call = |f: &Function| function(vec![Push(f.clone().into()).into(), Call(0).into()]);
+        assert!(matches!(
+            consts.function(&call(&host)),
+            Err(ConstError::Unsupported { at: 0, .. })
+        ));
+        match consts.function(&call(&failing)) {
+            Err(ConstError::Failed(e)) => assert!(matches!(e.error, OpError::FellOffEnd)),
+            _ => panic!("expected the expression to fail"),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Constant propagation across calls. Pure ops whose operands are all
+//! constants are evaluated ahead of time, and so are calls to pure callees
+//! with all-constant arguments, so a configuration constant passed through
+//! layers of helpers folds down to a single `Push`.
+//!
+//! A callee is pure if it only uses ops that can't observe or change
+//! anything outside its frame. Callees are folded first (each function
+//! once), which is what lets constants flow through several layers.
+//! Reaching callees through `Push(Function)` constants stands in for the
+//! module graph until there is a linker.
+
+use std::collections::HashMap;
+
+use crate::bytecode::ops::Push;
+use crate::bytecode::{relocate, Op, OpAction};
+use crate::datamodel::{Function, Identity, Value};
+use crate::CallFrame;
+
+/// Steps a folded call may take before we give up and leave it to runtime.
+pub const FOLD_STEP_LIMIT: usize = 10_000;
+
+fn is_pure(op: &Op) -> bool {
+    match op {
+        Op::Push(_) | Op::Pop(_) | Op::Load(_) | Op::Store(_) | Op::Select(_) => true,
+        Op::Return(_) | Op::Halt(_) => true,
+        Op::Jump(_) | Op::JumpIf(_) | Op::JumpIfNot(_) | Op::IncJumpLt(_) => true,
+        Op::SwitchStr(_) | Op::Switch(_) => true,
+        Op::Add(_) | Op::Sub(_) | Op::Mul(_) | Op::Div(_) | Op::Rem(_) | Op::Neg(_) => true,
+        Op::Eq(_) | Op::Ne(_) | Op::Lt(_) | Op::Le(_) | Op::Gt(_) | Op::Ge(_) => true,
+        Op::Same(_) => true,
+        Op::AddInt(_) | Op::SubInt(_) | Op::LtInt(_) | Op::Speculate(_) => true,
+        Op::AddIntUnchecked(_) | Op::SubIntUnchecked(_) => true,
+        Op::AddImm(_) | Op::AddLocals(_) => true,
+        Op::MakeVariant(_) | Op::IsTag(_) | Op::GetTag(_) | Op::Unwrap(_) | Op::Try(_) => true,
+        // calls, allocations, and anything reading mutable or host-registered state
+        Op::NewTable(_) | Op::NewRecord(_) | Op::NewMap(_) | Op::MapIter(_) => false,
+        Op::MakeClosure(_) | Op::LoadUpvalue(_) => false,
+        Op::MapInsert(_) | Op::MapGet(_) | Op::MapDelete(_) | Op::MapContains(_) => false,
+        // iterators may be native closures over outside state
+        Op::GetIter(_) | Op::ForIter(_) => false,
+        // constants can be mutable containers, read at runtime
+        Op::DeepEq(_) | Op::Cmp(_) => false,
+        Op::Call(_) | Op::Invoke(_) | Op::Implements(_) | Op::GetField(_) | Op::SetField(_) => {
+            false
+        }
+        Op::CallHost(_) => false,
+        // exceptions can leave the frame
+        Op::PushHandler(_) | Op::PopHandler(_) | Op::Throw(_) => false,
+        Op::Enter(_) | Op::Exit(_) => false,
+        Op::Yield(_) | Op::Resume(_) | Op::LoadModule(_) | Op::Intern(_) => false,
+    }
+}
+
+/// Deepest call chain a fold will follow.
+pub const FOLD_DEPTH_LIMIT: usize = 64;
+
+/// A callee can be folded if it is pure apart from calls, which `eval`
+/// checks in turn as it reaches them.
+fn is_foldable(func: &Function) -> bool {
+    func.ops
+        .iter()
+        .all(|op| is_pure(op) || matches!(op, Op::Call(_)))
+}
+
+/// Runs `func` in a scratch frame with `args` (first argument first),
+/// returning the result if it finishes without error within the limits.
+fn eval(func: &Function, args: &[Value], steps: &mut usize, depth: usize) -> Option<Value> {
+    if depth > FOLD_DEPTH_LIMIT || !is_foldable(func) {
+        return None;
+    }
+    let mut frame = CallFrame::new(func.clone());
+    for arg in args.iter().rev() {
+        frame.push(arg.clone());
+    }
+    loop {
+        *steps = steps.checked_sub(1)?;
+        match frame.exec().ok()? {
+            OpAction::None => {}
+            OpAction::Jump(offset) => frame.jump(offset),
+            OpAction::Return(val) => return Some(val),
+            OpAction::Call(callee, mut args) => {
+                // args arrive last-first, see `ops::Call`
+                args.reverse();
+                let val = eval(&callee, &args, steps, depth + 1)?;
+                frame.push(val);
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Folds `func` and, first, every function it references.
+pub fn propagate(func: &Function) -> Function {
+    Propagator::default().function(func)
+}
+
+#[derive(Default)]
+struct Propagator {
+    done: HashMap<usize, Function>,
+}
+
+struct Entry {
+    op: Op,
+    /// First original op index this entry stands for.
+    origin: usize,
+}
+
+impl Propagator {
+    fn function(&mut self, func: &Function) -> Function {
+        if let Some(done) = self.done.get(&func.identity()) {
+            return done.clone();
+        }
+        // recursion sees the unfolded function, which is still correct
+        self.done.insert(func.identity(), func.clone());
+        let folded = self.fold(func);
+        self.done.insert(func.identity(), folded.clone());
+        folded
+    }
+
+    fn fold(&mut self, func: &Function) -> Function {
+        let ops = &func.ops;
+        let mut targets = vec![false; ops.len() + 1];
+        for (i, op) in ops.iter().enumerate() {
+            for offset in op.jump_offsets() {
+                let t = (i as i64 + 1 + offset as i64).clamp(0, ops.len() as i64);
+                targets[t as usize] = true;
+            }
+        }
+        let mut out: Vec<Entry> = Vec::with_capacity(ops.len());
+        for (i, op) in ops.iter().enumerate() {
+            let op = match op {
+                Op::Push(Push(Value::Function(f))) => Push(self.function(f).into()).into(),
+                op => op.clone(),
+            };
+            out.push(Entry { op, origin: i });
+            while let Some((val, replaced, origin)) = self.try_fold(&out, &targets) {
+                out.truncate(out.len() - replaced);
+                out.push(Entry {
+                    op: Push(val).into(),
+                    origin,
+                });
+            }
+        }
+        // map every original index to the entry that now covers it
+        let mut map = vec![0; ops.len() + 1];
+        for (j, entry) in out.iter().enumerate() {
+            let end = out.get(j + 1).map_or(ops.len(), |e| e.origin);
+            for slot in &mut map[entry.origin..end] {
+                *slot = j;
+            }
+        }
+        map[ops.len()] = out.len();
+        let mut rebuilt: Vec<Op> = out.into_iter().map(|e| e.op).collect();
+        relocate(ops, &map, &mut rebuilt);
+        Function {
+            module: func.module.clone(),
+            ops: rebuilt.into(),
+        }
+    }
+
+    /// Tries to fold the tail of `out` into one constant, returning it with
+    /// how many entries it replaces and the origin of the first of them.
+    fn try_fold(&self, out: &[Entry], targets: &[bool]) -> Option<(Value, usize, usize)> {
+        let last = out.last()?;
+        let (argc, callee) = match &last.op {
+            Op::Call(call) => (call.0 as usize + 1, true),
+            op if is_pure(op) => match op.stack_effect() {
+                Some((pops, 1)) if pops > 0 => (pops, false),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let tail = out.len().checked_sub(argc + 1)?;
+        let operands = &out[tail..out.len() - 1];
+        // only the first operand may be jumped to, or the fold changes
+        // what a jump into the middle would have seen
+        if operands[1..]
+            .iter()
+            .chain([last])
+            .any(|e| targets[e.origin])
+        {
+            return None;
+        }
+        let mut values = Vec::with_capacity(argc);
+        for entry in operands {
+            match &entry.op {
+                Op::Push(Push(val)) => values.push(val.clone()),
+                _ => return None,
+            }
+        }
+        let mut steps = FOLD_STEP_LIMIT;
+        let result = match callee {
+            true => {
+                let f = match values.pop() {
+                    Some(Value::Function(f)) => f,
+                    _ => return None,
+                };
+                eval(&f, &values, &mut steps, 0)?
+            }
+            false => {
+                let mut ops: Vec<Op> = values.into_iter().map(|v| Push(v).into()).collect();
+                ops.push(last.op.clone());
+                ops.push(crate::bytecode::ops::Return.into());
+                let scratch = Function {
+                    module: crate::datamodel::Tuple::new(Vec::new()),
+                    ops: ops.into(),
+                };
+                eval(&scratch, &[], &mut steps, 0)?
+            }
+        };
+        Some((result, argc + 1, out[tail].origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+
+    #[test]
+    fn constants_flow_through_helpers() {
+        // scale(x) = x * 10
+        let scale = function(vec![
+            Push(Value::Integer(10)).into(),
+            Mul.into(),
+            Return.into(),
+        ]);
+        // timeout(x) = scale(x + 1)
+        let timeout = function(vec![
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Push(scale.into()).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(Value::Integer(2)).into(),
+            Push(timeout.into()).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        let folded = propagate(&main);
+        assert!(matches!(
+            &folded.ops[..],
+            [Op::Push(Push(Value::Integer(30))), Op::Return(_)]
+        ));
+    }
+
+    #[test]
+    fn leaves_impure_and_jump_targets_alone() {
+        // the Add is a jump target, so `1 + 2` must not fold
+        let main = function(vec![
+            Push(Value::Integer(1)).into(),
+            Jump(1).into(),
+            Push(Value::Integer(5)).into(),
+            Push(Value::Integer(2)).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let folded = propagate(&main);
+        assert_eq!(folded.ops.len(), main.ops.len());
+    }
+}
@@ -0,0 +1,230 @@
+//! Scalar replacement of tables that never escape their frame. A table
+//! created by `NewTable, Store(l)` whose every `Load(l)` feeds straight into
+//! a `GetField` or `SetField` is never seen by anything but the function
+//! itself, so each of its fields can live in a local of its own and the
+//! allocation goes away.
+//!
+//! Only straight-line builders are rewritten: everything from the
+//! allocation to the last field access must be free of branches and jump
+//! targets, and every field must be set before it is read, so a fresh table
+//! on each pass through a loop still behaves like one.
+
+use std::collections::{HashMap, HashSet};
+
+use super::inline::max_local;
+use crate::bytecode::ops::{Load, Store};
+use crate::bytecode::{relocate, Op};
+use crate::datamodel::{Function, PROTO_KEY};
+
+/// How a use of a candidate table is rewritten.
+enum Edit {
+    Remove,
+    Get(u64),
+    Set(u64),
+}
+
+fn jump_targets(ops: &[Op]) -> HashSet<usize> {
+    ops.iter()
+        .enumerate()
+        .flat_map(|(i, op)| {
+            let offsets = op.jump_offsets().into_iter();
+            offsets.map(move |offset| (i as i64 + 1 + offset as i64) as usize)
+        })
+        .collect()
+}
+
+/// Finds the op that consumes the value pushed by the `Load` at `at`,
+/// returning its index if it is a field access using the value as its
+/// table operand.
+fn consumer(ops: &[Op], at: usize, targets: &HashSet<usize>) -> Option<usize> {
+    // position of the loaded value, counted from the top of the stack
+    let mut depth = 1;
+    for (i, op) in ops.iter().enumerate().skip(at + 1) {
+        if targets.contains(&i) {
+            return None;
+        }
+        let (pops, pushes) = op.stack_effect()?;
+        if pops >= depth {
+            return match op {
+                Op::GetField(_) if depth == 1 => Some(i),
+                Op::SetField(_) if depth == 2 => Some(i),
+                _ => None,
+            };
+        }
+        depth = depth - pops + pushes;
+    }
+    None
+}
+
+/// Works out the edits replacing the table allocated at `at`, if it
+/// doesn't escape.
+fn analyze(ops: &[Op], at: usize, targets: &HashSet<usize>) -> Option<Vec<(usize, Edit)>> {
+    let local = match ops.get(at + 1)? {
+        Op::Store(Store(l)) if *l != 0 => *l,
+        _ => return None,
+    };
+    if targets.contains(&(at + 1)) {
+        return None;
+    }
+    let mut edits = vec![(at, Edit::Remove), (at + 1, Edit::Remove)];
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::Load(Load(l)) if *l == local => {
+                if i < at {
+                    return None;
+                }
+                let use_at = consumer(ops, i, targets)?;
+                edits.push((i, Edit::Remove));
+                edits.push(match &ops[use_at] {
+                    Op::GetField(op) => (use_at, Edit::Get(op.key)),
+                    Op::SetField(op) => (use_at, Edit::Set(op.key)),
+                    _ => unreachable!(),
+                });
+            }
+            Op::Store(Store(l)) if *l == local && i != at + 1 => return None,
+            Op::IncJumpLt(op) if op.local == local || op.limit == local => return None,
+            Op::AddImm(op) if op.local == local => return None,
+            Op::AddLocals(op) if op.a == local || op.b == local => return None,
+            Op::MakeClosure(op) if op.locals.contains(&local) => return None,
+            _ => {}
+        }
+    }
+    edits.sort_by_key(|(i, _)| *i);
+    // the whole builder must run straight through, setting before reading
+    let end = edits.last()?.0;
+    if (at + 1..=end).any(|i| targets.contains(&i) || ops[i].stack_effect().is_none()) {
+        return None;
+    }
+    let mut set = HashSet::new();
+    for (_, edit) in &edits {
+        match edit {
+            Edit::Set(PROTO_KEY) => return None,
+            Edit::Set(key) => {
+                set.insert(*key);
+            }
+            Edit::Get(key) if !set.contains(key) => return None,
+            _ => {}
+        }
+    }
+    Some(edits)
+}
+
+/// Replaces the non-escaping tables of `func` with locals.
+pub fn scalar_replace(func: &Function) -> Function {
+    let ops = &func.ops;
+    let targets = jump_targets(ops);
+    let mut next_local = max_local(ops).map_or(1, |i| i as usize + 1);
+    let mut rewrites = HashMap::new();
+    for at in 0..ops.len() {
+        if !matches!(ops[at], Op::NewTable(_)) {
+            continue;
+        }
+        let edits = match analyze(ops, at, &targets) {
+            Some(edits) => edits,
+            None => continue,
+        };
+        let mut slots = HashMap::new();
+        for (_, edit) in &edits {
+            if let Edit::Set(key) = edit {
+                let next = slots.len();
+                slots.entry(*key).or_insert(next);
+            }
+        }
+        if next_local + slots.len() > 256 {
+            break;
+        }
+        for (i, edit) in edits {
+            let slot = |key| (next_local + slots[&key]) as u8;
+            rewrites.insert(
+                i,
+                match edit {
+                    Edit::Remove => None,
+                    Edit::Get(key) => Some(Load(slot(key)).into()),
+                    Edit::Set(key) => Some(Store(slot(key)).into()),
+                },
+            );
+        }
+        next_local += slots.len();
+    }
+    let mut out = Vec::with_capacity(ops.len());
+    let mut map = Vec::with_capacity(ops.len() + 1);
+    for (i, op) in ops.iter().enumerate() {
+        map.push(out.len());
+        match rewrites.remove(&i) {
+            Some(Some(op)) => out.push(op),
+            Some(None) => {}
+            None => out.push(op.clone()),
+        }
+    }
+    map.push(out.len());
+    relocate(ops, &map, &mut out);
+    Function {
+        module: func.module.clone(),
+        ops: out.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::datamodel::{field_key, Value};
+    use crate::testing::function;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn replaces_local_builder() {
+        let (x, y) = (field_key("x"), field_key("y"));
+        // t = {}; t.x = 6; t.y = 7; return t.x * t.y
+        let func = function(vec![
+            NewTable.into(),
+            Store(1).into(),
+            Load(1).into(),
+            Push(Value::Integer(6)).into(),
+            SetField::new(x).into(),
+            Load(1).into(),
+            Push(Value::Integer(7)).into(),
+            SetField::new(y).into(),
+            Load(1).into(),
+            GetField::new(x).into(),
+            Load(1).into(),
+            GetField::new(y).into(),
+            Mul.into(),
+            Return.into(),
+        ]);
+        let replaced = scalar_replace(&func);
+        assert!(!replaced.ops.iter().any(|op| matches!(op, Op::NewTable(_))));
+        assert_eq!(replaced.ops.len(), 8);
+        for func in [func, replaced] {
+            let mut vm = VirtualMachine::new(func);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(42))));
+        }
+    }
+
+    #[test]
+    fn leaves_escaping_tables_alone() {
+        let x = field_key("x");
+        // t = {}; t.x = 1; return t
+        let returned = function(vec![
+            NewTable.into(),
+            Store(1).into(),
+            Load(1).into(),
+            Push(Value::Integer(1)).into(),
+            SetField::new(x).into(),
+            Load(1).into(),
+            Return.into(),
+        ]);
+        // t = {}; return t.x
+        let unset = function(vec![
+            NewTable.into(),
+            Store(1).into(),
+            Load(1).into(),
+            GetField::new(x).into(),
+            Return.into(),
+        ]);
+        for func in [returned, unset] {
+            let replaced = scalar_replace(&func);
+            assert_eq!(replaced.ops.len(), func.ops.len());
+        }
+    }
+}
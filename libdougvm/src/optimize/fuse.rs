@@ -0,0 +1,136 @@
+//! Operand fusion. An `Add` whose operands are a local and a small
+//! `Integer` constant, or two locals, is folded together with the ops that
+//! pushed them into one `AddImm` or `AddLocals`, so `x + 1` runs as one op
+//! instead of three. `x - c` becomes `x + -c`, which is the same for every
+//! kind of number.
+//!
+//! Only runs of ops that nothing jumps into the middle of are fused.
+
+use std::collections::HashSet;
+
+use crate::bytecode::ops::{AddImm, AddLocals, Load, Push};
+use crate::bytecode::{relocate, Op};
+use crate::datamodel::{Function, Value};
+
+/// The op `window` fuses into, if any.
+fn fused(window: &[Op]) -> Option<Op> {
+    let imm = |c: i64, sub: bool| match sub {
+        false => i32::try_from(c).ok(),
+        true => i32::try_from(c.checked_neg()?).ok(),
+    };
+    Some(match window {
+        [Op::Load(Load(local)), Op::Push(Push(Value::Integer(c))), Op::Add(_)] => AddImm {
+            local: *local,
+            imm: imm(*c, false)?,
+        }
+        .into(),
+        [Op::Load(Load(local)), Op::Push(Push(Value::Integer(c))), Op::Sub(_)] => AddImm {
+            local: *local,
+            imm: imm(*c, true)?,
+        }
+        .into(),
+        [Op::Load(Load(a)), Op::Load(Load(b)), Op::Add(_)] => AddLocals { a: *a, b: *b }.into(),
+        _ => return None,
+    })
+}
+
+/// A copy of `func` with each fusable `Add` and `Sub` fused with its
+/// operands.
+pub fn fuse(func: &Function) -> Function {
+    let ops = &func.ops;
+    let targets: HashSet<usize> = ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, op)| {
+            let offsets = op.jump_offsets().into_iter();
+            offsets.map(move |offset| (i as i64 + 1 + offset as i64) as usize)
+        })
+        .collect();
+    let mut out = Vec::with_capacity(ops.len());
+    let mut map = Vec::with_capacity(ops.len() + 1);
+    let mut i = 0;
+    while i < ops.len() {
+        let inner_targets = targets.contains(&(i + 1)) || targets.contains(&(i + 2));
+        if let Some(op) = ops.get(i..i + 3).and_then(fused).filter(|_| !inner_targets) {
+            map.extend([out.len(); 3]);
+            out.push(op);
+            i += 3;
+            continue;
+        }
+        map.push(out.len());
+        out.push(ops[i].clone());
+        i += 1;
+    }
+    map.push(out.len());
+    let mut rebuilt = out.clone();
+    relocate(ops, &map, &mut rebuilt);
+    Function {
+        module: func.module.clone(),
+        ops: rebuilt.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn operands_fuse_into_the_add() {
+        // x = 5; y = (x + 1) + (x - 2); return y + x
+        let func = function(vec![
+            Push(Value::Integer(5)).into(),
+            Store(1).into(),
+            Load(1).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Load(1).into(),
+            Push(Value::Integer(2)).into(),
+            Sub.into(),
+            Add.into(),
+            Store(2).into(),
+            Load(2).into(),
+            Load(1).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let fused = fuse(&func);
+        let names: Vec<_> = fused.ops.iter().map(Op::name).collect();
+        assert_eq!(
+            names,
+            [
+                "Push",
+                "Store",
+                "AddImm",
+                "AddImm",
+                "Add",
+                "Store",
+                "AddLocals",
+                "Return"
+            ]
+        );
+        let result = VirtualMachine::new(fused).run_until_exited();
+        assert!(matches!(result, Ok(Value::Integer(14))));
+
+        // the Push is a jump target, so the run can't be fused
+        let joined = function(vec![
+            Push(Value::Integer(1)).into(),
+            Store(1).into(),
+            Load(1).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Return.into(),
+            Jump(-4).into(),
+        ]);
+        assert_eq!(fuse(&joined).ops.len(), 7);
+        // too wide for an immediate
+        let wide = function(vec![
+            Load(0).into(),
+            Push(Value::Integer(i64::from(i32::MAX) + 1)).into(),
+            Add.into(),
+        ]);
+        assert_eq!(fuse(&wide).ops.len(), 3);
+    }
+}
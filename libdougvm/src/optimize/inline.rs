@@ -0,0 +1,190 @@
+//! Inlining of small callees. A call site `Push(f), Call(n)` whose callee
+//! `f` is straight-line code ending in its only `Return` is replaced by
+//! `f`'s body, with `f`'s locals moved above the caller's and the arguments
+//! shuffled into the order `f` expects (see `ops::Call`).
+//!
+//! Inlined locals are not reset between executions of the site; callees
+//! that read a local before storing it are not inlined.
+
+use crate::bytecode::ops::{AddImm, AddLocals, Load, MakeClosure, Push, Store};
+use crate::bytecode::{relocate, Op};
+use crate::datamodel::{Function, Value};
+
+pub struct InlineConfig {
+    /// Largest callee, in ops, that will be inlined.
+    pub max_callee_ops: usize,
+    /// Stop inlining once the caller has grown to this many ops.
+    pub max_caller_ops: usize,
+}
+
+impl Default for InlineConfig {
+    fn default() -> Self {
+        InlineConfig {
+            max_callee_ops: 16,
+            max_caller_ops: 4096,
+        }
+    }
+}
+
+pub(crate) fn max_local(ops: &[Op]) -> Option<u8> {
+    ops.iter()
+        .filter_map(|op| match op {
+            Op::Load(Load(i)) | Op::Store(Store(i)) => Some(*i),
+            Op::IncJumpLt(op) => Some(op.local.max(op.limit)),
+            Op::AddImm(op) => Some(op.local),
+            Op::AddLocals(op) => Some(op.a.max(op.b)),
+            Op::MakeClosure(op) => op.locals.iter().max().copied(),
+            _ => None,
+        })
+        .max()
+}
+
+/// Checks that `callee` is straight-line, balances its arguments down to
+/// one return value, and stores each local before loading it (local 0, the
+/// module, excepted).
+fn is_inlinable(callee: &Function, argc: usize, config: &InlineConfig) -> bool {
+    let ops = &callee.ops;
+    if ops.is_empty() || ops.len() > config.max_callee_ops {
+        return false;
+    }
+    let (body, last) = ops.split_at(ops.len() - 1);
+    if !matches!(last[0], Op::Return(_)) {
+        return false;
+    }
+    let mut depth = argc;
+    let mut stored = [false; 256];
+    stored[0] = true;
+    for op in body {
+        match op {
+            Op::Load(Load(i)) if !stored[*i as usize] => return false,
+            Op::AddImm(op) if !stored[op.local as usize] => return false,
+            Op::AddLocals(op) if !stored[op.a as usize] || !stored[op.b as usize] => return false,
+            Op::MakeClosure(op) if op.locals.iter().any(|&i| !stored[i as usize]) => return false,
+            // the caller has no upvalues, or others
+            Op::LoadUpvalue(_) => return false,
+            Op::Store(Store(i)) => stored[*i as usize] = true,
+            _ => {}
+        }
+        let (pops, pushes) = match op.stack_effect() {
+            Some(effect) => effect,
+            None => return false,
+        };
+        depth = match depth.checked_sub(pops) {
+            Some(d) => d + pushes,
+            None => return false,
+        };
+    }
+    depth == 1
+}
+
+/// Expands an inlinable call of `callee`, whose locals start at `base`.
+fn expand(callee: &Function, argc: usize, base: u8, out: &mut Vec<Op>) {
+    let shift = |i: u8| base + i;
+    // the module normally sits in the callee's local 0
+    out.push(Push(callee.module.clone().into()).into());
+    out.push(Store(shift(0)).into());
+    // caller's stack holds the args last-on-top; the callee wants first-on-top
+    for i in 0..argc {
+        out.push(Store(shift(1 + i as u8)).into());
+    }
+    for i in 0..argc {
+        out.push(Load(shift(1 + i as u8)).into());
+    }
+    for op in &callee.ops[..callee.ops.len() - 1] {
+        out.push(match op {
+            Op::Load(Load(i)) => Load(shift(*i)).into(),
+            Op::Store(Store(i)) => Store(shift(*i)).into(),
+            Op::AddImm(op) => AddImm {
+                local: shift(op.local),
+                imm: op.imm,
+            }
+            .into(),
+            Op::AddLocals(op) => AddLocals {
+                a: shift(op.a),
+                b: shift(op.b),
+            }
+            .into(),
+            Op::MakeClosure(op) => MakeClosure {
+                locals: op.locals.iter().map(|&i| shift(i)).collect(),
+            }
+            .into(),
+            op => op.clone(),
+        });
+    }
+}
+
+/// Inlines the eligible call sites of `func` (one level deep).
+pub fn inline(func: &Function, config: &InlineConfig) -> Function {
+    let ops = &func.ops;
+    let mut next_local = max_local(ops).map_or(1, |i| i as usize + 1);
+    let mut out = Vec::with_capacity(ops.len());
+    let mut map = Vec::with_capacity(ops.len() + 1);
+    let mut i = 0;
+    while i < ops.len() {
+        if let (Op::Push(Push(Value::Function(callee))), Some(Op::Call(call))) =
+            (&ops[i], ops.get(i + 1))
+        {
+            let argc = call.0 as usize;
+            let locals = max_local(&callee.ops).map_or(0, |l| l as usize).max(argc) + 1;
+            let grown = out.len() + callee.ops.len() + 2 * argc + 2;
+            if is_inlinable(callee, argc, config)
+                && next_local + locals <= 256
+                && grown <= config.max_caller_ops
+            {
+                map.push(out.len());
+                // the Call itself is never a jump target in compiler output
+                map.push(out.len());
+                expand(callee, argc, next_local as u8, &mut out);
+                next_local += locals;
+                i += 2;
+                continue;
+            }
+        }
+        map.push(out.len());
+        out.push(ops[i].clone());
+        i += 1;
+    }
+    map.push(out.len());
+    relocate(ops, &map, &mut out);
+    Function {
+        module: func.module.clone(),
+        ops: out.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+    use crate::VirtualMachine;
+
+    #[test]
+    fn inlines_small_callee() {
+        // sub(a, b) = a - b
+        let sub = function(vec![
+            Store(1).into(),
+            Store(2).into(),
+            Load(1).into(),
+            Load(2).into(),
+            Sub.into(),
+            Return.into(),
+        ]);
+        // if 1 { sub(10, 3) } else { 0 }
+        let caller = function(vec![
+            Push(Value::Integer(1)).into(),
+            JumpIfNot(4).into(),
+            Push(Value::Integer(10)).into(),
+            Push(Value::Integer(3)).into(),
+            Push(sub.into()).into(),
+            Call(2).into(),
+            Return.into(),
+        ]);
+        let inlined = inline(&caller, &InlineConfig::default());
+        assert!(!inlined.ops.iter().any(|op| matches!(op, Op::Call(_))));
+        for func in [caller, inlined] {
+            let mut vm = VirtualMachine::new(func);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(7))));
+        }
+    }
+}
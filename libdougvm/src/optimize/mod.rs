@@ -0,0 +1,11 @@
+//! Bytecode-to-bytecode optimization passes. Each pass takes a `Function`
+//! and returns an equivalent one, leaving the input untouched.
+
+pub mod consteval;
+pub mod constprop;
+pub mod escape;
+pub mod fuse;
+pub mod inline;
+pub mod pgo;
+pub mod ranges;
+pub mod shake;
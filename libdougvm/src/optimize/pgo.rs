@@ -0,0 +1,168 @@
+//! Profile-guided optimization. A `Profile` records how hot each function
+//! of a module was during a profiling run, keyed by module slot so it
+//! survives being written out and read back in a later build. `optimize`
+//! then spends its budget where the profile says it pays: hot functions
+//! get a larger inlining budget and integer speculation, cold ones are only
+//! cleaned up.
+
+use std::fmt;
+
+use super::constprop::propagate;
+use super::escape::scalar_replace;
+use super::inline::{inline, InlineConfig};
+use crate::bytecode::speculate::specialize_ints;
+use crate::datamodel::{Function, Tuple, Value};
+use crate::tiering::{Hotness, Tiering};
+
+/// Execution counts for the functions of one module, by module slot.
+#[derive(Clone, Default)]
+pub struct Profile {
+    slots: Vec<Option<Hotness>>,
+}
+
+impl Profile {
+    /// Reads the counters `tiering` gathered for the functions in `module`.
+    /// Promoted functions stop being counted, so profiling runs should use
+    /// a policy that never promotes.
+    pub fn collect(tiering: &Tiering, module: &Tuple) -> Profile {
+        let slots = (0..module.len())
+            .map(|i| match module.get(i) {
+                Some(Value::Function(func)) => tiering.hotness(&func).cloned(),
+                _ => None,
+            })
+            .collect();
+        Profile { slots }
+    }
+
+    pub fn hotness(&self, slot: usize) -> Option<&Hotness> {
+        self.slots.get(slot)?.as_ref()
+    }
+
+    /// Parses the format written by `Display`: one `slot invocations
+    /// back_edges` line per function that ran.
+    pub fn parse(text: &str) -> Option<Profile> {
+        let mut slots = Vec::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let mut fields = line.split_whitespace().map(|f| f.parse::<u64>().ok());
+            let (slot, invocations, back_edges) =
+                (fields.next()??, fields.next()??, fields.next()??);
+            if fields.next().is_some() {
+                return None;
+            }
+            let slot = usize::try_from(slot).ok()?;
+            if slots.len() <= slot {
+                slots.resize(slot + 1, None);
+            }
+            slots[slot] = Some(Hotness {
+                invocations,
+                back_edges,
+            });
+        }
+        Some(Profile { slots })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (slot, hotness) in self.slots.iter().enumerate() {
+            if let Some(h) = hotness {
+                writeln!(f, "{} {} {}", slot, h.invocations, h.back_edges)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct PgoConfig {
+    /// Invocations at which a function counts as hot.
+    pub hot_invocations: u64,
+    /// Loop back edges at which a function counts as hot.
+    pub hot_back_edges: u64,
+    pub hot_inline: InlineConfig,
+    pub cold_inline: InlineConfig,
+}
+
+impl Default for PgoConfig {
+    fn default() -> Self {
+        PgoConfig {
+            hot_invocations: 1_000,
+            hot_back_edges: 10_000,
+            hot_inline: InlineConfig {
+                max_callee_ops: 64,
+                ..InlineConfig::default()
+            },
+            cold_inline: InlineConfig {
+                max_callee_ops: 4,
+                ..InlineConfig::default()
+            },
+        }
+    }
+}
+
+fn optimize_function(func: &Function, hot: bool, config: &PgoConfig) -> Function {
+    let func = propagate(func);
+    let inline_config = match hot {
+        true => &config.hot_inline,
+        false => &config.cold_inline,
+    };
+    let func = scalar_replace(&inline(&func, inline_config));
+    match hot {
+        true => Function {
+            module: func.module.clone(),
+            ops: specialize_ints(&func.ops).into(),
+        },
+        false => func,
+    }
+}
+
+/// A copy of `module` with every function run through the pass pipeline,
+/// tuned by `profile`. Slots that aren't functions are copied unchanged.
+pub fn optimize(module: &Tuple, profile: &Profile, config: &PgoConfig) -> Tuple {
+    let items = (0..module.len())
+        .map(|i| match module.get(i) {
+            Some(Value::Function(func)) => {
+                let hot = profile.hotness(i).is_some_and(|h| {
+                    h.invocations >= config.hot_invocations || h.back_edges >= config.hot_back_edges
+                });
+                optimize_function(&func, hot, config).into()
+            }
+            other => other.unwrap_or(Value::None),
+        })
+        .collect();
+    Tuple::new(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::Op;
+    use crate::testing::function;
+
+    #[test]
+    fn profile_round_trips_and_drives_speculation() {
+        let add = || {
+            function(vec![
+                Store(1).into(),
+                Store(2).into(),
+                Load(1).into(),
+                Load(2).into(),
+                Add.into(),
+                Return.into(),
+            ])
+        };
+        let module = Tuple::new(vec![add().into(), Value::Integer(7), add().into()]);
+        let profile = Profile::parse("0 5000 0\n2 3 0\n").unwrap();
+        assert_eq!(profile.to_string(), "0 5000 0\n2 3 0\n");
+        assert!(Profile::parse("0 five 0").is_none());
+
+        let optimized = optimize(&module, &profile, &PgoConfig::default());
+        let speculated = |slot| match optimized.get(slot) {
+            Some(Value::Function(f)) => f.ops.iter().any(|op| matches!(op, Op::Speculate(_))),
+            _ => panic!("expected a function"),
+        };
+        assert!(speculated(0));
+        assert!(!speculated(2));
+        assert!(matches!(optimized.get(1), Some(Value::Integer(7))));
+    }
+}
@@ -0,0 +1,151 @@
+//! Integer range narrowing. Tracks the range of each `Integer` on the
+//! stack and in locals, and where an `Add` or `Sub` (or its `Int` form)
+//! can be shown not to overflow, swaps in the unchecked op, which skips
+//! the overflow check and the generic op's type dispatch.
+//!
+//! Ranges are only known from `Integer` constants, so a known range also
+//! means a known type. The analysis is per basic block: nothing is known
+//! at a jump or handler target, and nothing left on the stack is known
+//! after an op that calls, returns or otherwise doesn't have a fixed stack
+//! effect. That is enough for arithmetic on constants and on locals set
+//! from them in the same block.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::ops::{AddIntUnchecked, IncJumpLt, Load, Push, Store, SubIntUnchecked};
+use crate::bytecode::Op;
+use crate::datamodel::{Function, Value};
+
+/// The smallest and largest an `Integer` can be, if known.
+type Range = Option<(i64, i64)>;
+
+#[derive(Default)]
+struct Block {
+    stack: Vec<Range>,
+    locals: HashMap<u8, Range>,
+}
+
+impl Block {
+    /// Unknown once the block's own values run out.
+    fn pop(&mut self) -> Range {
+        self.stack.pop().flatten()
+    }
+}
+
+/// The range of `a + b`, or of `a - b` if `sub`, if it can't overflow.
+fn combine(a: Range, b: Range, sub: bool) -> Range {
+    let ((a_lo, a_hi), (b_lo, b_hi)) = (a?, b?);
+    match sub {
+        false => Some((a_lo.checked_add(b_lo)?, a_hi.checked_add(b_hi)?)),
+        true => Some((a_lo.checked_sub(b_hi)?, a_hi.checked_sub(b_lo)?)),
+    }
+}
+
+/// A copy of `func` with additions and subtractions that can't overflow
+/// made unchecked.
+pub fn narrow(func: &Function) -> Function {
+    let ops = &func.ops;
+    let targets: HashSet<usize> = ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, op)| {
+            let offsets = op.jump_offsets().into_iter();
+            offsets.map(move |offset| (i as i64 + 1 + offset as i64) as usize)
+        })
+        .collect();
+    let mut out = Vec::with_capacity(ops.len());
+    let mut block = Block::default();
+    for (i, op) in ops.iter().enumerate() {
+        if targets.contains(&i) {
+            block = Block::default();
+        }
+        let mut op = op.clone();
+        match &op {
+            Op::Push(Push(Value::Integer(i))) => block.stack.push(Some((*i, *i))),
+            Op::Load(Load(l)) => block.stack.push(block.locals.get(l).copied().flatten()),
+            Op::Store(Store(l)) => {
+                let range = block.pop();
+                block.locals.insert(*l, range);
+            }
+            Op::Add(_) | Op::AddInt(_) | Op::Sub(_) | Op::SubInt(_) => {
+                let sub = matches!(op, Op::Sub(_) | Op::SubInt(_));
+                let (b, a) = (block.pop(), block.pop());
+                let range = combine(a, b, sub);
+                if range.is_some() {
+                    op = match sub {
+                        false => AddIntUnchecked.into(),
+                        true => SubIntUnchecked.into(),
+                    };
+                }
+                block.stack.push(range);
+            }
+            Op::IncJumpLt(IncJumpLt { local, .. }) => {
+                block.locals.insert(*local, None);
+            }
+            other => match other.stack_effect() {
+                Some((pops, pushes)) => {
+                    let kept = block.stack.len().saturating_sub(pops);
+                    block.stack.truncate(kept);
+                    block.stack.extend((0..pushes).map(|_| None));
+                }
+                None => block.stack.clear(),
+            },
+        }
+        out.push(op);
+    }
+    Function {
+        module: func.module.clone(),
+        ops: out.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::bytecode::OpError;
+    use crate::testing::function;
+    use crate::{VirtualMachine, VmError};
+
+    #[test]
+    fn only_proven_arithmetic_is_unchecked() {
+        let func = function(vec![
+            Push(Value::Integer(2)).into(),
+            Push(Value::Integer(3)).into(),
+            Add.into(),
+            Store(1).into(),
+            Load(1).into(),
+            Push(Value::Integer(10)).into(),
+            Sub.into(),
+            // -5 + MIN overflows
+            Push(Value::Integer(i64::MIN)).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let narrowed = narrow(&func);
+        let names: Vec<_> = narrowed.ops.iter().map(Op::name).collect();
+        assert_eq!(names[2], "AddIntUnchecked");
+        assert_eq!(names[6], "SubIntUnchecked");
+        assert_eq!(names[8], "Add");
+        let result = VirtualMachine::new(narrowed).run_until_exited();
+        assert!(matches!(
+            result,
+            Err(VmError {
+                error: OpError::Overflow,
+                ..
+            })
+        ));
+
+        // the Load is a jump target, where nothing is known
+        let joined = function(vec![
+            Push(Value::Integer(1)).into(),
+            Store(1).into(),
+            Jump(0).into(),
+            Load(1).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        assert_eq!(narrow(&joined).ops[5].name(), "Add");
+    }
+}
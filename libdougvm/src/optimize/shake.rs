@@ -0,0 +1,147 @@
+//! Tree shaking of a module. Starting from a set of entry points, items of
+//! the module tuple are kept only if some reachable function refers to
+//! them through a `Push` constant: functions by identity, natives by
+//! address and strings by pointer. Everything else is replaced with
+//! `Value::None`, so indices into the module stay valid while the unused
+//! code and data are no longer shipped with it.
+//!
+//! A reachable function that loads its module (local 0) could hand it to
+//! anything, so then the whole module is kept.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::bytecode::ops::{Load, MakeClosure, Push};
+use crate::bytecode::Op;
+use crate::datamodel::{Identity, Tuple, Value};
+
+/// The key a `Push` constant and a module item are matched on.
+fn item_key(val: &Value) -> Option<usize> {
+    match val {
+        Value::Function(f) => Some(f.identity()),
+        Value::NativeFn(f) => Some(*f as usize),
+        Value::Str(s) => Some(Rc::as_ptr(s).cast::<()>() as usize),
+        _ => None,
+    }
+}
+
+/// The constants an op pushes, looking inside speculated ops.
+fn constants(op: &Op, out: &mut Vec<Value>) {
+    match op {
+        Op::Push(Push(val)) => out.push(val.clone()),
+        Op::Speculate(s) => {
+            constants(&s.fast, out);
+            constants(&s.generic, out);
+        }
+        _ => {}
+    }
+}
+
+/// Which items of `module` are reachable from the items at `entries`.
+pub fn reachable(module: &Tuple, entries: &[usize]) -> Vec<bool> {
+    let mut index = HashMap::new();
+    for i in 0..module.len() {
+        if let Some(key) = module.get(i).as_ref().and_then(item_key) {
+            index.entry(key).or_insert(i);
+        }
+    }
+    let mut keep = vec![false; module.len()];
+    let mut seen = HashSet::new();
+    let mut work: Vec<Value> = entries.iter().filter_map(|&i| module.get(i)).collect();
+    for &i in entries {
+        if let Some(slot) = keep.get_mut(i) {
+            *slot = true;
+        }
+    }
+    while let Some(val) = work.pop() {
+        if let Some(&i) = item_key(&val).and_then(|key| index.get(&key)) {
+            keep[i] = true;
+        }
+        let func = match val {
+            Value::Function(func) => func,
+            _ => continue,
+        };
+        if !seen.insert(func.identity()) {
+            continue;
+        }
+        let mut pushed = Vec::new();
+        for op in func.ops.iter() {
+            // local 0 is the module; loading or capturing it lets it escape
+            let escapes = match op {
+                Op::Load(Load(0)) => true,
+                Op::MakeClosure(MakeClosure { locals }) => locals.contains(&0),
+                _ => false,
+            };
+            if escapes {
+                return vec![true; module.len()];
+            }
+            constants(op, &mut pushed);
+        }
+        work.extend(pushed);
+    }
+    keep
+}
+
+/// A copy of `module` with the items unreachable from `entries` removed.
+pub fn shake(module: &Tuple, entries: &[usize]) -> Tuple {
+    let keep = reachable(module, entries);
+    let items = (0..module.len())
+        .map(|i| match keep[i] {
+            true => module.get(i).unwrap_or(Value::None),
+            false => Value::None,
+        })
+        .collect();
+    Tuple::new(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+
+    fn nop(_args: Vec<Value>) -> Value {
+        Value::None
+    }
+
+    #[test]
+    fn drops_unreachable_items() {
+        let helper = function(vec![Push(Value::NativeFn(nop)).into(), Return.into()]);
+        let main = function(vec![
+            Push(helper.clone().into()).into(),
+            Call(0).into(),
+            Return.into(),
+        ]);
+        let unused = function(vec![Push(Value::Integer(1)).into(), Return.into()]);
+        let module = Tuple::new(vec![
+            main.into(),
+            unused.into(),
+            helper.into(),
+            Value::NativeFn(nop),
+            Value::Str("unused".into()),
+        ]);
+        assert_eq!(reachable(&module, &[0]), [true, false, true, true, false]);
+        let shaken = shake(&module, &[0]);
+        assert!(matches!(shaken.get(1), Some(Value::None)));
+        assert!(matches!(shaken.get(2), Some(Value::Function(_))));
+    }
+
+    #[test]
+    fn keeps_everything_when_module_escapes() {
+        let main = function(vec![Load(0).into(), Return.into()]);
+        let module = Tuple::new(vec![main.into(), Value::Integer(1)]);
+        assert_eq!(reachable(&module, &[0]), [true, true]);
+    }
+
+    #[test]
+    fn keeps_everything_when_a_closure_captures_the_module() {
+        let inner = function(vec![LoadUpvalue(0).into(), Return.into()]);
+        let main = function(vec![
+            Push(inner.into()).into(),
+            MakeClosure { locals: [0].into() }.into(),
+            Return.into(),
+        ]);
+        let module = Tuple::new(vec![main.into(), Value::Integer(1)]);
+        assert_eq!(reachable(&module, &[0]), [true, true]);
+    }
+}
@@ -0,0 +1,525 @@
+//! A sampling profiler, for production runs where instrumenting every call
+//! would cost too much. Something outside the VM asks for samples through
+//! a `SampleHandle`: a `Sampler` thread every so often, or the host's own
+//! timer callback. At its next op boundary the VM notices the request, one
+//! atomic swap per op as for interrupts, and snapshots its frame chain.
+//!
+//! Each snapshot is added to the VM's `Profile` and published through the
+//! handle as the latest `Sample`, behind a lock held only to swap it in
+//! or out, so another thread can watch what the VM is doing as it runs.
+//! Samples name functions by identity (see `datamodel::Identity`); the
+//! profile keeps the functions so those can be resolved on the VM's
+//! thread.
+//!
+//! For benchmarking rather than production there is also an instrumented
+//! mode, `VirtualMachine::set_op_timing`, which times every op and keeps a
+//! latency histogram per op kind in `OpTimings`, exported as JSON lines
+//! for CI to compare between builds.
+//!
+//! To find a script's hot functions, `VirtualMachine::set_profiling` turns
+//! on a `Profiler` the VM tells about every op it runs and every frame it
+//! pushes and pops. It counts each function's calls and ops and times its
+//! frames, and its `report` ranks the functions by the time spent in them.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::datamodel::{Function, Identity};
+use crate::CallFrame;
+
+/// A snapshot of a VM's frames, innermost first, as the function's
+/// identity and the op the frame runs next.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Sample {
+    pub frames: Vec<(usize, usize)>,
+}
+
+#[derive(Default)]
+struct Shared {
+    requested: AtomicBool,
+    latest: Mutex<Option<Sample>>,
+}
+
+impl Shared {
+    fn latest(&self) -> MutexGuard<'_, Option<Sample>> {
+        // a sample is replaced whole, so a panic elsewhere can't leave a
+        // torn one behind
+        self.latest.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Asks a VM for samples and reads the latest back, from any thread.
+#[derive(Clone, Default)]
+pub struct SampleHandle {
+    shared: Arc<Shared>,
+}
+
+impl SampleHandle {
+    /// Asks for a sample at the VM's next op boundary.
+    pub fn request(&self) {
+        self.shared.requested.store(true, Ordering::Relaxed);
+    }
+
+    /// The most recent sample, if one was taken since the last call.
+    pub fn take_latest(&self) -> Option<Sample> {
+        self.shared.latest().take()
+    }
+
+    /// Clears the request, returning whether one was pending.
+    pub(crate) fn take_request(&self) -> bool {
+        self.shared.requested.swap(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn publish(&self, sample: Sample) {
+        *self.shared.latest() = Some(sample);
+    }
+}
+
+/// A thread requesting a sample every `interval` until stopped or dropped.
+pub struct Sampler {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sampler {
+    pub fn start(handle: SampleHandle, interval: Duration) -> Sampler {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let thread = thread::spawn(move || {
+            while !stopping.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                handle.request();
+            }
+        });
+        Sampler {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// The samples a VM has taken, counted by frame chain.
+#[derive(Default)]
+pub struct Profile {
+    samples: u64,
+    stacks: HashMap<Sample, u64>,
+    functions: HashMap<usize, Function>,
+}
+
+impl Profile {
+    pub(crate) fn record<'a>(&mut self, frames: impl Iterator<Item = &'a CallFrame>) -> Sample {
+        let mut sample = Sample { frames: Vec::new() };
+        for frame in frames {
+            let id = frame.function.identity();
+            self.functions
+                .entry(id)
+                .or_insert_with(|| frame.function.clone());
+            sample.frames.push((id, frame.cursor));
+        }
+        self.samples += 1;
+        *self.stacks.entry(sample.clone()).or_insert(0) += 1;
+        sample
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// The function a sample names.
+    pub fn function(&self, id: usize) -> Option<&Function> {
+        self.functions.get(&id)
+    }
+
+    /// Each distinct frame chain and how many samples caught it.
+    pub fn stacks(&self) -> impl Iterator<Item = (&Sample, u64)> {
+        self.stacks.iter().map(|(s, &n)| (s, n))
+    }
+
+    /// Samples by the function running when they were taken, most first.
+    pub fn self_counts(&self) -> Vec<(usize, u64)> {
+        let mut counts = HashMap::new();
+        for (sample, n) in &self.stacks {
+            if let Some(&(id, _)) = sample.frames.first() {
+                *counts.entry(id).or_insert(0) += n;
+            }
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// The profile in the folded format flame graph tools read: a line per
+    /// frame chain, outermost first, frames as `fn_<id>:<op>`.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(sample, n)| {
+                let mut line = String::new();
+                for (i, (id, cursor)) in sample.frames.iter().rev().enumerate() {
+                    if i > 0 {
+                        line.push(';');
+                    }
+                    write!(line, "fn_{:x}:{}", id, cursor).unwrap();
+                }
+                write!(line, " {}", n).unwrap();
+                line
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    }
+}
+
+/// What a `Profiler` counted for one function.
+#[derive(Clone, Copy, Default)]
+pub struct FunctionStats {
+    pub calls: u64,
+    /// Ops run in the function's own frames.
+    pub ops: u64,
+    /// Time in the function's own frames, its callees' excluded.
+    pub self_time: Duration,
+    /// Time from each call to its return, callees included; a recursive
+    /// call is counted in its outermost call's time only.
+    pub total_time: Duration,
+}
+
+/// A frame being profiled.
+struct Active {
+    id: usize,
+    start: Instant,
+    /// Time spent in the frame's callees so far.
+    callees: Duration,
+}
+
+/// Per-function calls, ops and wall time. Time is only counted for calls
+/// that have returned, or thrown out of their frame.
+#[derive(Default)]
+pub struct Profiler {
+    /// The profiled frames, outermost first.
+    active: Vec<Active>,
+    functions: HashMap<usize, (Function, FunctionStats)>,
+}
+
+impl Profiler {
+    fn stats(&mut self, func: &Function) -> &mut FunctionStats {
+        let entry = self.functions.entry(func.identity());
+        &mut entry
+            .or_insert_with(|| (func.clone(), FunctionStats::default()))
+            .1
+    }
+
+    pub(crate) fn op(&mut self, func: &Function) {
+        self.stats(func).ops += 1;
+    }
+
+    pub(crate) fn enter(&mut self, func: &Function) {
+        self.stats(func).calls += 1;
+        self.active.push(Active {
+            id: func.identity(),
+            start: Instant::now(),
+            callees: Duration::ZERO,
+        });
+    }
+
+    pub(crate) fn exit(&mut self) {
+        if let Some(frame) = self.active.pop() {
+            self.count(frame.id, frame.start.elapsed(), frame.callees);
+        }
+    }
+
+    /// A call that ran without a frame of its own (see `leaf`).
+    pub(crate) fn leaf(&mut self, func: &Function, ops: u64, elapsed: Duration) {
+        let stats = self.stats(func);
+        stats.calls += 1;
+        stats.ops += ops;
+        self.count(func.identity(), elapsed, Duration::ZERO);
+    }
+
+    fn count(&mut self, id: usize, elapsed: Duration, callees: Duration) {
+        let recursive = self.active.iter().any(|f| f.id == id);
+        if let Some((_, stats)) = self.functions.get_mut(&id) {
+            stats.self_time += elapsed.saturating_sub(callees);
+            if !recursive {
+                stats.total_time += elapsed;
+            }
+        }
+        if let Some(caller) = self.active.last_mut() {
+            caller.callees += elapsed;
+        }
+    }
+
+    /// Forgets the frames being profiled, when the VM drops them.
+    pub(crate) fn clear_active(&mut self) {
+        self.active.clear();
+    }
+
+    /// Every function that ran, the most time in its own frames first.
+    pub fn report(&self) -> Vec<(Function, FunctionStats)> {
+        let mut report: Vec<_> = self.functions.values().cloned().collect();
+        report.sort_by(|a, b| {
+            b.1.self_time
+                .cmp(&a.1.self_time)
+                .then(b.1.ops.cmp(&a.1.ops))
+        });
+        report
+    }
+
+    pub fn get(&self, func: &Function) -> Option<&FunctionStats> {
+        self.functions.get(&func.identity()).map(|(_, stats)| stats)
+    }
+}
+
+/// Buckets in an `OpHistogram`: bucket `i` counts latencies of `2^i` up
+/// to `2^(i + 1)` nanoseconds, with bucket 0 from zero.
+pub const HISTOGRAM_BUCKETS: usize = 64;
+
+/// The latencies of one op kind, bucketed by powers of two.
+#[derive(Clone)]
+pub struct OpHistogram {
+    pub count: u64,
+    pub total_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    buckets: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl Default for OpHistogram {
+    fn default() -> Self {
+        OpHistogram {
+            count: 0,
+            total_ns: 0,
+            min_ns: u64::MAX,
+            max_ns: 0,
+            buckets: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+}
+
+impl OpHistogram {
+    pub fn record(&mut self, ns: u64) {
+        self.count += 1;
+        self.total_ns = self.total_ns.saturating_add(ns);
+        self.min_ns = self.min_ns.min(ns);
+        self.max_ns = self.max_ns.max(ns);
+        self.buckets[bucket(ns)] += 1;
+    }
+
+    /// The count of each bucket.
+    pub fn buckets(&self) -> &[u64; HISTOGRAM_BUCKETS] {
+        &self.buckets
+    }
+
+    /// An upper bound on the `q` quantile (0 to 1), from the top of the
+    /// bucket it falls in.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return bucket_top(i).min(self.max_ns);
+            }
+        }
+        self.max_ns
+    }
+}
+
+fn bucket(ns: u64) -> usize {
+    (u64::BITS - ns.leading_zeros()).saturating_sub(1) as usize
+}
+
+fn bucket_top(i: usize) -> u64 {
+    1u64.checked_shl(i as u32 + 1)
+        .map_or(u64::MAX, |top| top - 1)
+}
+
+/// Op latencies by op kind, from a VM with op timing on.
+#[derive(Clone, Default)]
+pub struct OpTimings {
+    ops: HashMap<&'static str, OpHistogram>,
+}
+
+impl OpTimings {
+    pub(crate) fn record(&mut self, op: &'static str, elapsed: Duration) {
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.ops.entry(op).or_default().record(ns);
+    }
+
+    /// The histogram of an op kind, by name (see `bytecode::Op::name`).
+    pub fn get(&self, op: &str) -> Option<&OpHistogram> {
+        self.ops.get(op)
+    }
+
+    /// Op kinds that ran, by name.
+    pub fn ops(&self) -> impl Iterator<Item = (&'static str, &OpHistogram)> {
+        self.ops.iter().map(|(&name, h)| (name, h))
+    }
+
+    /// One JSON object per op kind and line, by name: count, total, min,
+    /// max, median and 99th percentile in nanoseconds, and the non-empty
+    /// buckets as `[bucket, count]` pairs.
+    pub fn to_jsonl(&self) -> String {
+        let mut names: Vec<_> = self.ops.keys().copied().collect();
+        names.sort_unstable();
+        let mut out = String::new();
+        for name in names {
+            let h = &self.ops[name];
+            let buckets: Vec<String> = (h.buckets.iter().enumerate())
+                .filter(|(_, &n)| n > 0)
+                .map(|(i, n)| format!("[{},{}]", i, n))
+                .collect();
+            writeln!(
+                out,
+                "{{\"op\":\"{}\",\"count\":{},\"total_ns\":{},\"min_ns\":{},\"max_ns\":{},\"p50_ns\":{},\"p99_ns\":{},\"buckets\":[{}]}}",
+                name,
+                h.count,
+                h.total_ns,
+                h.min_ns,
+                h.max_ns,
+                h.quantile(0.5),
+                h.quantile(0.99),
+                buckets.join(",")
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::datamodel::Value;
+    use crate::testing::function;
+    use crate::{VirtualMachine, VmState};
+
+    #[test]
+    fn samples_are_taken_at_op_boundaries() {
+        let spin = function(vec![Jump(-1).into()]);
+        let main = function(vec![Push(spin.clone().into()).into(), Call(0).into()]);
+        let mut vm = VirtualMachine::new(main.clone());
+        vm.set_leaf_calls(false);
+        let handle = vm.sample_handle();
+        assert!(matches!(vm.run_with_fuel(10), Ok(VmState::OutOfFuel)));
+        assert!(handle.take_latest().is_none());
+
+        handle.request();
+        assert!(matches!(vm.run_with_fuel(10), Ok(VmState::OutOfFuel)));
+        let sample = handle.take_latest().unwrap();
+        assert_eq!(sample.frames, [(spin.identity(), 0), (main.identity(), 2)]);
+        assert!(handle.take_latest().is_none());
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.samples(), 1);
+        assert_eq!(profile.self_counts(), [(spin.identity(), 1)]);
+        assert!(profile.function(main.identity()).is_some());
+        assert_eq!(
+            profile.folded(),
+            format!("fn_{:x}:2;fn_{:x}:0 1\n", main.identity(), spin.identity())
+        );
+    }
+
+    #[test]
+    fn the_profiler_counts_calls_ops_and_time_per_function() {
+        // double(x) = x + x, called twice
+        let double = function(vec![
+            Store(1).into(),
+            Load(1).into(),
+            Load(1).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let main = function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(double.clone().into()).into(),
+            Call(1).into(),
+            Push(double.clone().into()).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        for leaf_calls in [false, true] {
+            let mut vm = VirtualMachine::new(main.clone());
+            vm.set_leaf_calls(leaf_calls);
+            vm.set_profiling(true);
+            assert!(matches!(vm.run_until_exited(), Ok(Value::Integer(4))));
+            let profiler = vm.profiler().unwrap();
+            let stats = profiler.get(&double).unwrap();
+            assert_eq!((stats.calls, stats.ops), (2, 10));
+            let outer = profiler.get(&main).unwrap();
+            assert_eq!((outer.calls, outer.ops), (1, 6));
+            assert!(outer.total_time >= outer.self_time + stats.total_time);
+            assert_eq!(profiler.report().len(), 2);
+        }
+    }
+
+    #[test]
+    fn op_timings_bucket_by_powers_of_two() {
+        let mut h = OpHistogram::default();
+        for ns in [0, 1, 3, 700, 1000] {
+            h.record(ns);
+        }
+        assert_eq!(
+            (h.count, h.total_ns, h.min_ns, h.max_ns),
+            (5, 1704, 0, 1000)
+        );
+        assert_eq!(h.buckets()[..2], [2, 1]);
+        assert_eq!(h.buckets()[9], 2);
+        assert_eq!(h.quantile(0.5), 3);
+        assert_eq!(h.quantile(1.0), 1000);
+
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::Integer(1)).into(),
+            Push(Value::Integer(2)).into(),
+            Add.into(),
+            Return.into(),
+        ]));
+        vm.set_op_timing(true);
+        assert!(vm.run_until_exited().is_ok());
+        let timings = vm.take_op_timings().unwrap();
+        assert_eq!(timings.get("Push").unwrap().count, 2);
+        assert_eq!(timings.ops().count(), 3);
+        let jsonl = timings.to_jsonl();
+        assert_eq!(jsonl.lines().count(), 3);
+        assert!(jsonl.starts_with("{\"op\":\"Add\",\"count\":1,"));
+    }
+
+    #[test]
+    fn a_sampler_thread_profiles_a_running_vm() {
+        let spin = function(vec![
+            Push(Value::Integer(0)).into(),
+            Pop.into(),
+            Jump(-3).into(),
+        ]);
+        let mut vm = VirtualMachine::new(spin);
+        let sampler = Sampler::start(vm.sample_handle(), Duration::from_millis(1));
+        while vm.profile().unwrap().samples() < 3 {
+            assert!(matches!(vm.run_with_fuel(1000), Ok(VmState::OutOfFuel)));
+        }
+        sampler.stop();
+        let profile = vm.profile().unwrap();
+        let counted: u64 = profile.stacks().map(|(_, n)| n).sum();
+        assert_eq!(counted, profile.samples());
+    }
+}
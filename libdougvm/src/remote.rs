@@ -0,0 +1,581 @@
+//! A small protocol for controlling a VM host from another process, over
+//! any byte stream (`serve_tcp` and `serve_unix` accept connections), so
+//! tooling doesn't have to be linked into the host.
+//!
+//! Every message is a frame: a little-endian `u32` length, then a kind
+//! byte and its fields. Strings and byte strings are `u32`-length-prefixed
+//! chunks, and values use the `kv` store's encoding, so only plain data
+//! crosses the wire. A client may:
+//!
+//! - load a module, handing its bytes to the host's `Loader`;
+//! - invoke an exported function. An `Iter` result is streamed item by
+//!   item before the final `Done`;
+//! - attach, after which every failed call is preceded by a `Trace` with
+//!   the VM's backtrace at the point of failure.
+//!
+//! Requests are served one at a time, in order. Each invocation runs
+//! metered (see `Server::set_fuel`), so a request that never finishes
+//! fails rather than stalling every client behind it.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+use crate::codec::{decode_from, encode, write_chunk, Reader};
+use crate::datamodel::{List, Value};
+use crate::rpc::Exports;
+use crate::VirtualMachine;
+
+/// Largest frame either side will accept.
+pub const MAX_FRAME: usize = 16 << 20;
+
+/// Ops an invocation may run before it fails, unless the server is given
+/// another limit.
+pub const DEFAULT_INVOKE_FUEL: u64 = 100_000_000;
+
+const LOAD: u8 = 1;
+const INVOKE: u8 = 2;
+const ATTACH: u8 = 3;
+const LOADED: u8 = 0x81;
+const ITEM: u8 = 0x82;
+const DONE: u8 = 0x83;
+const FAILED: u8 = 0x84;
+const TRACE: u8 = 0x85;
+const ERROR: u8 = 0x86;
+
+pub enum Request {
+    Load {
+        name: String,
+        module: Vec<u8>,
+    },
+    Invoke {
+        id: u32,
+        module: String,
+        function: String,
+        args: Vec<Value>,
+    },
+    Attach,
+}
+
+pub enum Response {
+    Loaded {
+        name: String,
+    },
+    /// One item of an `Iter` result.
+    Item {
+        id: u32,
+        value: Value,
+    },
+    Done {
+        id: u32,
+        value: Value,
+    },
+    Failed {
+        id: u32,
+        reason: String,
+    },
+    Trace {
+        id: u32,
+        backtrace: String,
+    },
+    /// A request that couldn't be carried out at all, e.g. a failed load.
+    Error {
+        reason: String,
+    },
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn write_frame(w: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME {
+        return Err(invalid("frame too large"));
+    }
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+/// The next frame, or `None` if the stream ended cleanly between frames.
+fn read_frame(r: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid("frame too large"));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_chunk(out, s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, val: &Value) -> io::Result<()> {
+    encode(val, out).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a {} can't be sent", val.get_type().as_str()),
+        )
+    })
+}
+
+fn read_str(r: &mut Reader) -> io::Result<String> {
+    let chunk = r.chunk().ok_or_else(|| invalid("truncated string"))?;
+    String::from_utf8(chunk.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn read_u32(r: &mut Reader) -> io::Result<u32> {
+    r.u32().ok_or_else(|| invalid("truncated message"))
+}
+
+fn read_value(r: &mut Reader) -> io::Result<Value> {
+    decode_from(r).ok_or_else(|| invalid("malformed value"))
+}
+
+fn finish<T>(r: Reader, message: T) -> io::Result<Option<T>> {
+    match r.is_empty() {
+        true => Ok(Some(message)),
+        false => Err(invalid("trailing bytes")),
+    }
+}
+
+pub fn write_request(w: &mut impl Write, request: &Request) -> io::Result<()> {
+    let mut out = Vec::new();
+    match request {
+        Request::Load { name, module } => {
+            out.push(LOAD);
+            write_str(&mut out, name);
+            write_chunk(&mut out, module);
+        }
+        Request::Invoke {
+            id,
+            module,
+            function,
+            args,
+        } => {
+            out.push(INVOKE);
+            out.extend(id.to_le_bytes());
+            write_str(&mut out, module);
+            write_str(&mut out, function);
+            write_value(&mut out, &List::new(args.clone()).into())?;
+        }
+        Request::Attach => out.push(ATTACH),
+    }
+    write_frame(w, &out)
+}
+
+pub fn read_request(r: &mut impl Read) -> io::Result<Option<Request>> {
+    let payload = match read_frame(r)? {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    let mut r = Reader::new(&payload);
+    let request = match r.u8() {
+        Some(LOAD) => Request::Load {
+            name: read_str(&mut r)?,
+            module: r
+                .chunk()
+                .ok_or_else(|| invalid("truncated module"))?
+                .to_vec(),
+        },
+        Some(INVOKE) => Request::Invoke {
+            id: read_u32(&mut r)?,
+            module: read_str(&mut r)?,
+            function: read_str(&mut r)?,
+            args: match read_value(&mut r)? {
+                Value::List(l) => l.to_vec(),
+                _ => return Err(invalid("arguments must be a List")),
+            },
+        },
+        Some(ATTACH) => Request::Attach,
+        _ => return Err(invalid("unknown request")),
+    };
+    finish(r, request)
+}
+
+pub fn write_response(w: &mut impl Write, response: &Response) -> io::Result<()> {
+    let mut out = Vec::new();
+    match response {
+        Response::Loaded { name } => {
+            out.push(LOADED);
+            write_str(&mut out, name);
+        }
+        Response::Item { id, value } | Response::Done { id, value } => {
+            out.push(match response {
+                Response::Item { .. } => ITEM,
+                _ => DONE,
+            });
+            out.extend(id.to_le_bytes());
+            write_value(&mut out, value)?;
+        }
+        Response::Failed { id, reason: text }
+        | Response::Trace {
+            id,
+            backtrace: text,
+        } => {
+            out.push(match response {
+                Response::Failed { .. } => FAILED,
+                _ => TRACE,
+            });
+            out.extend(id.to_le_bytes());
+            write_str(&mut out, text);
+        }
+        Response::Error { reason } => {
+            out.push(ERROR);
+            write_str(&mut out, reason);
+        }
+    }
+    write_frame(w, &out)
+}
+
+pub fn read_response(r: &mut impl Read) -> io::Result<Option<Response>> {
+    let payload = match read_frame(r)? {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    let mut r = Reader::new(&payload);
+    let response = match r.u8() {
+        Some(LOADED) => Response::Loaded {
+            name: read_str(&mut r)?,
+        },
+        Some(ITEM) => Response::Item {
+            id: read_u32(&mut r)?,
+            value: read_value(&mut r)?,
+        },
+        Some(DONE) => Response::Done {
+            id: read_u32(&mut r)?,
+            value: read_value(&mut r)?,
+        },
+        Some(FAILED) => Response::Failed {
+            id: read_u32(&mut r)?,
+            reason: read_str(&mut r)?,
+        },
+        Some(TRACE) => Response::Trace {
+            id: read_u32(&mut r)?,
+            backtrace: read_str(&mut r)?,
+        },
+        Some(ERROR) => Response::Error {
+            reason: read_str(&mut r)?,
+        },
+        _ => return Err(invalid("unknown response")),
+    };
+    finish(r, response)
+}
+
+/// Turns a module's bytes into its exports. The host decides what a module
+/// is. A file written by `bytecode::serialize::save` is read back with
+/// `bytecode::serialize::load`, which gives the function to export.
+pub type Loader = Box<dyn Fn(&[u8]) -> Result<Exports, String>>;
+
+pub struct Server {
+    loader: Loader,
+    modules: HashMap<String, Exports>,
+    fuel: Option<u64>,
+}
+
+impl Server {
+    pub fn new(loader: Loader) -> Server {
+        Server {
+            loader,
+            modules: HashMap::new(),
+            fuel: Some(DEFAULT_INVOKE_FUEL),
+        }
+    }
+
+    /// The ops each invocation may run before it fails with a `Failed`
+    /// reply; `None` lets them run unmetered.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Makes a module available without a client having to load it.
+    pub fn add_module(&mut self, name: &str, exports: Exports) {
+        self.modules.insert(name.to_string(), exports);
+    }
+
+    /// Serves requests from `stream` until the client disconnects.
+    pub fn serve(&mut self, mut stream: impl Read + Write) -> io::Result<()> {
+        let mut attached = false;
+        while let Some(request) = read_request(&mut stream)? {
+            match request {
+                Request::Load { name, module } => {
+                    let response = match (self.loader)(&module) {
+                        Ok(exports) => {
+                            self.modules.insert(name.clone(), exports);
+                            Response::Loaded { name }
+                        }
+                        Err(reason) => Response::Error { reason },
+                    };
+                    write_response(&mut stream, &response)?;
+                }
+                Request::Invoke {
+                    id,
+                    module,
+                    function,
+                    args,
+                } => self.invoke(&mut stream, id, &module, &function, args, attached)?,
+                Request::Attach => attached = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn invoke(
+        &self,
+        stream: &mut impl Write,
+        id: u32,
+        module: &str,
+        function: &str,
+        args: Vec<Value>,
+        attached: bool,
+    ) -> io::Result<()> {
+        let func = match self.modules.get(module).and_then(|m| m.get(function)) {
+            Some(func) => func.clone(),
+            None => {
+                let reason = format!("no exported function {}.{}", module, function);
+                return write_response(stream, &Response::Failed { id, reason });
+            }
+        };
+        let mut vm = VirtualMachine::with_args(func, args);
+        vm.set_fuel(self.fuel);
+        let value = match vm.run_until_exited() {
+            Ok(Value::Iter(iter)) => {
+                while let Some(value) = iter.next() {
+                    send_value(stream, Response::Item { id, value })?;
+                }
+                Value::None
+            }
+            Ok(value) => value,
+            Err(e) => {
+                if attached {
+                    let backtrace = e.backtrace();
+                    write_response(stream, &Response::Trace { id, backtrace })?;
+                }
+                let reason = format!("{} failed: {}", function, e);
+                return write_response(stream, &Response::Failed { id, reason });
+            }
+        };
+        send_value(stream, Response::Done { id, value })
+    }
+
+    /// Serves each connection in turn, returning on the first error
+    /// accepting one.
+    pub fn serve_tcp(&mut self, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            // a misbehaving client only loses its own connection
+            let _ = self.serve(stream?);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub fn serve_unix(&mut self, listener: &UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let _ = self.serve(stream?);
+        }
+        Ok(())
+    }
+}
+
+/// Sends `response`, or a `Failed` in its place if its value can't be sent.
+fn send_value(stream: &mut impl Write, response: Response) -> io::Result<()> {
+    let id = match &response {
+        Response::Item { id, .. } | Response::Done { id, .. } => *id,
+        _ => unreachable!("only values are checked"),
+    };
+    match write_response(stream, &response) {
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            let reason = e.to_string();
+            write_response(stream, &Response::Failed { id, reason })
+        }
+        result => result,
+    }
+}
+
+/// The client side, over a connected stream.
+pub struct Client<S> {
+    stream: S,
+    next_id: u32,
+    traces: Vec<String>,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        Client {
+            stream,
+            next_id: 0,
+            traces: Vec::new(),
+        }
+    }
+
+    fn response(&mut self) -> io::Result<Response> {
+        read_response(&mut self.stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server hung up"))
+    }
+
+    pub fn load(&mut self, name: &str, module: &[u8]) -> io::Result<Result<(), String>> {
+        let request = Request::Load {
+            name: name.to_string(),
+            module: module.to_vec(),
+        };
+        write_request(&mut self.stream, &request)?;
+        match self.response()? {
+            Response::Loaded { .. } => Ok(Ok(())),
+            Response::Error { reason } => Ok(Err(reason)),
+            _ => Err(invalid("unexpected response")),
+        }
+    }
+
+    /// Asks for backtraces of failed calls; see `traces`.
+    pub fn attach(&mut self) -> io::Result<()> {
+        write_request(&mut self.stream, &Request::Attach)
+    }
+
+    /// Backtraces received so far, oldest first.
+    pub fn traces(&self) -> &[String] {
+        &self.traces
+    }
+
+    /// Calls `module.function`, passing streamed items to `on_item` as they
+    /// arrive.
+    pub fn invoke(
+        &mut self,
+        module: &str,
+        function: &str,
+        args: Vec<Value>,
+        mut on_item: impl FnMut(Value),
+    ) -> io::Result<Result<Value, String>> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let request = Request::Invoke {
+            id,
+            module: module.to_string(),
+            function: function.to_string(),
+            args,
+        };
+        write_request(&mut self.stream, &request)?;
+        loop {
+            match self.response()? {
+                Response::Item { id: i, value } if i == id => on_item(value),
+                Response::Done { id: i, value } if i == id => return Ok(Ok(value)),
+                Response::Failed { id: i, reason } if i == id => return Ok(Err(reason)),
+                Response::Trace { id: i, backtrace } if i == id => self.traces.push(backtrace),
+                _ => return Err(invalid("unexpected response")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Add, Call, Jump, Pop, Push, Return};
+    use crate::datamodel::Iter;
+    use crate::testing::function;
+    use std::net::TcpStream;
+    use std::thread;
+
+    fn count(_args: Vec<Value>) -> Value {
+        let mut n = 0;
+        Iter::new(move || {
+            n += 1;
+            (n <= 3).then_some(Value::Integer(n))
+        })
+        .into()
+    }
+
+    fn exports() -> Exports {
+        let mut exports = Exports::new();
+        exports.export("add", function(vec![Add.into(), Return.into()]));
+        let count = vec![
+            Push(Value::NativeFn(count)).into(),
+            Call(0).into(),
+            Return.into(),
+        ];
+        exports.export("count", function(count));
+        exports.export("broken", function(vec![Pop.into(), Pop.into()]));
+        exports.export("spin", function(vec![Jump(-1).into()]));
+        exports
+    }
+
+    #[test]
+    fn invokes_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let loader: Loader = Box::new(|bytes| match bytes {
+                b"math" => Ok(exports()),
+                _ => Err("unknown module".to_string()),
+            });
+            let mut server = Server::new(loader);
+            server.set_fuel(Some(1000));
+            let (stream, _) = listener.accept().unwrap();
+            server.serve(stream).unwrap();
+        });
+        let mut client = Client::new(TcpStream::connect(addr).unwrap());
+        assert!(client.load("m", b"nope").unwrap().is_err());
+        assert!(client.load("m", b"math").unwrap().is_ok());
+        let args = vec![Value::Integer(2), Value::Integer(3)];
+        let sum = client.invoke("m", "add", args, |_| {}).unwrap();
+        assert!(matches!(sum, Ok(Value::Integer(5))));
+        let mut items = Vec::new();
+        let done = client
+            .invoke("m", "count", Vec::new(), |v| items.push(v))
+            .unwrap();
+        assert!(matches!(done, Ok(Value::None)));
+        assert!(matches!(
+            items[..],
+            [Value::Integer(1), Value::Integer(2), Value::Integer(3)]
+        ));
+        client.attach().unwrap();
+        assert!(client
+            .invoke("m", "broken", Vec::new(), |_| {})
+            .unwrap()
+            .is_err());
+        let traces = client.traces();
+        assert!(traces[0].starts_with("Error: stack is empty in fn_"));
+        assert!(traces[0].ends_with(" at op 0\n"));
+        // a request that never finishes runs out of fuel instead
+        let spun = client.invoke("m", "spin", Vec::new(), |_| {}).unwrap();
+        assert!(spun.is_err());
+        let sum = client.invoke(
+            "m",
+            "add",
+            vec![Value::Integer(1), Value::Integer(1)],
+            |_| {},
+        );
+        assert!(matches!(sum.unwrap(), Ok(Value::Integer(2))));
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn deeply_nested_frames_are_rejected() {
+        // invoke m.f with args nested in a million one-item lists
+        let mut payload = vec![INVOKE];
+        payload.extend(7u32.to_le_bytes());
+        write_str(&mut payload, "m");
+        write_str(&mut payload, "f");
+        for _ in 0..1_000_000 {
+            payload.push(5);
+            payload.extend(1u32.to_le_bytes());
+        }
+        payload.push(0);
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend(payload);
+        match read_request(&mut &frame[..]) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("expected the frame to be rejected"),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Tracking of open host resources. Every VM keeps a ledger of the
+//! userdata (see `finalize`) created by the natives and host functions it
+//! calls, from creation until its finalizer has run, so an embedder can
+//! see what a script is holding open and cap it before a leak exhausts the
+//! process's file or socket handles.
+//!
+//! Userdata created outside a VM's calls, e.g. by the host directly, isn't
+//! tracked.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::{Rc, Weak};
+
+/// An open resource.
+#[derive(Clone)]
+pub struct Resource {
+    pub id: u64,
+    /// The userdata's Rust type.
+    pub kind: &'static str,
+    /// The call that opened it, as in `VmError::backtrace`.
+    pub opened_at: String,
+}
+
+#[derive(Default)]
+pub struct Resources {
+    live: RefCell<BTreeMap<u64, Resource>>,
+    next: Cell<u64>,
+}
+
+impl Resources {
+    pub fn open(&self) -> Vec<Resource> {
+        self.live.borrow().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.borrow().is_empty()
+    }
+
+    /// The next id to be handed out; resources opened from here on have
+    /// ids at least this.
+    pub(crate) fn mark(&self) -> u64 {
+        self.next.get()
+    }
+
+    /// Records where the resources opened since `mark` came from.
+    pub(crate) fn annotate_since(&self, mark: u64, opened_at: &str) {
+        for resource in self.live.borrow_mut().range_mut(mark..).map(|(_, r)| r) {
+            resource.opened_at = opened_at.to_string();
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Resources>>> = const { RefCell::new(None) };
+}
+
+/// Sets the ledger new userdata on this thread is recorded in, returning
+/// the previous one.
+pub fn set_current(ledger: Option<Rc<Resources>>) -> Option<Rc<Resources>> {
+    CURRENT.with(|c| c.replace(ledger))
+}
+
+/// A ledger entry, closed when dropped.
+pub(crate) struct Ticket {
+    ledger: Weak<Resources>,
+    id: u64,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Some(ledger) = self.ledger.upgrade() {
+            ledger.live.borrow_mut().remove(&self.id);
+        }
+    }
+}
+
+/// Records a resource of `kind` in the current ledger, if there is one.
+pub(crate) fn track(kind: &'static str) -> Option<Ticket> {
+    let ledger = CURRENT.with(|c| c.borrow().clone())?;
+    let id = ledger.next.get();
+    ledger.next.set(id + 1);
+    let resource = Resource {
+        id,
+        kind,
+        opened_at: String::new(),
+    };
+    ledger.live.borrow_mut().insert(id, resource);
+    Some(Ticket {
+        ledger: Rc::downgrade(&ledger),
+        id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::ops::{Call, Pop, Push, Return, Store};
+    use crate::bytecode::OpError;
+    use crate::datamodel::{Function, Tuple, Value};
+    use crate::finalize::userdata;
+    use crate::{VirtualMachine, VmError};
+
+    struct Handle;
+
+    fn open_handle(_args: Vec<Value>) -> Value {
+        userdata(Handle, |_| {})
+    }
+
+    /// Opens a handle into local 1, then opens and drops another.
+    fn program() -> VirtualMachine {
+        VirtualMachine::new(Function {
+            module: Tuple::new(Vec::new()),
+            ops: vec![
+                Push(Value::NativeFn(open_handle)).into(),
+                Call(0).into(),
+                Store(1).into(),
+                Push(Value::NativeFn(open_handle)).into(),
+                Call(0).into(),
+                Pop.into(),
+                Push(Value::None).into(),
+                Return.into(),
+            ]
+            .into(),
+        })
+    }
+
+    #[test]
+    fn vms_track_and_cap_open_handles() {
+        let mut vm = program();
+        for _ in 0..6 {
+            let action = vm.step().ok().unwrap();
+            assert!(vm.process(action).is_ok());
+        }
+        // the second handle stays open until its finalizer has run
+        assert_eq!(vm.open_resources().len(), 2);
+        crate::finalize::run_pending();
+        let open = vm.open_resources();
+        assert_eq!(open.len(), 1);
+        assert!(open[0].kind.ends_with("Handle"));
+        assert!(
+            open[0].opened_at.starts_with("#0 fn_") && open[0].opened_at.ends_with(" at op 1\n")
+        );
+
+        let mut vm = program();
+        vm.set_resource_limit(Some(1));
+        assert!(matches!(
+            vm.run_until_exited(),
+            Err(VmError {
+                error: OpError::TooManyResources(1),
+                ..
+            })
+        ));
+        crate::finalize::run_pending();
+        assert_eq!(vm.open_resources().len(), 1);
+    }
+}
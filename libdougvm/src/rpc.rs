@@ -0,0 +1,147 @@
+//! Calls between VMs in one process. A host publishes named `Exports` as a
+//! service with `register`; scripts reach it with the `rpc_call` native.
+//! Nothing is shared across the boundary: a `Local` service runs on the
+//! calling thread and gets deep copies of the arguments, and a `Remote`
+//! one runs on its own thread with arguments and results carried there and
+//! back as frozen segments (see `frozen`). Only data can cross, not
+//! functions or host objects.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use crate::datamodel::{Function, Tuple, Value};
+use crate::frozen::{Frozen, Segment};
+use crate::schema::Schema;
+use crate::VirtualMachine;
+
+/// Functions a service makes callable, by name.
+#[derive(Default)]
+pub struct Exports {
+    functions: HashMap<String, Function>,
+    schemas: HashMap<String, Schema>,
+}
+
+impl Exports {
+    pub fn new() -> Exports {
+        Exports::default()
+    }
+
+    pub fn export(&mut self, name: &str, func: Function) {
+        self.functions.insert(name.to_string(), func);
+    }
+
+    /// Rejects calls of `name` whose arguments, as a `Tuple`, don't match
+    /// `schema`.
+    pub fn set_schema(&mut self, name: &str, schema: Schema) {
+        self.schemas.insert(name.to_string(), schema);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name)
+    }
+
+    /// Runs an exported function to completion in a fresh VM.
+    pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let func = match self.get(name) {
+            Some(func) => func.clone(),
+            None => return Err(format!("no exported function {}", name)),
+        };
+        if let Some(schema) = self.schemas.get(name) {
+            let args = Tuple::new(args.clone()).into();
+            schema
+                .validate(&args)
+                .map_err(|e| format!("bad arguments to {}: {}", name, e))?;
+        }
+        VirtualMachine::with_args(func, args)
+            .run_until_exited()
+            .map_err(|e| format!("{} failed: {}", name, e))
+    }
+}
+
+fn crossing_error() -> String {
+    "value can't cross a VM boundary".to_string()
+}
+
+/// A deep copy of `val`, or an error if it holds anything but data.
+pub fn deep_copy(val: &Value) -> Result<Value, String> {
+    Ok(Frozen::freeze(val).ok_or_else(crossing_error)?.thaw())
+}
+
+type Reply = Result<Segment, String>;
+
+/// A handle to a service running on another thread.
+#[derive(Clone)]
+pub struct Remote {
+    requests: Sender<(String, Segment, Sender<Reply>)>,
+}
+
+impl Remote {
+    /// Starts a thread serving the exports `build` returns. `build` runs on
+    /// that thread, since functions can't be sent between threads. The
+    /// thread ends once every handle to it is dropped.
+    pub fn spawn(build: impl FnOnce() -> Exports + Send + 'static) -> Remote {
+        let (requests, incoming) = mpsc::channel::<(String, Segment, Sender<Reply>)>();
+        thread::spawn(move || {
+            let exports = build();
+            for (name, args, reply) in incoming {
+                let args = match args.get().thaw() {
+                    Value::Tuple(t) => (0..t.len()).filter_map(|i| t.get(i)).collect(),
+                    _ => Vec::new(),
+                };
+                let result = exports
+                    .call(&name, args)
+                    .and_then(|val| Segment::freeze(&val).ok_or_else(crossing_error));
+                let _ = reply.send(result);
+            }
+        });
+        Remote { requests }
+    }
+
+    /// Calls `name` and waits for its result.
+    pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let args = Segment::freeze(&Tuple::new(args).into()).ok_or_else(crossing_error)?;
+        let (reply, result) = mpsc::channel();
+        let stopped = || "service has stopped".to_string();
+        self.requests
+            .send((name.to_string(), args, reply))
+            .map_err(|_| stopped())?;
+        Ok(result.recv().map_err(|_| stopped())??.get().thaw())
+    }
+}
+
+pub enum Service {
+    Local(Rc<Exports>),
+    Remote(Remote),
+}
+
+impl Service {
+    pub fn call(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        match self {
+            Service::Local(exports) => {
+                let args = args.iter().map(deep_copy).collect::<Result<_, _>>()?;
+                deep_copy(&exports.call(name, args)?)
+            }
+            Service::Remote(remote) => remote.call(name, args.to_vec()),
+        }
+    }
+}
+
+thread_local! {
+    static SERVICES: RefCell<HashMap<String, Rc<Service>>> = RefCell::new(HashMap::new());
+}
+
+/// Makes `service` callable as `name` by scripts on this thread.
+pub fn register(name: &str, service: Service) {
+    SERVICES.with(|s| s.borrow_mut().insert(name.to_string(), Rc::new(service)));
+}
+
+pub fn unregister(name: &str) {
+    SERVICES.with(|s| s.borrow_mut().remove(name));
+}
+
+pub fn service(name: &str) -> Option<Rc<Service>> {
+    SERVICES.with(|s| s.borrow().get(name).cloned())
+}
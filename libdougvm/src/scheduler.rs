@@ -0,0 +1,575 @@
+//! Green-thread scheduling of script tasks. Each task is its own
+//! `VirtualMachine`, run a slice of ops at a time, so a script that loops
+//! forever only delays the others instead of blocking them.
+//!
+//! Slices are handed out by stride scheduling: every task has a `pass`
+//! that grows by the steps it ran divided by its priority, and the task
+//! with the lowest pass runs next. A task of priority 4 therefore gets
+//! four times the steps of a priority 1 task, and no ready task ever
+//! starves.
+//!
+//! Each slice is one `VirtualMachine::step_n`, so a task's interrupt handle,
+//! fuel and sampling work as they do for a VM run on its own. A task
+//! waiting on a stream or a suspended native call is parked: it gets no
+//! slices until the host has serviced the stream or resumed the call.
+//!
+//! The scheduler also owns the VM's timers (see `timer`): callbacks that
+//! tasks register are spawned as tasks of their own, at the registering
+//! task's priority, when `advance_time` passes their deadline. Events the
+//! host `emit`s are dispatched the same way, to the handlers registered
+//! for them (see `events`), before the next slice runs. Tasks spawned
+//! into a task group (see `group`) are cancelled along with their group.
+
+use std::collections::{BTreeMap, HashMap};
+use std::rc::{Rc, Weak};
+
+use crate::bytecode::OpError;
+use crate::datamodel::{Function, Value};
+use crate::events::{self, EventBus};
+use crate::group::{self, Outcome, TaskGroup};
+use crate::stream::Stream;
+use crate::timer::{take_requests, Request, Timer, TimerWheel};
+use crate::{VirtualMachine, VmError, VmState};
+
+pub type TaskId = u64;
+
+/// Fixed-point scale of `pass`, so dividing by a priority keeps precision.
+const STRIDE: u64 = 1 << 16;
+
+struct Task {
+    vm: VirtualMachine,
+    priority: u8,
+    pass: u64,
+    parked: Option<Park>,
+}
+
+/// What a parked task is waiting for.
+enum Park {
+    /// Room in a stream it writes to.
+    Sink(Rc<Stream>),
+    /// A value in a stream it reads from, or the stream closing.
+    Source(Rc<Stream>),
+    /// The host to `resume` it, through `task_mut`.
+    Suspended,
+}
+
+impl Park {
+    fn is_over(&self, vm: &VirtualMachine) -> bool {
+        match self {
+            Park::Sink(stream) => !stream.is_full() || stream.is_closed(),
+            Park::Source(stream) => !stream.is_empty() || stream.is_closed(),
+            Park::Suspended => !vm.is_suspended(),
+        }
+    }
+}
+
+pub struct Scheduler {
+    tasks: BTreeMap<TaskId, Task>,
+    next_id: TaskId,
+    quantum: u64,
+    finished: Vec<(TaskId, Result<Value, VmError>)>,
+    timers: TimerWheel,
+    events: EventBus,
+    /// The group, and index in it, of each task spawned into one.
+    grouped: HashMap<TaskId, (Weak<TaskGroup>, usize)>,
+}
+
+impl Scheduler {
+    /// A scheduler that runs each task for at most `quantum` ops per slice.
+    pub fn new(quantum: u64) -> Scheduler {
+        Scheduler {
+            tasks: BTreeMap::new(),
+            next_id: 0,
+            quantum: quantum.max(1),
+            finished: Vec::new(),
+            timers: TimerWheel::new(),
+            events: EventBus::default(),
+            grouped: HashMap::new(),
+        }
+    }
+
+    /// Starts a task running `func`. Higher priorities get proportionally
+    /// more steps; priority 0 is treated as 1.
+    pub fn spawn(&mut self, func: Function, priority: u8) -> TaskId {
+        self.spawn_call(func, Vec::new(), priority)
+    }
+
+    /// As `spawn`, calling `func` with `args` (first argument first).
+    pub fn spawn_call(&mut self, func: Function, args: Vec<Value>, priority: u8) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        // start level with the others, so a new task doesn't get to catch up
+        // on all the time it wasn't running
+        let pass = self.tasks.values().map(|t| t.pass).min().unwrap_or(0);
+        let task = Task {
+            vm: VirtualMachine::with_args(func, args),
+            priority: priority.max(1),
+            pass,
+            parked: None,
+        };
+        self.tasks.insert(id, task);
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub fn contains(&self, id: TaskId) -> bool {
+        self.tasks.contains_key(&id)
+    }
+
+    /// The running VM of a task, e.g. to read its `usage`.
+    pub fn task(&self, id: TaskId) -> Option<&VirtualMachine> {
+        self.tasks.get(&id).map(|t| &t.vm)
+    }
+
+    /// The running VM of a task, e.g. to `resume` it.
+    pub fn task_mut(&mut self, id: TaskId) -> Option<&mut VirtualMachine> {
+        self.tasks.get_mut(&id).map(|t| &mut t.vm)
+    }
+
+    /// Stops a task without running it any further.
+    pub fn cancel(&mut self, id: TaskId) -> bool {
+        let found = self.tasks.remove(&id).is_some();
+        self.record_outcome(id, Outcome::Cancelled);
+        found
+    }
+
+    fn record_outcome(&mut self, id: TaskId, outcome: Outcome) {
+        if let Some((group, index)) = self.grouped.remove(&id) {
+            if let Some(group) = group.upgrade() {
+                group.finish(index, outcome);
+            }
+        }
+    }
+
+    /// Whether a task is parked, waiting on a stream or a suspended call.
+    pub fn is_parked(&self, id: TaskId) -> bool {
+        self.tasks.get(&id).is_some_and(|t| t.parked.is_some())
+    }
+
+    /// Unparks the tasks whose wait is over. They rejoin level with the
+    /// others, as new tasks do, rather than catching up on the time they
+    /// were parked.
+    fn unpark(&mut self) {
+        let ready = self.tasks.values().filter(|t| t.parked.is_none());
+        let level = ready.map(|t| t.pass).min();
+        for task in self.tasks.values_mut() {
+            if task.parked.as_ref().is_some_and(|p| p.is_over(&task.vm)) {
+                task.parked = None;
+                task.pass = task.pass.max(level.unwrap_or(0));
+            }
+        }
+    }
+
+    /// Runs the ready task that is furthest behind for one slice. Returns
+    /// `false` if there was nothing ready to run.
+    pub fn run_slice(&mut self) -> bool {
+        self.take_requests(1);
+        self.dispatch_events();
+        self.unpark();
+        let ready = self.tasks.iter().filter(|(_, t)| t.parked.is_none());
+        let id = match ready.min_by_key(|(_, t)| t.pass) {
+            Some((id, _)) => *id,
+            None => return false,
+        };
+        let task = self.tasks.get_mut(&id).unwrap();
+        let before = task.vm.usage().steps;
+        let state = task.vm.step_n(self.quantum);
+        let result = match state {
+            Ok(VmState::Running) => None,
+            Ok(VmState::Exited(val)) => Some(Ok(val)),
+            // no more slices until the host services the stream
+            Ok(VmState::WaitingForSink(stream)) => {
+                task.parked = Some(Park::Sink(stream));
+                None
+            }
+            Ok(VmState::WaitingForSource(stream)) => {
+                task.parked = Some(Park::Source(stream));
+                None
+            }
+            // likewise until the host resumes it, through `task_mut`
+            Ok(VmState::Suspended(_)) => {
+                task.parked = Some(Park::Suspended);
+                None
+            }
+            Ok(VmState::HotLoop(_)) => Some(Err(task.vm.error(OpError::HotLoop, false))),
+            Ok(VmState::OutOfFuel) => Some(Err(task.vm.error(OpError::OutOfFuel, false))),
+            Err(e) => Some(Err(e)),
+        };
+        let steps = task.vm.usage().steps - before;
+        task.pass += steps * STRIDE / task.priority as u64;
+        let priority = task.priority;
+        if let Some(result) = result {
+            self.tasks.remove(&id);
+            let outcome = match &result {
+                Ok(val) => Outcome::Returned(val.clone()),
+                Err(_) => Outcome::Failed,
+            };
+            self.record_outcome(id, outcome);
+            self.finished.push((id, result));
+        }
+        self.take_requests(priority);
+        true
+    }
+
+    /// Acts on the timer, event and group requests made since the last
+    /// call, by a task of the given priority, and cancels the tasks whose
+    /// group went away or was cancelled.
+    fn take_requests(&mut self, priority: u8) {
+        self.take_timer_requests(priority);
+        self.take_event_requests(priority);
+        for request in group::take_requests() {
+            let id = self.spawn(request.func, priority);
+            self.grouped
+                .insert(id, (Rc::downgrade(&request.group), request.index));
+        }
+        let orphaned: Vec<TaskId> = self
+            .grouped
+            .iter()
+            .filter(|(_, (group, _))| group.upgrade().is_none_or(|g| g.is_cancelled()))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in orphaned {
+            self.cancel(id);
+        }
+    }
+
+    fn take_timer_requests(&mut self, priority: u8) {
+        for request in take_requests() {
+            match request {
+                Request::Start {
+                    id,
+                    delay,
+                    interval,
+                    func,
+                } => {
+                    let timer = Timer {
+                        func,
+                        interval,
+                        priority,
+                    };
+                    self.timers.insert(id, delay, timer);
+                }
+                Request::Cancel(id) => {
+                    self.timers.cancel(id);
+                }
+            }
+        }
+    }
+
+    fn take_event_requests(&mut self, priority: u8) {
+        for request in events::take_requests() {
+            match request {
+                events::Request::Subscribe { id, name, func } => {
+                    self.events.subscribe(id, name, func, priority)
+                }
+                events::Request::Unsubscribe(id) => {
+                    self.events.unsubscribe(id);
+                }
+            }
+        }
+    }
+
+    /// Queues an event for the handlers subscribed to `name`. It is
+    /// dispatched before the next slice runs.
+    pub fn emit(&mut self, name: &str, payload: Value) {
+        self.events.emit(name, payload);
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    fn dispatch_events(&mut self) {
+        for (func, payload, priority) in self.events.drain() {
+            self.spawn_call(func, vec![payload], priority);
+        }
+    }
+
+    /// Moves the timers' clock forward by `ms` milliseconds and spawns a task
+    /// for each callback that came due, returning how many did.
+    pub fn advance_time(&mut self, ms: u64) -> usize {
+        self.take_requests(1);
+        let fired = self.timers.advance(ms);
+        let count = fired.len();
+        for (_, timer) in fired {
+            self.spawn(timer.func, timer.priority);
+        }
+        count
+    }
+
+    pub fn timers(&self) -> &TimerWheel {
+        &self.timers
+    }
+
+    /// Runs slices until every task has finished or is parked. Tasks that
+    /// never finish keep this from returning; use `run_slice` to stay in
+    /// control.
+    pub fn run_until_idle(&mut self) {
+        while self.run_slice() {}
+    }
+
+    /// The results of the tasks that finished since the last call, in the
+    /// order they finished.
+    pub fn take_finished(&mut self) -> Vec<(TaskId, Result<Value, VmError>)> {
+        std::mem::take(&mut self.finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::natives::events::{off, on};
+    use crate::natives::group::{group_cancel, group_poll, group_spawn, task_group};
+    use crate::natives::timer::{after, cancel_timer, every};
+    use crate::testing::function;
+
+    #[test]
+    fn steps_are_shared_by_priority() {
+        let mut scheduler = Scheduler::new(10);
+        let busy = scheduler.spawn(function(vec![Jump(-1).into()]), 1);
+        let handler = scheduler.spawn(function(vec![Jump(-1).into()]), 3);
+        for _ in 0..400 {
+            assert!(scheduler.run_slice());
+        }
+        let steps = |id| scheduler.task(id).unwrap().usage().steps;
+        assert_eq!(steps(busy) + steps(handler), 4000);
+        assert_eq!(steps(handler), 3 * steps(busy));
+    }
+
+    #[test]
+    fn finished_tasks_report_results() {
+        let mut scheduler = Scheduler::new(2);
+        let spin = scheduler.spawn(function(vec![Jump(-1).into()]), 1);
+        let done = scheduler.spawn(
+            function(vec![
+                Push(Value::Integer(2)).into(),
+                Push(Value::Integer(3)).into(),
+                Mul.into(),
+                Return.into(),
+            ]),
+            1,
+        );
+        for _ in 0..4 {
+            scheduler.run_slice();
+        }
+        let finished = scheduler.take_finished();
+        assert!(matches!(&finished[..], [(id, Ok(Value::Integer(6)))] if *id == done));
+        assert!(scheduler.cancel(spin));
+        assert!(scheduler.is_empty() && !scheduler.run_slice());
+    }
+
+    #[test]
+    fn waiting_tasks_are_parked() {
+        use crate::natives::stream::stream_read;
+        use crate::stream::Stream;
+        use crate::suspend::suspend;
+
+        fn fetch(_args: Vec<Value>) -> Value {
+            suspend(7);
+            Value::None
+        }
+
+        let input = Stream::new(1);
+        // stream_read(input)
+        let reader = function(vec![
+            Push(input.to_value()).into(),
+            Push(Value::NativeFn(stream_read)).into(),
+            Call(1).into(),
+            Return.into(),
+        ]);
+        // fetch() + 1
+        let fetcher = function(vec![
+            Push(Value::NativeFn(fetch)).into(),
+            Call(0).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let mut scheduler = Scheduler::new(10);
+        let reader = scheduler.spawn(reader, 1);
+        let fetcher = scheduler.spawn(fetcher, 1);
+        let spin = scheduler.spawn(function(vec![Jump(-1).into()]), 1);
+        for _ in 0..3 {
+            assert!(scheduler.run_slice());
+        }
+        assert!(scheduler.is_parked(reader) && scheduler.is_parked(fetcher));
+        // only the spinning task gets slices while the others wait
+        let steps = |s: &Scheduler| s.task(reader).unwrap().usage().steps;
+        let before = steps(&scheduler);
+        for _ in 0..5 {
+            scheduler.run_slice();
+        }
+        assert_eq!(steps(&scheduler), before);
+        assert!(scheduler.cancel(spin));
+        assert!(!scheduler.run_slice());
+
+        assert!(input.push(Value::Integer(3)).is_ok());
+        let vm = scheduler.task_mut(fetcher).unwrap();
+        assert!(vm.resume(Value::Integer(41)));
+        scheduler.run_until_idle();
+        let mut finished = scheduler.take_finished();
+        finished.sort_by_key(|(id, _)| *id);
+        assert!(matches!(
+            &finished[..],
+            [(a, Ok(Value::Variant(v))), (b, Ok(Value::Integer(42)))]
+                if *a == reader && *b == fetcher && matches!(v.payload(), Value::Integer(3))
+        ));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn failed_tasks_keep_their_frames() {
+        let mut scheduler = Scheduler::new(10);
+        let fails = scheduler.spawn(
+            function(vec![
+                Push(Value::Str("x".into())).into(),
+                Neg.into(),
+                Return.into(),
+            ]),
+            1,
+        );
+        let spin = scheduler.spawn(function(vec![Jump(-1).into()]), 1);
+        scheduler.task(spin).unwrap().interrupt_handle().interrupt();
+        scheduler.run_slice();
+        scheduler.run_slice();
+        let finished = scheduler.take_finished();
+        let (failed, interrupted) = match &finished[..] {
+            [(a, Err(x)), (b, Err(y))] if *a == fails && *b == spin => (x, y),
+            _ => panic!("expected both tasks to fail"),
+        };
+        assert!(matches!(failed.error, OpError::BadType(_)));
+        assert!(matches!(&failed.frames[..], [frame] if frame.cursor == 1));
+        assert!(matches!(interrupted.error, OpError::Interrupted));
+    }
+
+    #[test]
+    fn timers_spawn_callbacks() {
+        let once = function(vec![Push(Value::Integer(7)).into(), Return.into()]);
+        let tick = function(vec![Push(Value::Integer(1)).into(), Return.into()]);
+        // after(10, once); every(4, tick)
+        let main = function(vec![
+            Push(Value::Integer(10)).into(),
+            Push(once.into()).into(),
+            Push(Value::NativeFn(after)).into(),
+            Call(2).into(),
+            Pop.into(),
+            Push(Value::Integer(4)).into(),
+            Push(tick.into()).into(),
+            Push(Value::NativeFn(every)).into(),
+            Call(2).into(),
+            Return.into(),
+        ]);
+        let mut scheduler = Scheduler::new(100);
+        scheduler.spawn(main, 2);
+        scheduler.run_until_idle();
+        let ticker = match scheduler.take_finished().pop() {
+            Some((_, Ok(Value::Integer(id)))) => id,
+            _ => panic!("expected a timer id"),
+        };
+        assert_eq!(scheduler.timers().len(), 2);
+
+        assert_eq!(scheduler.advance_time(9), 2);
+        assert_eq!(scheduler.advance_time(1), 1);
+        scheduler.run_until_idle();
+        let results: Vec<_> = scheduler
+            .take_finished()
+            .into_iter()
+            .map(|(_, r)| matches!(r, Ok(Value::Integer(7))))
+            .collect();
+        assert_eq!(results, [false, false, true]);
+
+        cancel_timer(vec![Value::Integer(ticker)]);
+        assert_eq!(scheduler.advance_time(100), 0);
+        assert!(scheduler.timers().is_empty());
+    }
+
+    #[test]
+    fn events_reach_handlers() {
+        // handler(x) = x * 2
+        let handler = function(vec![
+            Push(Value::Integer(2)).into(),
+            Mul.into(),
+            Return.into(),
+        ]);
+        // on("hit", handler)
+        let main = function(vec![
+            Push(Value::Str("hit".into())).into(),
+            Push(handler.into()).into(),
+            Push(Value::NativeFn(on)).into(),
+            Call(2).into(),
+            Return.into(),
+        ]);
+        let mut scheduler = Scheduler::new(100);
+        scheduler.spawn(main, 1);
+        scheduler.run_until_idle();
+        let id = match scheduler.take_finished().pop() {
+            Some((_, Ok(Value::Integer(id)))) => id,
+            _ => panic!("expected a subscription id"),
+        };
+        assert_eq!(scheduler.events().handlers("hit"), 1);
+
+        scheduler.emit("hit", Value::Integer(21));
+        scheduler.emit("miss", Value::Integer(0));
+        scheduler.run_until_idle();
+        let finished = scheduler.take_finished();
+        assert!(matches!(&finished[..], [(_, Ok(Value::Integer(42)))]));
+
+        off(vec![Value::Integer(id)]);
+        scheduler.emit("hit", Value::Integer(1));
+        scheduler.run_until_idle();
+        assert!(scheduler.take_finished().is_empty());
+    }
+
+    #[test]
+    fn groups_own_their_tasks() {
+        let five = function(vec![Push(Value::Integer(5)).into(), Return.into()]);
+        let spin = function(vec![Jump(-1).into()]);
+        let mut scheduler = Scheduler::new(10);
+        let group = task_group(Vec::new());
+        // natives get their arguments last-first
+        group_spawn(vec![five.into(), group.clone()]);
+        group_spawn(vec![spin.clone().into(), group.clone()]);
+        for _ in 0..4 {
+            scheduler.run_slice();
+        }
+        assert_eq!(scheduler.len(), 1);
+        let poll = |group: &Value| match group_poll(vec![group.clone()]) {
+            Value::Variant(v) => v.payload().clone(),
+            _ => panic!("expected an Option"),
+        };
+        assert!(matches!(poll(&group), Value::None));
+
+        group_cancel(vec![group.clone()]);
+        scheduler.run_slice();
+        assert!(scheduler.is_empty());
+        let results = match poll(&group) {
+            Value::List(l) => l.to_vec(),
+            _ => panic!("expected results"),
+        };
+        let tags: Vec<_> = results
+            .iter()
+            .map(|r| match r {
+                Value::Variant(v) => v.tag(),
+                _ => panic!("expected a Result"),
+            })
+            .collect();
+        assert_eq!(tags, [crate::datamodel::OK, crate::datamodel::ERR]);
+
+        // dropping a group cancels what it still owns
+        let dropped = task_group(Vec::new());
+        group_spawn(vec![spin.into(), dropped.clone()]);
+        scheduler.run_slice();
+        assert_eq!(scheduler.len(), 1);
+        drop(dropped);
+        scheduler.run_slice();
+        assert!(scheduler.is_empty());
+    }
+}
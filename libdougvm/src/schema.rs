@@ -0,0 +1,256 @@
+//! Schemas describing the shape of plain data, and a validator that checks
+//! a value against one and reports where it first differs, e.g.
+//! `$.items[2].name: expected Str, found Integer`. Natives and RPC exports
+//! (see `rpc::Exports::set_schema`) use them to reject malformed script
+//! data at the boundary rather than deep inside.
+//!
+//! Schemas are built in Rust, or from script data with `Schema::from_value`:
+//!
+//! - a `Str` names a kind (`"Integer"`, `"Str"`, ...), or is `"Any"` or
+//!   `"Number"`. A trailing `?` also allows `None`, and lets a table field
+//!   be missing;
+//! - a `List` of one schema matches lists whose items all match it, and a
+//!   `List` of several matches a value matching any of them;
+//! - a `Tuple` of schemas matches tuples item by item;
+//! - a `Table` of schemas matches tables with those fields. Other fields
+//!   are allowed. A table only keeps its fields' keys, so errors name them
+//!   as `#` and the key in hex; build the schema in Rust for real names.
+
+use std::fmt;
+
+use crate::datamodel::{field_key, Tag, Value, ValueType};
+
+pub enum Schema {
+    Any,
+    Type(ValueType),
+    /// `Integer`, `Real` or `Decimal`.
+    Number,
+    /// `None`, or whatever the inner schema matches.
+    Optional(Box<Schema>),
+    OneOf(Vec<Schema>),
+    List(Box<Schema>),
+    Tuple(Vec<Schema>),
+    /// Named fields; a missing field is only allowed if its schema is
+    /// `Optional`. Unless `closed`, other fields are allowed.
+    Table {
+        fields: Vec<Field>,
+        closed: bool,
+    },
+    /// A `Variant` with one of the listed tags, its payload matching that
+    /// tag's schema.
+    Variant(Vec<(Tag, Schema)>),
+}
+
+pub struct Field {
+    pub key: u64,
+    /// How error paths refer to the field.
+    pub name: String,
+    pub schema: Schema,
+}
+
+impl Field {
+    pub fn new(name: &str, schema: Schema) -> Field {
+        Field {
+            key: field_key(name),
+            name: name.to_string(),
+            schema,
+        }
+    }
+}
+
+/// Where in the value, and how, it failed to match.
+pub struct SchemaError {
+    /// From the root `$`, e.g. `$.items[2]`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schema::Any => write!(f, "Any"),
+            Schema::Type(t) => write!(f, "{}", t.as_str()),
+            Schema::Number => write!(f, "Number"),
+            Schema::Optional(inner) => write!(f, "{}?", inner),
+            Schema::OneOf(options) => {
+                let options: Vec<_> = options.iter().map(|s| s.to_string()).collect();
+                write!(f, "one of {}", options.join(", "))
+            }
+            Schema::List(_) => write!(f, "List"),
+            Schema::Tuple(_) => write!(f, "Tuple"),
+            Schema::Table { .. } => write!(f, "Table"),
+            Schema::Variant(_) => write!(f, "Variant"),
+        }
+    }
+}
+
+fn items(val: &Value) -> Option<Vec<Value>> {
+    match val {
+        Value::List(l) => Some(l.to_vec()),
+        Value::Tuple(t) => Some((0..t.len()).filter_map(|i| t.get(i)).collect()),
+        _ => None,
+    }
+}
+
+impl Schema {
+    pub fn validate(&self, val: &Value) -> Result<(), SchemaError> {
+        let mut path = String::from("$");
+        self.check(val, &mut path)
+    }
+
+    fn check(&self, val: &Value, path: &mut String) -> Result<(), SchemaError> {
+        let mismatch = |path: &str| SchemaError {
+            path: path.to_string(),
+            message: format!("expected {}, found {}", self, val.get_type().as_str()),
+        };
+        match (self, val) {
+            (Schema::Any, _) => Ok(()),
+            (Schema::Type(t), val) if val.get_type() == *t => Ok(()),
+            (Schema::Number, Value::Integer(_) | Value::Real(_) | Value::Decimal(_)) => Ok(()),
+            (Schema::Optional(_), Value::None) => Ok(()),
+            (Schema::Optional(inner), val) => inner.check(val, path),
+            (Schema::OneOf(options), val) => {
+                let mut scratch = path.clone();
+                match options.iter().any(|s| s.check(val, &mut scratch).is_ok()) {
+                    true => Ok(()),
+                    false => Err(mismatch(path)),
+                }
+            }
+            (Schema::List(item), Value::List(l)) => {
+                for (i, val) in l.to_vec().iter().enumerate() {
+                    nested(path, &format!("[{}]", i), |path| item.check(val, path))?;
+                }
+                Ok(())
+            }
+            (Schema::Tuple(schemas), Value::Tuple(t)) => {
+                if t.len() != schemas.len() {
+                    return Err(SchemaError {
+                        path: path.clone(),
+                        message: format!("expected {} items, found {}", schemas.len(), t.len()),
+                    });
+                }
+                let vals = items(val).unwrap_or_default();
+                for (i, (schema, val)) in schemas.iter().zip(&vals).enumerate() {
+                    nested(path, &format!("[{}]", i), |path| schema.check(val, path))?;
+                }
+                Ok(())
+            }
+            (Schema::Table { fields, closed }, Value::Table(t)) => {
+                for field in fields {
+                    let segment = format!(".{}", field.name);
+                    match t.get(field.key) {
+                        Some(val) => nested(path, &segment, |path| field.schema.check(&val, path))?,
+                        None if matches!(field.schema, Schema::Optional(_)) => {}
+                        None => {
+                            return Err(SchemaError {
+                                path: format!("{}{}", path, segment),
+                                message: "missing".to_string(),
+                            })
+                        }
+                    }
+                }
+                if *closed && t.len() > fields.len() {
+                    let known: Vec<u64> = fields.iter().map(|f| f.key).collect();
+                    if t.entries().iter().any(|(k, _)| !known.contains(k)) {
+                        return Err(SchemaError {
+                            path: path.clone(),
+                            message: "unexpected field".to_string(),
+                        });
+                    }
+                }
+                Ok(())
+            }
+            (Schema::Variant(tags), Value::Variant(v)) => {
+                match tags.iter().find(|(tag, _)| *tag == v.tag()) {
+                    Some((tag, schema)) => nested(path, &format!("<{}>", tag), |path| {
+                        schema.check(v.payload(), path)
+                    }),
+                    None => Err(SchemaError {
+                        path: path.clone(),
+                        message: format!("unexpected tag {}", v.tag()),
+                    }),
+                }
+            }
+            _ => Err(mismatch(path)),
+        }
+    }
+
+    /// Reads a schema written as script data (see the module docs).
+    pub fn from_value(val: &Value) -> Result<Schema, String> {
+        Ok(match val {
+            Value::Str(s) => {
+                let (name, optional) = match s.strip_suffix('?') {
+                    Some(name) => (name, true),
+                    None => (&**s, false),
+                };
+                let schema = match name {
+                    "Any" => Schema::Any,
+                    "Number" => Schema::Number,
+                    name => match ValueType::from_name(name) {
+                        Some(t) => Schema::Type(t),
+                        None => return Err(format!("unknown type {}", name)),
+                    },
+                };
+                match optional {
+                    true => Schema::Optional(Box::new(schema)),
+                    false => schema,
+                }
+            }
+            Value::List(l) => {
+                let mut options = l
+                    .to_vec()
+                    .iter()
+                    .map(Schema::from_value)
+                    .collect::<Result<Vec<_>, _>>()?;
+                match options.len() {
+                    0 => Schema::List(Box::new(Schema::Any)),
+                    1 => Schema::List(Box::new(options.pop().unwrap())),
+                    _ => Schema::OneOf(options),
+                }
+            }
+            Value::Tuple(_) => {
+                let schemas = items(val).unwrap_or_default();
+                Schema::Tuple(
+                    schemas
+                        .iter()
+                        .map(Schema::from_value)
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            Value::Table(t) => {
+                let mut fields = Vec::new();
+                for (key, val) in t.entries() {
+                    fields.push(Field {
+                        key,
+                        name: format!("#{:016x}", key),
+                        schema: Schema::from_value(&val)?,
+                    });
+                }
+                Schema::Table {
+                    fields,
+                    closed: false,
+                }
+            }
+            other => return Err(format!("a {} isn't a schema", other.get_type().as_str())),
+        })
+    }
+}
+
+/// Runs `check` with `segment` appended to `path`.
+fn nested(
+    path: &mut String,
+    segment: &str,
+    check: impl FnOnce(&mut String) -> Result<(), SchemaError>,
+) -> Result<(), SchemaError> {
+    let len = path.len();
+    path.push_str(segment);
+    let result = check(path);
+    path.truncate(len);
+    result
+}
@@ -0,0 +1,155 @@
+//! Source maps, so errors point at source code rather than op indices.
+//!
+//! A compiler that keeps debug metadata registers a `FunctionMap` for each
+//! function it emits, giving the span each op came from, with the VM's
+//! `SourceMaps` (see `VirtualMachine::source_maps`). Code that was itself
+//! generated, by a macro, a template or `eval`, has spans in the generated
+//! text; registering a `TextMap` for that text maps them on to the text it
+//! was generated from, which may be generated too. `resolve` follows the
+//! chain back to the end, so a `VmError`'s frames (see `FrameInfo::span`)
+//! point at the code the user wrote.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::datamodel::{Function, Identity, Str};
+
+/// Longest chain of generated texts `SourceMaps::resolve` follows, which
+/// also stops texts that map onto each other.
+pub const MAX_MAP_CHAIN: usize = 32;
+
+/// A position in a source text; lines and columns count from 1.
+#[derive(Clone, PartialEq)]
+pub struct Span {
+    pub file: Str,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// The spans a function's ops came from: `(op, span)` entries, each
+/// covering the ops from its own up to the next entry's.
+pub struct FunctionMap {
+    entries: Vec<(usize, Span)>,
+}
+
+impl FunctionMap {
+    pub fn new(mut entries: Vec<(usize, Span)>) -> FunctionMap {
+        entries.sort_by_key(|(op, _)| *op);
+        FunctionMap { entries }
+    }
+
+    pub fn span_at(&self, op: usize) -> Option<&Span> {
+        let covering = self.entries.partition_point(|(start, _)| *start <= op);
+        self.entries[..covering].last().map(|(_, span)| span)
+    }
+}
+
+/// Where a generated text came from: `(line, column, span)` entries, each
+/// covering the text from its position up to the next entry's, and
+/// standing for `span` in the text it was generated from.
+pub struct TextMap {
+    entries: Vec<(u32, u32, Span)>,
+}
+
+impl TextMap {
+    pub fn new(mut entries: Vec<(u32, u32, Span)>) -> TextMap {
+        entries.sort_by_key(|(line, column, _)| (*line, *column));
+        TextMap { entries }
+    }
+
+    /// The span the generated text at `line`, `column` stands for.
+    pub fn origin(&self, line: u32, column: u32) -> Option<&Span> {
+        let covering = self
+            .entries
+            .partition_point(|(l, c, _)| (*l, *c) <= (line, column));
+        self.entries[..covering].last().map(|(_, _, span)| span)
+    }
+}
+
+/// The source maps a VM knows, by function and by generated text.
+#[derive(Default)]
+pub struct SourceMaps {
+    /// Holding each function so its identity isn't reused.
+    functions: HashMap<usize, (Function, FunctionMap)>,
+    texts: HashMap<Str, TextMap>,
+}
+
+impl SourceMaps {
+    pub fn set_function(&mut self, func: &Function, map: FunctionMap) {
+        self.functions.insert(func.identity(), (func.clone(), map));
+    }
+
+    /// Marks `file` as generated, with `map` saying what from.
+    pub fn set_text(&mut self, file: Str, map: TextMap) {
+        self.texts.insert(file, map);
+    }
+
+    /// Where `span` came from: itself, unless it is in a generated text,
+    /// in which case the span that text maps it to, resolved in turn.
+    pub fn resolve(&self, span: &Span) -> Span {
+        let mut span = span;
+        for _ in 0..MAX_MAP_CHAIN {
+            let map = self.texts.get(&span.file);
+            match map.and_then(|m| m.origin(span.line, span.column)) {
+                Some(origin) => span = origin,
+                None => break,
+            }
+        }
+        span.clone()
+    }
+
+    /// The original span op `at` of `func` came from, if `func` has a map.
+    pub fn locate(&self, func: &Function, at: usize) -> Option<Span> {
+        let (_, map) = self.functions.get(&func.identity())?;
+        map.span_at(at).map(|span| self.resolve(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(file: &str, line: u32, column: u32) -> Span {
+        Span {
+            file: file.into(),
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn generated_spans_resolve_to_the_original() {
+        let mut maps = SourceMaps::default();
+        // lines 1-9 of the expansion came from line 2 of a template, the
+        // rest from its line 7, and the template from the user's line 3
+        maps.set_text(
+            "expanded".into(),
+            TextMap::new(vec![
+                (10, 1, span("template", 7, 5)),
+                (1, 1, span("template", 2, 1)),
+            ]),
+        );
+        maps.set_text(
+            "template".into(),
+            TextMap::new(vec![(1, 1, span("main.dg", 3, 9))]),
+        );
+        assert!(maps.resolve(&span("expanded", 12, 4)) == span("main.dg", 3, 9));
+        assert!(maps.resolve(&span("main.dg", 1, 1)) == span("main.dg", 1, 1));
+
+        // texts that map onto each other give up instead of looping
+        maps.set_text("a".into(), TextMap::new(vec![(1, 1, span("b", 1, 1))]));
+        maps.set_text("b".into(), TextMap::new(vec![(1, 1, span("a", 1, 1))]));
+        let looped = maps.resolve(&span("a", 1, 1));
+        assert!(looped.file.as_ref() == "a" || looped.file.as_ref() == "b");
+
+        let map = FunctionMap::new(vec![(3, span("x", 2, 1)), (0, span("x", 1, 1))]);
+        assert!(map.span_at(2) == Some(&span("x", 1, 1)));
+        assert!(map.span_at(9) == Some(&span("x", 2, 1)));
+    }
+}
@@ -0,0 +1,438 @@
+//! The standard library: a core set of host functions, so every embedder
+//! doesn't write their own. `install` registers them all in a
+//! `NativeRegistry`, for scripts to call with `CallHost`:
+//!
+//! - strings: `split(s, sep)`, `join(strs, sep)` and `format(template,
+//!   args...)`, where each `{}` in the template is the next argument and
+//!   `{{` and `}}` are literal braces
+//! - math: `abs`, `min` and `max` (of any number of arguments), `floor`,
+//!   `ceil`, `round`, `sqrt` and `pow`
+//! - lists: `sort(list)`, ordering as `Cmp` does, and `filter(list, f)`
+//!   and `map(list, f)`, calling `f` with each item (see `VmContext::call`)
+//! - introspection: `type_of(v)`, the name of its type, and `len(v)`
+//!
+//! Each returns a new value rather than changing its arguments. Arguments
+//! of the wrong kind throw a `NativeError` saying what was wanted.
+
+use std::cmp::Ordering;
+
+use crate::bytecode::ops::order;
+use crate::bytecode::OpError;
+use crate::datamodel::{List, NativeRegistry, Str, Value};
+use crate::exception::native_error;
+use crate::natives::str_arg;
+use crate::VmContext;
+
+type Builtin = fn(&mut VmContext, &[Value]) -> Result<Value, OpError>;
+
+const BUILTINS: &[(&str, Builtin)] = &[
+    ("split", split),
+    ("join", join),
+    ("format", format),
+    ("abs", abs),
+    ("min", min),
+    ("max", max),
+    ("floor", floor),
+    ("ceil", ceil),
+    ("round", round),
+    ("sqrt", sqrt),
+    ("pow", pow),
+    ("sort", sort),
+    ("filter", filter),
+    ("map", map),
+    ("type_of", type_of),
+    ("len", len),
+];
+
+/// Registers every standard library function in `registry`, replacing any
+/// it already has by the same name.
+pub fn install(registry: &mut NativeRegistry) {
+    for &(name, f) in BUILTINS {
+        registry.register(name, f);
+    }
+}
+
+fn wants(name: &str, wanted: &str) -> OpError {
+    OpError::Uncaught(native_error(&format!("{} wants {}", name, wanted)))
+}
+
+fn list_arg(args: &[Value], index: usize) -> Option<Vec<Value>> {
+    match args.get(index) {
+        Some(Value::List(l)) => Some(l.to_vec()),
+        _ => None,
+    }
+}
+
+fn split(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    match (str_arg(args, 0), str_arg(args, 1)) {
+        (Some(s), Some(sep)) if !sep.is_empty() => {
+            let parts = s.split(sep.as_ref()).map(|p| Value::Str(p.into()));
+            Ok(List::new(parts.collect()).into())
+        }
+        _ => Err(wants("split", "a Str and a non-empty separator")),
+    }
+}
+
+fn join(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let bad = || wants("join", "a List of Strs and a separator");
+    let (items, sep) = match (list_arg(args, 0), str_arg(args, 1)) {
+        (Some(items), Some(sep)) => (items, sep),
+        _ => return Err(bad()),
+    };
+    let mut parts = Vec::with_capacity(items.len());
+    for item in &items {
+        match item {
+            Value::Str(s) => parts.push(s.as_ref()),
+            _ => return Err(bad()),
+        }
+    }
+    Ok(Value::Str(parts.join(sep.as_ref()).as_str().into()))
+}
+
+/// How `format` shows a value: strings as they are, numbers as written,
+/// anything else by its type.
+fn show(val: &Value) -> String {
+    match val {
+        Value::Str(s) => s.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        Value::Decimal(d) => d.to_string(),
+        other => format!("<{}>", other.get_type().as_str()),
+    }
+}
+
+fn format(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let template = str_arg(args, 0).ok_or_else(|| wants("format", "a template Str"))?;
+    let mut fill = args[1..].iter();
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                out.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                let val = fill
+                    .next()
+                    .ok_or_else(|| wants("format", "an argument for each {}"))?;
+                out.push_str(&show(val));
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(Value::Str(out.as_str().into()))
+}
+
+fn real_arg(args: &[Value], index: usize) -> Option<f64> {
+    match args.get(index) {
+        Some(Value::Integer(i)) => Some(*i as f64),
+        Some(Value::Real(r)) => Some(*r),
+        _ => None,
+    }
+}
+
+fn abs(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    match args.first() {
+        Some(Value::Integer(i)) => i.checked_abs().map(Value::Integer).ok_or(OpError::Overflow),
+        Some(Value::Real(r)) => Ok(Value::Real(r.abs())),
+        _ => Err(wants("abs", "a number")),
+    }
+}
+
+/// The argument `pick` prefers, comparing as `Cmp` does.
+fn extreme(name: &str, args: &[Value], pick: Ordering) -> Result<Value, OpError> {
+    let (first, rest) = args
+        .split_first()
+        .ok_or_else(|| wants(name, "an argument"))?;
+    let mut best = first;
+    for val in rest {
+        if order(val, best)? == pick {
+            best = val;
+        }
+    }
+    Ok(best.clone())
+}
+
+fn min(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    extreme("min", args, Ordering::Less)
+}
+
+fn max(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    extreme("max", args, Ordering::Greater)
+}
+
+/// Integers are already whole, so `floor`, `ceil` and `round` give them
+/// back; reals stay reals.
+fn whole(name: &str, args: &[Value], f: fn(f64) -> f64) -> Result<Value, OpError> {
+    match args.first() {
+        Some(Value::Integer(i)) => Ok(Value::Integer(*i)),
+        Some(Value::Real(r)) => Ok(Value::Real(f(*r))),
+        _ => Err(wants(name, "a number")),
+    }
+}
+
+fn floor(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    whole("floor", args, f64::floor)
+}
+
+fn ceil(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    whole("ceil", args, f64::ceil)
+}
+
+fn round(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    whole("round", args, f64::round)
+}
+
+fn sqrt(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let x = real_arg(args, 0).ok_or_else(|| wants("sqrt", "a number"))?;
+    Ok(Value::Real(x.sqrt()))
+}
+
+/// An integer to a non-negative integer power is an integer; anything else
+/// is a real.
+fn pow(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    if let (Some(Value::Integer(base)), Some(Value::Integer(exp))) = (args.first(), args.get(1)) {
+        if let Ok(exp) = u32::try_from(*exp) {
+            return base
+                .checked_pow(exp)
+                .map(Value::Integer)
+                .ok_or(OpError::Overflow);
+        }
+    }
+    match (real_arg(args, 0), real_arg(args, 1)) {
+        (Some(base), Some(exp)) => Ok(Value::Real(base.powf(exp))),
+        _ => Err(wants("pow", "two numbers")),
+    }
+}
+
+fn sort(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let mut items = list_arg(args, 0).ok_or_else(|| wants("sort", "a List"))?;
+    let mut error = None;
+    items.sort_by(|a, b| {
+        order(a, b).unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            Ordering::Equal
+        })
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(List::new(items).into()),
+    }
+}
+
+fn filter(ctx: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let bad = || wants("filter", "a List and a function");
+    let items = list_arg(args, 0).ok_or_else(bad)?;
+    let keep = args.get(1).ok_or_else(bad)?;
+    let mut kept = Vec::new();
+    for item in items {
+        if ctx.call(keep, vec![item.clone()])?.is_truthy() {
+            kept.push(item);
+        }
+    }
+    Ok(List::new(kept).into())
+}
+
+fn map(ctx: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let bad = || wants("map", "a List and a function");
+    let items = list_arg(args, 0).ok_or_else(bad)?;
+    let func = args.get(1).ok_or_else(bad)?;
+    let mut mapped = Vec::with_capacity(items.len());
+    for item in items {
+        mapped.push(ctx.call(func, vec![item])?);
+    }
+    Ok(List::new(mapped).into())
+}
+
+fn type_of(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let val = args.first().ok_or_else(|| wants("type_of", "a value"))?;
+    Ok(Value::Str(Str::from(val.get_type().as_str())))
+}
+
+/// The length of a string in bytes, as the string natives count offsets,
+/// or the number of items in a container.
+fn len(_: &mut VmContext, args: &[Value]) -> Result<Value, OpError> {
+    let n = match args.first() {
+        Some(Value::Str(s)) => s.len(),
+        Some(Value::List(l)) => l.len(),
+        Some(Value::Tuple(t)) => t.len(),
+        Some(Value::Map(m)) => m.len(),
+        Some(Value::Table(t)) => t.len(),
+        Some(Value::Buffer(b)) => b.len(),
+        _ => return Err(wants("len", "a Str or a container")),
+    };
+    Ok(Value::Integer(n as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::*;
+    use crate::testing::function;
+    use crate::VirtualMachine;
+    use std::rc::Rc;
+
+    /// Calls the builtin `name` with `args` from a script.
+    fn call(name: &str, args: Vec<Value>) -> Result<Value, OpError> {
+        call_with(name, args, |_| {})
+    }
+
+    /// `call`, in a VM `configure` sets up first.
+    fn call_with(
+        name: &str,
+        args: Vec<Value>,
+        configure: impl FnOnce(&mut VirtualMachine),
+    ) -> Result<Value, OpError> {
+        let mut registry = NativeRegistry::new();
+        install(&mut registry);
+        let mut ops: Vec<crate::bytecode::Op> = Vec::new();
+        let argc = args.len() as u8;
+        for arg in args {
+            ops.push(Push(arg).into());
+        }
+        let index = registry.index_of(name).unwrap();
+        ops.push(CallHost { index, argc }.into());
+        ops.push(Return.into());
+        let mut vm = VirtualMachine::new(function(ops));
+        vm.set_registry(Rc::new(registry));
+        configure(&mut vm);
+        vm.run_until_exited().map_err(|e| e.error)
+    }
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    fn strs(val: Value) -> Vec<String> {
+        match val {
+            Value::List(l) => l.to_vec().iter().map(show).collect(),
+            _ => panic!("expected a List"),
+        }
+    }
+
+    #[test]
+    fn strings_split_join_and_format() {
+        let parts = call("split", vec![str("a,b,,c"), str(",")]).ok().unwrap();
+        assert_eq!(strs(parts.clone()), ["a", "b", "", "c"]);
+        let joined = call("join", vec![parts, str("-")]).ok().unwrap();
+        assert_eq!(show(&joined), "a-b--c");
+
+        let args = vec![
+            str("{} + {} = {{{}}}"),
+            Value::Integer(1),
+            Value::Real(0.5),
+            str("x"),
+        ];
+        assert_eq!(show(&call("format", args).ok().unwrap()), "1 + 0.5 = {x}");
+        assert!(matches!(
+            call("format", vec![str("{}")]),
+            Err(OpError::Uncaught(_))
+        ));
+    }
+
+    #[test]
+    fn math_keeps_integers_whole() {
+        let int = |name, args| match call(name, args) {
+            Ok(Value::Integer(i)) => i,
+            _ => panic!("expected an Integer from {}", name),
+        };
+        assert_eq!(int("abs", vec![Value::Integer(-3)]), 3);
+        assert_eq!(
+            int("pow", vec![Value::Integer(2), Value::Integer(10)]),
+            1024
+        );
+        assert_eq!(int("floor", vec![Value::Integer(7)]), 7);
+        let args = vec![Value::Integer(4), Value::Integer(-2), Value::Integer(9)];
+        assert_eq!(int("min", args.clone()), -2);
+        assert_eq!(int("max", args), 9);
+        assert!(matches!(
+            call("pow", vec![Value::Integer(2), Value::Integer(-1)]),
+            Ok(Value::Real(r)) if r == 0.5
+        ));
+        assert!(matches!(
+            call("abs", vec![Value::Integer(i64::MIN)]),
+            Err(OpError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn lists_sort_filter_and_map() {
+        let ints = List::new(vec![
+            Value::Integer(3),
+            Value::Integer(1),
+            Value::Integer(2),
+        ]);
+        let sorted = call("sort", vec![ints.clone().into()]).ok().unwrap();
+        assert_eq!(strs(sorted), ["1", "2", "3"]);
+        assert_eq!(strs(ints.clone().into()), ["3", "1", "2"]);
+        let mixed = List::new(vec![Value::Integer(1), str("a")]);
+        assert!(matches!(
+            call("sort", vec![mixed.into()]),
+            Err(OpError::BadType(_))
+        ));
+
+        // keeps the odd items, then doubles them, with script callbacks
+        let odd = function(vec![
+            Push(Value::Integer(2)).into(),
+            Rem.into(),
+            Return.into(),
+        ]);
+        let double = function(vec![
+            Store(1).into(),
+            Load(1).into(),
+            Load(1).into(),
+            Add.into(),
+            Return.into(),
+        ]);
+        let odds = call("filter", vec![ints.into(), odd.into()]).ok().unwrap();
+        assert_eq!(strs(odds.clone()), ["3", "1"]);
+        assert_eq!(
+            strs(call("map", vec![odds, double.into()]).ok().unwrap()),
+            ["6", "2"]
+        );
+        let list = || List::new(vec![Value::Integer(1)]).into();
+        assert!(matches!(
+            call("filter", vec![list()]),
+            Err(OpError::Uncaught(_))
+        ));
+        assert!(matches!(
+            call("map", vec![list()]),
+            Err(OpError::Uncaught(_))
+        ));
+    }
+
+    #[test]
+    fn callbacks_run_under_the_callers_limits() {
+        let spin = || function(vec![Jump(-1).into()]);
+        let args = || vec![List::new(vec![Value::Integer(1)]).into(), spin().into()];
+        let fueled = call_with("map", args(), |vm| vm.set_fuel(Some(1000)));
+        assert!(matches!(fueled, Err(OpError::OutOfFuel)));
+
+        let interrupted = call_with("filter", args(), |vm| {
+            let handle = vm.interrupt_handle();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                handle.interrupt();
+            });
+        });
+        assert!(matches!(interrupted, Err(OpError::Interrupted)));
+    }
+
+    #[test]
+    fn values_know_their_type_and_length() {
+        assert_eq!(
+            show(&call("type_of", vec![Value::Real(1.0)]).ok().unwrap()),
+            "Real"
+        );
+        assert!(matches!(
+            call("len", vec![str("héllo")]),
+            Ok(Value::Integer(6))
+        ));
+        assert!(matches!(
+            call("len", vec![Value::Integer(1)]),
+            Err(OpError::Uncaught(_))
+        ));
+    }
+}
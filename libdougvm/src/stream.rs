@@ -0,0 +1,107 @@
+//! Bounded streams between a script and its host. A script writing to a
+//! full stream, or reading from an empty one, doesn't fail: the VM stops
+//! with `VmState::WaitingForSink` or `WaitingForSource` and retries the
+//! call when it is next run, once the host has drained or filled the
+//! stream. So a script producing a lot of output holds at most
+//! `capacity` values in memory at a time.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::datamodel::Value;
+
+pub struct Stream {
+    buffer: RefCell<VecDeque<Value>>,
+    capacity: usize,
+    closed: Cell<bool>,
+}
+
+impl Stream {
+    /// A stream holding up to `capacity` values (at least one).
+    pub fn new(capacity: usize) -> Rc<Stream> {
+        Rc::new(Stream {
+            buffer: RefCell::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            closed: Cell::new(false),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.borrow().is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Adds `val` at the back, or hands it back if the stream is full or
+    /// closed.
+    pub fn push(&self, val: Value) -> Result<(), Value> {
+        if self.is_full() || self.is_closed() {
+            return Err(val);
+        }
+        self.buffer.borrow_mut().push_back(val);
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<Value> {
+        self.buffer.borrow_mut().pop_front()
+    }
+
+    /// Takes everything buffered so far.
+    pub fn drain(&self) -> Vec<Value> {
+        self.buffer.borrow_mut().drain(..).collect()
+    }
+
+    /// Stops further writes. Values already buffered can still be read;
+    /// after that, reads return `None` instead of waiting.
+    pub fn close(&self) {
+        self.closed.set(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+
+    pub fn to_value(self: &Rc<Stream>) -> Value {
+        Value::Unknown(self.clone())
+    }
+
+    pub fn from_value(val: &Value) -> Option<Rc<Stream>> {
+        match val {
+            Value::Unknown(u) => u.clone().downcast::<Stream>().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// What a blocked native is waiting for.
+pub enum Wait {
+    /// The host has to take values out of the stream.
+    Sink(Rc<Stream>),
+    /// The host has to put values into the stream, or close it.
+    Source(Rc<Stream>),
+}
+
+thread_local! {
+    static BLOCKED: RefCell<Option<(Wait, Vec<Value>)>> = const { RefCell::new(None) };
+}
+
+/// Called by a native that can't go on yet: the VM discards what it
+/// returns and calls it again with `args` when it is resumed.
+pub fn block(wait: Wait, args: Vec<Value>) {
+    BLOCKED.with(|b| *b.borrow_mut() = Some((wait, args)));
+}
+
+pub(crate) fn take_blocked() -> Option<(Wait, Vec<Value>)> {
+    BLOCKED.with(|b| b.borrow_mut().take())
+}
@@ -0,0 +1,61 @@
+//! Natives that finish asynchronously. A native that has started some
+//! work (a network request, say) and can't produce its result yet calls
+//! `suspend` with a token naming that work and returns anything; the VM
+//! discards what it returned and stops with `VmState::Suspended(token)`.
+//! When the work completes, the host hands the result to
+//! `VirtualMachine::resume`, where the script sees it as the native's
+//! return value, and runs the VM again. The script's state stays in the
+//! VM in between, so no thread is held waiting.
+
+use std::cell::Cell;
+
+/// Names a suspended call to the host; its meaning is up to the native.
+pub type Token = u64;
+
+thread_local! {
+    static SUSPENDED: Cell<Option<Token>> = const { Cell::new(None) };
+}
+
+/// Called by a native whose result will come later, through `resume`.
+pub fn suspend(token: Token) {
+    SUSPENDED.with(|s| s.set(Some(token)));
+}
+
+pub(crate) fn take_suspended() -> Option<Token> {
+    SUSPENDED.with(|s| s.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Add, Call, Push, Return};
+    use crate::datamodel::Value;
+    use crate::testing::function;
+    use crate::{VirtualMachine, VmState};
+
+    fn fetch(_args: Vec<Value>) -> Value {
+        suspend(7);
+        Value::None
+    }
+
+    #[test]
+    fn natives_suspend_until_resumed() {
+        // fetch() + 1
+        let mut vm = VirtualMachine::new(function(vec![
+            Push(Value::NativeFn(fetch)).into(),
+            Call(0).into(),
+            Push(Value::Integer(1)).into(),
+            Add.into(),
+            Return.into(),
+        ]));
+        assert!(matches!(vm.run_until_blocked(), Ok(VmState::Suspended(7))));
+        // running again without a result stays put
+        assert!(matches!(vm.run_until_blocked(), Ok(VmState::Suspended(7))));
+        assert!(vm.resume(Value::Integer(41)));
+        assert!(!vm.resume(Value::None));
+        assert!(matches!(
+            vm.run_until_blocked(),
+            Ok(VmState::Exited(Value::Integer(42)))
+        ));
+    }
+}
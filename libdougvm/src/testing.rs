@@ -0,0 +1,12 @@
+//! Fixtures shared by the unit tests.
+
+use crate::bytecode::Op;
+use crate::datamodel::{Function, Tuple};
+
+/// A function running `ops`, in an empty module.
+pub(crate) fn function(ops: Vec<Op>) -> Function {
+    Function {
+        module: Tuple::new(Vec::new()),
+        ops: ops.into(),
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use crate::datamodel::{Function, Identity};
+
+/// Execution counters for a single function, keyed by its op slice.
+#[derive(Clone, Default)]
+pub struct Hotness {
+    pub invocations: u64,
+    pub back_edges: u64,
+}
+
+/// Decides when a function is hot and how to promote it. `promote` returns
+/// the optimized replacement, or `None` to keep running the original.
+pub trait TieringPolicy {
+    fn is_hot(&self, hotness: &Hotness) -> bool;
+    fn promote(&mut self, func: &Function) -> Option<Function>;
+}
+
+/// A `TieringPolicy` that promotes once either counter reaches a threshold,
+/// using `optimize` to build the replacement.
+pub struct ThresholdPolicy<F: FnMut(&Function) -> Option<Function>> {
+    pub invocations: u64,
+    pub back_edges: u64,
+    pub optimize: F,
+}
+
+impl<F: FnMut(&Function) -> Option<Function>> TieringPolicy for ThresholdPolicy<F> {
+    fn is_hot(&self, hotness: &Hotness) -> bool {
+        hotness.invocations >= self.invocations || hotness.back_edges >= self.back_edges
+    }
+
+    fn promote(&mut self, func: &Function) -> Option<Function> {
+        (self.optimize)(func)
+    }
+}
+
+enum Tier {
+    // the original is kept so its op slice (and therefore its identity)
+    // can't be freed and reused by another function while we track it
+    Baseline {
+        _pin: Function,
+        hotness: Hotness,
+    },
+    Promoted {
+        _pin: Function,
+        promoted: Option<Function>,
+    },
+}
+
+pub struct Tiering {
+    policy: Box<dyn TieringPolicy>,
+    functions: HashMap<usize, Tier>,
+}
+
+impl Tiering {
+    pub fn new(policy: Box<dyn TieringPolicy>) -> Tiering {
+        Tiering {
+            policy,
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Records an invocation, returning the function that should actually
+    /// run (the promoted form, if there is one).
+    pub fn invoke(&mut self, func: Function) -> Function {
+        let promoted = self.record(&func, |h| h.invocations += 1);
+        promoted.unwrap_or(func)
+    }
+
+    /// Records a loop back edge. Promotion triggered here only takes effect
+    /// on the next invocation; frames already running keep their ops.
+    pub fn back_edge(&mut self, func: &Function) {
+        self.record(func, |h| h.back_edges += 1);
+    }
+
+    pub fn hotness(&self, func: &Function) -> Option<&Hotness> {
+        match self.functions.get(&func.identity()) {
+            Some(Tier::Baseline { hotness, .. }) => Some(hotness),
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, func: &Function, bump: impl FnOnce(&mut Hotness)) -> Option<Function> {
+        let tier = self
+            .functions
+            .entry(func.identity())
+            .or_insert_with(|| Tier::Baseline {
+                _pin: func.clone(),
+                hotness: Hotness::default(),
+            });
+        let hotness = match tier {
+            Tier::Promoted { promoted, .. } => return promoted.clone(),
+            Tier::Baseline { hotness, .. } => hotness,
+        };
+        bump(hotness);
+        if !self.policy.is_hot(hotness) {
+            return None;
+        }
+        let promoted = self.policy.promote(func);
+        *tier = Tier::Promoted {
+            _pin: func.clone(),
+            promoted: promoted.clone(),
+        };
+        promoted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Push, Return};
+    use crate::datamodel::Value;
+    use crate::testing::function;
+
+    fn constant(i: i64) -> Function {
+        function(vec![Push(Value::Integer(i)).into(), Return.into()])
+    }
+
+    #[test]
+    fn hot_functions_are_promoted_once() {
+        let (slow, fast) = (constant(1), constant(2));
+        let fast_id = fast.identity();
+        let mut promotions = 0;
+        let policy = ThresholdPolicy {
+            invocations: 3,
+            back_edges: u64::MAX,
+            optimize: move |_: &Function| {
+                promotions += 1;
+                assert_eq!(promotions, 1);
+                Some(fast.clone())
+            },
+        };
+        let mut tiering = Tiering::new(Box::new(policy));
+        for _ in 0..2 {
+            assert_eq!(tiering.invoke(slow.clone()).identity(), slow.identity());
+        }
+        let hotness = tiering.hotness(&slow).unwrap();
+        assert_eq!((hotness.invocations, hotness.back_edges), (2, 0));
+        assert_eq!(tiering.invoke(slow.clone()).identity(), fast_id);
+        assert!(tiering.hotness(&slow).is_none());
+        assert_eq!(tiering.invoke(slow.clone()).identity(), fast_id);
+    }
+
+    #[test]
+    fn back_edges_count_and_policies_can_decline() {
+        let slow = constant(1);
+        let policy = ThresholdPolicy {
+            invocations: u64::MAX,
+            back_edges: 2,
+            optimize: |_: &Function| None,
+        };
+        let mut tiering = Tiering::new(Box::new(policy));
+        tiering.back_edge(&slow);
+        assert_eq!(tiering.hotness(&slow).unwrap().back_edges, 1);
+        tiering.back_edge(&slow);
+        // promoted to nothing: the original keeps running, uncounted
+        assert!(tiering.hotness(&slow).is_none());
+        assert_eq!(tiering.invoke(slow.clone()).identity(), slow.identity());
+    }
+}
@@ -0,0 +1,198 @@
+//! Timers for script callbacks. Scripts register them through the
+//! `natives::timer` natives, which queue requests on the current thread;
+//! the `Scheduler` picks those up, keeps the timers in a `TimerWheel`, and
+//! spawns each callback as a task when the host advances time past its
+//! deadline. Time is whatever the host says it is, so simulations can run
+//! faster or slower than the wall clock.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+use crate::datamodel::Function;
+
+pub type TimerId = u64;
+
+/// Time is kept in whole milliseconds, one wheel slot each.
+pub const WHEEL_SLOTS: usize = 256;
+
+#[derive(Clone)]
+pub struct Timer {
+    pub func: Function,
+    /// Milliseconds between firings, or `None` to fire once.
+    pub interval: Option<u64>,
+    pub priority: u8,
+}
+
+struct Entry {
+    id: TimerId,
+    deadline: u64,
+    timer: Timer,
+}
+
+/// A hashed timing wheel: timers due at millisecond `t` sit in slot
+/// `t % WHEEL_SLOTS`, so inserting and cancelling are constant time and
+/// advancing costs one slot visit per elapsed millisecond.
+pub struct TimerWheel {
+    slots: Vec<Vec<Entry>>,
+    now: u64,
+    live: HashSet<TimerId>,
+}
+
+impl TimerWheel {
+    pub fn new() -> TimerWheel {
+        TimerWheel {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            now: 0,
+            live: HashSet::new(),
+        }
+    }
+
+    /// Milliseconds since the wheel was created.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    /// Schedules `timer` to fire `delay` milliseconds from now (at the
+    /// earliest on the next millisecond).
+    pub fn insert(&mut self, id: TimerId, delay: u64, timer: Timer) {
+        self.live.insert(id);
+        self.schedule(id, self.now.saturating_add(delay.max(1)), timer);
+    }
+
+    fn schedule(&mut self, id: TimerId, deadline: u64, timer: Timer) {
+        let slot = (deadline % WHEEL_SLOTS as u64) as usize;
+        self.slots[slot].push(Entry {
+            id,
+            deadline,
+            timer,
+        });
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        // the entry itself is dropped when its slot next comes around
+        self.live.remove(&id)
+    }
+
+    /// Moves time forward by `ms`, returning the timers that fired, in
+    /// deadline order. Repeating timers are rescheduled from their deadline,
+    /// so they don't drift however coarsely time is advanced.
+    pub fn advance(&mut self, ms: u64) -> Vec<(TimerId, Timer)> {
+        let end = self.now.saturating_add(ms);
+        let mut fired = Vec::new();
+        while self.now < end {
+            if self.live.is_empty() {
+                self.now = end;
+                break;
+            }
+            self.now += 1;
+            let slot = (self.now % WHEEL_SLOTS as u64) as usize;
+            let entries = std::mem::take(&mut self.slots[slot]);
+            for entry in entries {
+                if !self.live.contains(&entry.id) {
+                    continue;
+                }
+                if entry.deadline != self.now {
+                    self.slots[slot].push(entry);
+                    continue;
+                }
+                match entry.timer.interval {
+                    Some(interval) => {
+                        let next = entry.deadline.saturating_add(interval.max(1));
+                        self.schedule(entry.id, next, entry.timer.clone());
+                    }
+                    None => {
+                        self.live.remove(&entry.id);
+                    }
+                }
+                fired.push((entry.id, entry.timer));
+            }
+        }
+        fired
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        TimerWheel::new()
+    }
+}
+
+pub(crate) enum Request {
+    Start {
+        id: TimerId,
+        delay: u64,
+        interval: Option<u64>,
+        func: Function,
+    },
+    Cancel(TimerId),
+}
+
+thread_local! {
+    static REQUESTS: RefCell<Vec<Request>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<TimerId> = const { Cell::new(0) };
+}
+
+/// Queues a new timer, returning the id it will have.
+pub(crate) fn request_start(delay: u64, interval: Option<u64>, func: Function) -> TimerId {
+    let id = NEXT_ID.with(|n| {
+        let id = n.get();
+        n.set(id + 1);
+        id
+    });
+    let request = Request::Start {
+        id,
+        delay,
+        interval,
+        func,
+    };
+    REQUESTS.with(|r| r.borrow_mut().push(request));
+    id
+}
+
+pub(crate) fn request_cancel(id: TimerId) {
+    REQUESTS.with(|r| r.borrow_mut().push(Request::Cancel(id)));
+}
+
+pub(crate) fn take_requests() -> Vec<Request> {
+    REQUESTS.with(|r| std::mem::take(&mut *r.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::Tuple;
+
+    fn timer(interval: Option<u64>) -> Timer {
+        Timer {
+            func: Function {
+                module: Tuple::new(Vec::new()),
+                ops: Vec::new().into(),
+            },
+            interval,
+            priority: 1,
+        }
+    }
+
+    #[test]
+    fn fires_in_deadline_order_across_wraps() {
+        let mut wheel = TimerWheel::new();
+        wheel.insert(0, 300, timer(None));
+        wheel.insert(1, 44, timer(None));
+        wheel.insert(2, 100, timer(Some(100)));
+        assert!(wheel.advance(43).is_empty());
+        let ids =
+            |fired: Vec<(TimerId, Timer)>| fired.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(wheel.advance(300)), [1, 2, 2, 0, 2]);
+        assert!(wheel.cancel(2));
+        assert!(wheel.advance(1000).is_empty());
+        assert!(wheel.is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Moving values between VMs, including VMs on other threads, so one stage
+//! of a pipeline can hand its result to the next without both of them
+//! holding it.
+//!
+//! `Parcel::take` moves a value graph out of the VM it lives in: every
+//! list, map, table, tuple, record and buffer in it is emptied (tuple and
+//! record slots are set to `None`), so references the source still has see
+//! an empty container rather than a second copy. `Parcel::open` rebuilds
+//! the graph in the destination, with containers reached twice, cycles
+//! included, still shared, and symbols interned there by name.
+//!
+//! A parcel is `Send`. A buffer's bytes move without being copied; strings
+//! are immutable and may be shared with values outside the graph, so their
+//! text is copied and the source's are left alone. Only data moves:
+//! functions, iterators, coroutines and host objects are rejected, and a
+//! graph that is rejected is left as it was.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::datamodel::{
+    Buffer, Decimal, Duration, List, Map, Record, Shape, Table, Tag, Timestamp, Tuple, Value,
+    ValueType, Variant,
+};
+use crate::VirtualMachine;
+
+/// Deepest nesting of variants `Parcel::take` will follow. Containers are
+/// walked without recursing, so only variants count towards it.
+pub const MAX_TRANSFER_DEPTH: usize = 256;
+
+pub enum TransferError {
+    /// The graph holds a value of this type, which can't be moved.
+    Unmovable(ValueType),
+    /// Variants are nested deeper than `MAX_TRANSFER_DEPTH`.
+    TooDeep,
+}
+
+enum Item {
+    None,
+    Integer(i64),
+    Real(f64),
+    Decimal(Decimal),
+    Str(Box<str>),
+    Symbol(Box<str>),
+    Timestamp(Timestamp),
+    Duration(Duration),
+    Variant(Tag, Box<Item>),
+    /// The container at this index of `Parcel::nodes`.
+    Node(usize),
+}
+
+enum Node {
+    List(Vec<Item>),
+    Map(Vec<(Item, Item)>),
+    Table(Vec<(u64, Item)>),
+    Record(Vec<(u64, Item)>),
+    Tuple(Vec<Item>),
+    Buffer(Vec<u8>),
+}
+
+/// A value graph moved out of one VM, to be opened in another.
+pub struct Parcel {
+    root: Item,
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Packer {
+    /// Node index by container identity.
+    index: HashMap<usize, usize>,
+    /// The containers, in node order.
+    found: Vec<Value>,
+}
+
+impl Packer {
+    fn item(&mut self, val: &Value, depth: usize) -> Result<Item, TransferError> {
+        if depth > MAX_TRANSFER_DEPTH {
+            return Err(TransferError::TooDeep);
+        }
+        let identity = match val {
+            Value::None => return Ok(Item::None),
+            Value::Integer(i) => return Ok(Item::Integer(*i)),
+            Value::Real(r) => return Ok(Item::Real(*r)),
+            Value::Decimal(d) => return Ok(Item::Decimal(*d)),
+            Value::Str(s) => return Ok(Item::Str((**s).into())),
+            Value::Symbol(s) => return Ok(Item::Symbol((**s.name()).into())),
+            Value::Timestamp(t) => return Ok(Item::Timestamp(*t)),
+            Value::Duration(d) => return Ok(Item::Duration(*d)),
+            Value::Variant(v) => {
+                let payload = self.item(v.payload(), depth + 1)?;
+                return Ok(Item::Variant(v.tag(), Box::new(payload)));
+            }
+            Value::List(l) => Rc::as_ptr(&l.items).cast::<()>() as usize,
+            Value::Map(m) => Rc::as_ptr(&m.inner).cast::<()>() as usize,
+            Value::Table(t) => Rc::as_ptr(&t.inner).cast::<()>() as usize,
+            Value::Record(r) => Rc::as_ptr(&r.values).cast::<()>() as usize,
+            Value::Tuple(t) => Rc::as_ptr(&t.items).cast::<()>() as usize,
+            Value::Buffer(b) => Rc::as_ptr(&b.items).cast::<()>() as usize,
+            val => return Err(TransferError::Unmovable(val.get_type())),
+        };
+        let next = self.found.len();
+        let index = *self.index.entry(identity).or_insert(next);
+        if index == next {
+            self.found.push(val.clone());
+        }
+        Ok(Item::Node(index))
+    }
+
+    fn items(&mut self, vals: &[Value]) -> Result<Vec<Item>, TransferError> {
+        vals.iter().map(|v| self.item(v, 0)).collect()
+    }
+
+    fn fields(&mut self, fields: Vec<(u64, Value)>) -> Result<Vec<(u64, Item)>, TransferError> {
+        fields
+            .into_iter()
+            .map(|(k, v)| Ok((k, self.item(&v, 0)?)))
+            .collect()
+    }
+
+    /// The contents of a container, read without changing it; a buffer's
+    /// bytes are left for `empty` to move.
+    fn node(&mut self, val: &Value) -> Result<Node, TransferError> {
+        Ok(match val {
+            Value::List(l) => Node::List(self.items(&l.to_vec())?),
+            Value::Map(m) => Node::Map(
+                m.entries()
+                    .into_iter()
+                    .map(|(k, v)| Ok((self.item(&k, 0)?, self.item(&v, 0)?)))
+                    .collect::<Result<_, TransferError>>()?,
+            ),
+            Value::Table(t) => Node::Table(self.fields(t.entries())?),
+            Value::Record(r) => Node::Record(self.fields(r.entries())?),
+            Value::Tuple(t) => {
+                let items: Vec<_> = (0..t.len()).filter_map(|i| t.get(i)).collect();
+                Node::Tuple(self.items(&items)?)
+            }
+            _ => Node::Buffer(Vec::new()),
+        })
+    }
+}
+
+/// Empties a container that has been packed, moving a buffer's bytes into
+/// its node.
+fn empty(val: &Value, node: &mut Node) {
+    // the old contents are dropped after the borrow ends, in case
+    // dropping them runs finalizers that look at this container
+    match (val, node) {
+        (Value::List(l), _) => {
+            let items = std::mem::take(&mut *l.items.borrow_mut());
+            drop(items);
+        }
+        (Value::Map(m), _) => {
+            let entries = std::mem::take(&mut *m.inner.borrow_mut());
+            drop(entries);
+        }
+        (Value::Table(t), _) => {
+            let values = std::mem::take(&mut t.inner.borrow_mut().values);
+            t.inner.borrow_mut().shape = Shape::root();
+            drop(values);
+        }
+        (Value::Record(r), _) => {
+            for slot in 0..r.values.len() {
+                r.set_slot(slot, Value::None);
+            }
+        }
+        (Value::Tuple(t), _) => {
+            for i in 0..t.len() {
+                t.set(i, Value::None);
+            }
+        }
+        (Value::Buffer(b), Node::Buffer(bytes)) => {
+            *bytes = std::mem::take(&mut *b.items.borrow_mut())
+        }
+        _ => {}
+    }
+}
+
+impl Parcel {
+    /// Moves `val` and everything it reaches out of the VM it lives in.
+    pub fn take(val: &Value) -> Result<Parcel, TransferError> {
+        let mut packer = Packer::default();
+        let root = packer.item(val, 0)?;
+        let mut nodes = Vec::new();
+        // packing a node can find more, so go by index
+        while nodes.len() < packer.found.len() {
+            let found = packer.found[nodes.len()].clone();
+            nodes.push(packer.node(&found)?);
+        }
+        // only once the whole graph is known to move, so a rejected one
+        // is left intact
+        for (val, node) in packer.found.iter().zip(&mut nodes) {
+            empty(val, node);
+        }
+        Ok(Parcel { root, nodes })
+    }
+
+    /// Rebuilds the graph as values of `vm`.
+    pub fn open(self, vm: &mut VirtualMachine) -> Value {
+        let mut nodes = self.nodes;
+        // every container first, empty, so items can refer to any of them
+        let shells: Vec<Value> = nodes
+            .iter_mut()
+            .map(|node| match node {
+                Node::List(_) => List::new(Vec::new()).into(),
+                Node::Map(_) => Map::new().into(),
+                Node::Table(_) => Table::new().into(),
+                Node::Record(fields) => {
+                    let keys: Vec<_> = fields.iter().map(|(k, _)| *k).collect();
+                    let values = vec![Value::None; keys.len()];
+                    Record::new(Shape::of(&keys), values).unwrap().into()
+                }
+                Node::Tuple(items) => Tuple::new(vec![Value::None; items.len()]).into(),
+                Node::Buffer(bytes) => Buffer::new(std::mem::take(bytes)).into(),
+            })
+            .collect();
+        let mut value = |item: Item| unpack(item, &shells, vm);
+        for (shell, node) in shells.iter().zip(nodes) {
+            match (shell, node) {
+                (Value::List(l), Node::List(items)) => {
+                    let items: Vec<_> = items.into_iter().map(&mut value).collect();
+                    *l.items.borrow_mut() = items;
+                }
+                (Value::Map(m), Node::Map(entries)) => {
+                    for (k, v) in entries {
+                        // the keys came out of a map, so they are keys
+                        let _ = m.insert(value(k), value(v));
+                    }
+                }
+                (Value::Table(t), Node::Table(fields)) => {
+                    for (k, v) in fields {
+                        t.set(k, value(v));
+                    }
+                }
+                (Value::Record(r), Node::Record(fields)) => {
+                    for (slot, (_, v)) in fields.into_iter().enumerate() {
+                        r.set_slot(slot, value(v));
+                    }
+                }
+                (Value::Tuple(t), Node::Tuple(items)) => {
+                    for (i, v) in items.into_iter().enumerate() {
+                        t.set(i, value(v));
+                    }
+                }
+                _ => {}
+            }
+        }
+        value(self.root)
+    }
+}
+
+fn unpack(item: Item, shells: &[Value], vm: &mut VirtualMachine) -> Value {
+    match item {
+        Item::None => Value::None,
+        Item::Integer(i) => Value::Integer(i),
+        Item::Real(r) => Value::Real(r),
+        Item::Decimal(d) => Value::Decimal(d),
+        Item::Str(s) => Value::Str(s.into()),
+        Item::Symbol(name) => Value::Symbol(vm.intern(&name)),
+        Item::Timestamp(t) => Value::Timestamp(t),
+        Item::Duration(d) => Value::Duration(d),
+        Item::Variant(tag, payload) => Variant::new(tag, unpack(*payload, shells, vm)).into(),
+        Item::Node(i) => shells[i].clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::Return;
+    use crate::datamodel::Identity;
+    use crate::testing::function;
+
+    fn vm() -> VirtualMachine {
+        VirtualMachine::new(function(vec![Return.into()]))
+    }
+
+    #[test]
+    fn moved_graphs_are_emptied_at_the_source() {
+        fn is_send<T: Send>() {}
+        is_send::<Parcel>();
+
+        let mut source = vm();
+        let buffer = Buffer::new(vec![1, 2, 3]);
+        let inner = List::new(vec![Value::Str("big".into()), buffer.clone().into()]);
+        let outer = List::new(vec![inner.clone().into(), inner.clone().into()]);
+        let table = Table::new();
+        table.set(7, outer.clone().into());
+        table.set(8, Value::Symbol(source.intern("done")));
+        // a cycle back to the table
+        outer.items.borrow_mut().push(table.clone().into());
+
+        let parcel = Parcel::take(&table.clone().into()).ok().unwrap();
+        assert!(table.is_empty() && outer.is_empty() && inner.is_empty());
+        assert!(buffer.is_empty());
+
+        let mut dest = vm();
+        let moved: Table = parcel.open(&mut dest).try_into().ok().unwrap();
+        let outer: List = moved.get(7).unwrap().try_into().ok().unwrap();
+        let items = outer.to_vec();
+        assert_eq!(items.len(), 3);
+        // shared and cyclic containers are still shared
+        match (&items[0], &items[1], &items[2]) {
+            (Value::List(a), Value::List(b), Value::Table(t)) => {
+                assert!(Rc::ptr_eq(&a.items, &b.items));
+                assert!(Rc::ptr_eq(&t.inner, &moved.inner));
+                assert!(matches!(&a.to_vec()[1], Value::Buffer(b) if b.to_vec() == [1, 2, 3]));
+            }
+            _ => panic!("wrong shape"),
+        }
+        let done = dest.intern("done");
+        assert!(matches!(moved.get(8), Some(Value::Symbol(s)) if s.identity() == done.identity()));
+    }
+
+    #[test]
+    fn unmovable_graphs_are_left_alone() {
+        let list = List::new(vec![
+            Value::Integer(1),
+            function(vec![Return.into()]).into(),
+        ]);
+        assert!(matches!(
+            Parcel::take(&list.clone().into()),
+            Err(TransferError::Unmovable(ValueType::Function))
+        ));
+        assert_eq!(list.len(), 2);
+    }
+}
@@ -0,0 +1,77 @@
+//! Per-VM resource accounting, so a host running scripts for several
+//! tenants can bill each one and spot scripts that hog the machine.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::datamodel::NativeFn;
+
+/// What a `VirtualMachine` has used since it was created or its usage was
+/// last taken (see `VirtualMachine::take_usage`).
+#[derive(Clone, Default)]
+pub struct Usage {
+    /// Ops executed.
+    pub steps: u64,
+    /// Time spent inside `run_until_exited`.
+    pub wall_time: Duration,
+    /// Most values (stack slots and locals, over all frames) held at once.
+    /// Values are not sized, so this stands in for peak memory.
+    pub peak_values: usize,
+    /// Deepest the call stack got.
+    pub peak_frames: usize,
+    /// Native calls, by native.
+    natives: HashMap<usize, u64>,
+}
+
+impl Usage {
+    pub fn native_calls(&self) -> u64 {
+        self.natives.values().sum()
+    }
+
+    pub fn native_calls_to(&self, func: NativeFn) -> u64 {
+        self.natives.get(&(func as usize)).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn count_native(&mut self, func: NativeFn) {
+        *self.natives.entry(func as usize).or_insert(0) += 1;
+    }
+
+    pub(crate) fn observe(&mut self, values: usize, frames: usize) {
+        self.peak_values = self.peak_values.max(values);
+        self.peak_frames = self.peak_frames.max(frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datamodel::Value;
+
+    fn one(_args: Vec<Value>) -> Value {
+        Value::Integer(1)
+    }
+
+    fn two(_args: Vec<Value>) -> Value {
+        Value::Integer(2)
+    }
+
+    fn three(_args: Vec<Value>) -> Value {
+        Value::Integer(3)
+    }
+
+    #[test]
+    fn natives_and_peaks_are_counted() {
+        let mut usage = Usage::default();
+        usage.count_native(one);
+        usage.count_native(two);
+        usage.count_native(one);
+        assert_eq!(usage.native_calls(), 3);
+        assert_eq!(usage.native_calls_to(one), 2);
+        assert_eq!(usage.native_calls_to(two), 1);
+        assert_eq!(usage.native_calls_to(three), 0);
+
+        usage.observe(5, 2);
+        usage.observe(3, 4);
+        assert_eq!((usage.peak_values, usage.peak_frames), (5, 4));
+    }
+}
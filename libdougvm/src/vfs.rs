@@ -0,0 +1,387 @@
+//! The filesystem scripts see. File natives (and `kv`, `csv`) never use
+//! `std::fs` directly: they go through the `Vfs` the host registered for
+//! the thread with `set_vfs`, so a sandboxed script only sees the files the
+//! host chooses to expose.
+//!
+//! Paths are `/`-separated. Every filesystem here except `RealFs`
+//! normalizes them first: `.` is dropped and `..` can't climb above the
+//! root, as under `chroot`. `\` separates too, so a `..\` can't climb out
+//! of a `Jail` over a real Windows filesystem.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::rc::Rc;
+
+pub trait Vfs {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read>>;
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.open(path)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Replaces the file's contents, creating it if needed.
+    fn write(&self, path: &str, bytes: &[u8]) -> io::Result<()>;
+
+    /// Adds to the end of the file, creating it if needed. Durable once it
+    /// returns, where the filesystem can be.
+    fn append(&self, path: &str, bytes: &[u8]) -> io::Result<()>;
+
+    fn remove(&self, path: &str) -> io::Result<()>;
+
+    /// The names of the entries directly inside a directory, sorted.
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+
+    fn exists(&self, path: &str) -> bool;
+}
+
+/// `path` relative to the root, with `.` and `..` resolved.
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no such file: {}", path))
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "read-only filesystem")
+}
+
+/// The host's real filesystem, paths passed through unchanged.
+pub struct RealFs;
+
+impl Vfs for RealFs {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(io::BufReader::new(fs::File::open(path)?)))
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        fs::write(path, bytes)
+    }
+
+    fn append(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        file.write_all(bytes)?;
+        file.sync_data()
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        fs::metadata(path).is_ok()
+    }
+}
+
+/// Files held in memory. Directories exist implicitly while they contain
+/// a file.
+#[derive(Default)]
+pub struct MemoryFs {
+    files: RefCell<BTreeMap<String, Rc<[u8]>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Sets up a file, e.g. before handing the filesystem to a script.
+    pub fn insert(&self, path: &str, bytes: &[u8]) {
+        self.files
+            .borrow_mut()
+            .insert(normalize(path), bytes.into());
+    }
+}
+
+/// The names directly under `dir` among `paths` (all normalized).
+fn children<'a>(dir: &str, paths: impl Iterator<Item = &'a String>) -> BTreeSet<String> {
+    let prefix = match dir {
+        "" => String::new(),
+        dir => format!("{}/", dir),
+    };
+    paths
+        .filter_map(|p| p.strip_prefix(&prefix))
+        .filter_map(|rest| rest.split('/').next())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Vfs for MemoryFs {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read>> {
+        match self.files.borrow().get(&normalize(path)) {
+            Some(bytes) => Ok(Box::new(Cursor::new(bytes.clone()))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        self.insert(path, bytes);
+        Ok(())
+    }
+
+    fn append(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        let file = files.entry(normalize(path)).or_insert_with(|| Rc::from([]));
+        *file = [&file[..], bytes].concat().into();
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        match self.files.borrow_mut().remove(&normalize(path)) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let names = children(&normalize(path), self.files.borrow().keys());
+        match names.is_empty() && !normalize(path).is_empty() {
+            true => Err(not_found(path)),
+            false => Ok(names.into_iter().collect()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        let path = normalize(path);
+        let files = self.files.borrow();
+        files.contains_key(&path) || !children(&path, files.keys()).is_empty()
+    }
+}
+
+/// A read-only `lower` filesystem with writes redirected to memory: the
+/// script sees its own changes, but `lower` is never modified.
+pub struct Overlay {
+    lower: Rc<dyn Vfs>,
+    upper: MemoryFs,
+    /// Files of `lower` the script has removed.
+    removed: RefCell<BTreeSet<String>>,
+}
+
+impl Overlay {
+    pub fn new(lower: Rc<dyn Vfs>) -> Overlay {
+        Overlay {
+            lower,
+            upper: MemoryFs::new(),
+            removed: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    /// Whether `path` (normalized) still reads through to `lower`.
+    fn in_lower(&self, path: &str) -> bool {
+        !self.removed.borrow().contains(path) && self.lower.exists(path)
+    }
+}
+
+impl Vfs for Overlay {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read>> {
+        let path = normalize(path);
+        match self.upper.exists(&path) || !self.in_lower(&path) {
+            true => self.upper.open(&path),
+            false => self.lower.open(&path),
+        }
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        self.upper.write(path, bytes)
+    }
+
+    fn append(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = normalize(path);
+        if !self.upper.exists(&path) && self.in_lower(&path) {
+            self.upper.insert(&path, &self.lower.read(&path)?);
+        }
+        self.upper.append(&path, bytes)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let path = normalize(path);
+        let in_lower = self.in_lower(&path);
+        match (self.upper.remove(&path), in_lower) {
+            (_, true) => {
+                self.removed.borrow_mut().insert(path);
+                Ok(())
+            }
+            (result, false) => result,
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let path = normalize(path);
+        let prefix = match path.as_str() {
+            "" => String::new(),
+            dir => format!("{}/", dir),
+        };
+        let mut names: BTreeSet<String> = self
+            .upper
+            .list(&path)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        let lower = self.lower.list(&path);
+        for name in lower.as_ref().into_iter().flatten() {
+            if !self
+                .removed
+                .borrow()
+                .contains(&format!("{}{}", prefix, name))
+            {
+                names.insert(name.clone());
+            }
+        }
+        match (names.is_empty(), lower) {
+            (true, Err(e)) => Err(e),
+            _ => Ok(names.into_iter().collect()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        let path = normalize(path);
+        self.upper.exists(&path) || self.in_lower(&path)
+    }
+}
+
+/// Confines a script to the `root` directory of `inner`, as `chroot`
+/// would: `/` is `root` and `..` can't climb out of it. Symlinks inside
+/// `root` on a `RealFs` are still followed.
+pub struct Jail {
+    inner: Rc<dyn Vfs>,
+    root: String,
+}
+
+impl Jail {
+    pub fn new(inner: Rc<dyn Vfs>, root: &str) -> Jail {
+        Jail {
+            inner,
+            root: root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn resolve(&self, path: &str) -> String {
+        format!("{}/{}", self.root, normalize(path))
+    }
+}
+
+impl Vfs for Jail {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read>> {
+        self.inner.open(&self.resolve(path))
+    }
+
+    fn write(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write(&self.resolve(path), bytes)
+    }
+
+    fn append(&self, path: &str, bytes: &[u8]) -> io::Result<()> {
+        self.inner.append(&self.resolve(path), bytes)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(&self.resolve(path))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.inner.list(&self.resolve(path))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(&self.resolve(path))
+    }
+}
+
+/// Refuses every write; reads go to `inner`.
+pub struct ReadOnly(pub Rc<dyn Vfs>);
+
+impl Vfs for ReadOnly {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read>> {
+        self.0.open(path)
+    }
+
+    fn write(&self, _path: &str, _bytes: &[u8]) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn append(&self, _path: &str, _bytes: &[u8]) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn remove(&self, _path: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.0.list(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.0.exists(path)
+    }
+}
+
+thread_local! {
+    static VFS: RefCell<Rc<dyn Vfs>> = RefCell::new(Rc::new(RealFs));
+}
+
+/// Registers the filesystem for natives on this thread, returning the
+/// previous one.
+pub fn set_vfs(vfs: Rc<dyn Vfs>) -> Rc<dyn Vfs> {
+    VFS.with(|v| std::mem::replace(&mut *v.borrow_mut(), vfs))
+}
+
+pub fn vfs() -> Rc<dyn Vfs> {
+    VFS.with(|v| v.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_and_jail_confine_changes() {
+        let base = Rc::new(MemoryFs::new());
+        base.insert("srv/data/a.txt", b"a");
+        base.insert("srv/data/b.txt", b"b");
+        base.insert("etc/secret", b"s");
+        let jail = Jail::new(base.clone(), "/srv");
+        assert!(jail.exists("../../etc/../data/a.txt"));
+        assert!(!jail.exists("/../etc/secret"));
+        let overlay = Overlay::new(Rc::new(jail));
+        overlay.append("data/a.txt", b"!").unwrap();
+        overlay.write("data/c.txt", b"c").unwrap();
+        overlay.remove("data/b.txt").unwrap();
+        assert_eq!(overlay.read("data/a.txt").unwrap(), b"a!");
+        assert_eq!(overlay.list("data").unwrap(), ["a.txt", "c.txt"]);
+        assert!(overlay.open("data/b.txt").is_err());
+        // nothing reached the base
+        assert_eq!(base.read("srv/data/a.txt").unwrap(), b"a");
+        assert!(base.exists("srv/data/b.txt") && !base.exists("srv/data/c.txt"));
+        assert!(ReadOnly(base).write("x", b"").is_err());
+    }
+
+    #[test]
+    fn backslashes_separate_too() {
+        assert_eq!(normalize("..\\..\\etc\\secret"), "etc/secret");
+        assert_eq!(normalize("a\\.\\b/..\\c"), "a/c");
+    }
+}
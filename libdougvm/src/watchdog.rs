@@ -0,0 +1,216 @@
+//! Hot-loop detection. A fuel limit stops a runaway script eventually, but
+//! says nothing about where it was stuck. With a `Watchdog` installed, a
+//! VM counts how often each loop head (a function and the op a backward
+//! jump lands on) is reached, and once one passes the threshold without a
+//! progress signal it pauses with `VmState::HotLoop` and a `HotLoop` report
+//! instead.
+//!
+//! Progress is signalled by the script calling the `progress` native, or
+//! by the host calling `VirtualMachine::progress`, e.g. after servicing a
+//! stream. Either resets every count.
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+
+use crate::bytecode::graph::describe;
+use crate::bytecode::spec::render;
+use crate::bytecode::Op;
+use crate::datamodel::{Function, Identity, Value};
+
+thread_local! {
+    static PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Signals progress to the watchdog of the VM running on this thread.
+pub fn progress() {
+    PROGRESS.with(|p| p.set(true));
+}
+
+pub(crate) fn take_progress() -> bool {
+    PROGRESS.with(|p| p.replace(false))
+}
+
+/// An op the VM ran, as kept in the recent trace.
+#[derive(Clone)]
+pub struct TraceEntry {
+    /// Frames below this one; 0 is the outermost.
+    pub depth: usize,
+    pub function: Function,
+    pub cursor: usize,
+}
+
+impl TraceEntry {
+    pub fn op(&self) -> Option<&Op> {
+        self.function.ops.get(self.cursor)
+    }
+}
+
+pub struct Watchdog {
+    threshold: u64,
+    trace_len: usize,
+    counts: HashMap<(usize, usize), u64>,
+    trace: VecDeque<TraceEntry>,
+}
+
+impl Watchdog {
+    /// Trips when a loop head is reached more than `threshold` times
+    /// between progress signals, keeping the last `trace_len` ops run.
+    pub fn new(threshold: u64, trace_len: usize) -> Watchdog {
+        Watchdog {
+            threshold,
+            trace_len,
+            counts: HashMap::new(),
+            trace: VecDeque::with_capacity(trace_len),
+        }
+    }
+
+    /// A watchdog with the same settings that has seen nothing yet.
+    pub(crate) fn fresh(&self) -> Watchdog {
+        Watchdog::new(self.threshold, self.trace_len)
+    }
+
+    pub(crate) fn record(&mut self, depth: usize, function: &Function, cursor: usize) {
+        if self.trace_len == 0 {
+            return;
+        }
+        if self.trace.len() == self.trace_len {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(TraceEntry {
+            depth,
+            function: function.clone(),
+            cursor,
+        });
+    }
+
+    /// Counts a backward jump to `cursor`, returning the iterations so far
+    /// if that trips the watchdog. The count starts again afterwards, so a
+    /// resumed VM pauses again if the loop keeps spinning.
+    pub(crate) fn back_edge(&mut self, function: &Function, cursor: usize) -> Option<u64> {
+        let count = self
+            .counts
+            .entry((function.identity(), cursor))
+            .or_default();
+        *count += 1;
+        match *count > self.threshold {
+            true => Some(std::mem::take(count)),
+            false => None,
+        }
+    }
+
+    pub fn progress(&mut self) {
+        self.counts.clear();
+    }
+
+    pub(crate) fn trace(&self) -> Vec<TraceEntry> {
+        self.trace.iter().cloned().collect()
+    }
+}
+
+/// Where a VM was spinning when its watchdog tripped.
+pub struct HotLoop {
+    pub function: Function,
+    /// The loop head: the op the backward jump lands on.
+    pub cursor: usize,
+    pub iterations: u64,
+    /// As `VirtualMachine::backtrace`.
+    pub backtrace: String,
+    pub locals: Vec<Value>,
+    pub stack: Vec<Value>,
+    /// The last ops run, oldest first.
+    pub trace: Vec<TraceEntry>,
+}
+
+impl HotLoop {
+    /// A human-readable dump for logs.
+    pub fn render(&self) -> String {
+        let mut out = format!(
+            "hot loop at op {} ({} iterations without progress)\n",
+            self.cursor, self.iterations
+        );
+        out.push_str(&self.backtrace);
+        out.push_str("locals:\n");
+        for (i, val) in self.locals.iter().enumerate() {
+            out.push_str(&format!("  {} = {}\n", i, render(val)));
+        }
+        out.push_str("stack (top last):\n");
+        for val in &self.stack {
+            out.push_str(&format!("  {}\n", render(val)));
+        }
+        out.push_str("recent ops:\n");
+        for entry in &self.trace {
+            // the cursor ran off the end of the function
+            let op = match entry.op() {
+                Some(op) => describe(op, entry.cursor, entry.function.ops.len()),
+                None => "(end)".to_string(),
+            };
+            let indent = "  ".repeat(entry.depth);
+            out.push_str(&format!("  {}{:>4}  {}\n", indent, entry.cursor, op));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ops::{Call, IncJumpLt, Pop, Push, Return, Store};
+    use crate::datamodel::Tuple;
+    use crate::{natives, VirtualMachine, VmState};
+
+    /// Counts local 1 up to 10, calling `progress` each time round if asked.
+    fn counter(signal: bool) -> VirtualMachine {
+        let mut ops: Vec<Op> = vec![
+            Push(Value::Integer(0)).into(),
+            Store(1).into(),
+            Push(Value::Integer(10)).into(),
+            Store(2).into(),
+        ];
+        if signal {
+            ops.push(Push(Value::NativeFn(natives::watchdog::progress)).into());
+            ops.push(Call(0).into());
+            ops.push(Pop.into());
+        }
+        // back to the top of the loop, op 4
+        let offset = 4 - (ops.len() as i32 + 1);
+        ops.push(
+            IncJumpLt {
+                local: 1,
+                limit: 2,
+                offset,
+            }
+            .into(),
+        );
+        ops.push(Push(Value::None).into());
+        ops.push(Return.into());
+        let mut vm = VirtualMachine::new(Function {
+            module: Tuple::new(Vec::new()),
+            ops: ops.into(),
+        });
+        vm.set_watchdog(Watchdog::new(5, 3));
+        vm
+    }
+
+    #[test]
+    fn pauses_a_spinning_loop() {
+        let mut vm = counter(false);
+        let report = match vm.run_until_blocked() {
+            Ok(VmState::HotLoop(report)) => report,
+            _ => panic!("expected the watchdog to trip"),
+        };
+        assert_eq!((report.cursor, report.iterations), (4, 6));
+        assert!(matches!(report.locals[1], Value::Integer(6)));
+        assert_eq!(report.trace.len(), 3);
+        assert!(report
+            .render()
+            .starts_with("hot loop at op 4 (6 iterations"));
+        // resuming runs the loop to completion, under a fresh count
+        assert!(matches!(vm.run_until_blocked(), Ok(VmState::Exited(_))));
+    }
+
+    #[test]
+    fn progress_keeps_it_quiet() {
+        let mut vm = counter(true);
+        assert!(matches!(vm.run_until_exited(), Ok(Value::None)));
+    }
+}